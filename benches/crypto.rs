@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use bwbio::bench::{sample_unlock_request, unlock_round_trip};
+use bwbio::crypto::{Aes256CbcHmacKey, SecretString};
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+
+fn message_crypto(c: &mut Criterion) {
+    let secret = Aes256CbcHmacKey::new().unwrap();
+    let payload = vec![0u8; 256];
+    let enc_cbc = secret.encrypt(&payload).unwrap();
+    let enc_gcm = secret.encrypt_gcm(&payload).unwrap();
+
+    c.bench_function("encrypt_cbc_hmac_256b", |b| {
+        b.iter(|| secret.encrypt(black_box(&payload)).unwrap())
+    });
+    c.bench_function("decrypt_cbc_hmac_256b", |b| {
+        b.iter(|| secret.decrypt(black_box(&enc_cbc)).unwrap())
+    });
+    c.bench_function("encrypt_gcm_256b", |b| {
+        b.iter(|| secret.encrypt_gcm(black_box(&payload)).unwrap())
+    });
+    c.bench_function("decrypt_gcm_256b", |b| {
+        b.iter(|| secret.decrypt(black_box(&enc_gcm)).unwrap())
+    });
+}
+
+fn unlock(c: &mut Criterion) {
+    let secret = Aes256CbcHmacKey::new().unwrap();
+    let request = sample_unlock_request(&secret).unwrap();
+    let user_key = SecretString::from("A".repeat(88));
+    c.bench_function("unlock_round_trip", |b| {
+        b.iter(|| unlock_round_trip(&secret, black_box(&request), &user_key).unwrap())
+    });
+}
+
+criterion_group!(benches, message_crypto, unlock);
+criterion_main!(benches);
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::config::config;
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{OpenOptions, create_dir_all, read_to_string},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+};
+
+/// What happened to a stored key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditAction {
+    Import,
+    Export,
+    ExportFailed,
+    Delete,
+    /// Recorded for both the old and the new user id.
+    Rename,
+}
+
+impl Display for AuditAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuditAction::Import => "import",
+            AuditAction::Export => "export",
+            AuditAction::ExportFailed => "export failed",
+            AuditAction::Delete => "delete",
+            AuditAction::Rename => "rename",
+        })
+    }
+}
+
+/// Which part of bwbio asked for the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditSource {
+    #[default]
+    Cli,
+    Tui,
+    /// The native messaging host, i.e. a browser unlock.
+    Browser,
+}
+
+impl Display for AuditSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuditSource::Cli => "CLI",
+            AuditSource::Tui => "TUI",
+            AuditSource::Browser => "browser host",
+        })
+    }
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    /// Unix seconds.
+    pub time: u64,
+    pub action: AuditAction,
+    pub user_id: String,
+    pub source: AuditSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Append-only JSON lines file recording key imports, exports and deletions. Lives at
+/// the configured `audit_log` (or `BW_AUDIT_LOG`), or `.audit.log` in the key directory.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(bw_key_directory: PathBuf) -> Self {
+        let path = config()
+            .audit_log()
+            .unwrap_or_else(|| bw_key_directory.join(".audit.log"));
+        Self { path }
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // A single write of a whole line, so concurrent hosts don't interleave entries.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+        Ok(())
+    }
+
+    /// All entries, oldest first. Lines that don't parse are skipped.
+    pub fn entries(&self) -> Result<Vec<AuditEntry>> {
+        match read_to_string(&self.path) {
+            Ok(s) => Ok(s
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
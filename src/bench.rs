@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::crypto::{Aes256CbcHmacKey, SecretBytes, SecretString};
+use crate::error::Result;
+use crate::proto::{
+    EncString, EncryptedMessage, ResponseData, ResponseMessage, validate_encrypted_message,
+};
+use serde_json::{Value, from_slice, from_value, json, to_vec};
+use std::time::{Duration, Instant};
+
+/// Encrypts an `unlockWithBiometricsForUser` request the way the extension would.
+pub fn sample_unlock_request(secret: &Aes256CbcHmacKey) -> Result<EncString> {
+    let request = json!({
+        "command": "unlockWithBiometricsForUser",
+        "userId": "00000000-0000-0000-0000-000000000000",
+        "messageId": 1,
+        "timestamp": 0
+    });
+    secret.encrypt(&to_vec(&request)?)
+}
+
+/// Host-side work of one unlock: decrypt and validate the request, then build and encrypt the
+/// reply carrying `user_key`. Windows Hello and key unwrapping are not included.
+pub fn unlock_round_trip(
+    secret: &Aes256CbcHmacKey,
+    request: &EncString,
+    user_key: &SecretString,
+) -> Result<EncString> {
+    let decrypted = from_slice::<Value>(&secret.decrypt(request)?)?;
+    validate_encrypted_message(&decrypted)?;
+    let msg = from_value::<EncryptedMessage>(decrypted)?;
+    let response = ResponseMessage::with_key(
+        msg.command().name(),
+        msg.message_id().clone(),
+        ResponseData::Bool(true),
+        Some(user_key.clone()),
+    );
+    let plaintext = SecretBytes::from(to_vec(&response)?);
+    secret.encrypt_with(request.enc_type()?, plaintext.expose())
+}
+
+pub struct BenchResult {
+    pub name: &'static str,
+    pub iterations: u32,
+    pub mean: Duration,
+}
+
+fn measure(
+    name: &'static str,
+    iterations: u32,
+    mut f: impl FnMut() -> Result<()>,
+) -> Result<BenchResult> {
+    f()?; // warm up
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f()?;
+    }
+    Ok(BenchResult {
+        name,
+        iterations,
+        mean: start.elapsed() / iterations.max(1),
+    })
+}
+
+/// Times the message crypto and a full unlock round trip with a throwaway key.
+pub fn run(iterations: u32) -> Result<Vec<BenchResult>> {
+    let secret = Aes256CbcHmacKey::new()?;
+    let request = sample_unlock_request(&secret)?;
+    let user_key = SecretString::from("A".repeat(88));
+    let payload = vec![0u8; 256];
+    Ok(vec![
+        measure("encrypt (AES-CBC-HMAC)", iterations, || {
+            secret.encrypt(&payload).map(drop)
+        })?,
+        measure("encrypt (AES-GCM)", iterations, || {
+            secret.encrypt_gcm(&payload).map(drop)
+        })?,
+        measure("decrypt request", iterations, || {
+            secret.decrypt(&request).map(drop).map_err(Into::into)
+        })?,
+        measure("unlock round trip", iterations, || {
+            unlock_round_trip(&secret, &request, &user_key).map(drop)
+        })?,
+    ])
+}
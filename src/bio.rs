@@ -1,77 +1,596 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
- 
+
+use crate::presence::{BiometricVerifier, PresenceError, biometric_only_selected};
+use crate::proto::BiometricsStatus;
 use std::{
-    thread::{sleep, spawn},
-    time::Duration,
+    collections::HashMap,
+    mem::size_of,
+    process, ptr,
+    sync::OnceLock,
+    thread::{JoinHandle, sleep, spawn},
+    time::{Duration, Instant},
 };
 use windows::{
     Security::Credentials::UI::{
         UserConsentVerificationResult, UserConsentVerifier, UserConsentVerifierAvailability,
     },
     Win32::{
+        Devices::BiometricFramework::{
+            WINBIO_IDENTITY, WINBIO_POOL_SYSTEM, WINBIO_UNIT_SCHEMA, WinBioCancel,
+            WinBioCloseSession, WinBioEnumBiometricUnits, WinBioFree, WinBioIdentify,
+            WinBioOpenSession,
+        },
+        Foundation::{CloseHandle, HANDLE, HWND, LPARAM},
+        Security::{EqualSid, GetTokenInformation, PSID, TOKEN_QUERY, TOKEN_USER, TokenUser},
         System::{
-            Threading::{AttachThreadInput, GetCurrentThreadId},
+            Console::GetConsoleWindow,
+            Diagnostics::ToolHelp::{
+                CreateToolhelp32Snapshot, PROCESSENTRY32W, Process32FirstW, Process32NextW,
+                TH32CS_SNAPPROCESS,
+            },
+            RemoteDesktop::{
+                WTS_CURRENT_SERVER_HANDLE, WTS_CURRENT_SESSION, WTSClientProtocolType,
+                WTSFreeMemory, WTSQuerySessionInformationW,
+            },
+            Threading::{GetCurrentProcess, OpenProcessToken},
             WinRT::IUserConsentVerifierInterop,
         },
-        UI::{
-            Input::KeyboardAndMouse::SetFocus,
-            WindowsAndMessaging::{
-                BringWindowToTop, FindWindowW, GetForegroundWindow, GetWindowThreadProcessId,
-                HWND_DESKTOP, SetForegroundWindow,
-            },
+        UI::WindowsAndMessaging::{
+            CreateWindowExW, DestroyWindow, DispatchMessageW, EnumWindows, GW_OWNER,
+            GetForegroundWindow, GetSystemMetrics, GetWindow, GetWindowThreadProcessId,
+            HWND_DESKTOP, IsWindow, IsWindowVisible, MSG, PM_REMOVE, PeekMessageW, SM_CXSCREEN,
+            SM_CYSCREEN, SetForegroundWindow, TranslateMessage, WINDOW_STYLE, WS_CAPTION,
+            WS_EX_DLGMODALFRAME, WS_EX_TOPMOST, WS_POPUP, WS_SYSMENU, WS_VISIBLE,
         },
     },
-    core::{HSTRING, factory, w},
+    core::{BOOL, GUID, HSTRING, PWSTR, factory, w},
 };
-use windows_future::IAsyncOperation;
+use windows_future::{AsyncStatus, IAsyncOperation};
+use windows_registry::LOCAL_MACHINE;
+
+const ESS_REGISTRY_KEY: &str =
+    r"SYSTEM\CurrentControlSet\Control\DeviceGuard\Scenarios\SecureBiometrics";
+
+// winbio_types.h
+const WINBIO_TYPE_FACIAL_FEATURES: u32 = 0x2;
+const WINBIO_TYPE_FINGERPRINT: u32 = 0x8;
+const WINBIO_ID_TYPE_SID: u32 = 3;
+const WINBIO_FLAG_DEFAULT: u32 = 0;
+const WINBIO_DB_DEFAULT: *const GUID = 1 as _;
+// winuser.h
+const SS_CENTER: u32 = 0x1;
+const SS_CENTERIMAGE: u32 = 0x200;
+/// How long a biometric-only check waits for a finger or face.
+const BIOMETRIC_SAMPLE_TIMEOUT: Duration = Duration::from_secs(30);
+const PROMPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static PROMPT_CANCELLED: OnceLock<fn() -> bool> = OnceLock::new();
+
+/// Windows Hello, or the fingerprint or face sensor alone in biometric-only mode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WindowsHello;
+
+impl BiometricVerifier for WindowsHello {
+    fn status(&self) -> BiometricsStatus {
+        get_biometrics_status()
+    }
+
+    fn authenticate(&self, reason: &str) -> bool {
+        authenticate_with_biometrics(reason)
+    }
+
+    fn try_authenticate(&self, reason: &str) -> Result<(), PresenceError> {
+        let cancelled = PROMPT_CANCELLED.get().copied().unwrap_or(|| false);
+        authenticate_with_biometrics_async(reason).finish(|| !cancelled())
+    }
+}
+
+/// [`BiometricVerifier::verify_presence`] with [`WindowsHello`].
+pub fn verify_user_presence(reason: &str) -> Result<(), PresenceError> {
+    WindowsHello.verify_presence(reason)
+}
+
+/// Installs a check that blocking prompts poll while they wait; once it returns false the
+/// prompt is cancelled and counts as failed. The native messaging host uses this to close the
+/// dialog when the browser goes away.
+pub fn cancel_prompts_when(cancelled: fn() -> bool) {
+    let _ = PROMPT_CANCELLED.set(cancelled);
+}
+
+/// Shows the Windows Hello prompt and waits for it, or until the check installed with
+/// [`cancel_prompts_when`] cancels it.
+pub fn authenticate_with_biometrics(reason: &str) -> bool {
+    let cancelled = PROMPT_CANCELLED.get().copied().unwrap_or(|| false);
+    authenticate_with_biometrics_async(reason).wait_while(|| !cancelled())
+}
+
+/// Shows the Windows Hello consent dialog with `reason` as its message, owned by the window
+/// of the browser (or console) that started bwbio so it opens attached to it and focused. In
+/// biometric-only mode the sensor is read directly instead, as the dialog also accepts the PIN.
+/// Returns at once; dropping the handle cancels the prompt.
+pub fn authenticate_with_biometrics_async(reason: &str) -> BiometricPrompt {
+    if biometric_only_selected() {
+        return BiometricPrompt::sample(reason);
+    }
+    // Without a window to attach to, the dialog opens on its own as Windows places it.
+    let owner = owner_window().unwrap_or(HWND_DESKTOP);
+    let op = unsafe {
+        factory::<UserConsentVerifier, IUserConsentVerifierInterop>().and_then(|interop| {
+            interop
+                .RequestVerificationForWindowAsync::<IAsyncOperation<UserConsentVerificationResult>>(
+                    owner,
+                    &HSTRING::from(reason),
+                )
+        })
+    };
+    BiometricPrompt(op.map_or(Prompt::Failed, Prompt::Consent))
+}
+
+/// A running presence check. Cancelled when dropped before it completes.
+pub struct BiometricPrompt(Prompt);
+
+enum Prompt {
+    Consent(IAsyncOperation<UserConsentVerificationResult>),
+    /// A Windows Biometric Framework identify running on `identify`.
+    Sample {
+        session: u32,
+        identify: Option<JoinHandle<Result<(), PresenceError>>>,
+        deadline: Instant,
+        /// Why the sample was refused, once it was.
+        error: Option<PresenceError>,
+    },
+    /// The prompt could not be shown.
+    Failed,
+}
+
+impl BiometricPrompt {
+    /// Waits for a fingerprint or face sample through the Windows Biometric Framework and
+    /// checks it belongs to the current Windows user. The system pool only serves the
+    /// foreground process and has no dialog of its own, so bwbio shows a window asking for the
+    /// sample and brings it to the front; the check fails at once when that isn't allowed.
+    fn sample(reason: &str) -> Self {
+        let Some(factor) = biometric_factor() else {
+            return Self(Prompt::Failed);
+        };
+        let Ok(session) = (unsafe {
+            WinBioOpenSession(
+                factor,
+                WINBIO_POOL_SYSTEM,
+                WINBIO_FLAG_DEFAULT,
+                None,
+                Some(WINBIO_DB_DEFAULT),
+            )
+        }) else {
+            return Self(Prompt::Failed);
+        };
+        let sensor = if factor == WINBIO_TYPE_FINGERPRINT {
+            "touch the fingerprint reader"
+        } else {
+            "look at the camera"
+        };
+        let message = format!("{reason}: {sensor}");
+        let identify = spawn(move || {
+            let window = SampleWindow::show(&message);
+            if !window.is_foreground() {
+                return Err(PresenceError::NoFocus);
+            }
+            let identify = spawn(move || {
+                let mut identity = WINBIO_IDENTITY::default();
+                let identified =
+                    unsafe { WinBioIdentify(session, None, Some(&mut identity), None, None) };
+                identified.is_ok()
+                    && identity.Type == WINBIO_ID_TYPE_SID
+                    && is_current_user(&identity)
+            });
+            while !identify.is_finished() {
+                // Closing the window cancels the check.
+                if !window.pump() {
+                    unsafe {
+                        let _ = WinBioCancel(session);
+                    }
+                }
+                sleep(PROMPT_POLL_INTERVAL);
+            }
+            match identify.join() {
+                Ok(true) => Ok(()),
+                _ => Err(PresenceError::Failed),
+            }
+        });
+        Self(Prompt::Sample {
+            session,
+            identify: Some(identify),
+            deadline: Instant::now() + BIOMETRIC_SAMPLE_TIMEOUT,
+            error: None,
+        })
+    }
+
+    /// The outcome once the prompt has finished, `None` while it is still up.
+    pub fn poll(&mut self) -> Option<bool> {
+        match &mut self.0 {
+            Prompt::Consent(op) => match op.Status() {
+                Ok(AsyncStatus::Started) => None,
+                Ok(AsyncStatus::Completed) => {
+                    Some(op.GetResults() == Ok(UserConsentVerificationResult::Verified))
+                }
+                _ => Some(false),
+            },
+            Prompt::Sample {
+                identify,
+                deadline,
+                error,
+                ..
+            } => match identify.take_if(|h| h.is_finished()) {
+                Some(h) => match h.join() {
+                    Ok(Ok(())) => Some(true),
+                    Ok(Err(e)) => {
+                        error.get_or_insert(e);
+                        Some(false)
+                    }
+                    Err(_) => Some(false),
+                },
+                None if identify.is_none() => Some(false),
+                None => {
+                    if Instant::now() >= *deadline && error.is_none() {
+                        *error = Some(PresenceError::TimedOut(BIOMETRIC_SAMPLE_TIMEOUT.as_secs()));
+                        self.cancel();
+                    }
+                    None
+                }
+            },
+            Prompt::Failed => Some(false),
+        }
+    }
+
+    /// Closes the prompt; it then finishes as failed.
+    pub fn cancel(&self) {
+        match &self.0 {
+            Prompt::Consent(op) => {
+                let _ = op.Cancel();
+            }
+            Prompt::Sample { session, .. } => unsafe {
+                let _ = WinBioCancel(*session);
+            },
+            Prompt::Failed => {}
+        }
+    }
+
+    pub fn wait(self) -> bool {
+        self.wait_while(|| true)
+    }
+
+    /// Waits for the prompt, cancelling it as soon as `keep_waiting` returns false.
+    pub fn wait_while(mut self, keep_waiting: impl FnMut() -> bool) -> bool {
+        self.finish(keep_waiting).is_ok()
+    }
+
+    /// [`BiometricPrompt::wait_while`], with the reason when the user wasn't verified.
+    fn finish(&mut self, mut keep_waiting: impl FnMut() -> bool) -> Result<(), PresenceError> {
+        loop {
+            match self.poll() {
+                Some(true) => return Ok(()),
+                Some(false) => {
+                    return Err(match &mut self.0 {
+                        Prompt::Sample { error, .. } => error.take(),
+                        _ => None,
+                    }
+                    .unwrap_or(PresenceError::Failed));
+                }
+                None => {}
+            }
+            if !keep_waiting() {
+                self.cancel();
+            }
+            sleep(PROMPT_POLL_INTERVAL);
+        }
+    }
+}
+
+impl Drop for BiometricPrompt {
+    fn drop(&mut self) {
+        if self.poll().is_none() {
+            self.cancel();
+        }
+        if let Prompt::Sample {
+            session, identify, ..
+        } = &mut self.0
+        {
+            if let Some(h) = identify.take() {
+                let _ = h.join();
+            }
+            unsafe {
+                let _ = WinBioCloseSession(*session);
+            }
+        }
+    }
+}
+
+/// Biometrics status plus the machine settings that change how Windows Hello behaves for bwbio.
+#[derive(Debug, Clone, Copy)]
+pub struct BiometricsDetails {
+    pub status: BiometricsStatus,
+    /// Windows Hello Enhanced Sign-in Security: face and fingerprint matching is isolated with
+    /// virtualization-based security.
+    pub enhanced_sign_in_security: bool,
+    pub biometric_only: bool,
+}
 
-pub fn authenticate_with_biometrics() -> bool {
-    spawn(|| {
-        for _ in 0..40 {
-            sleep(Duration::from_millis(50));
-            center_security_prompt();
+impl BiometricsDetails {
+    /// Explanations worth showing next to the status.
+    pub fn notes(&self) -> Vec<&'static str> {
+        let mut notes = Vec::new();
+        if self.enhanced_sign_in_security {
+            notes.push(
+                "Enhanced Sign-in Security is on: secure biometric sensors only serve Windows sign-in, and a gesture-bound CNG key may ask for the PIN instead of biometrics",
+            );
+            if self.biometric_only {
+                notes.push(
+                    "BW_BIOMETRIC_ONLY can't read the sensors while Enhanced Sign-in Security is on, so unlocks fail",
+                );
+            }
         }
-    });
+        notes
+    }
+}
+
+pub fn biometrics_details() -> BiometricsDetails {
+    BiometricsDetails {
+        status: get_biometrics_status(),
+        enhanced_sign_in_security: enhanced_sign_in_security_enabled(),
+        biometric_only: biometric_only_selected(),
+    }
+}
+
+/// Whether Windows Hello Enhanced Sign-in Security (VBS-isolated biometrics) is enabled.
+pub fn enhanced_sign_in_security_enabled() -> bool {
+    LOCAL_MACHINE
+        .open(ESS_REGISTRY_KEY)
+        .and_then(|key| key.get_u32("Enabled"))
+        .is_ok_and(|enabled| enabled == 1)
+}
+
+pub fn get_biometrics_status() -> BiometricsStatus {
+    // Windows Hello may report itself available over RDP and then fail or prompt on the
+    // remote machine's console, so check this first.
+    if is_remote_session() {
+        return BiometricsStatus::RemoteSession;
+    }
+    if biometric_only_selected() {
+        // ESS keeps its secure sensors away from the Windows Biometric Framework's system pool.
+        return match biometric_factor() {
+            Some(_) if !enhanced_sign_in_security_enabled() => BiometricsStatus::Available,
+            _ => BiometricsStatus::HardwareUnavailable,
+        };
+    }
+    UserConsentVerifier::CheckAvailabilityAsync()
+        .and_then(|async_op| async_op.get())
+        .map_or(
+            BiometricsStatus::PlatformUnsupported,
+            |availability| match availability {
+                UserConsentVerifierAvailability::Available => BiometricsStatus::Available,
+                UserConsentVerifierAvailability::DeviceNotPresent
+                | UserConsentVerifierAvailability::DeviceBusy => {
+                    BiometricsStatus::HardwareUnavailable
+                }
+                UserConsentVerifierAvailability::NotConfiguredForUser => {
+                    BiometricsStatus::NotEnrolled
+                }
+                UserConsentVerifierAvailability::DisabledByPolicy => {
+                    BiometricsStatus::DisabledByPolicy
+                }
+                _ => BiometricsStatus::PlatformUnsupported,
+            },
+        )
+}
+
+/// Whether the current session is a Remote Desktop one.
+pub fn is_remote_session() -> bool {
     unsafe {
-        factory::<UserConsentVerifier, IUserConsentVerifierInterop>()
-            .unwrap()
-            .RequestVerificationForWindowAsync::<IAsyncOperation<UserConsentVerificationResult>>(
-                HWND_DESKTOP,
-                &HSTRING::new(),
-            )
-            .is_ok_and(|async_op| async_op.get() == Ok(UserConsentVerificationResult::Verified))
+        let mut protocol = PWSTR::null();
+        let mut len = 0;
+        if WTSQuerySessionInformationW(
+            Some(WTS_CURRENT_SERVER_HANDLE),
+            WTS_CURRENT_SESSION,
+            WTSClientProtocolType,
+            &mut protocol,
+            &mut len,
+        )
+        .is_err()
+        {
+            return false;
+        }
+        // WTS_PROTOCOL_TYPE_RDP; 0 is the console.
+        let remote = len as usize >= size_of::<u16>() && *(protocol.0 as *const u16) == 2;
+        WTSFreeMemory(protocol.0 as _);
+        remote
     }
 }
 
-pub fn get_biometrics_status() -> i32 {
-    UserConsentVerifier::CheckAvailabilityAsync().map_or(5, |async_op| {
-        async_op.get().map_or(5, |availability| {
-            #[allow(non_snake_case)]
-            match availability {
-                UserConsentVerifierAvailability::Available => 0,
-                UserConsentVerifierAvailability::DeviceNotPresent => 2,
-                UserConsentVerifierAvailability::NotConfiguredForUser => 7,
-                UserConsentVerifierAvailability::DisabledByPolicy => 5,
-                UserConsentVerifierAvailability::DeviceBusy => 2,
-                _ => 5,
+/// The first biometric factor with a sensor in the system pool, fingerprint before face.
+fn biometric_factor() -> Option<u32> {
+    [WINBIO_TYPE_FINGERPRINT, WINBIO_TYPE_FACIAL_FEATURES]
+        .into_iter()
+        .find(|&factor| unsafe {
+            let mut units: *mut WINBIO_UNIT_SCHEMA = ptr::null_mut();
+            let mut count = 0;
+            let found = WinBioEnumBiometricUnits(factor, &mut units, &mut count).is_ok();
+            if !units.is_null() {
+                let _ = WinBioFree(units as _);
             }
+            found && count > 0
         })
-    })
 }
 
-fn center_security_prompt() {
-    let hwnd = unsafe { FindWindowW(w!("Credential Dialog Xaml Host"), None) };
-    if let Ok(hwnd) = hwnd {
+fn is_current_user(identity: &WINBIO_IDENTITY) -> bool {
+    unsafe {
+        let mut token = HANDLE::default();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token).is_err() {
+            return false;
+        }
+        let mut len = 0;
+        let _ = GetTokenInformation(token, TokenUser, None, 0, &mut len);
+        // u64 elements keep the buffer aligned for TOKEN_USER.
+        let mut buffer = vec![0u64; (len as usize).div_ceil(size_of::<u64>())];
+        let read = GetTokenInformation(
+            token,
+            TokenUser,
+            Some(buffer.as_mut_ptr() as _),
+            len,
+            &mut len,
+        );
+        let _ = CloseHandle(token);
+        if read.is_err() {
+            return false;
+        }
+        let user = &*(buffer.as_ptr() as *const TOKEN_USER);
+        let sample_sid = PSID(identity.Value.AccountSid.Data.as_ptr() as _);
+        EqualSid(user.User.Sid, sample_sid).is_ok()
+    }
+}
+
+/// The console window when bwbio runs in a visible one, otherwise the visible top-level window
+/// of the nearest ancestor process that has one: the browser for the native messaging host
+/// (which browsers may start through `cmd.exe`), Windows Terminal for the CLI. The foreground
+/// window wins when several of that process's windows qualify.
+fn owner_window() -> Option<HWND> {
+    let console = unsafe { GetConsoleWindow() };
+    if !console.is_invalid() && unsafe { IsWindowVisible(console) }.as_bool() {
+        return Some(console);
+    }
+    let parents = processes()
+        .iter()
+        .map(|p| (p.th32ProcessID, p.th32ParentProcessID))
+        .collect::<HashMap<_, _>>();
+    let all_windows = top_level_windows();
+    let foreground = unsafe { GetForegroundWindow() };
+    let mut pid = process::id();
+    // Bounded, as PIDs are reused and a stale parent entry could form a cycle.
+    for _ in 0..8 {
+        pid = *parents.get(&pid)?;
+        let windows = all_windows
+            .iter()
+            .filter(|(_, owner)| *owner == pid)
+            .map(|&(hwnd, _)| hwnd)
+            .collect::<Vec<_>>();
+        if windows.contains(&foreground) {
+            return Some(foreground);
+        }
+        if let Some(&hwnd) = windows.first() {
+            return Some(hwnd);
+        }
+    }
+    None
+}
+
+/// Every running process.
+fn processes() -> Vec<PROCESSENTRY32W> {
+    let mut found = Vec::new();
+    let Ok(snapshot) = (unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) }) else {
+        return found;
+    };
+    let mut entry = PROCESSENTRY32W {
+        dwSize: size_of::<PROCESSENTRY32W>() as u32,
+        ..Default::default()
+    };
+    let mut next = unsafe { Process32FirstW(snapshot, &mut entry) };
+    while next.is_ok() {
+        found.push(entry);
+        next = unsafe { Process32NextW(snapshot, &mut entry) };
+    }
+    unsafe {
+        let _ = CloseHandle(snapshot);
+    }
+    found
+}
+
+/// Visible, unowned top-level windows with the PID of the process owning each.
+fn top_level_windows() -> Vec<(HWND, u32)> {
+    unsafe extern "system" fn visit(hwnd: HWND, lparam: LPARAM) -> BOOL {
+        let found = unsafe { &mut *(lparam.0 as *mut Vec<(HWND, u32)>) };
+        let mut pid = 0;
+        unsafe {
+            GetWindowThreadProcessId(hwnd, Some(&mut pid));
+            if IsWindowVisible(hwnd).as_bool() && GetWindow(hwnd, GW_OWNER).is_err() {
+                found.push((hwnd, pid));
+            }
+        }
+        true.into()
+    }
+
+    let mut found = Vec::<(HWND, u32)>::new();
+    unsafe {
+        let _ = EnumWindows(Some(visit), LPARAM(&mut found as *mut _ as isize));
+    }
+    found
+}
+
+/// The window of a biometric-only check: a plain static control showing the request, owned by
+/// the browser's window so it opens in front of it. It lives on the thread that created it,
+/// which must pump its messages.
+struct SampleWindow(HWND);
+
+impl SampleWindow {
+    fn show(message: &str) -> Self {
+        let (width, height) = (420, 120);
+        let hwnd = unsafe {
+            let (x, y) = (
+                (GetSystemMetrics(SM_CXSCREEN) - width) / 2,
+                (GetSystemMetrics(SM_CYSCREEN) - height) / 2,
+            );
+            CreateWindowExW(
+                WS_EX_TOPMOST | WS_EX_DLGMODALFRAME,
+                w!("STATIC"),
+                &HSTRING::from(message),
+                WS_POPUP
+                    | WS_CAPTION
+                    | WS_SYSMENU
+                    | WS_VISIBLE
+                    | WINDOW_STYLE(SS_CENTER | SS_CENTERIMAGE),
+                x,
+                y,
+                width,
+                height,
+                owner_window(),
+                None,
+                None,
+                None,
+            )
+        };
+        let window = Self(hwnd.unwrap_or_default());
+        if !window.0.is_invalid() {
+            // Only granted while the owner is in front; otherwise the check fails with NoFocus.
+            unsafe {
+                let _ = SetForegroundWindow(window.0);
+            }
+            window.pump();
+        }
+        window
+    }
+
+    /// Whether the window holds the foreground, so the sensor's samples reach bwbio.
+    fn is_foreground(&self) -> bool {
+        !self.0.is_invalid() && unsafe { GetForegroundWindow() } == self.0
+    }
+
+    /// Handles pending messages; false once the user closed the window.
+    fn pump(&self) -> bool {
         unsafe {
-            let fg_hwnd = GetForegroundWindow();
-            let cur_id = GetCurrentThreadId();
-            let fg_id = GetWindowThreadProcessId(fg_hwnd, None);
-            let _ = AttachThreadInput(cur_id, fg_id, true);
-            let _ = SetForegroundWindow(hwnd);
-            let _ = BringWindowToTop(hwnd);
-            let _ = SetFocus(Some(hwnd));
-            let _ = AttachThreadInput(cur_id, fg_id, false);
+            let mut msg = MSG::default();
+            while PeekMessageW(&mut msg, None, 0, 0, PM_REMOVE).as_bool() {
+                let _ = TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+            IsWindow(Some(self.0)).as_bool()
+        }
+    }
+}
+
+impl Drop for SampleWindow {
+    fn drop(&mut self) {
+        if !self.0.is_invalid() {
+            unsafe {
+                let _ = DestroyWindow(self.0);
+            }
         }
     }
 }
@@ -2,56 +2,383 @@
 // Copyright (C) 2025 Aalivexy
 
 use crate::{
-    bio::{authenticate_with_biometrics, get_biometrics_status},
-    crypto::{Aes256CbcHmacKey, rsa_encrypt},
-    kmgr::KeyManager,
-    proto::{EncString, EncryptedMessage, ResponseData, ResponseMessage},
+    audit::AuditSource,
+    config::{check_config, config},
+    crypto::{Aes256CbcHmacKey, OaepHash, SecretBytes, SecretString, rsa_encrypt, self_test},
+    error::{BwbioError, Result},
+    kmgr::{KeyManager, default_key_dir},
+    log::init_logging,
+    presence::{BiometricVerifier, DEFAULT_REASON, platform_verifier},
+    proto::{
+        BiometricsStatus, Command, EncString, EncType, EncryptedMessage, ProtoError, ResponseData,
+        ResponseMessage, validate_encrypted_message, validate_message,
+    },
 };
-use anyhow::{Result, anyhow};
 use serde_json::{Value, from_slice, from_value, json, to_vec};
 use std::{
-    io::{BufReader, ErrorKind, Read, Write, stdin, stdout},
-    sync::OnceLock,
+    env,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write, stdin, stdout},
+    sync::{Mutex, MutexGuard, OnceLock, PoisonError},
+    thread,
+    time::Duration,
 };
+use thiserror::Error;
+use tracing::{debug, error, info, warn};
+#[cfg(windows)]
+use windows::Win32::{
+    Foundation::ERROR_BROKEN_PIPE,
+    System::{
+        Console::{GetStdHandle, STD_INPUT_HANDLE},
+        Pipes::PeekNamedPipe,
+    },
+};
+
+static SHARED_SECRET: OnceLock<Mutex<Aes256CbcHmacKey>> = OnceLock::new();
+/// The key manager, or why it could not be opened; requests needing it are then answered as
+/// failed instead of ending the host.
+static KEY_MANAGER: OnceLock<Result<KeyManager, String>> = OnceLock::new();
+static VERIFIER: OnceLock<Box<dyn BiometricVerifier>> = OnceLock::new();
+static LEGACY_KEY_FIELD: OnceLock<bool> = OnceLock::new();
+static OAEP_HASH: OnceLock<OaepHash> = OnceLock::new();
+/// Keys exported together for a batch of unlock requests, consumed as the batch is answered.
+static PREFETCHED: Mutex<Vec<(String, Result<SecretString>)>> = Mutex::new(Vec::new());
+static OUTPUT: OnceLock<Mutex<HostOutput>> = OnceLock::new();
+static TRACE: OnceLock<bool> = OnceLock::new();
+/// How often the host drops expired entries from the key cache.
+const CACHE_PRUNE_SECONDS: u64 = 15;
+/// Reply fields holding a user key, never printed by `--trace`.
+const TRACE_REDACTED_FIELDS: [&str; 2] = ["userKeyB64", "keyB64"];
+
+#[derive(Debug, Error)]
+pub enum HostError {
+    #[error("Crypto self-test failed: {0}")]
+    SelfTest(#[source] Box<BwbioError>),
+    #[error("Key manager unavailable: {0}")]
+    KeyManagerUnavailable(String),
+    /// The single check for a batch of unlocks failed, which refuses all of them.
+    #[error("Batched unlock refused: {0}")]
+    BatchRefused(String),
+    /// Reading requests from or writing replies to the browser.
+    #[error("Browser connection failed: {0}")]
+    Io(#[source] io::Error),
+}
+
+/// Origins of the Bitwarden extension in the Chromium-based browsers' stores.
+pub const CHROMIUM_EXTENSION_ORIGINS: [&str; 4] = [
+    "chrome-extension://nngceckbapebfimnlniiiahkandclblb/",
+    "chrome-extension://hccnnhgbibccigepcmlgppchkpfdophk/",
+    "chrome-extension://jbkfoedolllekgbhcbcoahefnbanhhlh/",
+    "chrome-extension://ccnckbpmaceehanjmeomladnmlffdjgn/",
+];
+/// Id of the Bitwarden extension for Firefox.
+pub const FIREFOX_EXTENSION_ID: &str = "{446900e4-71c2-419f-a6a7-df9c091e268b}";
+
+/// Where the host reads requests and writes replies.
+pub struct HostIo {
+    pub input: Box<dyn Read>,
+    pub output: Box<dyn Write + Send>,
+    /// One JSON message per line instead of length-prefixed native messaging frames.
+    pub lines: bool,
+    /// Print every message to stderr, encrypted ones decrypted.
+    pub trace: bool,
+    /// Answers `authenticateWithBiometrics` and `getBiometricsStatus`.
+    pub verifier: Box<dyn BiometricVerifier>,
+    /// Where keys are served from; `None` opens the one [`KeyManager::from_env`] describes.
+    pub key_manager: Option<KeyManager>,
+}
+
+impl HostIo {
+    /// What the browser starts the host with.
+    pub fn stdio() -> Self {
+        Self {
+            input: Box::new(stdin()),
+            output: Box::new(stdout()),
+            lines: false,
+            trace: false,
+            verifier: platform_verifier(),
+            key_manager: None,
+        }
+    }
+}
 
-static SHARED_SECRET: OnceLock<Aes256CbcHmacKey> = OnceLock::new();
-static KEY_MANAGER: OnceLock<KeyManager> = OnceLock::new();
+struct HostOutput {
+    writer: Box<dyn Write + Send>,
+    lines: bool,
+}
 
+/// Serves the browser that started the host over stdin and stdout.
 pub fn launch_native_messaging() -> Result<()> {
-    SHARED_SECRET.get_or_init(Aes256CbcHmacKey::new);
-    KEY_MANAGER.get_or_init(KeyManager::default);
-    let mut r = BufReader::new(stdin());
+    serve(HostIo::stdio())
+}
+
+/// Runs the native messaging loop on `io` until the input ends.
+pub fn serve(io: HostIo) -> Result<()> {
+    if let Ok(dir) = default_key_dir() {
+        init_logging(dir);
+    }
+    info!("bwbio {} started", env!("CARGO_PKG_VERSION"));
+    let result = run_host(io);
+    match &result {
+        Ok(()) => info!("Input closed, stopping"),
+        Err(e) => error!("Host stopped: {e}"),
+    }
+    result
+}
+
+fn run_host(io: HostIo) -> Result<()> {
+    // A config file that doesn't load would quietly turn off switches such as biometric-only.
+    check_config()?;
+    OUTPUT.get_or_init(|| {
+        Mutex::new(HostOutput {
+            writer: io.output,
+            lines: io.lines,
+        })
+    });
+    TRACE.get_or_init(|| io.trace);
+    VERIFIER.get_or_init(|| io.verifier);
+    self_test().map_err(|e| HostError::SelfTest(Box::new(e)))?;
+    let secret = Aes256CbcHmacKey::new()?;
+    SHARED_SECRET.get_or_init(|| Mutex::new(secret));
+    let kmgr = KEY_MANAGER.get_or_init(|| {
+        io.key_manager
+            .map_or_else(KeyManager::from_env, Ok)
+            .map(|kmgr| kmgr.with_audit_source(AuditSource::Browser))
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = kmgr {
+        error!("Key manager unavailable: {e}");
+    }
+    if let Ok(kmgr) = kmgr {
+        // Best effort: a store that cannot be listed fails again on the first real request.
+        let _ = kmgr.purge_expired();
+        if kmgr.key_cache_enabled() {
+            // Zeroize expired cache entries even when no further unlock arrives.
+            thread::spawn(|| {
+                loop {
+                    thread::sleep(Duration::from_secs(CACHE_PRUNE_SECONDS));
+                    if let Ok(kmgr) = key_manager() {
+                        kmgr.prune_cache();
+                    }
+                }
+            });
+        }
+    }
+    LEGACY_KEY_FIELD.get_or_init(|| config().legacy_key_field());
+    OAEP_HASH.get_or_init(|| config().oaep_hash());
+    // A prompt left open after the browser quits would otherwise linger with nobody to answer.
+    #[cfg(windows)]
+    crate::bio::cancel_prompts_when(browser_disconnected);
+    let lines = io.lines;
+    let mut r = BufReader::new(io.input);
     send(json!({
         "command": "connected",
         "app_id": "com.8bit.bitwarden"
     }))?;
 
     loop {
-        let len_buf = read_exact(&mut r, 4)?;
-        if len_buf.is_empty() {
+        let Some(first) = read_message(&mut r, lines)? else {
             break Ok(());
+        };
+        // Messages the extension sent back-to-back usually arrive in the same read.
+        let mut batch = vec![first];
+        while message_buffered(&r, lines) {
+            match read_message(&mut r, lines)? {
+                Some(msg) => batch.push(msg),
+                None => break,
+            }
         }
-        let len = u32::from_ne_bytes(len_buf.try_into().unwrap());
+        handle_batch(&batch)?;
+    }
+}
 
-        let msg_buf = read_exact(&mut r, len as usize)?;
-        if msg_buf.is_empty() {
-            break Ok(());
+/// Answers messages read together, unwrapping the keys of their unlock requests at once.
+fn handle_batch(batch: &[Vec<u8>]) -> Result<()> {
+    prefetch_unlocks(batch);
+    let result = batch.iter().try_for_each(|msg| parse_message(msg));
+    prefetched().clear();
+    result
+}
+
+fn read_message<R: Read>(r: &mut BufReader<R>, lines: bool) -> Result<Option<Vec<u8>>> {
+    if lines {
+        let mut line = Vec::new();
+        // Blank lines between messages are skipped.
+        while line.trim_ascii().is_empty() {
+            line.clear();
+            if r.read_until(b'\n', &mut line).map_err(HostError::Io)? == 0 {
+                return Ok(None);
+            }
         }
+        trace("<-", line.trim_ascii());
+        return Ok(Some(line));
+    }
+    let len_buf = read_exact(r, 4)?;
+    if len_buf.is_empty() {
+        return Ok(None);
+    }
+    let len = u32::from_ne_bytes(len_buf.try_into().unwrap());
 
-        parse_message(&msg_buf)?
+    let msg_buf = read_exact(r, len as usize)?;
+    if msg_buf.is_empty() {
+        return Ok(None);
     }
+    trace("<-", &msg_buf);
+    Ok(Some(msg_buf))
+}
+
+/// Whether a whole message is already buffered, so reading it won't block.
+fn message_buffered<R>(r: &BufReader<R>, lines: bool) -> bool {
+    let buf = r.buffer();
+    if lines {
+        return buf.contains(&b'\n');
+    }
+    buf.len() >= 4 && buf.len() - 4 >= u32::from_ne_bytes(buf[..4].try_into().unwrap()) as usize
+}
+
+/// Prints a message to stderr when tracing is on, with the user key fields of decrypted replies
+/// redacted.
+fn trace(direction: &str, msg: &[u8]) {
+    if !TRACE.get().copied().unwrap_or(false) {
+        return;
+    }
+    match from_slice::<Value>(msg) {
+        Ok(mut value) => {
+            redact_keys(&mut value);
+            eprintln!("{direction} {value}");
+        }
+        Err(_) => eprintln!("{direction} {}", String::from_utf8_lossy(msg)),
+    }
+}
+
+/// Replaces every non-null [`TRACE_REDACTED_FIELDS`] value in `value`.
+fn redact_keys(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                if TRACE_REDACTED_FIELDS.contains(&name.as_str()) && !field.is_null() {
+                    *field = json!("[redacted]");
+                } else {
+                    redact_keys(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_keys),
+        _ => {}
+    }
+}
+
+fn prefetched() -> MutexGuard<'static, Vec<(String, Result<SecretString>)>> {
+    PREFETCHED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Decrypts the keys for several unlock requests in one batch after a single Windows Hello
+/// prompt; [`handle_message`] then answers them from [`PREFETCHED`]. A refused prompt refuses
+/// the whole batch, so the user isn't asked again for each message.
+fn prefetch_unlocks(batch: &[Vec<u8>]) {
+    let user_ids = batch
+        .iter()
+        .filter_map(|msg| unlock_user_id(msg))
+        .collect::<Vec<_>>();
+    if user_ids.len() < 2 {
+        return;
+    }
+    let kmgr = match key_manager() {
+        Ok(kmgr) => kmgr,
+        Err(e) => {
+            warn!("Batched unlock skipped: {e}");
+            return;
+        }
+    };
+    let ids = user_ids.iter().map(String::as_str).collect::<Vec<_>>();
+    match kmgr.export_keys(&ids) {
+        Ok(keys) => prefetched().extend(user_ids.into_iter().zip(keys)),
+        Err(e) => {
+            let reason = e.to_string();
+            prefetched().extend(user_ids.into_iter().map(|user_id| {
+                let refused = HostError::BatchRefused(reason.clone()).into();
+                (user_id, Err(refused))
+            }));
+        }
+    }
+}
+
+/// The user id of a valid unlock request encrypted under the current shared secret.
+fn unlock_user_id(msg: &[u8]) -> Option<String> {
+    let msg = from_slice::<Value>(msg).ok()?;
+    validate_message(&msg).ok()?;
+    let enc_str: EncString = from_value(msg.get("message")?.clone()).ok()?;
+    let decrypted = shared_secret().decrypt(&enc_str).ok()?;
+    let decrypted = from_slice::<Value>(&decrypted).ok()?;
+    validate_encrypted_message(&decrypted).ok()?;
+    match from_value::<EncryptedMessage>(decrypted).ok()?.command() {
+        Command::UnlockWithBiometricsForUser { user_id } => Some(user_id.clone()),
+        _ => None,
+    }
+}
+
+fn take_prefetched(user_id: &str) -> Option<Result<SecretString>> {
+    let mut prefetched = prefetched();
+    let idx = prefetched.iter().position(|(id, _)| id == user_id)?;
+    Some(prefetched.remove(idx).1)
+}
+
+/// Whether the browser closed its end of stdin. Peeking leaves any pending message unread.
+#[cfg(windows)]
+fn browser_disconnected() -> bool {
+    unsafe {
+        GetStdHandle(STD_INPUT_HANDLE).is_ok_and(|handle| {
+            PeekNamedPipe(handle, None, 0, None, None, None)
+                .is_err_and(|e| e.code() == ERROR_BROKEN_PIPE.to_hresult())
+        })
+    }
+}
+
+fn key_manager() -> Result<&'static KeyManager> {
+    KEY_MANAGER
+        .wait()
+        .as_ref()
+        .map_err(|e| HostError::KeyManagerUnavailable(e.clone()).into())
+}
+
+fn verifier() -> &'static dyn BiometricVerifier {
+    VERIFIER.wait().as_ref()
+}
+
+fn shared_secret() -> MutexGuard<'static, Aes256CbcHmacKey> {
+    SHARED_SECRET
+        .wait()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
 }
 
 fn send(msg: Value) -> Result<()> {
-    let serialized = to_vec(&msg)?;
-    stdout().write_all(&(serialized.len() as u32).to_ne_bytes())?;
-    stdout().write_all(&serialized)?;
-    stdout().flush()?;
-    Ok(())
+    let serialized = to_vec(&msg).map_err(ProtoError::from)?;
+    trace("->", &serialized);
+    let mut output = OUTPUT.wait().lock().unwrap_or_else(PoisonError::into_inner);
+    let HostOutput { writer, lines } = &mut *output;
+    let written = if *lines {
+        writer
+            .write_all(&serialized)
+            .and_then(|_| writer.write_all(b"\n"))
+    } else {
+        writer
+            .write_all(&(serialized.len() as u32).to_ne_bytes())
+            .and_then(|_| writer.write_all(&serialized))
+    };
+    written
+        .and_then(|_| writer.flush())
+        .map_err(|e| HostError::Io(e).into())
 }
 
-fn send_encrypted(app_id: &str, message: ResponseMessage) -> Result<()> {
-    let enc_str = SHARED_SECRET.wait().encrypt(&to_vec(&message)?)?;
+fn send_encrypted(app_id: &str, enc_type: EncType, message: ResponseMessage) -> Result<()> {
+    let message = if *LEGACY_KEY_FIELD.wait() {
+        message.with_legacy_key()
+    } else {
+        message
+    };
+    let plaintext = SecretBytes::from(to_vec(&message).map_err(ProtoError::from)?);
+    trace("-> (decrypted)", plaintext.expose());
+    let enc_str = shared_secret().encrypt_with(enc_type, plaintext.expose())?;
     send(json!({
         "appId": app_id,
         "messageId": message.message_id(),
@@ -68,16 +395,17 @@ fn read_exact<R: Read>(reader: &mut R, buf_len: usize) -> Result<Vec<u8>> {
         Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
             Ok(buf) // EOF
         }
-        Err(e) => Err(e.into()),
+        Err(e) => Err(HostError::Io(e).into()),
     }
 }
 
 fn parse_message(msg: &[u8]) -> Result<()> {
-    let msg = from_slice::<Value>(msg)?;
+    let msg = from_slice::<Value>(msg).map_err(ProtoError::from)?;
+    validate_message(&msg)?;
     let app_id = msg
         .get("appId")
         .and_then(Value::as_str)
-        .ok_or(anyhow!("Missing 'appId' field"))?;
+        .ok_or(ProtoError::MissingField("appId"))?;
     if let Some(message) = msg.get("message")
         && let Some(command) = message.get("command")
         && let Some(command) = command.as_str()
@@ -85,7 +413,19 @@ fn parse_message(msg: &[u8]) -> Result<()> {
         && let Some(public_key) = message.get("publicKey")
         && let Some(public_key) = public_key.as_str()
     {
-        let shared_secret = rsa_encrypt(public_key, &SHARED_SECRET.wait().to_vec())?;
+        // Extensions that announce an OAEP digest get it; otherwise use the configured default.
+        let hash = match message.get("oaepHash").and_then(Value::as_str) {
+            Some(hash) => hash.parse()?,
+            None => *OAEP_HASH.wait(),
+        };
+        // Every handshake gets a fresh secret; replacing the old one wipes it on drop.
+        let shared_secret = {
+            let new_secret = Aes256CbcHmacKey::new()?;
+            let mut secret = shared_secret();
+            *secret = new_secret;
+            rsa_encrypt(public_key, secret.to_vec().expose(), hash)?
+        };
+        info!("Encryption set up with {app_id} ({hash:?})");
         send(json!({
             "command": "setupEncryption",
             "appId": app_id,
@@ -94,87 +434,409 @@ fn parse_message(msg: &[u8]) -> Result<()> {
     } else {
         let enc_str: EncString = from_value(
             msg.get("message")
-                .ok_or(anyhow!("Missing 'message' field"))?
+                .ok_or(ProtoError::MissingField("message"))?
                 .clone(),
-        )?;
-        handle_message(
-            app_id,
-            from_slice(&SHARED_SECRET.wait().decrypt(
-                &enc_str.iv()?,
-                &enc_str.mac()?,
-                &enc_str.data()?,
-            )?)?,
         )
+        .map_err(ProtoError::from)?;
+        // bwbio's own AES-GCM type isn't part of the protocol, so it is treated as unknown.
+        let decrypted = match enc_str.enc_type() {
+            Ok(enc_type) if !enc_type.in_browser_protocol() => {
+                Err(ProtoError::UnknownEncType(enc_type as i32))
+            }
+            _ => shared_secret().decrypt(&enc_str),
+        };
+        match decrypted {
+            Ok(decrypted) => {
+                trace("<- (decrypted)", &decrypted);
+                let decrypted = from_slice::<Value>(&decrypted).map_err(ProtoError::from)?;
+                validate_encrypted_message(&decrypted)?;
+                // Reply with the same cipher suite the extension used for the request.
+                handle_message(
+                    app_id,
+                    enc_str.enc_type()?,
+                    from_value(decrypted).map_err(ProtoError::from)?,
+                )
+            }
+            // The extension renegotiates the shared secret when told the current one is unusable.
+            Err(e) => {
+                warn!("Message from {app_id} can't be decrypted ({e}), renegotiating");
+                send(json!({
+                    "command": "invalidateEncryption",
+                    "appId": app_id
+                }))
+            }
+        }
     }
 }
 
-fn handle_message(app_id: &str, msg: EncryptedMessage) -> Result<()> {
+fn handle_message(app_id: &str, enc_type: EncType, msg: EncryptedMessage) -> Result<()> {
+    let command = msg.command().name();
+    debug!("{command} from {app_id}");
     match msg.command() {
-        "unlockWithBiometricsForUser" => {
-            let user_id = msg.user_id().ok_or(anyhow!("Missing 'userId' field"))?;
-            KEY_MANAGER
-                .wait()
-                .export_key(user_id)
-                .and_then(|bw_key| {
-                    send_encrypted(
-                        app_id,
-                        ResponseMessage::with_key(
-                            "unlockWithBiometricsForUser",
-                            msg.message_id(),
-                            ResponseData::Bool(true),
-                            Some(bw_key),
-                        ),
-                    )
-                })
-                .or_else(|_| {
-                    send_encrypted(
-                        app_id,
-                        ResponseMessage::new(
-                            "unlockWithBiometricsForUser",
-                            msg.message_id(),
-                            ResponseData::Bool(false),
-                        ),
-                    )
-                })?;
-        }
-        "authenticateWithBiometrics" => {
+        Command::UnlockWithBiometricsForUser { user_id } => {
+            let exported = take_prefetched(user_id)
+                .unwrap_or_else(|| key_manager().and_then(|kmgr| kmgr.export_key(user_id)))
+                .inspect(|_| info!("Key for {user_id} released"))
+                .inspect_err(|e| warn!("Unlock for {user_id} refused: {e}"));
+            let response = match exported {
+                Ok(bw_key) => ResponseMessage::with_key(
+                    command,
+                    msg.message_id().clone(),
+                    ResponseData::Bool(true),
+                    Some(bw_key),
+                ),
+                // The reason is only logged; error text can name paths the browser needn't see.
+                Err(_) => ResponseMessage::new(
+                    command,
+                    msg.message_id().clone(),
+                    ResponseData::Bool(false),
+                ),
+            };
+            send_encrypted(app_id, enc_type, response)?;
+        }
+        Command::AuthenticateWithBiometrics => {
+            let verified = verifier().authenticate(DEFAULT_REASON);
+            info!(
+                "Windows Hello verification {}",
+                if verified { "succeeded" } else { "failed" }
+            );
             send_encrypted(
                 app_id,
+                enc_type,
                 ResponseMessage::new(
-                    "authenticateWithBiometrics",
-                    msg.message_id(),
-                    ResponseData::Bool(authenticate_with_biometrics()),
+                    command,
+                    msg.message_id().clone(),
+                    ResponseData::Bool(verified),
                 ),
             )?;
         }
-        "getBiometricsStatus" => {
+        Command::GetBiometricsStatus => {
             send_encrypted(
                 app_id,
+                enc_type,
+                ResponseMessage::new(command, msg.message_id().clone(), verifier().status()),
+            )?;
+        }
+        Command::GetBiometricsStatusForUser { user_id } => {
+            // A key store that can't be read is reported like a missing key, so the extension
+            // falls back to the master password instead of losing the host.
+            let exists = key_manager()
+                .and_then(|kmgr| kmgr.check_key_exists(user_id))
+                .unwrap_or(false);
+            send_encrypted(
+                app_id,
+                enc_type,
                 ResponseMessage::new(
-                    "getBiometricsStatus",
-                    msg.message_id(),
-                    ResponseData::Number(get_biometrics_status()),
+                    command,
+                    msg.message_id().clone(),
+                    if exists {
+                        BiometricsStatus::Available
+                    } else {
+                        BiometricsStatus::ManualSetupNeeded
+                    },
                 ),
             )?;
         }
-        "getBiometricsStatusForUser" => {
-            let user_id = msg.user_id().ok_or(anyhow!("Missing 'userId' field"))?;
-            KEY_MANAGER
-                .wait()
-                .check_key_exists(user_id)
-                .and_then(|exists| {
-                    send_encrypted(
-                        app_id,
-                        ResponseMessage::new(
-                            "getBiometricsStatusForUser",
-                            msg.message_id(),
-                            ResponseData::Number(if exists { 0 } else { 4 }),
-                        ),
-                    )
-                })?;
-        }
-        _ => {}
+        Command::Unknown(_) => warn!("Ignored unknown command {command}"),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::RsaKeyPair;
+    use crate::mock::{MemoryKeyWrap, MockVerifier, TempKeyDir};
+    use std::{str::FromStr, sync::Arc};
+
+    const APP_ID: &str = "test-extension";
+    const ALICE: &str = "alice";
+    const KEY: &str = "dXNlciBrZXkgb2YgYWxpY2U=";
+
+    /// Collects the host's replies, one JSON message per line.
+    #[derive(Clone, Default)]
+    struct Replies(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Replies {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Replies {
+        fn take(&self) -> Vec<Value> {
+            let output =
+                std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner));
+            output
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| from_slice(line).unwrap())
+                .collect()
+        }
+    }
+
+    struct Host {
+        /// The extension's handshake key; 2048 bits leave room for the 64-byte secret under
+        /// OAEP with SHA-256.
+        keypair: RsaKeyPair,
+        verifier: MockVerifier,
+        replies: Replies,
+        _dir: TempKeyDir,
+    }
+
+    /// The host's state lives in statics, so every test shares one host with `alice`'s key
+    /// stored, and takes this lock while talking to it.
+    fn host() -> MutexGuard<'static, Host> {
+        static HOST: OnceLock<Mutex<Host>> = OnceLock::new();
+        let host = HOST.get_or_init(|| {
+            let verifier = MockVerifier::approving();
+            let replies = Replies::default();
+            let dir = TempKeyDir::named("browser-host");
+            let wrap = MemoryKeyWrap::new(Box::new(verifier.clone())).unwrap();
+            let kmgr = KeyManager::with_wrapper(Box::new(wrap), dir.path().to_path_buf()).unwrap();
+            kmgr.import_key(ALICE, KEY).unwrap();
+            OUTPUT.get_or_init(|| {
+                Mutex::new(HostOutput {
+                    writer: Box::new(replies.clone()),
+                    lines: true,
+                })
+            });
+            VERIFIER.get_or_init(|| Box::new(verifier.clone()));
+            SHARED_SECRET.get_or_init(|| Mutex::new(Aes256CbcHmacKey::new().unwrap()));
+            KEY_MANAGER.get_or_init(|| Ok(kmgr.with_audit_source(AuditSource::Browser)));
+            LEGACY_KEY_FIELD.get_or_init(|| false);
+            OAEP_HASH.get_or_init(|| OaepHash::Sha1);
+            Mutex::new(Host {
+                keypair: RsaKeyPair::generate(2048).unwrap(),
+                verifier,
+                replies,
+                _dir: dir,
+            })
+        });
+        let host = host.lock().unwrap_or_else(PoisonError::into_inner);
+        host.verifier.set_approve(true);
+        host.verifier.set_status(BiometricsStatus::Available);
+        host.replies.take();
+        host
+    }
+
+    impl Host {
+        fn exchange(&self, messages: &[Value]) -> Vec<Value> {
+            let batch = messages
+                .iter()
+                .map(|msg| to_vec(msg).unwrap())
+                .collect::<Vec<_>>();
+            handle_batch(&batch).unwrap();
+            self.replies.take()
+        }
+
+        /// Runs the handshake the extension starts with and returns the shared secret.
+        fn setup_encryption(&self, oaep_hash: Option<&str>) -> Aes256CbcHmacKey {
+            let mut message = json!({
+                "command": "setupEncryption",
+                "publicKey": self.keypair.public_key_b64().unwrap(),
+            });
+            if let Some(hash) = oaep_hash {
+                message["oaepHash"] = json!(hash);
+            }
+            let replies = self.exchange(&[json!({ "appId": APP_ID, "message": message })]);
+            let [reply] = replies.as_slice() else {
+                panic!("expected one reply, got {replies:?}");
+            };
+            assert_eq!(reply["command"], "setupEncryption");
+            assert_eq!(reply["appId"], APP_ID);
+            let hash = oaep_hash.map_or(OaepHash::Sha1, |hash| hash.parse().unwrap());
+            let secret = self
+                .keypair
+                .decrypt(reply["sharedSecret"].as_str().unwrap(), hash)
+                .unwrap();
+            Aes256CbcHmacKey::from_slice(secret.expose()).unwrap()
+        }
+    }
+
+    fn request(secret: &Aes256CbcHmacKey, enc_type: EncType, command: Value) -> Value {
+        let enc_str = secret
+            .encrypt_with(enc_type, &to_vec(&command).unwrap())
+            .unwrap();
+        json!({ "appId": APP_ID, "message": enc_str })
+    }
+
+    fn decrypt_reply(secret: &Aes256CbcHmacKey, reply: &Value) -> Value {
+        assert_eq!(reply["appId"], APP_ID);
+        let enc_str =
+            EncString::from_str(reply["message"]["encryptedString"].as_str().unwrap()).unwrap();
+        let decrypted = from_slice::<Value>(&secret.decrypt(&enc_str).unwrap()).unwrap();
+        assert_eq!(decrypted["messageId"], reply["messageId"]);
+        decrypted
+    }
+
+    fn unlock(user_id: &str, message_id: i64) -> Value {
+        json!({
+            "command": "unlockWithBiometricsForUser",
+            "userId": user_id,
+            "messageId": message_id,
+            "timestamp": 0,
+        })
+    }
+
+    #[test]
+    fn unlock_roundtrip() {
+        let host = host();
+        let secret = host.setup_encryption(None);
+        let replies = host.exchange(&[request(
+            &secret,
+            EncType::AesCbc256HmacSha256B64,
+            unlock(ALICE, 1),
+        )]);
+        let reply = decrypt_reply(&secret, &replies[0]);
+        assert_eq!(reply["command"], "unlockWithBiometricsForUser");
+        assert_eq!(reply["response"], true);
+        assert_eq!(reply["userKeyB64"], KEY);
+        assert!(reply.get("keyB64").is_none());
+        // The reply uses the cipher suite of the request.
+        let enc_str = replies[0]["message"]["encryptedString"].as_str().unwrap();
+        assert_eq!(
+            EncString::from_str(enc_str).unwrap().enc_type().unwrap(),
+            EncType::AesCbc256HmacSha256B64
+        );
+    }
+
+    #[test]
+    fn bwbio_enc_type_is_refused() {
+        let host = host();
+        let secret = host.setup_encryption(None);
+        let prompts = host.verifier.prompts().len();
+        let replies = host.exchange(&[request(
+            &secret,
+            EncType::BwbioAesGcm256B64,
+            unlock(ALICE, 13),
+        )]);
+        assert_eq!(
+            replies,
+            [json!({ "command": "invalidateEncryption", "appId": APP_ID })]
+        );
+        assert_eq!(host.verifier.prompts().len(), prompts);
+    }
+
+    #[test]
+    fn unlock_refusals() {
+        let host = host();
+        let secret = host.setup_encryption(Some("SHA-256"));
+        let replies = host.exchange(&[request(
+            &secret,
+            EncType::AesCbc256HmacSha256B64,
+            unlock("bob", 2),
+        )]);
+        let reply = decrypt_reply(&secret, &replies[0]);
+        assert_eq!(reply["response"], false);
+        assert!(reply["userKeyB64"].is_null());
+
+        host.verifier.set_approve(false);
+        let replies = host.exchange(&[request(
+            &secret,
+            EncType::AesCbc256HmacSha256B64,
+            unlock(ALICE, 3),
+        )]);
+        let reply = decrypt_reply(&secret, &replies[0]);
+        assert_eq!(reply["response"], false);
+        assert!(reply["userKeyB64"].is_null());
+    }
+
+    #[test]
+    fn batched_unlocks_prompt_once() {
+        let host = host();
+        let secret = host.setup_encryption(None);
+        let prompts = host.verifier.prompts().len();
+        let replies = host.exchange(&[
+            request(&secret, EncType::AesCbc256HmacSha256B64, unlock(ALICE, 4)),
+            request(&secret, EncType::AesCbc256HmacSha256B64, unlock(ALICE, 5)),
+        ]);
+        assert_eq!(host.verifier.prompts().len(), prompts + 1);
+        assert_eq!(replies.len(), 2);
+        for (reply, message_id) in replies.iter().zip([4, 5]) {
+            let reply = decrypt_reply(&secret, reply);
+            assert_eq!(reply["messageId"], message_id);
+            assert_eq!(reply["userKeyB64"], KEY);
+        }
+    }
+
+    #[test]
+    fn refused_batch_prompts_once() {
+        let host = host();
+        let secret = host.setup_encryption(None);
+        host.verifier.set_approve(false);
+        let prompts = host.verifier.prompts().len();
+        let replies = host.exchange(&[
+            request(&secret, EncType::AesCbc256HmacSha256B64, unlock(ALICE, 11)),
+            request(&secret, EncType::AesCbc256HmacSha256B64, unlock(ALICE, 12)),
+        ]);
+        assert_eq!(host.verifier.prompts().len(), prompts + 1);
+        assert_eq!(replies.len(), 2);
+        for (reply, message_id) in replies.iter().zip([11, 12]) {
+            let reply = decrypt_reply(&secret, reply);
+            assert_eq!(reply["messageId"], message_id);
+            assert_eq!(reply["response"], false);
+            assert!(reply["userKeyB64"].is_null());
+        }
+    }
+
+    #[test]
+    fn biometrics_status() {
+        let host = host();
+        let secret = host.setup_encryption(None);
+        let status = |command: Value| {
+            let replies =
+                host.exchange(&[request(&secret, EncType::AesCbc256HmacSha256B64, command)]);
+            decrypt_reply(&secret, &replies[0])["response"].clone()
+        };
+        let for_user = |user_id: &str| json!({ "command": "getBiometricsStatusForUser", "userId": user_id, "messageId": 6 });
+        assert_eq!(status(for_user(ALICE)), 0);
+        assert_eq!(status(for_user("bob")), 4);
+        let get_status = json!({ "command": "getBiometricsStatus", "messageId": "7" });
+        assert_eq!(status(get_status.clone()), 0);
+        host.verifier.set_status(BiometricsStatus::NotEnrolled);
+        assert_eq!(status(get_status), 7);
+
+        host.verifier.set_status(BiometricsStatus::Available);
+        let authenticate = json!({ "command": "authenticateWithBiometrics", "messageId": 8 });
+        assert_eq!(status(authenticate.clone()), true);
+        host.verifier.set_approve(false);
+        assert_eq!(status(authenticate), false);
+    }
+
+    #[test]
+    fn stale_secret_is_renegotiated() {
+        let host = host();
+        host.setup_encryption(None);
+        let stale = Aes256CbcHmacKey::new().unwrap();
+        let replies = host.exchange(&[request(
+            &stale,
+            EncType::AesCbc256HmacSha256B64,
+            unlock(ALICE, 9),
+        )]);
+        assert_eq!(
+            replies,
+            [json!({ "command": "invalidateEncryption", "appId": APP_ID })]
+        );
+    }
+
+    #[test]
+    fn unknown_command_is_ignored() {
+        let host = host();
+        let secret = host.setup_encryption(None);
+        let command = json!({ "command": "somethingNew", "messageId": 10 });
+        assert!(
+            host.exchange(&[request(&secret, EncType::AesCbc256HmacSha256B64, command)])
+                .is_empty()
+        );
+    }
+}
@@ -1,17 +1,59 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
 
-use crate::cng::CngProvider;
-use crate::cng::default_key_name;
-use crate::kmgr::KeyManager;
+use crate::bench;
+use crate::bio::{WindowsHello, authenticate_with_biometrics, biometrics_details};
+use crate::browser::{self, HostIo};
+use crate::clipboard;
+use crate::cng::{CngKeyAlgorithm, CngKeyOptions, CngProvider, CngProviderKind};
+use crate::config::{CONFIG_KEYS, Config, Overrides, check_config, config, set_overrides};
+use crate::crypto::{SecretString, base64_decode, base64_encode, self_test};
+use crate::desktop::{biometric_user_ids, default_data_path, read_accounts};
+use crate::doctor::{self, CheckStatus};
+use crate::error::BwbioError;
+use crate::install::{
+    BrowserFamily, EXE_NAME, NATIVE_MESSAGING_HOSTS, RegistryAccessDenied, RegistryScope,
+    UninstallOptions, create_start_menu_shortcut, default_install_dir, detect_browsers,
+    enable_autostart, find_existing_install, is_installed, key_dir_for, migrate_install,
+    native_messaging_manifest, perform_install, perform_uninstall, register_elevated,
+    register_native_messaging_manifest_in, registered_manifest, remember_browsers, same_dir,
+    select_browsers, unregister_machine_manifest, unregister_native_messaging_manifest,
+};
+use crate::keystore::open_key_store;
+use crate::kmgr::{
+    KeyHealth, KeyManager, KeyManagerError, KeyMetadata, active_cng_key_name, default_key_dir,
+    delete_cng_key, machine_key_selected,
+};
+use crate::log::{Log, LogLevel, format_utc, init_logging, line_level};
+use crate::presence::PresenceError;
+use crate::progress::Progress;
+use crate::proto::BiometricsStatus;
+use crate::update::{apply_update, latest_release};
+use anyhow::{Context, Result, anyhow, bail};
 use argh::FromArgs;
+use dialoguer::{Confirm, Password};
+use serde_json::{Value, json};
 use std::env;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions, read_to_string, write};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 use windows_strings::HSTRING;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Key management command line tool
 struct KmgrCmd {
+    /// print results and errors as JSON on stdout
+    #[argh(switch)]
+    json: bool,
+    /// store keys in this directory (overrides BW_KEY_DIR and the config file)
+    #[argh(option)]
+    key_dir: Option<PathBuf>,
+    /// name of the CNG key to use (overrides CNG_KEY_NAME and the config file)
+    #[argh(option)]
+    key_name: Option<String>,
     #[argh(subcommand)]
     cmd: Command,
 }
@@ -21,9 +63,38 @@ struct KmgrCmd {
 enum Command {
     List(ListCmd),
     Import(ImportCmd),
+    ImportDesktop(ImportDesktopCmd),
+    MigrateDesktop(MigrateDesktopCmd),
     Export(ExportCmd),
     Delete(DeleteCmd),
+    Rename(RenameCmd),
     Check(CheckCmd),
+    SelfTest(SelfTestCmd),
+    Bench(BenchCmd),
+    Rotate(RotateCmd),
+    Backup(BackupCmd),
+    Restore(RestoreCmd),
+    Expire(ExpireCmd),
+    Recover(RecoverCmd),
+    Server(ServerCmd),
+    Audit(AuditCmd),
+    Lock(LockCmd),
+    Doctor(DoctorCmd),
+    Status(StatusCmd),
+    Verify(VerifyCmd),
+    Install(InstallCmd),
+    Uninstall(UninstallCmd),
+    Purge(PurgeCmd),
+    Register(RegisterCmd),
+    Unregister(UnregisterCmd),
+    Browsers(BrowsersCmd),
+    Manifest(ManifestCmd),
+    Update(UpdateCmd),
+    Serve(ServeCmd),
+    TestBio(TestBioCmd),
+    Logs(LogsCmd),
+    Config(ConfigCmd),
+    Agent(AgentCmd),
     Cng(CngCmd),
 }
 
@@ -39,9 +110,39 @@ struct ImportCmd {
     /// user id
     #[argh(positional)]
     user_id: String,
-    /// plaintext key
+    /// plaintext key; prefer --stdin, --file or the prompt, which keep it out of shell history
     #[argh(positional)]
-    key: String,
+    key: Option<String>,
+    /// read the key from standard input
+    #[argh(switch)]
+    stdin: bool,
+    /// read the key from a file
+    #[argh(option)]
+    file: Option<PathBuf>,
+    /// server the account belongs to, e.g. https://vault.bitwarden.eu
+    #[argh(option)]
+    server: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Import key from Bitwarden desktop's data.json (Require master password)
+#[argh(subcommand, name = "import-desktop")]
+struct ImportDesktopCmd {
+    /// path to data.json (default: %APPDATA%\Bitwarden\data.json)
+    #[argh(option)]
+    data: Option<PathBuf>,
+    /// user id to import (default: the only account)
+    #[argh(option)]
+    user_id: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Import keys from Bitwarden desktop's biometric unlock (Require biometrics)
+#[argh(subcommand, name = "migrate-desktop")]
+struct MigrateDesktopCmd {
+    /// user id to import (default: every account with biometric unlock)
+    #[argh(option)]
+    user_id: Option<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -51,6 +152,12 @@ struct ExportCmd {
     /// user id
     #[argh(positional)]
     user_id: String,
+    /// copy the key to the clipboard instead of printing it
+    #[argh(switch)]
+    copy: bool,
+    /// with --copy, seconds until the clipboard is cleared again; 0 leaves it (default: 30)
+    #[argh(option, default = "30")]
+    clear_after: u64,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -62,6 +169,18 @@ struct DeleteCmd {
     user_id: String,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// Move a key to another user id, e.g. after the account id changed
+#[argh(subcommand, name = "rename")]
+struct RenameCmd {
+    /// current user id
+    #[argh(positional)]
+    old_user_id: String,
+    /// new user id
+    #[argh(positional)]
+    new_user_id: String,
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// Check if key exists
 #[argh(subcommand, name = "check")]
@@ -71,10 +190,346 @@ struct CheckCmd {
     user_id: String,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// Run crypto known-answer tests
+#[argh(subcommand, name = "self-test")]
+struct SelfTestCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Measure message crypto and unlock round-trip latency
+#[argh(subcommand, name = "bench")]
+struct BenchCmd {
+    /// iterations per measurement (default: 10000)
+    #[argh(option, default = "10000")]
+    iterations: u32,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Move all keys to a new CNG key and delete the old one (Require biometrics)
+#[argh(subcommand, name = "rotate")]
+struct RotateCmd {
+    /// name of the new CNG key
+    #[argh(positional)]
+    new_name: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Write all keys to a passphrase-encrypted backup (Require biometrics)
+#[argh(subcommand, name = "backup")]
+struct BackupCmd {
+    /// backup file to write
+    #[argh(positional)]
+    path: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Import all keys from a backup
+#[argh(subcommand, name = "restore")]
+struct RestoreCmd {
+    /// backup file to read
+    #[argh(positional)]
+    path: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Refuse a key after it has gone unused for a number of days
+#[argh(subcommand, name = "expire")]
+struct ExpireCmd {
+    /// user id
+    #[argh(positional)]
+    user_id: String,
+    /// days without use before the key expires (omit to remove the expiry)
+    #[argh(option)]
+    days: Option<u32>,
+    /// delete the key once it expires instead of only refusing it
+    #[argh(switch)]
+    delete: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Replace a stored key with its DPAPI copy, e.g. after a TPM clear
+#[argh(subcommand, name = "recover")]
+struct RecoverCmd {
+    /// user id
+    #[argh(positional)]
+    user_id: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Record the server a stored key's account belongs to
+#[argh(subcommand, name = "server")]
+struct ServerCmd {
+    /// user id
+    #[argh(positional)]
+    user_id: String,
+    /// server URL (omit to forget it)
+    #[argh(positional)]
+    url: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Clear cached decrypted keys in every bwbio process
+#[argh(subcommand, name = "lock")]
+struct LockCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Check TPM, Windows Hello, keys and browser registration and print a report
+#[argh(subcommand, name = "doctor")]
+struct DoctorCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Summarize the installation, browser registration, key protection, stored keys and Windows Hello
+#[argh(subcommand, name = "status")]
+struct StatusCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Remove everything bwbio set up: stored keys, the CNG key, browser registration, manifest and
+/// installed executable, even if the installation is incomplete
+#[argh(subcommand, name = "purge")]
+struct PurgeCmd {
+    /// install directory (default: %LOCALAPPDATA%\bwbio)
+    #[argh(option)]
+    dir: Option<PathBuf>,
+    /// don't ask for confirmation
+    #[argh(switch, short = 'y')]
+    yes: bool,
+    /// leave the stored keys and the CNG key in place
+    #[argh(switch)]
+    keep_keys: bool,
+    /// leave the CNG key in place
+    #[argh(switch)]
+    keep_cng_key: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List installed browsers and which native messaging host each one starts for Bitwarden
+#[argh(subcommand, name = "browsers")]
+struct BrowsersCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Replace the installed executable with the latest release, after checking its SHA-256
+#[argh(subcommand, name = "update")]
+struct UpdateCmd {
+    /// only report whether a newer release exists
+    #[argh(switch)]
+    check: bool,
+    /// install directory (default: %LOCALAPPDATA%\bwbio)
+    #[argh(option)]
+    dir: Option<PathBuf>,
+}
+
+/// Native messaging manifest commands
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "manifest")]
+struct ManifestCmd {
+    #[argh(subcommand)]
+    cmd: ManifestSubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum ManifestSubCommand {
+    Print(ManifestPrintCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print the native messaging manifest for registering bwbio by hand
+#[argh(subcommand, name = "print")]
+struct ManifestPrintCmd {
+    /// browser family: chromium (default; Chrome, Edge, Brave, ...) or firefox
+    #[argh(option, default = "BrowserFamily::default()")]
+    browser: BrowserFamily,
+    /// executable the manifest starts (default: this one)
+    #[argh(option)]
+    exe: Option<PathBuf>,
+    /// write the manifest to this file instead of stdout
+    #[argh(option)]
+    out: Option<PathBuf>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Decrypt every stored key (biometric, once) and report the ones that are damaged or can't be
+/// unwrapped by the current key
+#[argh(subcommand, name = "verify")]
+struct VerifyCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Install bwbio and register it with every supported browser, without prompting
+#[argh(subcommand, name = "install")]
+struct InstallCmd {
+    /// install directory (default: %LOCALAPPDATA%\bwbio)
+    #[argh(option)]
+    dir: Option<PathBuf>,
+    /// comma separated browsers to register, e.g. chrome,firefox; remembered for later (default:
+    /// the remembered ones, else every installed browser)
+    #[argh(option)]
+    browser: Option<String>,
+    /// move an install found in another directory here, with its keys and registrations
+    #[argh(switch)]
+    migrate: bool,
+    /// add a Start Menu shortcut to the management menu
+    #[argh(switch)]
+    shortcut: bool,
+    /// start the background agent at logon
+    #[argh(switch)]
+    autostart: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Unregister and remove an installation, including its keys, without prompting
+#[argh(subcommand, name = "uninstall")]
+struct UninstallCmd {
+    /// install directory (default: %LOCALAPPDATA%\bwbio)
+    #[argh(option)]
+    dir: Option<PathBuf>,
+    /// leave the stored keys and the CNG key in place
+    #[argh(switch)]
+    keep_keys: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Register the installed manifest with browsers
+#[argh(subcommand, name = "register")]
+struct RegisterCmd {
+    /// install directory holding the manifest (default: %LOCALAPPDATA%\bwbio)
+    #[argh(option)]
+    dir: Option<PathBuf>,
+    /// comma separated browsers, e.g. chrome,edge; remembered for later (default: the
+    /// remembered ones, else every installed browser)
+    #[argh(option)]
+    browser: Option<String>,
+    /// register for every user of the PC under HKEY_LOCAL_MACHINE (needs elevation)
+    #[argh(switch)]
+    machine: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show the Windows Hello status the extension would get, then show one Windows Hello prompt
+#[argh(subcommand, name = "test-bio")]
+struct TestBioCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print, follow or clear the native messaging host log
+#[argh(subcommand, name = "logs")]
+struct LogsCmd {
+    /// keep printing lines as they are written
+    #[argh(switch, short = 'f')]
+    follow: bool,
+    /// only show lines at this level or more severe: error, warn, info or debug
+    #[argh(option)]
+    level: Option<LogLevel>,
+    /// only show the last N lines
+    #[argh(option, short = 'n')]
+    lines: Option<usize>,
+    /// delete the log file
+    #[argh(switch)]
+    clear: bool,
+    /// print where the log file is
+    #[argh(switch)]
+    path: bool,
+}
+
+/// Show or change settings in the config file
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "config")]
+struct ConfigCmd {
+    #[argh(subcommand)]
+    cmd: ConfigSubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum ConfigSubCommand {
+    Get(ConfigGetCmd),
+    Set(ConfigSetCmd),
+    List(ConfigListCmd),
+    Path(ConfigPathCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print the value of a setting
+#[argh(subcommand, name = "get")]
+struct ConfigGetCmd {
+    #[argh(positional)]
+    key: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Change a setting, or unset it when no value is given
+#[argh(subcommand, name = "set")]
+struct ConfigSetCmd {
+    #[argh(positional)]
+    key: String,
+    #[argh(positional)]
+    value: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List all settings
+#[argh(subcommand, name = "list")]
+struct ConfigListCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print where the config file is
+#[argh(subcommand, name = "path")]
+struct ConfigPathCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Background agent started at logon by `install --autostart`; it has nothing to do yet and
+/// exits right away
+#[argh(subcommand, name = "agent")]
+struct AgentCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Run the native messaging host on stdin/stdout, files or a named pipe, to debug the protocol
+#[argh(subcommand, name = "serve")]
+struct ServeCmd {
+    /// read requests from this file or named pipe (\\.\pipe\<name>) instead of stdin
+    #[argh(option)]
+    input: Option<PathBuf>,
+    /// write replies to this file or named pipe instead of stdout
+    #[argh(option)]
+    output: Option<PathBuf>,
+    /// one JSON message per line instead of length-prefixed native messaging frames
+    #[argh(switch)]
+    lines: bool,
+    /// print every message to stderr, decrypted; this includes the keys sent on unlock
+    #[argh(switch)]
+    trace: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Remove the manifest registration from browsers
+#[argh(subcommand, name = "unregister")]
+struct UnregisterCmd {
+    /// comma separated browsers, e.g. chrome,edge (default: the remembered ones, else every
+    /// installed browser)
+    #[argh(option)]
+    browser: Option<String>,
+    /// remove bwbio's registrations under HKEY_LOCAL_MACHINE instead (needs elevation)
+    #[argh(switch)]
+    machine: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show the audit log of key imports, exports and deletions
+#[argh(subcommand, name = "audit")]
+struct AuditCmd {
+    /// only show entries for this user id
+    #[argh(option)]
+    user_id: Option<String>,
+    /// show at most this many of the newest entries
+    #[argh(option)]
+    limit: Option<usize>,
+}
+
 /// CNG provider commands
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "cng")]
 struct CngCmd {
+    /// use the machine-wide key store (creating keys requires administrator rights)
+    #[argh(switch)]
+    machine: bool,
     #[argh(subcommand)]
     cmd: CngSubCommand,
 }
@@ -85,6 +540,10 @@ enum CngSubCommand {
     List(CngListCmd),
     Create(CngCreateCmd),
     Delete(CngDeleteCmd),
+    Migrate(CngMigrateCmd),
+    Attest(CngAttestCmd),
+    Info(CngInfoCmd),
+    Providers(CngProvidersCmd),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -99,6 +558,16 @@ struct CngCreateCmd {
     /// key name
     #[argh(positional)]
     key_name: String,
+    /// key algorithm: rsa (default), rsa-3072, rsa-4096, ecdh-p256, ecdh-p384 or ecdh-p521
+    #[argh(option, default = "CngKeyAlgorithm::default()")]
+    algorithm: CngKeyAlgorithm,
+    /// key length in bits, replacing the one implied by --algorithm: 2048, 3072 or 4096 for
+    /// rsa; 256, 384 or 521 for ecdh
+    #[argh(option)]
+    length: Option<u32>,
+    /// require a Windows Hello/PIN gesture enforced by the key storage provider
+    #[argh(switch)]
+    require_gesture: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -110,102 +579,1420 @@ struct CngDeleteCmd {
     key_name: String,
 }
 
-pub fn kmgr_cli() {
-    let cmd: KmgrCmd = argh::from_env();
-    let key_name = match env::var("CNG_KEY_NAME") {
-        Ok(s) => HSTRING::from(s),
-        Err(_) => default_key_name(),
-    };
-    let key_dir = env::var("BW_KEY_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| {
-            env::current_exe()
-                .expect("Failed to get current exe path")
-                .parent()
-                .expect("Failed to get parent dir")
-                .to_path_buf()
-                .join("keys")
+#[derive(FromArgs, PartialEq, Debug)]
+/// Replace the CNG key with one of another algorithm and re-wrap all keys (Require biometrics)
+#[argh(subcommand, name = "migrate")]
+struct CngMigrateCmd {
+    /// target algorithm, as for `cng create --algorithm`
+    #[argh(positional)]
+    algorithm: CngKeyAlgorithm,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Export the TPM key attestation claim of a CNG key
+#[argh(subcommand, name = "attest")]
+struct CngAttestCmd {
+    /// key name
+    #[argh(positional)]
+    key_name: String,
+    /// name of the attestation identity key (AIK) that signs the claim
+    #[argh(option)]
+    aik: Option<String>,
+    /// base64 nonce from the verifier, bound into the claim
+    #[argh(option)]
+    nonce: Option<String>,
+    /// write the claim to this file instead of printing it as base64
+    #[argh(option)]
+    out: Option<PathBuf>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show how a CNG key is configured
+#[argh(subcommand, name = "info")]
+struct CngInfoCmd {
+    /// key name
+    #[argh(positional)]
+    key_name: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List installed key storage providers and the one in use
+#[argh(subcommand, name = "providers")]
+struct CngProvidersCmd {}
+
+fn providers(out: &Output) -> Result<()> {
+    let current = CngProvider::new().map(|p| p.kind());
+    let providers = CngProvider::enumerate_providers()?;
+    if out.json {
+        let providers = providers
+            .iter()
+            .map(|p| {
+                json!({
+                    "name": p.name,
+                    "comment": p.comment,
+                    "inUse": current.as_ref().is_ok_and(|&k| Some(k) == p.kind),
+                })
+            })
+            .collect::<Vec<_>>();
+        out.print(json!({
+            "providers": providers,
+            "tpmAvailable": CngProvider::tpm_available(),
+            "inUse": current.as_ref().ok().map(ToString::to_string),
+        }));
+        return Ok(());
+    }
+    for p in providers {
+        let marker = if current.as_ref().is_ok_and(|&k| Some(k) == p.kind) {
+            "*"
+        } else {
+            " "
+        };
+        if p.comment.is_empty() {
+            println!("{marker} {}", p.name);
+        } else {
+            println!("{marker} {} ({})", p.name, p.comment);
+        }
+    }
+    println!(
+        "TPM: {}",
+        if CngProvider::tpm_available() {
+            "available"
+        } else {
+            "not available"
+        }
+    );
+    match current {
+        Ok(kind) => println!("In use: {kind}"),
+        Err(e) => println!("In use: none ({e})"),
+    }
+    Ok(())
+}
+
+/// `, email, imported …, last used …` for the parts of `metadata` that are known.
+pub(crate) fn describe_metadata(metadata: &KeyMetadata) -> String {
+    let time = |secs: u64| format_utc(UNIX_EPOCH + Duration::from_secs(secs));
+    let mut out = String::new();
+    if let Some(email) = &metadata.email {
+        out += &format!(", {email}");
+    }
+    if let Some(server_url) = &metadata.server_url {
+        out += &format!(", {server_url}");
+    }
+    if let Some(imported_at) = metadata.imported_at {
+        out += &format!(", imported {}", time(imported_at));
+    }
+    if let Some(last_exported_at) = metadata.last_exported_at {
+        out += &format!(", last used {}", time(last_exported_at));
+    }
+    if let Some(days) = metadata.expire_after_days {
+        let action = if metadata.delete_when_expired {
+            "deleted"
+        } else {
+            "refused"
+        };
+        out += &format!(", {action} after {days} unused days");
+    }
+    out
+}
+
+fn audit(kmgr: &KeyManager, cmd: AuditCmd, out: &Output) -> Result<()> {
+    let mut entries = kmgr.audit_log().entries()?;
+    if let Some(user_id) = &cmd.user_id {
+        entries.retain(|e| &e.user_id == user_id);
+    }
+    let skip = cmd
+        .limit
+        .map_or(0, |limit| entries.len().saturating_sub(limit));
+    if out.json {
+        out.print(json!({ "entries": &entries[skip..] }));
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("No audit log entries.");
+    }
+    for e in &entries[skip..] {
+        let time = format_utc(UNIX_EPOCH + Duration::from_secs(e.time));
+        print!("{time}  {:<13}  {}  via {}", e.action, e.user_id, e.source);
+        match &e.error {
+            Some(error) => println!(": {error}"),
+            None => println!(),
+        }
+    }
+    Ok(())
+}
+
+fn info(provider: &CngProvider, key_name: &str, out: &Output) -> Result<()> {
+    let key = provider.open_key(HSTRING::from(key_name))?;
+    let props = key.properties()?;
+    let export = props.export_policy_names();
+    if out.json {
+        out.print(json!({
+            "name": key_name,
+            "provider": props.provider,
+            "algorithm": props.algorithm,
+            "length": props.length,
+            "exportPolicy": export,
+            "uiPolicy": props.ui_policy,
+            "gestureRequired": key.is_gesture_bound(),
+            "container": props.unique_name,
+            "created": props.modified.map(format_utc),
+        }));
+        return Ok(());
+    }
+    println!("Name:          {key_name}");
+    println!("Provider:      {}", props.provider);
+    println!("Algorithm:     {}", props.algorithm);
+    println!("Length:        {} bits", props.length);
+    println!(
+        "Export policy: {}",
+        if export.is_empty() {
+            "not exportable".to_string()
+        } else {
+            export.join(", ")
+        }
+    );
+    println!(
+        "UI policy:     {}",
+        match props.ui_policy {
+            Some(flags) if key.is_gesture_bound() => format!("gesture required (0x{flags:x})"),
+            Some(flags) => format!("0x{flags:x}"),
+            None => "none".to_string(),
+        }
+    );
+    println!("Container:     {}", props.unique_name);
+    if let Some(modified) = props.modified {
+        println!("Created:       {}", format_utc(modified));
+    }
+    Ok(())
+}
+
+fn attest(provider: &CngProvider, cmd: CngAttestCmd, out: &Output) -> Result<()> {
+    if provider.kind() != CngProviderKind::Platform {
+        bail!(
+            "Attestation requires the TPM-backed {}",
+            CngProviderKind::Platform
+        );
+    }
+    let key = provider.open_key(HSTRING::from(cmd.key_name.as_str()))?;
+    let aik = cmd
+        .aik
+        .map(|name| provider.open_key(HSTRING::from(name)))
+        .transpose()?;
+    let nonce = cmd.nonce.as_deref().map(base64_decode).transpose()?;
+    let claim = key.attest(aik.as_ref(), nonce.as_deref().unwrap_or_default())?;
+    match cmd.out {
+        Some(path) => {
+            write(&path, claim)?;
+            out.done(
+                json!({ "path": path }),
+                &format!("Attestation claim written to {}.", path.display()),
+            );
+        }
+        None => {
+            let claim = base64_encode(&claim);
+            out.done(json!({ "claim": claim }), &claim);
+        }
+    }
+    Ok(())
+}
+
+/// The key to import from exactly one of the argument, stdin or a file, or a hidden prompt when
+/// none is given.
+fn read_import_key(
+    key: Option<String>,
+    stdin: bool,
+    file: Option<PathBuf>,
+) -> Result<SecretString> {
+    let key = match (key, stdin, file) {
+        (Some(key), false, None) => SecretString::from(key),
+        (None, true, None) => {
+            let mut key = String::new();
+            io::stdin().read_to_string(&mut key)?;
+            SecretString::from(key)
+        }
+        (None, false, Some(path)) => SecretString::from(
+            read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?,
+        ),
+        // Masked, so the key isn't shown on screen even when typed or pasted by hand.
+        (None, false, None) if io::stdin().is_terminal() => SecretString::from(
+            Password::new()
+                .with_prompt("User key (base64)")
+                .interact()?,
+        ),
+        (None, false, None) => {
+            bail!("No key given and no terminal to prompt on; pass it with --stdin or --file")
+        }
+        _ => bail!("Pass the key only one way: as an argument, --stdin or --file"),
+    };
+    let trimmed = key.expose().trim();
+    if trimmed.is_empty() {
+        bail!("The key is empty");
+    }
+    Ok(SecretString::from(trimmed.to_string()))
+}
+
+/// Copies the key to the clipboard and, unless `clear_after` is 0, waits to clear it again.
+fn export_to_clipboard(
+    kmgr: &KeyManager,
+    user_id: &str,
+    clear_after: u64,
+    out: &Output,
+) -> Result<()> {
+    let key = kmgr.export_key(user_id)?;
+    clipboard::copy_secret(key.expose())?;
+    out.done(
+        json!({ "userId": user_id, "copied": true, "clearAfterSeconds": clear_after }),
+        &if clear_after == 0 {
+            "Key copied to the clipboard.".to_string()
+        } else {
+            format!("Key copied to the clipboard; it is cleared in {clear_after} seconds.")
+        },
+    );
+    if clear_after > 0 {
+        thread::sleep(Duration::from_secs(clear_after));
+        if clipboard::clear_if_unchanged(key.expose())? && !out.json {
+            println!("Clipboard cleared.");
+        }
+    }
+    Ok(())
+}
+
+fn import_desktop(kmgr: &KeyManager, cmd: ImportDesktopCmd) -> Result<String> {
+    let path = cmd
+        .data
+        .or_else(default_data_path)
+        .ok_or(anyhow!("APPDATA not set. Pass --data explicitly."))?;
+    let accounts = read_accounts(&path)?;
+    let account = match cmd.user_id {
+        Some(user_id) => accounts.iter().find(|a| a.user_id() == user_id),
+        None if accounts.len() > 1 => {
+            let ids = accounts.iter().map(|a| a.user_id()).collect::<Vec<_>>();
+            bail!(
+                "Multiple accounts found, pass --user-id: {}",
+                ids.join(", ")
+            );
+        }
+        None => accounts.first(),
+    }
+    .ok_or(anyhow!("No matching account found in {}", path.display()))?;
+    let password = SecretString::from(
+        Password::new()
+            .with_prompt(format!("Master password for {}", account.email()))
+            .interact()?,
+    );
+    kmgr.import_desktop_account(account, password.expose())?;
+    Ok(account.user_id().to_string())
+}
+
+fn migrate_desktop(kmgr: &KeyManager, cmd: MigrateDesktopCmd, out: &Output) -> Result<()> {
+    let user_ids = match cmd.user_id {
+        Some(user_id) => vec![user_id],
+        None => biometric_user_ids()?,
+    };
+    if user_ids.is_empty() {
+        bail!("Bitwarden desktop has no accounts with biometric unlock enabled");
+    }
+    let progress = if out.json {
+        Progress::hidden()
+    } else {
+        Progress::bar(user_ids.len(), "")
+    };
+    let mut results = Vec::new();
+    for user_id in user_ids {
+        progress.step(&format!("Importing {user_id} (confirm with Windows Hello)"));
+        let result = kmgr.import_desktop_biometric(&user_id);
+        if !out.json {
+            progress.suspend(|| match &result {
+                Ok(_) => println!("Key for {user_id} imported successfully."),
+                Err(e) => eprintln!("Failed to import key for {user_id}: {e}"),
+            });
+        }
+        progress.inc();
+        results.push(match result {
+            Ok(_) => json!({ "userId": user_id, "ok": true }),
+            Err(e) => json!({ "userId": user_id, "ok": false, "error": e.to_string() }),
         });
-    let kmgr = KeyManager::new(key_name, key_dir);
+    }
+    drop(progress);
+    if out.json {
+        out.print(json!({ "results": results }));
+    }
+    Ok(())
+}
+
+fn install(
+    InstallCmd {
+        dir,
+        browser,
+        migrate,
+        shortcut,
+        autostart,
+    }: InstallCmd,
+    out: &Output,
+) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => default_install_dir()?,
+    };
+    let hosts = select_browsers(browser.as_deref())?;
+    let previous = find_existing_install().filter(|old| !same_dir(old, &dir));
+    let installed = {
+        let progress = Progress::spinner_unless(out.json, "Installing bwbio");
+        match &previous {
+            Some(old) if migrate => migrate_install(old, &dir, &hosts, &progress),
+            _ => perform_install(&dir, &hosts, &progress),
+        }
+    };
+    // The registration comes last, so only it is retried.
+    let exe = match installed {
+        Err(e) if e.is::<RegistryAccessDenied>() && offer_elevation(&e, out) => {
+            register_elevated(&dir, &hosts, RegistryScope::User)?;
+            dir.join(EXE_NAME)
+        }
+        result => result?,
+    };
+    if browser.is_some() {
+        remember_browsers(&hosts)?;
+    }
+    if shortcut {
+        create_start_menu_shortcut(&exe)?;
+    }
+    if autostart {
+        enable_autostart(&exe).context("Failed to add the logon autostart entry")?;
+    }
+    match previous {
+        Some(old) if migrate => out.done(
+            json!({ "installDir": dir, "exe": exe, "migratedFrom": old }),
+            &format!(
+                "bwbio moved from {} to {}, with its keys.",
+                old.display(),
+                dir.display()
+            ),
+        ),
+        Some(old) => {
+            if !out.json {
+                eprintln!(
+                    "Warning: bwbio is also installed in {}; `install --migrate` moves it here with its keys.",
+                    old.display()
+                );
+            }
+            out.done(
+                json!({ "installDir": dir, "exe": exe, "otherInstall": old }),
+                &format!("bwbio installed to {}.", dir.display()),
+            )
+        }
+        None => out.done(
+            json!({ "installDir": dir, "exe": exe }),
+            &format!("bwbio installed to {}.", dir.display()),
+        ),
+    }
+    Ok(())
+}
+
+fn browsers(out: &Output) {
+    let browsers = detect_browsers();
+    if out.json {
+        out.print(json!({ "browsers": browsers }));
+        return;
+    }
+    for browser in &browsers {
+        let installed = if browser.installed.is_some() {
+            "installed"
+        } else {
+            "not found"
+        };
+        match &browser.target {
+            Some(target) => println!("{:<16} {installed:<10} -> {target}", browser.name),
+            None => println!("{:<16} {installed:<10} not registered", browser.name),
+        }
+    }
+}
+
+fn update(UpdateCmd { check, dir }: UpdateCmd, out: &Output) -> Result<()> {
+    let current = env!("CARGO_PKG_VERSION");
+    let release = latest_release()?;
+    if !release.is_newer() {
+        out.done(
+            json!({ "version": current, "latest": release, "updateAvailable": false }),
+            &format!("bwbio {current} is up to date."),
+        );
+        return Ok(());
+    }
+    if check {
+        out.done(
+            json!({ "version": current, "latest": release, "updateAvailable": true }),
+            &format!(
+                "bwbio {} is available (running {current}): {}",
+                release.version, release.url
+            ),
+        );
+        return Ok(());
+    }
+    let dir = match dir {
+        Some(dir) => dir,
+        None => default_install_dir()?,
+    };
+    if !is_installed(&dir) {
+        bail!("bwbio is not installed in {}", dir.display());
+    }
+    let exe = apply_update(
+        &dir,
+        &release,
+        &select_browsers(None)?,
+        &Progress::spinner_unless(out.json, "Updating bwbio"),
+    )?;
+    out.done(
+        json!({ "version": release.version, "previousVersion": current, "exe": exe }),
+        &format!(
+            "bwbio updated from {current} to {}. Browsers pick it up the next time they start the host.",
+            release.version
+        ),
+    );
+    Ok(())
+}
+
+fn print_manifest(cmd: ManifestPrintCmd, out: &Output) -> Result<()> {
+    let exe = match cmd.exe {
+        Some(exe) => exe,
+        None => env::current_exe().context("Failed to get current exe path")?,
+    };
+    let exe = exe.canonicalize().unwrap_or(exe);
+    let manifest = native_messaging_manifest(&exe, cmd.browser);
+    let Some(path) = cmd.out else {
+        if out.json {
+            out.print(json!({ "manifest": manifest }));
+        } else {
+            println!("{}", serde_json::to_string_pretty(&manifest)?);
+        }
+        return Ok(());
+    };
+    write(&path, serde_json::to_string_pretty(&manifest)?)?;
+    out.done(
+        json!({ "path": path }),
+        &format!(
+            "Manifest written to {}. Point the browser's com.8bit.bitwarden registry key at it.",
+            path.display()
+        ),
+    );
+    Ok(())
+}
+
+fn purge(cmd: PurgeCmd, out: &Output) -> Result<()> {
+    let dir = match cmd.dir {
+        Some(dir) => dir,
+        None => default_install_dir()?,
+    };
+    let key_dir = key_dir_for(&dir);
+    let confirmed = cmd.yes
+        || Confirm::new()
+            .with_prompt(format!(
+                "Remove the browser registration and {}{}?",
+                dir.display(),
+                match (cmd.keep_keys, cmd.keep_cng_key) {
+                    (true, _) => String::new(),
+                    (false, true) => format!(", and the stored keys in {}", key_dir.display()),
+                    (false, false) =>
+                        format!(", the stored keys in {} and the CNG key", key_dir.display()),
+                }
+            ))
+            .default(false)
+            .interact()
+            .unwrap_or(false);
+    if !confirmed {
+        out.done(json!({ "purged": false }), "Purge cancelled.");
+        return Ok(());
+    }
+    perform_uninstall(
+        &dir,
+        &key_dir,
+        UninstallOptions {
+            keep_keys: cmd.keep_keys,
+            keep_cng_key: cmd.keep_cng_key,
+            ..Default::default()
+        },
+    )?;
+    out.done(
+        json!({
+            "purged": true,
+            "installDir": dir,
+            "keptKeys": cmd.keep_keys,
+            "keptCngKey": cmd.keep_keys || cmd.keep_cng_key,
+        }),
+        "Purge finished.",
+    );
+    Ok(())
+}
+
+fn uninstall(UninstallCmd { dir, keep_keys }: UninstallCmd, out: &Output) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => default_install_dir()?,
+    };
+    let key_dir = key_dir_for(&dir);
+    if !is_installed(&dir) {
+        bail!("bwbio is not installed in {}", dir.display());
+    }
+    perform_uninstall(
+        &dir,
+        &key_dir,
+        UninstallOptions {
+            keep_keys,
+            ..Default::default()
+        },
+    )?;
+    out.done(
+        json!({ "installDir": dir, "keptKeys": keep_keys }),
+        &if keep_keys {
+            format!("Uninstall finished; keys kept in {}.", key_dir.display())
+        } else {
+            "Uninstall finished.".to_string()
+        },
+    );
+    Ok(())
+}
+
+fn register(
+    RegisterCmd {
+        dir,
+        browser,
+        machine,
+    }: RegisterCmd,
+    out: &Output,
+) -> Result<()> {
+    let hosts = select_browsers(browser.as_deref())?;
+    let dir = match dir {
+        Some(dir) => dir,
+        None => default_install_dir()?,
+    };
+    let scope = if machine {
+        RegistryScope::Machine
+    } else {
+        RegistryScope::User
+    };
+    let registered = match register_native_messaging_manifest_in(&dir, &hosts, scope) {
+        Err(e) if e.is::<RegistryAccessDenied>() && offer_elevation(&e, out) => {
+            register_elevated(&dir, &hosts, scope)?;
+            hosts.iter().map(|h| h.id).collect()
+        }
+        result => result?,
+    };
+    if browser.is_some() {
+        remember_browsers(&hosts)?;
+    }
+    out.done(
+        json!({ "browsers": registered }),
+        &format!("Registered with: {}.", registered.join(", ")),
+    );
+    Ok(())
+}
+
+/// Asks whether to retry a step the registry refused as administrator; never when the output
+/// is JSON or nobody is at the console.
+fn offer_elevation(e: &anyhow::Error, out: &Output) -> bool {
+    !out.json
+        && io::stdin().is_terminal()
+        && Confirm::new()
+            .with_prompt(format!("{e}. Retry this step as administrator?"))
+            .default(true)
+            .interact()
+            .unwrap_or(false)
+}
+
+fn unregister(UnregisterCmd { browser, machine }: UnregisterCmd, out: &Output) -> Result<()> {
+    let hosts = select_browsers(browser.as_deref())?;
+    let removed = if machine {
+        unregister_machine_manifest(&hosts)?
+    } else {
+        unregister_native_messaging_manifest(&hosts)
+    };
+    out.done(
+        json!({ "browsers": removed }),
+        &format!("Unregistered from: {}.", removed.join(", ")),
+    );
+    Ok(())
+}
+
+/// Reports what the extension would be told, then prompts once if Windows Hello is available.
+fn test_bio(out: &Output) -> CliExit {
+    let details = biometrics_details();
+    let code = details.status.wire_code();
+    let verified = (details.status == BiometricsStatus::Available)
+        .then(|| authenticate_with_biometrics("Test Windows Hello for bwbio"));
+    if out.json {
+        out.print(json!({
+            "status": details.status.to_string(),
+            "statusCode": code,
+            "notes": details.notes(),
+            "verified": verified,
+        }));
+    } else {
+        println!(
+            "Status: {} (sent to the extension as {code})",
+            details.status
+        );
+        for note in details.notes() {
+            println!("Note: {note}.");
+        }
+        println!(
+            "Verification: {}",
+            match verified {
+                Some(true) => "succeeded",
+                Some(false) => "failed or cancelled",
+                None => "skipped, Windows Hello is not available",
+            }
+        );
+    }
+    match verified {
+        Some(true) => CliExit::Success,
+        Some(false) => CliExit::AuthFailed,
+        None => CliExit::Unavailable,
+    }
+}
+
+/// How often `logs --follow` looks for new lines.
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// One line each; unlike `doctor` nothing is probed beyond opening the key storage provider.
+fn status(kmgr: &KeyManager, key_name: HSTRING, key_dir: &Path, out: &Output) -> Result<()> {
+    let installed = default_install_dir()?
+        .join(EXE_NAME)
+        .canonicalize()
+        .ok()
+        .map(|p| p.display().to_string());
+    let browsers = NATIVE_MESSAGING_HOSTS
+        .iter()
+        .filter(|host| registered_manifest(host).is_some())
+        .map(|host| host.browser)
+        .collect::<Vec<_>>();
+    let key_name = active_cng_key_name(&*open_key_store(key_dir.to_path_buf())?, key_name);
+    let provider = match CngProvider::new() {
+        Ok(p) => p.kind().to_string(),
+        Err(e) => format!("unavailable: {e}"),
+    };
+    let key_count = kmgr
+        .list_keys()
+        .context("Failed to list stored keys")?
+        .len();
+    let biometrics = biometrics_details().status;
+    if out.json {
+        out.print(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "installedPath": installed,
+            "registeredBrowsers": browsers,
+            "provider": provider,
+            "cngKeyName": key_name.to_string(),
+            "machineKey": machine_key_selected(),
+            "keyDir": key_dir,
+            "storedKeys": key_count,
+            "biometrics": biometrics.to_string(),
+        }));
+        return Ok(());
+    }
+    println!("bwbio {}", env!("CARGO_PKG_VERSION"));
+    println!("Installed:      {}", installed.as_deref().unwrap_or("no"));
+    println!(
+        "Browsers:       {}",
+        if browsers.is_empty() {
+            "none registered".to_string()
+        } else {
+            browsers.join(", ")
+        }
+    );
+    println!("Provider:       {provider}");
+    println!(
+        "CNG key:        {key_name}{}",
+        if machine_key_selected() {
+            " (machine-wide)"
+        } else {
+            ""
+        }
+    );
+    println!("Stored keys:    {key_count} in {}", key_dir.display());
+    println!("Windows Hello:  {biometrics}");
+    Ok(())
+}
+
+/// Works on the file itself rather than [`config()`], so a file that doesn't parse is reported
+/// instead of ignored. Environment variables aren't applied.
+fn configure(cmd: ConfigCmd, out: &Output) -> Result<()> {
+    let path = Config::path()?;
     match cmd.cmd {
-        Command::List(_) => match kmgr.list_keys() {
-            Ok(keys) => {
-                if keys.is_empty() {
-                    println!("No keys found.");
+        ConfigSubCommand::Path(_) => out.done(json!({ "path": path }), &path.display().to_string()),
+        ConfigSubCommand::Get(ConfigGetCmd { key }) => {
+            let value = Config::load()?.get(&key)?;
+            out.done(
+                json!({ "key": key, "value": value }),
+                value.as_deref().unwrap_or("(not set)"),
+            );
+        }
+        ConfigSubCommand::Set(ConfigSetCmd { key, value }) => {
+            let mut config = Config::load()?;
+            config.set(&key, value.as_deref())?;
+            config.save()?;
+            let value = config.get(&key)?;
+            let message = match &value {
+                Some(v) => format!("{key} = {v}"),
+                None => format!("{key} unset"),
+            };
+            out.done(json!({ "key": key, "value": value }), &message);
+        }
+        ConfigSubCommand::List(_) => {
+            let config = Config::load()?;
+            let settings = CONFIG_KEYS
+                .iter()
+                .map(|key| Ok((key.to_string(), config.get(key)?.into())))
+                .collect::<Result<serde_json::Map<String, Value>>>()?;
+            if out.json {
+                out.print(json!({ "path": path, "settings": settings }));
+                return Ok(());
+            }
+            println!("{}", path.display());
+            for (key, value) in &settings {
+                println!("  {key:<18} {}", value.as_str().unwrap_or("(not set)"));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn logs(cmd: LogsCmd, out: &Output) -> Result<()> {
+    let log = Log::new(default_key_dir()?);
+    if cmd.path {
+        out.done(
+            json!({ "path": log.path() }),
+            &log.path().display().to_string(),
+        );
+        return Ok(());
+    }
+    if cmd.clear {
+        log.clear()?;
+        out.done(json!({ "path": log.path() }), "Log cleared.");
+        return Ok(());
+    }
+    if cmd.follow && out.json {
+        bail!("--follow can't be combined with --json");
+    }
+    let shown = |line: &&str| {
+        cmd.level
+            .is_none_or(|level| line_level(line).is_some_and(|l| l <= level))
+    };
+    let contents = log.read()?;
+    let lines = contents.lines().filter(shown).collect::<Vec<_>>();
+    let skip = cmd.lines.map_or(0, |n| lines.len().saturating_sub(n));
+    if out.json {
+        out.print(json!({ "path": log.path(), "lines": &lines[skip..] }));
+        return Ok(());
+    }
+    for line in &lines[skip..] {
+        println!("{line}");
+    }
+    if !cmd.follow {
+        return Ok(());
+    }
+    let mut offset = contents.len() as u64;
+    loop {
+        thread::sleep(LOG_POLL_INTERVAL);
+        let Ok(mut file) = File::open(log.path()) else {
+            offset = 0;
+            continue;
+        };
+        let len = file.metadata()?.len();
+        // Cleared or replaced since the last read.
+        if len < offset {
+            offset = 0;
+        }
+        if len == offset {
+            continue;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut new = String::new();
+        file.read_to_string(&mut new)?;
+        // Keep a partly written last line for the next round.
+        let complete = new.rfind('\n').map_or(0, |i| i + 1);
+        for line in new[..complete].lines().filter(shown) {
+            println!("{line}");
+        }
+        offset += complete as u64;
+    }
+}
+
+fn serve(cmd: ServeCmd) -> Result<()> {
+    let (input, output): (Box<dyn Read>, Box<dyn Write + Send>) = match (cmd.input, cmd.output) {
+        // One duplex handle, as a named pipe can't always be opened twice.
+        (Some(input), Some(output)) if input == output => {
+            let file = OpenOptions::new().read(true).write(true).open(&input)?;
+            (Box::new(file.try_clone()?), Box::new(file))
+        }
+        (input, output) => (
+            match input {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(io::stdin()),
+            },
+            match output {
+                // Pipes must be opened, not created.
+                Some(path) if path.starts_with(r"\\.\pipe\") => {
+                    Box::new(OpenOptions::new().write(true).open(path)?)
+                }
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(io::stdout()),
+            },
+        ),
+    };
+    Ok(browser::serve(HostIo {
+        input,
+        output,
+        lines: cmd.lines,
+        trace: cmd.trace,
+        verifier: Box::new(WindowsHello),
+        key_manager: None,
+    })?)
+}
+
+/// Where command results go: human-readable text, or with `--json` one JSON object on stdout
+/// per command, `{"ok": true, ...}` or `{"ok": false, "error": {"message": ...}}`.
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    /// Prints a successful result object.
+    fn print(&self, mut value: Value) {
+        if let Value::Object(map) = &mut value {
+            map.insert("ok".to_string(), Value::Bool(true));
+        }
+        println!("{value}");
+    }
+
+    /// Reports a finished action: `value` with `--json`, otherwise `message`.
+    fn done(&self, value: Value, message: &str) {
+        if self.json {
+            self.print(value);
+        } else {
+            println!("{message}");
+        }
+    }
+
+    /// Reports a failure with its whole context chain.
+    fn fail(&self, e: &anyhow::Error, code: CliExit) {
+        if self.json {
+            println!(
+                "{}",
+                json!({ "ok": false, "error": { "message": format!("{e:#}"), "exitCode": code as u8 } })
+            );
+        } else {
+            eprintln!("{e:#}");
+        }
+    }
+}
+
+/// Exit codes of the command line tool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CliExit {
+    Success = 0,
+    /// Any error not listed below; also `check` for a missing key, `doctor` with failed checks.
+    Failure = 1,
+    /// Bad arguments or user id.
+    Usage = 2,
+    /// No key is stored for the user, or it expired.
+    KeyNotFound = 3,
+    /// Windows Hello was failed or cancelled.
+    AuthFailed = 4,
+    /// Windows Hello can't be used: a Remote Desktop session, or in biometric-only mode no sensor
+    /// or no way to bring the prompt to the front.
+    Unavailable = 5,
+}
+
+impl CliExit {
+    /// The exit code for the first error in the chain that has one of its own.
+    fn of(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            match cause.downcast_ref::<BwbioError>() {
+                Some(BwbioError::Key(KeyManagerError::InvalidUserId(_))) => return CliExit::Usage,
+                Some(BwbioError::Key(
+                    KeyManagerError::KeyNotFound(_) | KeyManagerError::KeyExpired { .. },
+                )) => return CliExit::KeyNotFound,
+                Some(BwbioError::Bio(
+                    PresenceError::Unavailable(_)
+                    | PresenceError::NoBiometricSensor
+                    | PresenceError::NoFocus,
+                )) => return CliExit::Unavailable,
+                // Includes cancelling the prompt of a gesture-bound CNG key.
+                Some(e) if e.is_auth_failure() => return CliExit::AuthFailed,
+                _ => {}
+            }
+        }
+        CliExit::Failure
+    }
+}
+
+impl From<CliExit> for ExitCode {
+    fn from(code: CliExit) -> Self {
+        ExitCode::from(code as u8)
+    }
+}
+
+/// Like [`argh::from_env`], but returns usage errors as [`CliExit::Usage`] instead of exiting
+/// with 1.
+/// Deployment tools such as Intune expect `--silent-install [--dir <dir>] [--browsers <list>]`,
+/// which is `install [--dir <dir>] [--browser <list>]`; any other arguments pass through.
+fn silent_install_args(args: &[String]) -> Vec<&str> {
+    let silent = args.iter().any(|a| a == "--silent-install");
+    args.iter()
+        .map(|a| match a.as_str() {
+            "--silent-install" => "install",
+            "--browsers" if silent => "--browser",
+            a => a,
+        })
+        .collect()
+}
+
+fn parse_args() -> Result<KmgrCmd, CliExit> {
+    let strings = env::args().collect::<Vec<_>>();
+    let cmd = Path::new(&strings[0])
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or(&strings[0]);
+    let args = silent_install_args(&strings[1..]);
+    KmgrCmd::from_args(&[cmd], &args).map_err(|early_exit| match early_exit.status {
+        Ok(()) => {
+            println!("{}", early_exit.output);
+            CliExit::Success
+        }
+        Err(()) => {
+            eprintln!(
+                "{}\nRun {cmd} --help for more information.",
+                early_exit.output
+            );
+            CliExit::Usage
+        }
+    })
+}
+
+pub fn kmgr_cli() -> ExitCode {
+    let cmd = match parse_args() {
+        Ok(cmd) => cmd,
+        Err(code) => return code.into(),
+    };
+    set_overrides(Overrides {
+        key_dir: cmd.key_dir,
+        cng_key_name: cmd.key_name,
+    });
+    if let Ok(dir) = default_key_dir() {
+        init_logging(dir);
+    }
+    let out = Output { json: cmd.json };
+    match run(cmd.cmd, &out) {
+        Ok(code) => code,
+        Err(e) => {
+            let code = CliExit::of(&e);
+            out.fail(&e, code);
+            code
+        }
+    }
+    .into()
+}
+
+fn run(command: Command, out: &Output) -> Result<CliExit> {
+    // A config file that doesn't load would quietly turn its switches off; `config` is left
+    // working so the file can be fixed.
+    if !matches!(command, Command::Config(_)) {
+        check_config()?;
+    }
+    // These don't touch stored keys, so they run without a key manager.
+    match command {
+        Command::Install(cmd) => install(cmd, out).context("Installation failed")?,
+        Command::Uninstall(cmd) => uninstall(cmd, out).context("Uninstall failed")?,
+        Command::Purge(cmd) => purge(cmd, out).context("Purge failed")?,
+        Command::Register(cmd) => {
+            register(cmd, out).context("Failed to register browser integration")?
+        }
+        Command::Unregister(cmd) => {
+            unregister(cmd, out).context("Failed to unregister browser integration")?
+        }
+        Command::TestBio(_) => return Ok(test_bio(out)),
+        Command::Browsers(_) => browsers(out),
+        Command::Manifest(ManifestCmd {
+            cmd: ManifestSubCommand::Print(cmd),
+        }) => print_manifest(cmd, out).context("Failed to write the manifest")?,
+        Command::Update(cmd) => update(cmd, out).context("Update failed")?,
+        Command::Logs(cmd) => logs(cmd, out).context("Failed to read the log")?,
+        Command::Config(cmd) => configure(cmd, out).context("Failed to access the config file")?,
+        Command::Agent(_) => {}
+        // The host opens its own key manager, as it does when started by a browser.
+        Command::Serve(cmd) => serve(cmd).context("Native messaging host failed")?,
+        command => return run_with_key_manager(command, out),
+    }
+    Ok(CliExit::Success)
+}
+
+fn run_with_key_manager(command: Command, out: &Output) -> Result<CliExit> {
+    let key_name = config().cng_key_name();
+    let key_dir = default_key_dir()?;
+    let mut kmgr = KeyManager::new(key_name.clone(), key_dir.clone())?;
+    match command {
+        Command::List(_) => {
+            let keys = kmgr.list_keys().context("Failed to list keys")?;
+            if out.json {
+                out.print(json!({ "keys": keys }));
+            } else if keys.is_empty() {
+                println!("No keys found.");
+            } else {
+                for k in keys {
+                    println!("Key: {}{}", k.user_id, describe_metadata(&k.metadata));
+                }
+            }
+        }
+        Command::Import(ImportCmd {
+            user_id,
+            key,
+            stdin,
+            file,
+            server,
+        }) => {
+            read_import_key(key, stdin, file)
+                .and_then(|key| {
+                    kmgr.import_key(&user_id, key.expose())?;
+                    match &server {
+                        Some(server) => Ok(kmgr.set_server_url(&user_id, Some(server))?),
+                        None => Ok(()),
+                    }
+                })
+                .context("Failed to import key")?;
+            out.done(json!({ "userId": user_id }), "Key imported successfully.");
+        }
+        Command::ImportDesktop(cmd) => {
+            let user_id = import_desktop(&kmgr, cmd)
+                .context("Failed to import key from Bitwarden desktop")?;
+            out.done(
+                json!({ "userId": user_id }),
+                &format!("Key for {user_id} imported successfully."),
+            );
+        }
+        Command::MigrateDesktop(cmd) => {
+            migrate_desktop(&kmgr, cmd, out).context("Failed to migrate from Bitwarden desktop")?
+        }
+        Command::Export(ExportCmd {
+            user_id,
+            copy: true,
+            clear_after,
+        }) => export_to_clipboard(&kmgr, &user_id, clear_after, out)
+            .context("Failed to export key")?,
+        Command::Export(ExportCmd { user_id, .. }) => {
+            let k = kmgr.export_key(&user_id).context("Failed to export key")?;
+            out.done(json!({ "userId": user_id, "key": k.expose() }), k.expose());
+        }
+        Command::Delete(DeleteCmd { user_id }) => {
+            kmgr.delete_key(&user_id).context("Failed to delete key")?;
+            out.done(json!({ "userId": user_id }), "Key deleted successfully.");
+        }
+        Command::Rename(RenameCmd {
+            old_user_id,
+            new_user_id,
+        }) => {
+            kmgr.rename_key(&old_user_id, &new_user_id)
+                .context("Failed to rename key")?;
+            out.done(
+                json!({ "oldUserId": old_user_id, "userId": new_user_id }),
+                &format!("Key for {old_user_id} moved to {new_user_id}."),
+            );
+        }
+        Command::Check(CheckCmd { user_id }) => {
+            let exists = kmgr
+                .check_key_exists(&user_id)
+                .context("Failed to check key")?;
+            out.done(
+                json!({ "userId": user_id, "exists": exists }),
+                if exists {
+                    "Key exists."
                 } else {
-                    for k in keys {
-                        println!("Key: {k}");
+                    "Key does not exist."
+                },
+            );
+            if !exists {
+                return Ok(CliExit::Failure);
+            }
+        }
+        Command::SelfTest(_) => {
+            self_test().context("Crypto self-test failed")?;
+            out.done(json!({}), "Crypto self-test passed.");
+        }
+        Command::Bench(BenchCmd { iterations }) => {
+            let results = bench::run(iterations).context("Benchmark failed")?;
+            if out.json {
+                let results = results
+                    .iter()
+                    .map(|r| {
+                        json!({
+                            "name": r.name,
+                            "iterations": r.iterations,
+                            "meanNanos": r.mean.as_nanos() as u64,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                out.print(json!({ "results": results }));
+            } else {
+                for r in results {
+                    println!(
+                        "{:<24} {:>10.2?} ({} iterations)",
+                        r.name, r.mean, r.iterations
+                    );
+                }
+            }
+        }
+        Command::Backup(BackupCmd { path }) => {
+            let n = Password::new()
+                .with_prompt("Backup passphrase")
+                .with_confirmation("Confirm passphrase", "Passphrases don't match")
+                .interact()
+                .map_err(anyhow::Error::from)
+                .and_then(|p| Ok(kmgr.backup(&path, SecretString::from(p))?))
+                .context("Failed to back up keys")?;
+            out.done(
+                json!({ "count": n, "path": path }),
+                &format!("{n} key(s) backed up to {}.", path.display()),
+            );
+        }
+        Command::Restore(RestoreCmd { path }) => {
+            let n = Password::new()
+                .with_prompt("Backup passphrase")
+                .interact()
+                .map_err(anyhow::Error::from)
+                .and_then(|p| Ok(kmgr.restore(&path, SecretString::from(p))?))
+                .context("Failed to restore keys")?;
+            out.done(json!({ "count": n }), &format!("{n} key(s) restored."));
+        }
+        Command::Rotate(RotateCmd { new_name }) => {
+            let progress = Progress::spinner_unless(out.json, "Rotating the CNG key");
+            let n = kmgr
+                .rotate_cng_key(&new_name, &mut |step| progress.step(step))
+                .context("Failed to rotate CNG key")?;
+            drop(progress);
+            out.done(
+                json!({ "keyName": new_name, "count": n }),
+                &format!("Rotated to CNG key {new_name}; {n} key(s) re-wrapped."),
+            );
+        }
+        Command::Expire(ExpireCmd {
+            user_id,
+            days,
+            delete,
+        }) => {
+            kmgr.set_expiry(&user_id, days, delete)
+                .context("Failed to set key expiry")?;
+            out.done(
+                json!({ "userId": user_id, "expireAfterDays": days, "deleteWhenExpired": delete }),
+                &match days {
+                    Some(days) => format!("Key for {user_id} expires after {days} unused days."),
+                    None => format!("Key for {user_id} no longer expires."),
+                },
+            );
+        }
+        Command::Recover(RecoverCmd { user_id }) => {
+            let confirmed = Confirm::new()
+                .with_prompt(format!(
+                    "Replace the stored key for {user_id} with its DPAPI copy?"
+                ))
+                .default(false)
+                .interact()
+                .unwrap_or(false);
+            if !confirmed {
+                out.done(
+                    json!({ "userId": user_id, "recovered": false }),
+                    "Recovery cancelled.",
+                );
+            } else {
+                kmgr.recover_from_dpapi(&user_id)
+                    .context("Failed to recover key")?;
+                out.done(
+                    json!({ "userId": user_id }),
+                    &format!("Key for {user_id} recovered from its DPAPI copy."),
+                );
+            }
+        }
+        Command::Server(ServerCmd { user_id, url }) => {
+            kmgr.set_server_url(&user_id, url.as_deref())
+                .context("Failed to set server")?;
+            out.done(
+                json!({ "userId": user_id, "serverUrl": url }),
+                &format!("Server for {user_id} updated."),
+            );
+        }
+        Command::Audit(cmd) => audit(&kmgr, cmd, out).context("Failed to read the audit log")?,
+        Command::Lock(_) => {
+            kmgr.lock_keys().context("Failed to lock keys")?;
+            out.done(
+                json!({}),
+                "Cached keys cleared; the next unlock will ask for Windows Hello.",
+            );
+        }
+        Command::Doctor(_) => {
+            let checks = doctor::run(&kmgr, key_name, &key_dir);
+            let passed = checks.iter().all(|c| c.status != CheckStatus::Fail);
+            if out.json {
+                out.print(json!({ "passed": passed, "checks": checks }));
+            } else {
+                for check in &checks {
+                    println!("{check}");
+                }
+                println!();
+                println!(
+                    "bwbio {}: {}",
+                    env!("CARGO_PKG_VERSION"),
+                    if passed {
+                        "no problems found"
+                    } else {
+                        "problems found"
+                    }
+                );
+            }
+            if !passed {
+                return Ok(CliExit::Failure);
+            }
+        }
+        Command::Status(_) => status(&kmgr, key_name, &key_dir, out)?,
+        Command::Verify(_) => {
+            let results = kmgr.verify_keys().context("Failed to verify stored keys")?;
+            let healthy = results.iter().all(|(_, h)| *h == KeyHealth::Healthy);
+            if out.json {
+                let keys = results
+                    .iter()
+                    .map(|(user_id, health)| {
+                        let mut value = json!(health);
+                        value["userId"] = json!(user_id);
+                        value
+                    })
+                    .collect::<Vec<_>>();
+                out.print(json!({ "healthy": healthy, "keys": keys }));
+            } else if results.is_empty() {
+                println!("No keys stored.");
+            } else {
+                for (user_id, health) in &results {
+                    match health {
+                        KeyHealth::Healthy => println!("[OK] {user_id}"),
+                        KeyHealth::IntegrityFailed(e) => println!("[DAMAGED] {user_id}: {e}"),
+                        KeyHealth::Undecryptable(e) => {
+                            println!("[UNDECRYPTABLE] {user_id}: {e}")
+                        }
                     }
                 }
             }
-            Err(e) => eprintln!("Failed to list keys: {e}"),
-        },
-        Command::Import(ImportCmd { user_id, key }) => match kmgr.import_key(&user_id, &key) {
-            Ok(_) => println!("Key imported successfully."),
-            Err(e) => eprintln!("Failed to import key: {e}"),
-        },
-        Command::Export(ExportCmd { user_id }) => match kmgr.export_key(&user_id) {
-            Ok(k) => println!("{k}"),
-            Err(e) => eprintln!("Failed to export key: {e}"),
-        },
-        Command::Delete(DeleteCmd { user_id }) => match kmgr.delete_key(&user_id) {
-            Ok(_) => println!("Key deleted successfully."),
-            Err(e) => eprintln!("Failed to delete key: {e}"),
-        },
-        Command::Check(CheckCmd { user_id }) => match kmgr.check_key_exists(&user_id) {
-            Ok(true) => println!("Key exists."),
-            Ok(false) => println!("Key does not exist."),
-            Err(e) => eprintln!("Failed to check key: {e}"),
-        },
+            if !healthy {
+                return Ok(CliExit::Failure);
+            }
+        }
+        Command::Install(_)
+        | Command::Uninstall(_)
+        | Command::Purge(_)
+        | Command::Browsers(_)
+        | Command::Manifest(_)
+        | Command::Update(_)
+        | Command::Register(_)
+        | Command::Unregister(_)
+        | Command::Serve(_)
+        | Command::TestBio(_)
+        | Command::Logs(_)
+        | Command::Config(_)
+        | Command::Agent(_) => unreachable!("handled before the key manager is opened"),
+        Command::Cng(CngCmd {
+            cmd: CngSubCommand::Providers(_),
+            ..
+        }) => providers(out).context("Failed to list key storage providers")?,
         Command::Cng(cng_cmd) => {
-            let provider = match CngProvider::new() {
-                Ok(p) => p,
-                Err(e) => {
-                    eprintln!("Failed to open CNG provider: {e}");
-                    return;
-                }
+            let provider = match CngProvider::new().context("Failed to open CNG provider")? {
+                p if cng_cmd.machine => p.machine_scope(),
+                p => p,
             };
             match cng_cmd.cmd {
-                CngSubCommand::List(_) => match provider.enum_keys() {
-                    Ok(keys) => {
+                CngSubCommand::List(_) => {
+                    let keys = provider.enum_keys().context("Failed to list CNG keys")?;
+                    let scope = if provider.is_machine_scope() {
+                        "machine"
+                    } else {
+                        "user"
+                    };
+                    if out.json {
+                        let keys = keys
+                            .iter()
+                            .map(|k| {
+                                json!({
+                                    "name": k.name,
+                                    "algorithm": k.algorithm,
+                                    "machine": k.is_machine_key(),
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        out.print(json!({
+                            "provider": provider.kind().to_string(),
+                            "scope": scope,
+                            "keys": keys,
+                        }));
+                    } else {
+                        println!("Provider: {} ({scope} keys)", provider.kind());
                         if keys.is_empty() {
                             println!("No CNG keys found.");
                         } else {
                             for k in keys {
-                                println!(
-                                    "Key: {}, Algorithm: {}",
-                                    unsafe { k.pszName.display() },
-                                    unsafe { k.pszAlgid.display() }
-                                );
+                                let scope = if k.is_machine_key() { ", Machine" } else { "" };
+                                println!("Key: {}, Algorithm: {}{scope}", k.name, k.algorithm);
                             }
                         }
                     }
-                    Err(e) => eprintln!("Failed to list CNG keys: {e}"),
-                },
-                CngSubCommand::Create(CngCreateCmd { key_name }) => {
-                    match provider.create_key(HSTRING::from(key_name.as_str())) {
-                        Ok(_) => {
-                            println!("CNG key '{key_name}' created successfully.")
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to create CNG key '{key_name}': {e}")
-                        }
-                    }
+                }
+                CngSubCommand::Create(CngCreateCmd {
+                    key_name,
+                    algorithm,
+                    length,
+                    require_gesture,
+                }) => {
+                    let algorithm = match length {
+                        Some(bits) => algorithm.with_length(bits)?,
+                        None => algorithm,
+                    };
+                    provider
+                        .create_key(
+                            HSTRING::from(key_name.as_str()),
+                            CngKeyOptions {
+                                algorithm,
+                                gesture_bound: require_gesture,
+                            },
+                        )
+                        .with_context(|| format!("Failed to create CNG key '{key_name}'"))?;
+                    out.done(
+                        json!({ "keyName": key_name, "algorithm": algorithm.to_string() }),
+                        &format!("CNG key '{key_name}' created successfully."),
+                    );
                 }
                 CngSubCommand::Delete(CngDeleteCmd { key_name }) => {
-                    match provider.open_key(HSTRING::from(key_name.as_str())) {
-                        Ok(key) => match key.delete() {
-                            Ok(_) => {
-                                println!("CNG key '{key_name}' deleted successfully.")
-                            }
-                            Err(e) => eprintln!("Failed to delete CNG key '{key_name}': {e}"),
-                        },
-                        Err(e) => {
-                            eprintln!("Failed to open CNG key '{key_name}': {e}")
-                        }
-                    }
+                    let key = provider
+                        .open_key(HSTRING::from(key_name.as_str()))
+                        .with_context(|| format!("Failed to open CNG key '{key_name}'"))?;
+                    delete_cng_key(key)
+                        .with_context(|| format!("Failed to delete CNG key '{key_name}'"))?;
+                    out.done(
+                        json!({ "keyName": key_name }),
+                        &format!("CNG key '{key_name}' deleted successfully."),
+                    );
+                }
+                CngSubCommand::Providers(_) => unreachable!(),
+                CngSubCommand::Info(CngInfoCmd { key_name }) => {
+                    info(&provider, &key_name, out).context("Failed to read CNG key properties")?
+                }
+                CngSubCommand::Attest(cmd) => {
+                    attest(&provider, cmd, out).context("Failed to create attestation claim")?
+                }
+                CngSubCommand::Migrate(CngMigrateCmd { algorithm }) => {
+                    let progress = Progress::spinner_unless(out.json, "Migrating the CNG key");
+                    let n = kmgr
+                        .migrate_cng_key(algorithm, &mut |step| progress.step(step))
+                        .context("Failed to migrate CNG key")?;
+                    drop(progress);
+                    out.done(
+                        json!({ "algorithm": algorithm.to_string(), "count": n }),
+                        &format!("CNG key migrated to {algorithm}; {n} key(s) re-wrapped."),
+                    );
                 }
             }
         }
     }
+    Ok(CliExit::Success)
 }
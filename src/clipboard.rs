@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use anyhow::{Result, bail};
+use std::{iter, ptr, slice};
+use windows::Win32::Foundation::{GlobalFree, HANDLE, HGLOBAL};
+use windows::Win32::System::DataExchange::{
+    CloseClipboard, EmptyClipboard, GetClipboardData, OpenClipboard, RegisterClipboardFormatW,
+    SetClipboardData,
+};
+use windows::Win32::System::Memory::{GMEM_MOVEABLE, GlobalAlloc, GlobalLock, GlobalUnlock};
+use windows::Win32::System::Ole::CF_UNICODETEXT;
+use windows::core::w;
+use zeroize::Zeroizing;
+
+/// Puts `text` on the clipboard, marked so it stays out of clipboard history and cloud
+/// clipboard sync.
+pub fn copy_secret(text: &str) -> Result<()> {
+    let wide = Zeroizing::new(
+        text.encode_utf16()
+            .chain(iter::once(0))
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>(),
+    );
+    with_clipboard(|| unsafe {
+        EmptyClipboard()?;
+        set_data(CF_UNICODETEXT.0 as u32, &wide)?;
+        let _ = set_data(
+            RegisterClipboardFormatW(w!("ExcludeClipboardContentFromMonitorProcessing")),
+            &[0],
+        );
+        let _ = set_data(
+            RegisterClipboardFormatW(w!("CanIncludeInClipboardHistory")),
+            &0u32.to_le_bytes(),
+        );
+        let _ = set_data(
+            RegisterClipboardFormatW(w!("CanUploadToCloudClipboard")),
+            &0u32.to_le_bytes(),
+        );
+        Ok(())
+    })
+}
+
+/// Empties the clipboard if it still holds `text`, so anything copied since is left alone.
+/// Returns whether it was cleared.
+pub fn clear_if_unchanged(text: &str) -> Result<bool> {
+    with_clipboard(|| unsafe {
+        let Ok(data) = GetClipboardData(CF_UNICODETEXT.0 as u32) else {
+            return Ok(false);
+        };
+        let ptr = GlobalLock(HGLOBAL(data.0)) as *const u16;
+        if ptr.is_null() {
+            return Ok(false);
+        }
+        let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+        let unchanged = slice::from_raw_parts(ptr, len)
+            .iter()
+            .copied()
+            .eq(text.encode_utf16());
+        let _ = GlobalUnlock(HGLOBAL(data.0));
+        if unchanged {
+            EmptyClipboard()?;
+        }
+        Ok(unchanged)
+    })
+}
+
+fn with_clipboard<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    if unsafe { OpenClipboard(None) }.is_err() {
+        bail!("The clipboard is in use by another application");
+    }
+    let result = f();
+    unsafe {
+        let _ = CloseClipboard();
+    }
+    result
+}
+
+/// Hands a copy of `bytes` to the clipboard as `format`. The clipboard must be open.
+unsafe fn set_data(format: u32, bytes: &[u8]) -> Result<()> {
+    unsafe {
+        let mem = GlobalAlloc(GMEM_MOVEABLE, bytes.len())?;
+        let dst = GlobalLock(mem) as *mut u8;
+        if dst.is_null() {
+            let _ = GlobalFree(Some(mem));
+            bail!("Failed to lock clipboard memory");
+        }
+        ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len());
+        let _ = GlobalUnlock(mem);
+        // On success the clipboard owns the memory.
+        if let Err(e) = SetClipboardData(format, Some(HANDLE(mem.0))) {
+            let _ = GlobalFree(Some(mem));
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
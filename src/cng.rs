@@ -1,101 +1,535 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
 
-use crate::bio::{authenticate_with_biometrics, get_biometrics_status};
-use anyhow::{Result, bail};
-use std::{ffi::c_void, ptr::null_mut};
+use crate::bio::verify_user_presence;
+use crate::crypto::{EcCurve, SecretBytes};
+use crate::error::{BwbioError, Result};
+use crate::presence::{DEFAULT_REASON, Presence, biometric_only_selected};
+use std::{
+    ffi::c_void,
+    fmt::{self, Display, Formatter},
+    mem::{self, size_of},
+    ptr::null_mut,
+    slice,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tracing::info;
 use windows::Win32::{
-    Foundation::{NTE_BAD_KEYSET, NTE_NO_MORE_ITEMS},
-    Security::Cryptography::{
-        BCRYPT_RSA_ALGORITHM, CERT_KEY_SPEC, MS_PLATFORM_KEY_STORAGE_PROVIDER,
-        NCRYPT_EXPORT_POLICY_PROPERTY, NCRYPT_FLAGS, NCRYPT_KEY_HANDLE, NCRYPT_LENGTH_PROPERTY,
-        NCRYPT_OVERWRITE_KEY_FLAG, NCRYPT_PAD_PKCS1_FLAG, NCRYPT_PROV_HANDLE, NCRYPT_SILENT_FLAG,
-        NCryptCreatePersistedKey, NCryptDecrypt, NCryptDeleteKey, NCryptEncrypt, NCryptEnumKeys,
-        NCryptFinalizeKey, NCryptFreeBuffer, NCryptKeyName, NCryptOpenKey,
-        NCryptOpenStorageProvider, NCryptSetProperty,
+    Foundation::{
+        ERROR_CANCELLED, HLOCAL, LocalFree, NTE_BAD_DATA, NTE_BAD_KEYSET, NTE_DECRYPTION_FAILURE,
+        NTE_INVALID_PARAMETER, NTE_NO_MORE_ITEMS, NTE_USER_CANCELLED,
+    },
+    Security::{
+        Authorization::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1},
+        Cryptography::{
+            BCRYPT_ECCPUBLIC_BLOB, BCRYPT_ECDH_P256_ALGORITHM, BCRYPT_ECDH_P384_ALGORITHM,
+            BCRYPT_ECDH_P521_ALGORITHM, BCRYPT_ECDH_PUBLIC_P256_MAGIC,
+            BCRYPT_ECDH_PUBLIC_P384_MAGIC, BCRYPT_ECDH_PUBLIC_P521_MAGIC, BCRYPT_KDF_HASH,
+            BCRYPT_OAEP_PADDING_INFO, BCRYPT_RSA_ALGORITHM, BCRYPT_SHA256_ALGORITHM,
+            BCRYPTBUFFER_VERSION, BCryptBuffer, BCryptBufferDesc, CERT_KEY_SPEC,
+            KDF_HASH_ALGORITHM, MS_KEY_STORAGE_PROVIDER, MS_PLATFORM_KEY_STORAGE_PROVIDER,
+            NCRYPT_ALGORITHM_PROPERTY, NCRYPT_ALLOW_ARCHIVING_FLAG, NCRYPT_ALLOW_EXPORT_FLAG,
+            NCRYPT_ALLOW_PLAINTEXT_EXPORT_FLAG, NCRYPT_CLAIM_AUTHORITY_AND_SUBJECT,
+            NCRYPT_CLAIM_SUBJECT_ONLY, NCRYPT_EXPORT_POLICY_PROPERTY, NCRYPT_FLAGS, NCRYPT_HANDLE,
+            NCRYPT_KEY_HANDLE, NCRYPT_LAST_MODIFIED_PROPERTY, NCRYPT_LENGTH_PROPERTY,
+            NCRYPT_MACHINE_KEY_FLAG, NCRYPT_NAME_PROPERTY, NCRYPT_OVERWRITE_KEY_FLAG,
+            NCRYPT_PAD_OAEP_FLAG, NCRYPT_PAD_PKCS1_FLAG, NCRYPT_PROV_HANDLE,
+            NCRYPT_PROVIDER_HANDLE_PROPERTY, NCRYPT_SECRET_HANDLE, NCRYPT_SECURITY_DESCR_PROPERTY,
+            NCRYPT_SILENT_FLAG, NCRYPT_UI_FORCE_HIGH_PROTECTION_FLAG, NCRYPT_UI_POLICY,
+            NCRYPT_UI_POLICY_PROPERTY, NCRYPT_UI_PROTECT_KEY_FLAG, NCRYPT_UNIQUE_NAME_PROPERTY,
+            NCRYPT_USE_CONTEXT_PROPERTY, NCRYPTBUFFER_CLAIM_KEYATTESTATION_NONCE,
+            NCRYPTBUFFER_VERSION, NCryptCreateClaim, NCryptCreatePersistedKey, NCryptDecrypt,
+            NCryptDeleteKey, NCryptDeriveKey, NCryptEncrypt, NCryptEnumKeys,
+            NCryptEnumStorageProviders, NCryptExportKey, NCryptFinalizeKey, NCryptFreeBuffer,
+            NCryptFreeObject, NCryptGetProperty, NCryptImportKey, NCryptKeyName, NCryptOpenKey,
+            NCryptOpenStorageProvider, NCryptProviderName, NCryptSecretAgreement,
+            NCryptSetProperty,
+        },
+        DACL_SECURITY_INFORMATION, OBJECT_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR,
     },
 };
-use windows::core::PCWSTR;
+use windows::core::{PCWSTR, w};
 use windows_strings::HSTRING;
 
 pub fn default_key_name() -> HSTRING {
     HSTRING::from("bw-bio")
 }
 
+#[derive(Debug, Error)]
+pub enum CngError {
+    #[error(transparent)]
+    Os(#[from] windows::core::Error),
+    #[error("RSA keys can be 2048, 3072 or 4096 bits long, not {0}")]
+    RsaKeyLength(u32),
+    #[error("ECDH keys can be 256, 384 or 521 bits long, not {0}")]
+    EcdhKeyLength(u32),
+    #[error(
+        "Unsupported CNG key algorithm: {0} (expected rsa-2048, rsa-3072, rsa-4096, ecdh-p256, \
+         ecdh-p384 or ecdh-p521)"
+    )]
+    UnknownAlgorithm(String),
+    /// The key itself uses an algorithm bwbio doesn't wrap with.
+    #[error("Unsupported CNG key algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("Expected an uncompressed {0} point")]
+    InvalidPoint(EcCurve),
+    #[error("Invalid {0} property")]
+    InvalidProperty(&'static str),
+    #[error("Unexpected ECC public key blob")]
+    UnexpectedBlob,
+    #[error("Unexpected derived secret length: {0}")]
+    SecretLength(u32),
+    #[error("{len} bytes exceed the {max}-byte limit of one RSA-OAEP block")]
+    BlockTooLarge { len: usize, max: usize },
+}
+
+impl CngError {
+    /// Whether the KSP prompt of a gesture-bound key was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        let CngError::Os(e) = self else {
+            return false;
+        };
+        [NTE_USER_CANCELLED, ERROR_CANCELLED.to_hresult()].contains(&e.code())
+    }
+
+    /// Whether decryption failed on the blob itself, such as a padding that doesn't decode.
+    fn is_bad_data(&self) -> bool {
+        let CngError::Os(e) = self else {
+            return false;
+        };
+        [NTE_BAD_DATA, NTE_DECRYPTION_FAILURE, NTE_INVALID_PARAMETER].contains(&e.code())
+    }
+}
+
+/// Algorithm of the CNG key that protects the per-file data keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CngKeyAlgorithm {
+    /// Data keys are encrypted directly with the RSA key (OAEP).
+    Rsa { bits: u32 },
+    /// Data keys are wrapped with ECIES against the ECDH key; smaller and faster on most TPMs.
+    Ecdh(EcCurve),
+}
+
+impl Default for CngKeyAlgorithm {
+    fn default() -> Self {
+        CngKeyAlgorithm::Rsa { bits: 2048 }
+    }
+}
+
+impl CngKeyAlgorithm {
+    /// The same kind of key with a size of `bits`: the modulus for RSA, the curve for ECDH.
+    pub fn with_length(self, bits: u32) -> Result<Self> {
+        Ok(match (self, bits) {
+            (CngKeyAlgorithm::Rsa { .. }, 2048 | 3072 | 4096) => CngKeyAlgorithm::Rsa { bits },
+            (CngKeyAlgorithm::Rsa { .. }, _) => return Err(CngError::RsaKeyLength(bits).into()),
+            (CngKeyAlgorithm::Ecdh(_), 256) => CngKeyAlgorithm::Ecdh(EcCurve::P256),
+            (CngKeyAlgorithm::Ecdh(_), 384) => CngKeyAlgorithm::Ecdh(EcCurve::P384),
+            (CngKeyAlgorithm::Ecdh(_), 521) => CngKeyAlgorithm::Ecdh(EcCurve::P521),
+            (CngKeyAlgorithm::Ecdh(_), _) => return Err(CngError::EcdhKeyLength(bits).into()),
+        })
+    }
+}
+
+impl FromStr for CngKeyAlgorithm {
+    type Err = CngError;
+
+    fn from_str(s: &str) -> Result<Self, CngError> {
+        let s = s.to_ascii_lowercase().replace('_', "-");
+        match s.as_str() {
+            "rsa" | "rsa2048" | "rsa-2048" => Ok(CngKeyAlgorithm::Rsa { bits: 2048 }),
+            "rsa3072" | "rsa-3072" => Ok(CngKeyAlgorithm::Rsa { bits: 3072 }),
+            "rsa4096" | "rsa-4096" => Ok(CngKeyAlgorithm::Rsa { bits: 4096 }),
+            "ecdh" | "ecdh-p256" | "p256" => Ok(CngKeyAlgorithm::Ecdh(EcCurve::P256)),
+            "ecdh-p384" | "p384" => Ok(CngKeyAlgorithm::Ecdh(EcCurve::P384)),
+            "ecdh-p521" | "p521" => Ok(CngKeyAlgorithm::Ecdh(EcCurve::P521)),
+            _ => Err(CngError::UnknownAlgorithm(s)),
+        }
+    }
+}
+
+impl Display for CngKeyAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CngKeyAlgorithm::Rsa { bits } => write!(f, "rsa-{bits}"),
+            CngKeyAlgorithm::Ecdh(curve) => write!(f, "ecdh-{curve}"),
+        }
+    }
+}
+
+/// Parameters for [`CngProvider::create_key`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CngKeyOptions {
+    pub algorithm: CngKeyAlgorithm,
+    /// Attach an `NCRYPT_UI_POLICY` so the KSP itself demands a Windows Hello/PIN gesture before
+    /// every private-key operation.
+    pub gesture_bound: bool,
+}
+
+fn ecdh_algorithm(curve: EcCurve) -> PCWSTR {
+    match curve {
+        EcCurve::P256 => BCRYPT_ECDH_P256_ALGORITHM,
+        EcCurve::P384 => BCRYPT_ECDH_P384_ALGORITHM,
+        EcCurve::P521 => BCRYPT_ECDH_P521_ALGORITHM,
+    }
+}
+
+fn ecdh_public_magic(curve: EcCurve) -> u32 {
+    match curve {
+        EcCurve::P256 => BCRYPT_ECDH_PUBLIC_P256_MAGIC,
+        EcCurve::P384 => BCRYPT_ECDH_PUBLIC_P384_MAGIC,
+        EcCurve::P521 => BCRYPT_ECDH_PUBLIC_P521_MAGIC,
+    }
+}
+
+/// RSA padding used for CNG encrypt/decrypt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CngPadding {
+    /// PKCS#1 v1.5, as written by earlier versions.
+    Pkcs1,
+    OaepSha256,
+}
+
+struct OaepPaddingInfo(BCRYPT_OAEP_PADDING_INFO);
+
+// Only holds a pointer to a static wide string.
+unsafe impl Sync for OaepPaddingInfo {}
+
+static OAEP_SHA256: OaepPaddingInfo = OaepPaddingInfo(BCRYPT_OAEP_PADDING_INFO {
+    pszAlgId: BCRYPT_SHA256_ALGORITHM,
+    pbLabel: null_mut(),
+    cbLabel: 0,
+});
+
+/// UTF-16 bytes of `s` including the terminating NUL, as string properties expect.
+fn wide_bytes(s: &HSTRING) -> &[u8] {
+    unsafe { slice::from_raw_parts(s.as_ptr() as *const u8, (s.len() + 1) * 2) }
+}
+
+fn wide_to_string(buf: &[u8]) -> String {
+    let wide = buf
+        .chunks_exact(2)
+        .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&wide)
+}
+
+/// Which key storage provider holds the wrapping key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CngProviderKind {
+    /// TPM-backed Microsoft Platform Crypto Provider.
+    Platform,
+    /// Microsoft Software Key Storage Provider, used when no TPM is available; the key is
+    /// protected by DPAPI only.
+    Software,
+}
+
+impl CngProviderKind {
+    fn ksp_name(self) -> PCWSTR {
+        match self {
+            CngProviderKind::Platform => MS_PLATFORM_KEY_STORAGE_PROVIDER,
+            CngProviderKind::Software => MS_KEY_STORAGE_PROVIDER,
+        }
+    }
+}
+
+impl Display for CngProviderKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CngProviderKind::Platform => "Microsoft Platform Crypto Provider (TPM)",
+            CngProviderKind::Software => "Microsoft Software Key Storage Provider (no TPM)",
+        })
+    }
+}
+
+/// SYSTEM and Administrators manage machine keys; any signed-in user may use them.
+const MACHINE_KEY_SDDL: PCWSTR = w!("D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GR;;;AU)");
+
+fn set_machine_key_acl(key: &CngKey) -> Result<()> {
+    unsafe {
+        let mut sd = PSECURITY_DESCRIPTOR::default();
+        let mut sd_len = 0u32;
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            MACHINE_KEY_SDDL,
+            SDDL_REVISION_1,
+            &mut sd,
+            Some(&mut sd_len),
+        )?;
+        let result = NCryptSetProperty(
+            key.handle.into(),
+            NCRYPT_SECURITY_DESCR_PROPERTY,
+            slice::from_raw_parts(sd.0 as *const u8, sd_len as usize),
+            NCRYPT_FLAGS(DACL_SECURITY_INFORMATION.0) | NCRYPT_SILENT_FLAG,
+        );
+        LocalFree(Some(HLOCAL(sd.0)));
+        Ok(result?)
+    }
+}
+
+/// A key listed by [`CngProvider::enum_keys`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CngKeyInfo {
+    pub name: String,
+    pub algorithm: String,
+    /// `NCryptKeyName::dwFlags`; only `NCRYPT_MACHINE_KEY_FLAG` is defined.
+    pub flags: u32,
+}
+
+impl CngKeyInfo {
+    pub fn is_machine_key(&self) -> bool {
+        self.flags & NCRYPT_MACHINE_KEY_FLAG.0 != 0
+    }
+}
+
+/// How a key is configured, as reported by [`CngKey::properties`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CngKeyProperties {
+    /// Algorithm identifier, e.g. `RSA` or `ECDH_P256`.
+    pub algorithm: String,
+    /// Key size in bits.
+    pub length: u32,
+    /// `NCRYPT_ALLOW_*` export flags; bwbio creates keys with none.
+    pub export_policy: u32,
+    /// `NCRYPT_UI_POLICY::dwFlags`, if the key has a UI policy.
+    pub ui_policy: Option<u32>,
+    pub provider: String,
+    /// Name of the key's container in the provider's store.
+    pub unique_name: String,
+    /// When the key was last written, which for bwbio's keys is when they were created.
+    pub modified: Option<SystemTime>,
+}
+
+impl CngKeyProperties {
+    pub fn export_policy_names(&self) -> Vec<&'static str> {
+        [
+            (NCRYPT_ALLOW_EXPORT_FLAG, "export"),
+            (NCRYPT_ALLOW_PLAINTEXT_EXPORT_FLAG, "plaintext export"),
+            (NCRYPT_ALLOW_ARCHIVING_FLAG, "archiving"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| self.export_policy & flag != 0)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+/// An installed key storage provider, as listed by [`CngProvider::enumerate_providers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CngProviderInfo {
+    pub name: String,
+    pub comment: String,
+    /// Set for the providers bwbio can use.
+    pub kind: Option<CngProviderKind>,
+}
+
+/// Owned NCRYPT provider handle, freed on drop.
 pub struct CngProvider {
     provider: NCRYPT_PROV_HANDLE,
+    kind: CngProviderKind,
+    machine: bool,
+}
+
+impl Drop for CngProvider {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = NCryptFreeObject(self.provider.into());
+        }
+    }
 }
 
 impl CngProvider {
+    /// Opens the Platform Crypto Provider, falling back to the Software KSP on machines without
+    /// a usable TPM. Key files record which one wrapped them, and the key manager opens the
+    /// Platform provider alone once any is TPM-sealed, so a TPM failure can't silently move new
+    /// keys to the software provider.
     pub fn new() -> Result<Self> {
+        Self::open(CngProviderKind::Platform)
+            .or_else(|e| Self::open(CngProviderKind::Software).map_err(|_| e))
+    }
+
+    pub fn open(kind: CngProviderKind) -> Result<Self> {
         let mut provider = NCRYPT_PROV_HANDLE::default();
         unsafe {
-            NCryptOpenStorageProvider(&mut provider, MS_PLATFORM_KEY_STORAGE_PROVIDER, 0)?;
+            NCryptOpenStorageProvider(&mut provider, kind.ksp_name(), 0)?;
         }
-        Ok(Self { provider })
+        Ok(Self {
+            provider,
+            kind,
+            machine: false,
+        })
     }
 
-    pub fn enum_keys(&self) -> Result<Vec<NCryptKeyName>> {
+    /// Lists the key storage providers registered on this machine.
+    pub fn enumerate_providers() -> Result<Vec<CngProviderInfo>> {
+        let kinds = [CngProviderKind::Platform, CngProviderKind::Software].map(|kind| {
+            (
+                kind,
+                unsafe { kind.ksp_name().to_string() }.unwrap_or_default(),
+            )
+        });
+        unsafe {
+            let mut count = 0u32;
+            let mut list: *mut NCryptProviderName = null_mut();
+            NCryptEnumStorageProviders(&mut count, &mut list, 0)?;
+            // The strings live in the same buffer, so copy them out before freeing it.
+            let providers = slice::from_raw_parts(list, count as usize)
+                .iter()
+                .map(|p| {
+                    let name = p.pszName.to_string().unwrap_or_default();
+                    CngProviderInfo {
+                        comment: p.pszComment.to_string().unwrap_or_default(),
+                        kind: kinds.iter().find(|(_, n)| *n == name).map(|(k, _)| *k),
+                        name,
+                    }
+                })
+                .collect();
+            NCryptFreeBuffer(list as *mut _)?;
+            Ok(providers)
+        }
+    }
+
+    /// Whether the TPM-backed Platform Crypto Provider can be opened.
+    pub fn tpm_available() -> bool {
+        Self::open(CngProviderKind::Platform).is_ok()
+    }
+
+    /// Makes key enumeration, creation and opening use the machine-wide key store
+    /// (`NCRYPT_MACHINE_KEY_FLAG`) instead of the current user's.
+    pub fn machine_scope(mut self) -> Self {
+        self.machine = true;
+        self
+    }
+
+    pub fn kind(&self) -> CngProviderKind {
+        self.kind
+    }
+
+    pub fn is_machine_scope(&self) -> bool {
+        self.machine
+    }
+
+    fn scope_flags(&self) -> NCRYPT_FLAGS {
+        if self.machine {
+            NCRYPT_MACHINE_KEY_FLAG
+        } else {
+            NCRYPT_FLAGS(0)
+        }
+    }
+
+    pub fn enum_keys(&self) -> Result<Vec<CngKeyInfo>> {
         unsafe {
             let mut enum_state: *mut c_void = null_mut();
             let mut keys = Vec::new();
-            loop {
+            let result = loop {
                 let mut key_ptr: *mut NCryptKeyName = null_mut();
                 match NCryptEnumKeys(
                     self.provider,
                     PCWSTR::null(),
                     &mut key_ptr,
                     &mut enum_state,
-                    NCRYPT_SILENT_FLAG,
+                    NCRYPT_SILENT_FLAG | self.scope_flags(),
                 ) {
                     Ok(_) => {
                         if key_ptr.is_null() {
                             continue;
                         }
-                        let key = *key_ptr;
-                        keys.push(key);
+                        // The strings live in the same buffer, so copy them out before freeing it.
+                        let key = &*key_ptr;
+                        keys.push(CngKeyInfo {
+                            name: key.pszName.to_string().unwrap_or_default(),
+                            algorithm: key.pszAlgid.to_string().unwrap_or_default(),
+                            flags: key.dwFlags,
+                        });
                         NCryptFreeBuffer(key_ptr as *mut _)?;
                     }
-                    Err(e) if e.code() == NTE_NO_MORE_ITEMS => break,
-                    Err(e) => return Err(e.into()),
+                    Err(e) if e.code() == NTE_NO_MORE_ITEMS => break Ok(keys),
+                    Err(e) => break Err(e.into()),
                 }
+            };
+            if !enum_state.is_null() {
+                NCryptFreeBuffer(enum_state)?;
             }
-            NCryptFreeBuffer(enum_state)?;
-            Ok(keys)
+            result
         }
     }
 
-    pub fn create_key(&self, key_name: HSTRING) -> Result<CngKey> {
+    pub fn create_key(&self, key_name: HSTRING, options: CngKeyOptions) -> Result<CngKey> {
+        let algorithm = options.algorithm;
         unsafe {
             let mut key_handle = NCRYPT_KEY_HANDLE::default();
             NCryptCreatePersistedKey(
                 self.provider,
                 &mut key_handle,
-                BCRYPT_RSA_ALGORITHM,
+                match algorithm {
+                    CngKeyAlgorithm::Rsa { .. } => BCRYPT_RSA_ALGORITHM,
+                    CngKeyAlgorithm::Ecdh(curve) => ecdh_algorithm(curve),
+                },
                 PCWSTR::from_raw(key_name.as_ptr()),
                 CERT_KEY_SPEC(0),
-                NCRYPT_OVERWRITE_KEY_FLAG,
-            )?;
-            let key_length = 2048u32;
-            NCryptSetProperty(
-                key_handle.into(),
-                NCRYPT_LENGTH_PROPERTY,
-                &key_length.to_ne_bytes(),
-                NCRYPT_SILENT_FLAG,
+                NCRYPT_OVERWRITE_KEY_FLAG | self.scope_flags(),
             )?;
+            // Owned from here on, so every early return below frees the handle.
+            let key = CngKey::new(key_handle);
+            // The curve fixes the size of ECC keys.
+            if let CngKeyAlgorithm::Rsa { bits } = algorithm {
+                NCryptSetProperty(
+                    key.handle.into(),
+                    NCRYPT_LENGTH_PROPERTY,
+                    &bits.to_ne_bytes(),
+                    NCRYPT_SILENT_FLAG,
+                )?;
+            }
+            if options.gesture_bound {
+                let friendly_name = HSTRING::from("Bitwarden biometric unlock");
+                let description =
+                    HSTRING::from("Protects the Bitwarden user keys stored by bwbio.");
+                let policy = NCRYPT_UI_POLICY {
+                    dwVersion: 1,
+                    dwFlags: NCRYPT_UI_PROTECT_KEY_FLAG | NCRYPT_UI_FORCE_HIGH_PROTECTION_FLAG,
+                    pszCreationTitle: PCWSTR::from_raw(friendly_name.as_ptr()),
+                    pszFriendlyName: PCWSTR::from_raw(friendly_name.as_ptr()),
+                    pszDescription: PCWSTR::from_raw(description.as_ptr()),
+                };
+                NCryptSetProperty(
+                    key.handle.into(),
+                    NCRYPT_UI_POLICY_PROPERTY,
+                    slice::from_raw_parts(
+                        &policy as *const NCRYPT_UI_POLICY as *const u8,
+                        size_of::<NCRYPT_UI_POLICY>(),
+                    ),
+                    NCRYPT_SILENT_FLAG,
+                )?;
+            }
             let export_policy = 0u32;
             NCryptSetProperty(
-                key_handle.into(),
+                key.handle.into(),
                 NCRYPT_EXPORT_POLICY_PROPERTY,
                 &export_policy.to_ne_bytes(),
                 NCRYPT_SILENT_FLAG,
             )?;
-            NCryptFinalizeKey(key_handle, NCRYPT_FLAGS(0))?;
-            Ok(CngKey::new(key_handle))
+            NCryptFinalizeKey(key.handle, NCRYPT_FLAGS(0))?;
+            if self.machine {
+                set_machine_key_acl(&key)?;
+            }
+            info!(
+                name = %key_name,
+                %algorithm,
+                provider = %self.kind,
+                machine = self.machine,
+                "CNG key created"
+            );
+            Ok(key)
         }
     }
 
     pub fn open_key(&self, key_name: HSTRING) -> Result<CngKey> {
+        self.open_or_create_key(key_name, CngKeyOptions::default())
+    }
+
+    /// Opens `key_name`, creating it with `options` if it doesn't exist yet.
+    pub fn open_or_create_key(&self, key_name: HSTRING, options: CngKeyOptions) -> Result<CngKey> {
         unsafe {
             let mut key_handle = NCRYPT_KEY_HANDLE::default();
             match NCryptOpenKey(
@@ -103,82 +537,428 @@ impl CngProvider {
                 &mut key_handle,
                 PCWSTR::from_raw(key_name.as_ptr()),
                 CERT_KEY_SPEC(0),
-                NCRYPT_FLAGS(0),
+                self.scope_flags(),
             ) {
                 Ok(_) => Ok(CngKey::new(key_handle)),
-                Err(e) if e.code() == NTE_BAD_KEYSET => self.create_key(key_name),
+                Err(e) if e.code() == NTE_BAD_KEYSET => self.create_key(key_name, options),
                 Err(e) => Err(e.into()),
             }
         }
     }
+
+    /// Imports an uncompressed SEC1 point on `curve` as an ephemeral ECDH public key.
+    pub fn import_ecc_public_key(&self, curve: EcCurve, point: &[u8]) -> Result<CngKey> {
+        let (Some((&0x04, xy)), true) = (point.split_first(), point.len() == curve.point_len())
+        else {
+            return Err(CngError::InvalidPoint(curve).into());
+        };
+        let mut blob = Vec::with_capacity(8 + xy.len());
+        blob.extend_from_slice(&ecdh_public_magic(curve).to_le_bytes());
+        blob.extend_from_slice(&(curve.field_len() as u32).to_le_bytes());
+        blob.extend_from_slice(xy);
+        unsafe {
+            let mut key_handle = NCRYPT_KEY_HANDLE::default();
+            NCryptImportKey(
+                self.provider,
+                None,
+                BCRYPT_ECCPUBLIC_BLOB,
+                None,
+                &mut key_handle,
+                &blob,
+                NCRYPT_SILENT_FLAG,
+            )?;
+            Ok(CngKey::new(key_handle))
+        }
+    }
 }
 
+/// Owned NCRYPT key handle, freed on drop.
 pub struct CngKey {
     handle: NCRYPT_KEY_HANDLE,
 }
 
+impl Drop for CngKey {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = NCryptFreeObject(self.handle.into());
+        }
+    }
+}
+
 impl CngKey {
     pub fn new(handle: NCRYPT_KEY_HANDLE) -> Self {
         Self { handle }
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+    fn get_property(&self, property: PCWSTR) -> Result<Vec<u8>> {
         unsafe {
             let mut out_len = 0u32;
-            NCryptEncrypt(
+            NCryptGetProperty(
+                self.handle.into(),
+                property,
+                None,
+                &mut out_len,
+                OBJECT_SECURITY_INFORMATION(0),
+            )?;
+            let mut buffer = vec![0u8; out_len as usize];
+            NCryptGetProperty(
+                self.handle.into(),
+                property,
+                Some(&mut buffer),
+                &mut out_len,
+                OBJECT_SECURITY_INFORMATION(0),
+            )?;
+            buffer.truncate(out_len as usize);
+            Ok(buffer)
+        }
+    }
+
+    fn get_u32_property(&self, property: PCWSTR) -> Result<u32> {
+        self.get_property(property)?
+            .first_chunk::<4>()
+            .map(|b| u32::from_ne_bytes(*b))
+            .ok_or(CngError::InvalidProperty("key").into())
+    }
+
+    pub fn name(&self) -> Result<HSTRING> {
+        Ok(HSTRING::from(wide_to_string(
+            &self.get_property(NCRYPT_NAME_PROPERTY)?,
+        )))
+    }
+
+    /// Algorithm and size as recorded on the key itself, which decides how data keys are wrapped.
+    pub fn algorithm(&self) -> Result<CngKeyAlgorithm> {
+        match wide_to_string(&self.get_property(NCRYPT_ALGORITHM_PROPERTY)?).as_str() {
+            "RSA" => Ok(CngKeyAlgorithm::Rsa {
+                bits: self.get_u32_property(NCRYPT_LENGTH_PROPERTY)?,
+            }),
+            "ECDH_P256" => Ok(CngKeyAlgorithm::Ecdh(EcCurve::P256)),
+            "ECDH_P384" => Ok(CngKeyAlgorithm::Ecdh(EcCurve::P384)),
+            "ECDH_P521" => Ok(CngKeyAlgorithm::Ecdh(EcCurve::P521)),
+            other => Err(CngError::UnsupportedAlgorithm(other.to_string()).into()),
+        }
+    }
+
+    /// Reads the properties that decide how well the key is protected.
+    pub fn properties(&self) -> Result<CngKeyProperties> {
+        // NCRYPT_UI_POLICY: dwVersion (4) | dwFlags (4) | ...
+        let ui_policy = self
+            .get_property(NCRYPT_UI_POLICY_PROPERTY)
+            .ok()
+            .and_then(|policy| {
+                policy
+                    .get(4..8)
+                    .map(|f| u32::from_ne_bytes(f.try_into().unwrap()))
+            });
+        // FILETIME: 100 ns intervals since 1601-01-01.
+        let modified = self
+            .get_property(NCRYPT_LAST_MODIFIED_PROPERTY)
+            .ok()
+            .and_then(|ft| ft.first_chunk::<8>().map(|b| u64::from_ne_bytes(*b)))
+            .and_then(|ft| {
+                UNIX_EPOCH.checked_add(Duration::from_nanos(
+                    ft.checked_sub(116_444_736_000_000_000)? * 100,
+                ))
+            });
+        let provider = unsafe {
+            let handle = self.get_property(NCRYPT_PROVIDER_HANDLE_PROPERTY)?;
+            let handle = handle
+                .first_chunk::<{ size_of::<usize>() }>()
+                .map(|b| NCRYPT_PROV_HANDLE(usize::from_ne_bytes(*b)))
+                .ok_or(CngError::InvalidProperty("provider handle"))?;
+            let mut buffer = [0u8; 512];
+            let mut out_len = 0u32;
+            let result = NCryptGetProperty(
+                handle.into(),
+                NCRYPT_NAME_PROPERTY,
+                Some(&mut buffer),
+                &mut out_len,
+                OBJECT_SECURITY_INFORMATION(0),
+            );
+            // The property hands out a new reference to the provider.
+            let _ = NCryptFreeObject(handle.into());
+            result?;
+            wide_to_string(&buffer[..out_len as usize])
+        };
+        Ok(CngKeyProperties {
+            algorithm: wide_to_string(&self.get_property(NCRYPT_ALGORITHM_PROPERTY)?),
+            length: self.get_u32_property(NCRYPT_LENGTH_PROPERTY)?,
+            export_policy: self.get_u32_property(NCRYPT_EXPORT_POLICY_PROPERTY)?,
+            ui_policy,
+            provider,
+            unique_name: wide_to_string(&self.get_property(NCRYPT_UNIQUE_NAME_PROPERTY)?),
+            modified,
+        })
+    }
+
+    /// TPM key attestation claim (`NCryptCreateClaim`). With an attestation identity key the
+    /// claim is signed by it and bound to `nonce`; without one only the subject's creation data
+    /// is returned.
+    pub fn attest(&self, aik: Option<&CngKey>, nonce: &[u8]) -> Result<Vec<u8>> {
+        let claim_type = match aik {
+            Some(_) => NCRYPT_CLAIM_AUTHORITY_AND_SUBJECT,
+            None => NCRYPT_CLAIM_SUBJECT_ONLY,
+        };
+        let mut nonce_buf = BCryptBuffer {
+            cbBuffer: nonce.len() as u32,
+            BufferType: NCRYPTBUFFER_CLAIM_KEYATTESTATION_NONCE,
+            pvBuffer: nonce.as_ptr() as *mut c_void,
+        };
+        let params = BCryptBufferDesc {
+            ulVersion: NCRYPTBUFFER_VERSION,
+            cBuffers: 1,
+            pBuffers: &mut nonce_buf,
+        };
+        let params = (!nonce.is_empty()).then_some(&params as *const BCryptBufferDesc);
+        unsafe {
+            let mut out_len = 0u32;
+            NCryptCreateClaim(
+                Some(self.handle),
+                aik.map(|k| k.handle),
+                claim_type,
+                params,
+                None,
+                &mut out_len,
+                0,
+            )?;
+            let mut buffer = vec![0u8; out_len as usize];
+            NCryptCreateClaim(
+                Some(self.handle),
+                aik.map(|k| k.handle),
+                claim_type,
+                params,
+                Some(&mut buffer),
+                &mut out_len,
+                0,
+            )?;
+            buffer.truncate(out_len as usize);
+            Ok(buffer)
+        }
+    }
+
+    /// Whether the key carries a UI policy, i.e. the KSP prompts for a gesture on every use.
+    pub fn is_gesture_bound(&self) -> bool {
+        // NCRYPT_UI_POLICY: dwVersion (4) | dwFlags (4) | ...
+        self.get_property(NCRYPT_UI_POLICY_PROPERTY)
+            .ok()
+            .and_then(|policy| {
+                policy
+                    .get(4..8)
+                    .map(|f| u32::from_ne_bytes(f.try_into().unwrap()))
+            })
+            .is_some_and(|flags| {
+                flags & (NCRYPT_UI_PROTECT_KEY_FLAG | NCRYPT_UI_FORCE_HIGH_PROTECTION_FLAG) != 0
+            })
+    }
+
+    /// Gesture-bound keys are gated by the KSP prompt; others fall back to an app-level
+    /// Windows Hello check. `reason` is the message either prompt shows. The KSP prompt may take
+    /// a PIN, so biometric-only mode adds the app-level check for gesture-bound keys too.
+    /// A `presence` token from [`CngKey::with_user_presence`] means that already happened.
+    fn require_user_presence(&self, reason: &str, presence: Option<&Presence>) -> Result<()> {
+        if presence.is_some() {
+            return Ok(());
+        }
+        if self.is_gesture_bound() {
+            if biometric_only_selected() {
+                verify_user_presence(reason)?;
+            }
+            let context = HSTRING::from(reason);
+            unsafe {
+                NCryptSetProperty(
+                    self.handle.into(),
+                    NCRYPT_USE_CONTEXT_PROPERTY,
+                    wide_bytes(&context),
+                    NCRYPT_FLAGS(0),
+                )?;
+            }
+            return Ok(());
+        }
+        Ok(verify_user_presence(reason)?)
+    }
+
+    /// Runs `f` after a single user presence check, so the key operations it passes the token to
+    /// don't prompt again. Gesture-bound keys still prompt per operation, as the KSP enforces that.
+    pub fn with_user_presence<T>(
+        &self,
+        reason: &str,
+        f: impl FnOnce(&Presence) -> Result<T>,
+    ) -> Result<T> {
+        Presence::scope(|| self.require_user_presence(reason, None), f)
+    }
+
+    /// Public half of an ECC key as an uncompressed SEC1 point.
+    pub fn export_ecc_public_key(&self) -> Result<Vec<u8>> {
+        let blob = unsafe {
+            let mut out_len = 0u32;
+            NCryptExportKey(
                 self.handle,
-                Some(data),
+                None,
+                BCRYPT_ECCPUBLIC_BLOB,
                 None,
                 None,
                 &mut out_len,
-                NCRYPT_PAD_PKCS1_FLAG,
+                NCRYPT_SILENT_FLAG,
             )?;
             let mut buffer = vec![0u8; out_len as usize];
-            NCryptEncrypt(
+            NCryptExportKey(
                 self.handle,
-                Some(data),
+                None,
+                BCRYPT_ECCPUBLIC_BLOB,
                 None,
                 Some(&mut buffer),
                 &mut out_len,
-                NCRYPT_PAD_PKCS1_FLAG,
+                NCRYPT_SILENT_FLAG,
             )?;
-            buffer.resize(out_len as usize, 0);
-            Ok(buffer)
+            buffer.truncate(out_len as usize);
+            buffer
+        };
+        // BCRYPT_ECCKEY_BLOB: magic (4) | coordinate length (4, LE) | X | Y, both big-endian.
+        match (blob.get(4..8), blob.get(8..)) {
+            (Some(len), Some(xy))
+                if xy.len() == 2 * u32::from_le_bytes(len.try_into().unwrap()) as usize =>
+            {
+                Ok([&[0x04], xy].concat())
+            }
+            _ => Err(CngError::UnexpectedBlob.into()),
+        }
+    }
+
+    /// ECDH with `peer`, returning SHA-256 of the shared x-coordinate (CNG's `HASH` KDF).
+    pub fn derive_shared_secret(
+        &self,
+        peer: &CngKey,
+        presence: Option<&Presence>,
+    ) -> Result<SecretBytes> {
+        self.require_user_presence(DEFAULT_REASON, presence)?;
+        unsafe {
+            let mut secret = NCRYPT_SECRET_HANDLE::default();
+            NCryptSecretAgreement(self.handle, peer.handle, &mut secret, NCRYPT_FLAGS(0))?;
+            let mut hash_alg = BCryptBuffer {
+                cbBuffer: (BCRYPT_SHA256_ALGORITHM.len() as u32 + 1) * 2,
+                BufferType: KDF_HASH_ALGORITHM,
+                pvBuffer: BCRYPT_SHA256_ALGORITHM.as_ptr() as *mut c_void,
+            };
+            let params = BCryptBufferDesc {
+                ulVersion: BCRYPTBUFFER_VERSION,
+                cBuffers: 1,
+                pBuffers: &mut hash_alg,
+            };
+            let mut derived = SecretBytes::from(vec![0u8; 32]);
+            let mut out_len = 0u32;
+            let res = NCryptDeriveKey(
+                secret,
+                BCRYPT_KDF_HASH,
+                Some(&params),
+                Some(derived.expose_mut()),
+                &mut out_len,
+                0,
+            );
+            NCryptFreeObject(NCRYPT_HANDLE(secret.0))?;
+            res?;
+            if out_len != 32 {
+                return Err(CngError::SecretLength(out_len).into());
+            }
+            Ok(derived)
         }
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        if get_biometrics_status() == 0 && !authenticate_with_biometrics() {
-            bail!("Biometric authentication failed");
+    fn padding_info(padding: CngPadding) -> (Option<*const c_void>, NCRYPT_FLAGS) {
+        match padding {
+            CngPadding::Pkcs1 => (None, NCRYPT_PAD_PKCS1_FLAG),
+            CngPadding::OaepSha256 => (
+                Some(&OAEP_SHA256.0 as *const BCRYPT_OAEP_PADDING_INFO as *const c_void),
+                NCRYPT_PAD_OAEP_FLAG,
+            ),
         }
+    }
+
+    /// Encrypts a single RSA-OAEP (SHA-256) block, so `data` is limited to the key size in bytes minus 66
+    /// (190 bytes for RSA-2048). Longer secrets go through a hybrid envelope, see
+    /// [`crate::kmgr::KeyManager::seal`].
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if let CngKeyAlgorithm::Rsa { bits } = self.algorithm()? {
+            let max = (bits / 8).saturating_sub(66) as usize;
+            if data.len() > max {
+                return Err(CngError::BlockTooLarge {
+                    len: data.len(),
+                    max,
+                }
+                .into());
+            }
+        }
+        let (info, flags) = Self::padding_info(CngPadding::OaepSha256);
         unsafe {
             let mut out_len = 0u32;
-            NCryptDecrypt(
+            NCryptEncrypt(self.handle, Some(data), info, None, &mut out_len, flags)?;
+            let mut buffer = vec![0u8; out_len as usize];
+            NCryptEncrypt(
                 self.handle,
                 Some(data),
-                None,
-                None,
+                info,
+                Some(&mut buffer),
                 &mut out_len,
-                NCRYPT_PAD_PKCS1_FLAG,
+                flags,
             )?;
+            buffer.resize(out_len as usize, 0);
+            Ok(buffer)
+        }
+    }
+
+    fn decrypt_with(&self, data: &[u8], padding: CngPadding) -> Result<SecretBytes> {
+        let (info, flags) = Self::padding_info(padding);
+        unsafe {
+            let mut out_len = 0u32;
+            NCryptDecrypt(self.handle, Some(data), info, None, &mut out_len, flags)?;
             let mut buffer = vec![0u8; out_len as usize];
             NCryptDecrypt(
                 self.handle,
                 Some(data),
-                None,
+                info,
                 Some(&mut buffer),
                 &mut out_len,
-                NCRYPT_PAD_PKCS1_FLAG,
+                flags,
             )?;
             buffer.resize(out_len as usize, 0);
-            Ok(buffer)
+            Ok(SecretBytes::from(buffer))
+        }
+    }
+
+    /// Decrypts an OAEP blob, falling back to PKCS#1 v1.5 for blobs written by older versions.
+    /// Returns the padding that worked so callers can re-wrap legacy blobs. Only a blob that
+    /// doesn't decode is retried; a cancelled prompt or any other failure is returned as is.
+    pub fn decrypt(
+        &self,
+        data: &[u8],
+        presence: Option<&Presence>,
+    ) -> Result<(SecretBytes, CngPadding)> {
+        self.require_user_presence(DEFAULT_REASON, presence)?;
+        // OAEP decoding of a PKCS#1 blob fails outright, so trying OAEP first is unambiguous.
+        match self.decrypt_with(data, CngPadding::OaepSha256) {
+            Ok(plaintext) => Ok((plaintext, CngPadding::OaepSha256)),
+            Err(BwbioError::Cng(e)) if e.is_bad_data() => Ok((
+                self.decrypt_with(data, CngPadding::Pkcs1)?,
+                CngPadding::Pkcs1,
+            )),
+            Err(e) => Err(e),
         }
     }
 
+    /// Deletes the key after a Windows Hello check. The check is app-level even for gesture-bound
+    /// keys, as the KSP doesn't prompt for deletion.
+    pub fn delete_verified(self) -> Result<()> {
+        verify_user_presence("Delete the bwbio encryption key")?;
+        self.delete()
+    }
+
     pub fn delete(self) -> Result<()> {
+        let name = self.name().map(|n| n.to_string()).unwrap_or_default();
         unsafe {
             NCryptDeleteKey(self.handle, 0)?;
         }
+        info!(name, "CNG key deleted");
+        // A successful NCryptDeleteKey also frees the handle; on failure it is dropped as usual.
+        mem::forget(self);
         Ok(())
     }
 }
@@ -0,0 +1,536 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+#[cfg(windows)]
+use crate::cng::{CngKeyAlgorithm, default_key_name};
+use crate::crypto::OaepHash;
+use crate::error::Result;
+#[cfg(all(windows, feature = "installer"))]
+use crate::install::select_browsers;
+use crate::keystore::KeyStoreKind;
+use crate::log::LogLevel;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fs::{create_dir_all, read_to_string, write},
+    io::{self, ErrorKind},
+    path::PathBuf,
+    sync::OnceLock,
+};
+use thiserror::Error;
+#[cfg(windows)]
+use windows_strings::HSTRING;
+
+/// The configuration as loaded on first use, and why loading failed if it did.
+static CONFIG: OnceLock<(Config, Option<String>)> = OnceLock::new();
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+/// Names accepted by `config get/set`, in the order `config list` shows them.
+pub const CONFIG_KEYS: [&str; 21] = [
+    "install_dir",
+    "key_dir",
+    "key_store",
+    "audit_log",
+    "hash_key_names",
+    "dpapi_backup",
+    "cng_key_name",
+    "cng_machine_key",
+    "cng_algorithm",
+    "cng_gesture",
+    "cng_delete_verify",
+    "allow_software_ksp",
+    "biometric_only",
+    "browsers",
+    "prompt_text",
+    "key_cache_seconds",
+    "oaep_hash",
+    "legacy_key_field",
+    "simple_menu",
+    "log_file",
+    "log_level",
+];
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[cfg(not(target_os = "linux"))]
+    #[error("APPDATA not set. Set BW_CONFIG to the config file.")]
+    NoConfigDir,
+    #[error("Invalid {}: {source}", .path.display())]
+    Invalid {
+        path: PathBuf,
+        #[source]
+        source: Box<toml::de::Error>,
+    },
+    #[error("Failed to access {}: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to serialize the configuration: {0}")]
+    Serialize(#[from] toml::ser::Error),
+    #[error("Unknown setting {0:?}; expected one of {keys}", keys = CONFIG_KEYS.join(", "))]
+    UnknownKey(String),
+    #[error("Invalid {key}: {reason}")]
+    InvalidValue { key: &'static str, reason: String },
+    #[error("Unknown log level {0:?}; expected error, warn, info or debug")]
+    UnknownLogLevel(String),
+    /// Carries the load error as text, since [`check_config`] reports it on every call.
+    #[error("The configuration can't be loaded, so its settings would be ignored: {0}")]
+    Unusable(String),
+}
+
+/// Settings shared by the CLI, the TUI and the native messaging host, stored as TOML at
+/// `BW_CONFIG` or `%APPDATA%\bwbio\config.toml` (`$XDG_CONFIG_HOME/bwbio/config.toml` on
+/// Linux). The matching environment variables still take precedence, so a one-off override
+/// doesn't need the file edited.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Where bwbio was installed, when not `%LOCALAPPDATA%\bwbio`; recorded by the installer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_dir: Option<PathBuf>,
+    /// Replaces the `keys` directory next to the executable; `BW_KEY_DIR` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_dir: Option<PathBuf>,
+    /// `file`, `registry` or `credman`; `BW_KEY_STORE` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_store: Option<String>,
+    /// Replaces `.audit.log` in the key directory; `BW_AUDIT_LOG` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<PathBuf>,
+    /// `BW_HASH_KEY_NAMES` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_key_names: Option<bool>,
+    /// `BW_DPAPI_BACKUP` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dpapi_backup: Option<bool>,
+    /// `CNG_KEY_NAME` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cng_key_name: Option<String>,
+    /// `BW_CNG_MACHINE_KEY` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cng_machine_key: Option<bool>,
+    /// `BW_CNG_ALGORITHM` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cng_algorithm: Option<String>,
+    /// `BW_CNG_GESTURE` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cng_gesture: Option<bool>,
+    /// `BW_CNG_DELETE_VERIFY` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cng_delete_verify: Option<bool>,
+    /// `BW_ALLOW_SOFTWARE_KSP` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_software_ksp: Option<bool>,
+    /// `BW_BIOMETRIC_ONLY` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub biometric_only: Option<bool>,
+    /// Browser ids registered by `install` and `register` when `--browser` isn't given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub browsers: Option<Vec<String>>,
+    /// Windows Hello message for unlocks; `{account}` is replaced with the accounts unlocked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_text: Option<String>,
+    /// `BW_KEY_CACHE_SECONDS` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_cache_seconds: Option<u64>,
+    /// `BW_OAEP_HASH` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oaep_hash: Option<String>,
+    /// `BW_LEGACY_KEY_FIELD` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub legacy_key_field: Option<bool>,
+    /// `BW_SIMPLE_MENU` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub simple_menu: Option<bool>,
+    /// Replaces `bwbio.log` in the key directory; `BW_LOG_FILE` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+    /// `BW_LOG_LEVEL` overrides it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LogLevel>,
+}
+
+/// Settings given on the command line, which beat both the environment and the file.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub key_dir: Option<PathBuf>,
+    pub cng_key_name: Option<String>,
+}
+
+/// Installs the command line settings. Only the first call has an effect, so it must happen
+/// before anything resolves a setting.
+pub fn set_overrides(overrides: Overrides) {
+    let _ = OVERRIDES.set(overrides);
+}
+
+fn overrides() -> &'static Overrides {
+    OVERRIDES.get_or_init(Overrides::default)
+}
+
+fn loaded() -> &'static (Config, Option<String>) {
+    CONFIG.get_or_init(|| match Config::load() {
+        Ok(config) => (config, None),
+        Err(e) => (Config::default(), Some(e.to_string())),
+    })
+}
+
+/// The configuration as loaded on first use. A file that can't be read or parsed counts as
+/// empty here, which would turn its switches off, so the host and the CLI call
+/// [`check_config`] first and exporting a key refuses to go on.
+pub fn config() -> &'static Config {
+    &loaded().0
+}
+
+/// Fails when the configuration file couldn't be loaded.
+pub fn check_config() -> Result<()> {
+    match &loaded().1 {
+        Some(reason) => Err(ConfigError::Unusable(reason.clone()).into()),
+        None => Ok(()),
+    }
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        if let Some(path) = env::var_os("BW_CONFIG") {
+            return Ok(PathBuf::from(path));
+        }
+        #[cfg(target_os = "linux")]
+        return Ok(crate::xdg::config_home()?.join("bwbio").join("config.toml"));
+        #[cfg(not(target_os = "linux"))]
+        env::var_os("APPDATA")
+            .map(|s| PathBuf::from(s).join("bwbio").join("config.toml"))
+            .ok_or_else(|| ConfigError::NoConfigDir.into())
+    }
+
+    /// Reads the file; a missing file is an empty configuration.
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        match read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(|source| {
+                ConfigError::Invalid {
+                    path,
+                    source: Box::new(source),
+                }
+                .into()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(source) => Err(ConfigError::Io { path, source }.into()),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        let contents = toml::to_string(self).map_err(ConfigError::from)?;
+        path.parent()
+            .map_or(Ok(()), create_dir_all)
+            .and_then(|_| write(&path, contents))
+            .map_err(|source| ConfigError::Io { path, source }.into())
+    }
+
+    /// Sets `key` in the file and saves it, unless it already has that value.
+    pub fn update(key: &str, value: Option<&str>) -> Result<()> {
+        let mut config = Self::load()?;
+        if config.get(key)?.as_deref() != value {
+            config.set(key, value)?;
+            config.save()?;
+        }
+        Ok(())
+    }
+
+    /// The value of `key` as `config set` accepts it, `None` when unset.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(match key {
+            "install_dir" => self.install_dir.as_ref().map(|p| p.display().to_string()),
+            "key_dir" => self.key_dir.as_ref().map(|p| p.display().to_string()),
+            "key_store" => self.key_store.clone(),
+            "audit_log" => self.audit_log.as_ref().map(|p| p.display().to_string()),
+            "hash_key_names" => self.hash_key_names.map(|b| b.to_string()),
+            "dpapi_backup" => self.dpapi_backup.map(|b| b.to_string()),
+            "cng_key_name" => self.cng_key_name.clone(),
+            "cng_machine_key" => self.cng_machine_key.map(|b| b.to_string()),
+            "cng_algorithm" => self.cng_algorithm.clone(),
+            "cng_gesture" => self.cng_gesture.map(|b| b.to_string()),
+            "cng_delete_verify" => self.cng_delete_verify.map(|b| b.to_string()),
+            "allow_software_ksp" => self.allow_software_ksp.map(|b| b.to_string()),
+            "biometric_only" => self.biometric_only.map(|b| b.to_string()),
+            "browsers" => self.browsers.as_ref().map(|b| b.join(",")),
+            "prompt_text" => self.prompt_text.clone(),
+            "key_cache_seconds" => self.key_cache_seconds.map(|s| s.to_string()),
+            "oaep_hash" => self.oaep_hash.clone(),
+            "legacy_key_field" => self.legacy_key_field.map(|b| b.to_string()),
+            "simple_menu" => self.simple_menu.map(|b| b.to_string()),
+            "log_file" => self.log_file.as_ref().map(|p| p.display().to_string()),
+            "log_level" => self
+                .log_level
+                .map(|level| level.to_string().to_ascii_lowercase()),
+            _ => return Err(ConfigError::UnknownKey(key.to_string()).into()),
+        })
+    }
+
+    /// Sets `key`, or unsets it with `None`. Values are checked the way they are used.
+    pub fn set(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        let value = value.map(str::trim).filter(|v| !v.is_empty());
+        match key {
+            "install_dir" => self.install_dir = value.map(PathBuf::from),
+            "key_dir" => self.key_dir = value.map(PathBuf::from),
+            "key_store" => {
+                self.key_store = value
+                    .map(|v| v.parse::<KeyStoreKind>().map(|_| v.to_ascii_lowercase()))
+                    .transpose()?
+            }
+            "audit_log" => self.audit_log = value.map(PathBuf::from),
+            "hash_key_names" => {
+                self.hash_key_names = value.map(flag("hash_key_names")).transpose()?
+            }
+            "dpapi_backup" => self.dpapi_backup = value.map(flag("dpapi_backup")).transpose()?,
+            "cng_key_name" => self.cng_key_name = value.map(str::to_string),
+            "cng_machine_key" => {
+                self.cng_machine_key = value.map(flag("cng_machine_key")).transpose()?
+            }
+            "cng_algorithm" => self.cng_algorithm = value.map(cng_algorithm).transpose()?,
+            "cng_gesture" => self.cng_gesture = value.map(flag("cng_gesture")).transpose()?,
+            "cng_delete_verify" => {
+                self.cng_delete_verify = value.map(flag("cng_delete_verify")).transpose()?
+            }
+            "allow_software_ksp" => {
+                self.allow_software_ksp = value.map(flag("allow_software_ksp")).transpose()?
+            }
+            "biometric_only" => {
+                self.biometric_only = value.map(flag("biometric_only")).transpose()?
+            }
+            "browsers" => self.browsers = value.map(browser_ids).transpose()?,
+            "prompt_text" => self.prompt_text = value.map(str::to_string),
+            "key_cache_seconds" => {
+                self.key_cache_seconds = value
+                    .map(|v| {
+                        v.parse().map_err(|_| ConfigError::InvalidValue {
+                            key: "key_cache_seconds",
+                            reason: "expected a whole number".to_string(),
+                        })
+                    })
+                    .transpose()?
+            }
+            "oaep_hash" => {
+                self.oaep_hash = value
+                    .map(|v| v.parse::<OaepHash>().map(|_| v.to_ascii_lowercase()))
+                    .transpose()?
+            }
+            "legacy_key_field" => {
+                self.legacy_key_field = value.map(flag("legacy_key_field")).transpose()?
+            }
+            "simple_menu" => self.simple_menu = value.map(flag("simple_menu")).transpose()?,
+            "log_file" => self.log_file = value.map(PathBuf::from),
+            "log_level" => self.log_level = value.map(str::parse).transpose()?,
+            _ => return Err(ConfigError::UnknownKey(key.to_string()).into()),
+        }
+        Ok(())
+    }
+
+    /// `--key-dir`, then `BW_KEY_DIR`, then `key_dir`, then `default`.
+    pub fn key_dir(&self, default: PathBuf) -> PathBuf {
+        overrides()
+            .key_dir
+            .clone()
+            .or_else(|| env::var_os("BW_KEY_DIR").map(PathBuf::from))
+            .or_else(|| self.key_dir.clone())
+            .unwrap_or(default)
+    }
+
+    /// `BW_KEY_STORE`, then `key_store`, then files in the key directory. An unknown store is
+    /// an error rather than the file store, which may not hold the keys.
+    pub fn key_store(&self) -> Result<KeyStoreKind> {
+        match env::var("BW_KEY_STORE")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .or_else(|| self.key_store.clone())
+        {
+            Some(kind) => Ok(kind.trim().parse()?),
+            None => Ok(KeyStoreKind::default()),
+        }
+    }
+
+    /// `BW_AUDIT_LOG`, then `audit_log`.
+    pub fn audit_log(&self) -> Option<PathBuf> {
+        env::var_os("BW_AUDIT_LOG")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| self.audit_log.clone())
+    }
+
+    /// `BW_HASH_KEY_NAMES`, then `hash_key_names`.
+    pub fn hash_key_names(&self) -> bool {
+        env_flag("BW_HASH_KEY_NAMES")
+            .or(self.hash_key_names)
+            .unwrap_or(false)
+    }
+
+    /// `BW_DPAPI_BACKUP`, then `dpapi_backup`.
+    pub fn dpapi_backup(&self) -> bool {
+        env_flag("BW_DPAPI_BACKUP")
+            .or(self.dpapi_backup)
+            .unwrap_or(false)
+    }
+
+    /// `--key-name`, then `CNG_KEY_NAME`, then `cng_key_name`, then `bw-bio`.
+    #[cfg(windows)]
+    pub fn cng_key_name(&self) -> HSTRING {
+        overrides()
+            .cng_key_name
+            .clone()
+            .or_else(|| env::var("CNG_KEY_NAME").ok())
+            .or_else(|| self.cng_key_name.clone())
+            .map(HSTRING::from)
+            .unwrap_or_else(default_key_name)
+    }
+
+    /// `BW_CNG_MACHINE_KEY`, then `cng_machine_key`.
+    pub fn cng_machine_key(&self) -> bool {
+        env_flag("BW_CNG_MACHINE_KEY")
+            .or(self.cng_machine_key)
+            .unwrap_or(false)
+    }
+
+    /// `BW_CNG_ALGORITHM`, then `cng_algorithm`, then RSA-2048.
+    #[cfg(windows)]
+    pub fn cng_algorithm(&self) -> CngKeyAlgorithm {
+        env::var("BW_CNG_ALGORITHM")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .or_else(|| self.cng_algorithm.as_deref().and_then(|s| s.parse().ok()))
+            .unwrap_or_default()
+    }
+
+    /// `BW_CNG_GESTURE`, then `cng_gesture`.
+    pub fn cng_gesture(&self) -> bool {
+        env_flag("BW_CNG_GESTURE")
+            .or(self.cng_gesture)
+            .unwrap_or(false)
+    }
+
+    /// `BW_CNG_DELETE_VERIFY`, then `cng_delete_verify`; on unless turned off.
+    pub fn cng_delete_verify(&self) -> bool {
+        env_flag("BW_CNG_DELETE_VERIFY")
+            .or(self.cng_delete_verify)
+            .unwrap_or(true)
+    }
+
+    /// `BW_ALLOW_SOFTWARE_KSP`, then `allow_software_ksp`.
+    pub fn allow_software_ksp(&self) -> bool {
+        env_flag("BW_ALLOW_SOFTWARE_KSP")
+            .or(self.allow_software_ksp)
+            .unwrap_or(false)
+    }
+
+    /// `BW_BIOMETRIC_ONLY`, then `biometric_only`.
+    pub fn biometric_only(&self) -> bool {
+        env_flag("BW_BIOMETRIC_ONLY")
+            .or(self.biometric_only)
+            .unwrap_or(false)
+    }
+
+    /// `BW_OAEP_HASH`, then `oaep_hash`, then SHA-1.
+    pub fn oaep_hash(&self) -> OaepHash {
+        env::var("BW_OAEP_HASH")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .or_else(|| self.oaep_hash.as_deref().and_then(|s| s.parse().ok()))
+            .unwrap_or_default()
+    }
+
+    /// `BW_LEGACY_KEY_FIELD`, then `legacy_key_field`.
+    pub fn legacy_key_field(&self) -> bool {
+        env_flag("BW_LEGACY_KEY_FIELD")
+            .or(self.legacy_key_field)
+            .unwrap_or(false)
+    }
+
+    /// `BW_SIMPLE_MENU`, then `simple_menu`.
+    pub fn simple_menu(&self) -> bool {
+        env_flag("BW_SIMPLE_MENU")
+            .or(self.simple_menu)
+            .unwrap_or(false)
+    }
+
+    /// `BW_KEY_CACHE_SECONDS`, then `key_cache_seconds`.
+    pub fn key_cache_seconds(&self) -> Option<u64> {
+        env::var("BW_KEY_CACHE_SECONDS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .or(self.key_cache_seconds)
+    }
+
+    /// `BW_LOG_FILE`, then `log_file`.
+    pub fn log_file(&self) -> Option<PathBuf> {
+        env::var_os("BW_LOG_FILE")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| self.log_file.clone())
+    }
+
+    /// `BW_LOG_LEVEL`, then `log_level`.
+    pub fn log_level(&self) -> Option<LogLevel> {
+        env::var("BW_LOG_LEVEL")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .or(self.log_level)
+    }
+}
+
+/// A switch set in the environment: on unless it is `0`, `false`, `no` or `off`. An empty value
+/// counts as unset.
+fn env_flag(name: &str) -> Option<bool> {
+    let value = env::var(name).ok()?;
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" => None,
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => Some(true),
+    }
+}
+
+/// Parses a switch given to `config set` for `key`.
+fn flag(key: &'static str) -> impl Fn(&str) -> Result<bool, ConfigError> {
+    move |value| match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(ConfigError::InvalidValue {
+            key,
+            reason: "expected true or false".to_string(),
+        }),
+    }
+}
+
+/// The algorithm as `BW_CNG_ALGORITHM` accepts it.
+#[cfg(windows)]
+fn cng_algorithm(value: &str) -> Result<String> {
+    value.parse::<CngKeyAlgorithm>()?;
+    Ok(value.to_ascii_lowercase())
+}
+
+/// Without CNG the algorithm is only recorded.
+#[cfg(not(windows))]
+fn cng_algorithm(value: &str) -> Result<String> {
+    Ok(value.to_ascii_lowercase())
+}
+
+/// The browser ids in a comma separated list, checked against the browsers bwbio registers with.
+#[cfg(all(windows, feature = "installer"))]
+fn browser_ids(list: &str) -> Result<Vec<String>> {
+    let hosts = select_browsers(Some(list)).map_err(|e| ConfigError::InvalidValue {
+        key: "browsers",
+        reason: e.to_string(),
+    })?;
+    Ok(hosts.iter().map(|h| h.id.to_string()).collect())
+}
+
+/// Without the installer nothing registers with browsers, so the ids are only split.
+#[cfg(not(all(windows, feature = "installer")))]
+fn browser_ids(list: &str) -> Result<Vec<String>> {
+    Ok(list
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect())
+}
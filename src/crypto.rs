@@ -1,94 +1,1022 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
 
-use crate::proto::EncString;
+use crate::error::{BwbioError, Result};
+use crate::proto::{EncString, EncType, ProtoError};
 use aes::{
     Aes256,
     cipher::{
-        BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::Pkcs7,
+        BlockDecryptMut, BlockEncryptMut, InnerIvInit, block_padding::Pkcs7,
         generic_array::GenericArray,
     },
 };
-use anyhow::{Result, anyhow};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::Engine;
+use hkdf::Hkdf;
 use hmac::{Hmac, Mac};
-use rand::{Rng, RngCore};
-use rsa::{Oaep, RsaPublicKey, pkcs8::DecodePublicKey};
+use p256::ecdsa::signature::Verifier;
+use p256::elliptic_curve::{
+    AffinePoint, CurveArithmetic, FieldBytesSize, PublicKey, SecretKey,
+    ecdh::diffie_hellman,
+    generic_array::typenum::Unsigned,
+    sec1::{FromEncodedPoint, ModulusSize, ToEncodedPoint},
+};
+use rand::{SeedableRng, rngs::StdRng};
+#[cfg(not(windows))]
+use rand::{TryRngCore, rngs::OsRng};
+use rsa::{
+    Oaep, RsaPrivateKey, RsaPublicKey,
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey},
+};
+use serde::{Serialize, Serializer};
 use sha1::Sha1;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::{self, Debug, Formatter},
+    str::FromStr,
+};
 use subtle::ConstantTimeEq;
+use thiserror::Error;
+#[cfg(windows)]
+use windows::Win32::{
+    Foundation::{HLOCAL, LocalFree},
+    Security::Cryptography::{
+        BCRYPT_USE_SYSTEM_PREFERRED_RNG, BCryptGenRandom, CRYPT_INTEGER_BLOB,
+        CRYPTPROTECT_UI_FORBIDDEN, CryptProtectData, CryptUnprotectData,
+    },
+};
+use zeroize::Zeroize;
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    /// `BCryptGenRandom` or DPAPI failed.
+    #[cfg(windows)]
+    #[error(transparent)]
+    Os(windows::core::Error),
+    /// The system random generator failed.
+    #[cfg(not(windows))]
+    #[error(transparent)]
+    Os(rand::rand_core::OsError),
+    #[error("{0} is only available on Windows")]
+    Unsupported(&'static str),
+    #[error("Invalid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Rsa(#[from] rsa::Error),
+    #[error("Invalid RSA public key: {0}")]
+    RsaPublicKey(#[from] rsa::pkcs8::spki::Error),
+    #[error("Unsupported OAEP hash: {0}")]
+    UnknownOaepHash(String),
+    #[error("Invalid Argon2 parameters: {0}")]
+    Argon2Params(argon2::Error),
+    #[error("Argon2 derivation failed: {0}")]
+    Argon2(argon2::Error),
+    #[error("HKDF expand failed")]
+    Hkdf,
+    #[error("Invalid key length: {0}")]
+    KeyLength(usize),
+    #[error("Invalid EC public key")]
+    InvalidPublicKey,
+    #[error("Signature doesn't verify")]
+    BadSignature,
+    #[error("{0} too short")]
+    TooShort(&'static str),
+    #[error("AES-GCM encryption failed")]
+    Encrypt,
+    #[error("AES-GCM authentication failed")]
+    Authentication,
+    #[error("Wrong passphrase or corrupted key file")]
+    WrongPassphrase,
+    #[error("Secret is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("{0}")]
+    SelfTest(String),
+}
+
+/// Fills `buf` from the system-preferred CSPRNG (`BCryptGenRandom`).
+#[cfg(windows)]
+pub fn secure_random(buf: &mut [u8]) -> Result<()> {
+    unsafe { BCryptGenRandom(None, buf, BCRYPT_USE_SYSTEM_PREFERRED_RNG) }
+        .ok()
+        .map_err(CryptoError::Os)?;
+    Ok(())
+}
+
+/// Fills `buf` from the operating system's CSPRNG (`getrandom`).
+#[cfg(not(windows))]
+pub fn secure_random(buf: &mut [u8]) -> Result<()> {
+    OsRng.try_fill_bytes(buf).map_err(CryptoError::Os)?;
+    Ok(())
+}
+
+/// Copies a DPAPI output blob, then wipes and frees it.
+#[cfg(windows)]
+unsafe fn take_dpapi_blob(blob: CRYPT_INTEGER_BLOB) -> Vec<u8> {
+    unsafe {
+        let data = std::slice::from_raw_parts_mut(blob.pbData, blob.cbData as usize);
+        let out = data.to_vec();
+        data.zeroize();
+        LocalFree(Some(HLOCAL(blob.pbData as _)));
+        out
+    }
+}
+
+/// Encrypts `data` for the current Windows user with DPAPI (`CryptProtectData`). Unlike the CNG
+/// key this survives a TPM clear, but any process running as the user can decrypt it.
+#[cfg(windows)]
+pub fn dpapi_protect(data: &[u8]) -> Result<Vec<u8>> {
+    let input = CRYPT_INTEGER_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut _,
+    };
+    let mut output = CRYPT_INTEGER_BLOB::default();
+    unsafe {
+        CryptProtectData(
+            &input,
+            windows::core::w!("bwbio"),
+            None,
+            None,
+            None,
+            CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+        .map_err(CryptoError::Os)?;
+        Ok(take_dpapi_blob(output))
+    }
+}
+
+#[cfg(windows)]
+pub fn dpapi_unprotect(data: &[u8]) -> Result<SecretBytes> {
+    let input = CRYPT_INTEGER_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut _,
+    };
+    let mut output = CRYPT_INTEGER_BLOB::default();
+    unsafe {
+        CryptUnprotectData(
+            &input,
+            None,
+            None,
+            None,
+            None,
+            CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+        .map_err(CryptoError::Os)?;
+        Ok(SecretBytes::from(take_dpapi_blob(output)))
+    }
+}
+
+/// DPAPI has no counterpart elsewhere; the copies it would protect are refused.
+#[cfg(not(windows))]
+pub fn dpapi_protect(_data: &[u8]) -> Result<Vec<u8>> {
+    Err(CryptoError::Unsupported("DPAPI").into())
+}
+
+#[cfg(not(windows))]
+pub fn dpapi_unprotect(_data: &[u8]) -> Result<SecretBytes> {
+    Err(CryptoError::Unsupported("DPAPI").into())
+}
+
+fn secure_random_array<const N: usize>() -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    secure_random(&mut buf)?;
+    Ok(buf)
+}
+
+/// A CSPRNG seeded from [`secure_random`], for APIs that take an RNG, such as RSA padding.
+/// Their RNG can't report errors, so the system generator is only read here, where a failure
+/// is returned instead.
+fn system_rng() -> Result<StdRng> {
+    Ok(StdRng::from_seed(secure_random_array()?))
+}
+
+/// Byte buffer holding secret material; wiped when dropped.
+#[derive(Clone, Default)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    pub fn into_secret_string(mut self) -> Result<SecretString> {
+        match String::from_utf8(std::mem::take(&mut self.0)) {
+            Ok(s) => Ok(SecretString(s)),
+            Err(e) => {
+                e.into_bytes().zeroize();
+                Err(CryptoError::InvalidUtf8.into())
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Debug for SecretBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBytes(<redacted>)")
+    }
+}
+
+/// String holding secret material; wiped when dropped.
+#[derive(Clone, Default)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(<redacted>)")
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
 
 pub fn base64_decode(input: &str) -> Result<Vec<u8>> {
-    Ok(base64::engine::general_purpose::STANDARD.decode(input)?)
+    Ok(base64::engine::general_purpose::STANDARD
+        .decode(input)
+        .map_err(CryptoError::from)?)
 }
 
 pub fn base64_encode(input: &[u8]) -> String {
     base64::engine::general_purpose::STANDARD.encode(input)
 }
 
-pub fn rsa_encrypt(public_key_b64: &str, message: &[u8]) -> Result<String> {
+/// Digest used for RSA-OAEP when wrapping the shared secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OaepHash {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl FromStr for OaepHash {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, CryptoError> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha1" | "sha-1" => Ok(OaepHash::Sha1),
+            "sha256" | "sha-256" => Ok(OaepHash::Sha256),
+            _ => Err(CryptoError::UnknownOaepHash(s.to_string())),
+        }
+    }
+}
+
+fn oaep_padding(hash: OaepHash) -> Oaep {
+    match hash {
+        OaepHash::Sha1 => Oaep::new::<Sha1>(),
+        OaepHash::Sha256 => Oaep::new::<rsa::sha2::Sha256>(),
+    }
+}
+
+pub fn rsa_encrypt(public_key_b64: &str, message: &[u8], hash: OaepHash) -> Result<String> {
     let public_key = base64_decode(public_key_b64)?;
-    let public_key = RsaPublicKey::from_public_key_der(&public_key)?;
-    let mut rng = rand::rng();
-    let padding = Oaep::new::<Sha1>();
-    let ct = public_key.encrypt(&mut rng, padding, message)?;
+    let public_key = RsaPublicKey::from_public_key_der(&public_key).map_err(CryptoError::from)?;
+    let ct = public_key
+        .encrypt(&mut system_rng()?, oaep_padding(hash), message)
+        .map_err(CryptoError::from)?;
     Ok(base64_encode(&ct))
 }
 
-pub fn generate_mac(mac_key: &[u8; 32], iv: &[u8], data: &[u8]) -> Result<[u8; 32]> {
-    let mut hmac = Hmac::<Sha256>::new_from_slice(mac_key).unwrap();
+/// Local RSA keypair, used when bwbio plays the extension side of the handshake.
+pub struct RsaKeyPair {
+    private_key: RsaPrivateKey,
+}
+
+impl RsaKeyPair {
+    pub fn generate(bits: usize) -> Result<Self> {
+        Ok(Self {
+            private_key: RsaPrivateKey::new(&mut system_rng()?, bits).map_err(CryptoError::from)?,
+        })
+    }
+
+    /// DER-encoded SubjectPublicKeyInfo in base64, as sent in `setupEncryption`.
+    pub fn public_key_b64(&self) -> Result<String> {
+        let der = self
+            .private_key
+            .to_public_key()
+            .to_public_key_der()
+            .map_err(CryptoError::from)?;
+        Ok(base64_encode(der.as_bytes()))
+    }
+
+    pub fn decrypt(&self, ciphertext_b64: &str, hash: OaepHash) -> Result<SecretBytes> {
+        let ciphertext = base64_decode(ciphertext_b64)?;
+        Ok(SecretBytes::from(
+            self.private_key
+                .decrypt(oaep_padding(hash), &ciphertext)
+                .map_err(CryptoError::from)?,
+        ))
+    }
+}
+
+pub fn generate_mac(mac_key: &[u8; 32], iv: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC takes any key");
     hmac.update(iv);
     hmac.update(data);
-    Ok((*hmac.finalize().into_bytes()).try_into().unwrap())
+    hmac.finalize().into_bytes().into()
+}
+
+/// Checks an ECDSA P-256 signature over SHA-256 of `data`, DER-encoded as `openssl dgst -sha256
+/// -sign` writes it, against `public_key`, an SEC1 point.
+pub fn verify_p256_signature(public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<()> {
+    let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|_| CryptoError::InvalidPublicKey)?;
+    let signature =
+        p256::ecdsa::Signature::from_der(signature).map_err(|_| CryptoError::BadSignature)?;
+    key.verify(data, &signature)
+        .map_err(|_| CryptoError::BadSignature.into())
+}
+
+/// Key derivation settings of a Bitwarden account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    Pbkdf2 {
+        iterations: u32,
+    },
+    Argon2id {
+        iterations: u32,
+        memory_mib: u32,
+        parallelism: u32,
+    },
+}
+
+/// Derives the Bitwarden master key from the master password, salted with the account email.
+pub fn derive_master_key(password: &str, email: &str, kdf: Kdf) -> Result<SecretBytes> {
+    let email = email.trim().to_lowercase();
+    let mut master_key = SecretBytes::from(vec![0u8; 32]);
+    match kdf {
+        Kdf::Pbkdf2 { iterations } => pbkdf2::pbkdf2_hmac::<Sha256>(
+            password.as_bytes(),
+            email.as_bytes(),
+            iterations,
+            &mut master_key.0,
+        ),
+        Kdf::Argon2id {
+            iterations,
+            memory_mib,
+            parallelism,
+        } => {
+            let memory_kib = memory_mib
+                .checked_mul(1024)
+                .ok_or(CryptoError::Argon2Params(argon2::Error::MemoryTooMuch))?;
+            let params = Params::new(memory_kib, iterations, parallelism, Some(32))
+                .map_err(CryptoError::Argon2Params)?;
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(
+                    password.as_bytes(),
+                    &Sha256::digest(email.as_bytes()),
+                    &mut master_key.0,
+                )
+                .map_err(CryptoError::Argon2)?;
+        }
+    }
+    Ok(master_key)
+}
+
+/// Expands a master key into the AES/MAC key pair that protects the account's user key.
+pub fn stretch_master_key(master_key: &SecretBytes) -> Result<Aes256CbcHmacKey> {
+    let hkdf = Hkdf::<Sha256>::from_prk(master_key.expose())
+        .map_err(|_| CryptoError::KeyLength(master_key.expose().len()))?;
+    let mut key = SecretBytes::from(vec![0u8; 64]);
+    let (enc_key, mac_key) = key.0.split_at_mut(32);
+    hkdf.expand(b"enc", enc_key)
+        .and_then(|_| hkdf.expand(b"mac", mac_key))
+        .map_err(|_| CryptoError::Hkdf)?;
+    Aes256CbcHmacKey::from_slice(key.expose())
 }
 
+/// HKDF info for the AES-GCM key of an [`Aes256CbcHmacKey`].
+const GCM_KEY_INFO: &[u8] = b"bwbio aes-256-gcm";
+
+/// AES-256-CBC + HMAC-SHA256 key pair. The AES key schedules and the keyed HMAC state are built
+/// once here, since this key encrypts and decrypts every message of a session. bwbio's own
+/// AES-GCM type ([`EncType::BwbioAesGcm256B64`]) uses its own key, derived from the pair with
+/// HKDF, so no AES key serves two modes.
 pub struct Aes256CbcHmacKey {
     enc_key: [u8; 32],
     mac_key: [u8; 32],
+    cipher: Aes256,
+    gcm: Aes256Gcm,
+    hmac: Hmac<Sha256>,
 }
 
 impl Aes256CbcHmacKey {
-    pub fn new() -> Self {
-        let mut rng = rand::rng();
-        let mut enc_key = [0u8; 32];
-        let mut mac_key = [0u8; 32];
-        rng.fill_bytes(&mut enc_key);
-        rng.fill_bytes(&mut mac_key);
-        Self { enc_key, mac_key }
+    pub fn new() -> Result<Self> {
+        let mut key = SecretBytes::from(vec![0u8; 64]);
+        secure_random(&mut key.0)?;
+        Ok(Self::from_keys(&key.0[..32], &key.0[32..]))
     }
 
-    pub fn to_vec(&self) -> Vec<u8> {
+    fn from_keys(enc_key: &[u8], mac_key: &[u8]) -> Self {
+        let mut gcm_key = SecretBytes::from(vec![0u8; 32]);
+        Hkdf::<Sha256>::new(Some(mac_key), enc_key)
+            .expand(GCM_KEY_INFO, &mut gcm_key.0)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self {
+            enc_key: enc_key.try_into().unwrap(),
+            mac_key: mac_key.try_into().unwrap(),
+            cipher: Aes256::new(GenericArray::from_slice(enc_key)),
+            gcm: Aes256Gcm::new(GenericArray::from_slice(gcm_key.expose())),
+            hmac: <Hmac<Sha256> as Mac>::new_from_slice(mac_key).unwrap(),
+        }
+    }
+
+    /// Rebuilds a key from the 64-byte `enc_key || mac_key` form produced by [`Self::to_vec`].
+    pub fn from_slice(key: &[u8]) -> Result<Self> {
+        if key.len() != 64 {
+            return Err(CryptoError::KeyLength(key.len()).into());
+        }
+        Ok(Self::from_keys(&key[..32], &key[32..]))
+    }
+
+    pub fn to_vec(&self) -> SecretBytes {
         let mut key_vec = Vec::with_capacity(64);
         key_vec.extend_from_slice(&self.enc_key);
         key_vec.extend_from_slice(&self.mac_key);
-        key_vec
+        SecretBytes::from(key_vec)
+    }
+
+    fn mac(&self, iv: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut hmac = self.hmac.clone();
+        hmac.update(iv);
+        hmac.update(data);
+        hmac.finalize().into_bytes().into()
     }
 
-    pub fn decrypt(&self, iv: &[u8], mac: &[u8], data: &[u8]) -> Result<Vec<u8>> {
-        let res = generate_mac(&self.mac_key, iv, data)?;
-        if res.ct_ne(mac).into() {
-            return Err(anyhow!("MAC verification failed"));
+    pub fn decrypt(&self, enc_str: &EncString) -> Result<Vec<u8>, ProtoError> {
+        let enc_type = enc_str.enc_type()?;
+        let iv = enc_str.iv()?;
+        let data = enc_str.data()?;
+        if enc_type == EncType::BwbioAesGcm256B64 {
+            if iv.len() != 12 {
+                return Err(ProtoError::InvalidIvLength(iv.len()));
+            }
+            return self
+                .gcm
+                .decrypt(Nonce::from_slice(&iv), data.as_slice())
+                .map_err(|_| ProtoError::MacMismatch);
         }
-        let key = GenericArray::from_slice(&self.enc_key);
-        let iv = GenericArray::from_slice(iv);
-        cbc::Decryptor::<Aes256>::new(key, iv)
-            .decrypt_padded_vec_mut::<Pkcs7>(data)
-            .map_err(|e| anyhow!("AES decrypt error: {:?}", e))
+        if iv.len() != 16 {
+            return Err(ProtoError::InvalidIvLength(iv.len()));
+        }
+        if enc_type == EncType::AesCbc256HmacSha256B64 {
+            let mac = enc_str.mac()?.unwrap_or_default();
+            if self.mac(&iv, &data).ct_ne(&mac).into() {
+                return Err(ProtoError::MacMismatch);
+            }
+        }
+        let iv = GenericArray::from_slice(&iv);
+        cbc::Decryptor::<Aes256>::inner_iv_init(self.cipher.clone(), iv)
+            .decrypt_padded_vec_mut::<Pkcs7>(&data)
+            .map_err(|_| ProtoError::Decrypt)
     }
 
     pub fn encrypt(&self, msg: &[u8]) -> Result<EncString> {
-        let iv = rand::rng().random::<[u8; 16]>();
-        let key = GenericArray::from_slice(&self.enc_key);
-        let data =
-            cbc::Encryptor::<Aes256>::new(key, &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(msg);
-        let mac = generate_mac(&self.mac_key, &iv, &data)?;
+        let iv = secure_random_array::<16>()?;
+        let data = cbc::Encryptor::<Aes256>::inner_iv_init(self.cipher.clone(), &iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(msg);
+        let mac = self.mac(&iv, &data);
 
-        Ok(EncString::new(&data, &iv, &mac))
+        Ok(EncString::new(
+            EncType::AesCbc256HmacSha256B64,
+            &data,
+            &iv,
+            Some(&mac),
+        ))
     }
+
+    pub fn encrypt_gcm(&self, msg: &[u8]) -> Result<EncString> {
+        let iv = secure_random_array::<12>()?;
+        let data = self
+            .gcm
+            .encrypt(Nonce::from_slice(&iv), msg)
+            .map_err(|_| CryptoError::Encrypt)?;
+        Ok(EncString::new(EncType::BwbioAesGcm256B64, &data, &iv, None))
+    }
+
+    /// Encrypts with the given cipher suite; unauthenticated type 0 is upgraded to type 2.
+    pub fn encrypt_with(&self, enc_type: EncType, msg: &[u8]) -> Result<EncString> {
+        match enc_type {
+            EncType::BwbioAesGcm256B64 => self.encrypt_gcm(msg),
+            EncType::AesCbc256B64 | EncType::AesCbc256HmacSha256B64 => self.encrypt(msg),
+        }
+    }
+}
+
+/// Payload sealed under a random AES-256-GCM data key; only the data key is wrapped by an
+/// external key (e.g. the CNG RSA key), so payload size isn't bounded by the RSA block size.
+pub struct HybridEnvelope {
+    wrapped_key: Vec<u8>,
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+impl HybridEnvelope {
+    pub fn seal(plaintext: &[u8], wrap: impl FnOnce(&[u8]) -> Result<Vec<u8>>) -> Result<Self> {
+        let mut data_key = SecretBytes::from(vec![0u8; 32]);
+        secure_random(&mut data_key.0)?;
+        let nonce = secure_random_array::<12>()?;
+        let ciphertext = Aes256Gcm::new(GenericArray::from_slice(data_key.expose()))
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| CryptoError::Encrypt)?;
+        Ok(Self {
+            wrapped_key: wrap(data_key.expose())?,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub fn open(&self, unwrap: impl FnOnce(&[u8]) -> Result<SecretBytes>) -> Result<SecretBytes> {
+        let data_key = unwrap(&self.wrapped_key)?;
+        if data_key.expose().len() != 32 {
+            return Err(CryptoError::KeyLength(data_key.expose().len()).into());
+        }
+        Aes256Gcm::new(GenericArray::from_slice(data_key.expose()))
+            .decrypt(Nonce::from_slice(&self.nonce), self.ciphertext.as_slice())
+            .map(SecretBytes::from)
+            .map_err(|_| CryptoError::Authentication.into())
+    }
+
+    /// Layout: `wrapped_key_len (u32 LE) | wrapped_key | nonce (12) | ciphertext+tag`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + self.wrapped_key.len() + 12 + self.ciphertext.len());
+        out.extend_from_slice(&(self.wrapped_key.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.wrapped_key);
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&self.ciphertext);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (len, rest) = bytes
+            .split_first_chunk::<4>()
+            .ok_or(CryptoError::TooShort("Envelope"))?;
+        let len = u32::from_le_bytes(*len) as usize;
+        // A GCM tag is always present, so the ciphertext is at least 16 bytes.
+        if rest.len() < len + 12 + 16 {
+            return Err(CryptoError::TooShort("Envelope").into());
+        }
+        let (wrapped_key, rest) = rest.split_at(len);
+        let (nonce, ciphertext) = rest.split_at(12);
+        Ok(Self {
+            wrapped_key: wrapped_key.to_vec(),
+            nonce: nonce.try_into().unwrap(),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+}
+
+/// Seals data with AES-256-GCM under an Argon2id key derived from a passphrase, for backup
+/// archives.
+pub struct PassphraseWrap {
+    passphrase: SecretString,
+}
+
+impl PassphraseWrap {
+    const SALT_LEN: usize = 16;
+
+    pub fn new(passphrase: SecretString) -> Self {
+        Self { passphrase }
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<SecretBytes> {
+        // Bitwarden's default Argon2id parameters: 64 MiB, 3 iterations, 4 lanes.
+        let params = Params::new(64 * 1024, 3, 4, Some(32)).map_err(CryptoError::Argon2Params)?;
+        let mut key = SecretBytes::from(vec![0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(self.passphrase.expose().as_bytes(), salt, &mut key.0)
+            .map_err(CryptoError::Argon2)?;
+        Ok(key)
+    }
+
+    /// Layout: `salt (16) | nonce (12) | ciphertext+tag`.
+    pub fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>> {
+        let salt = secure_random_array::<{ Self::SALT_LEN }>()?;
+        let nonce = secure_random_array::<12>()?;
+        let key = self.derive(&salt)?;
+        let ciphertext = Aes256Gcm::new(GenericArray::from_slice(key.expose()))
+            .encrypt(Nonce::from_slice(&nonce), data_key)
+            .map_err(|_| CryptoError::Encrypt)?;
+        Ok([salt.as_slice(), &nonce, &ciphertext].concat())
+    }
+
+    pub fn unwrap(&self, wrapped: &[u8]) -> Result<SecretBytes> {
+        if wrapped.len() < Self::SALT_LEN + 12 + 16 {
+            return Err(CryptoError::TooShort("Wrapped key").into());
+        }
+        let (salt, rest) = wrapped.split_at(Self::SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(12);
+        let key = self.derive(salt)?;
+        Aes256Gcm::new(GenericArray::from_slice(key.expose()))
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map(SecretBytes::from)
+            .map_err(|_| CryptoError::WrongPassphrase.into())
+    }
+}
+
+/// NIST curves usable for ECDH key wrapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcCurve {
+    P256,
+    P384,
+    P521,
+}
+
+impl EcCurve {
+    /// Length of one big-endian coordinate.
+    pub fn field_len(self) -> usize {
+        match self {
+            EcCurve::P256 => 32,
+            EcCurve::P384 => 48,
+            EcCurve::P521 => 66,
+        }
+    }
+
+    /// Length of an uncompressed SEC1 point.
+    pub fn point_len(self) -> usize {
+        1 + 2 * self.field_len()
+    }
+}
+
+impl fmt::Display for EcCurve {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EcCurve::P256 => "p256",
+            EcCurve::P384 => "p384",
+            EcCurve::P521 => "p521",
+        })
+    }
+}
+
+/// Draws an ephemeral key on `C`, returning its uncompressed point and the shared x-coordinate
+/// with `recipient_point`.
+fn ephemeral_agreement<C>(recipient_point: &[u8]) -> Result<(Vec<u8>, SecretBytes)>
+where
+    C: CurveArithmetic,
+    FieldBytesSize<C>: ModulusSize,
+    AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+{
+    let recipient = PublicKey::<C>::from_sec1_bytes(recipient_point)
+        .map_err(|_| CryptoError::InvalidPublicKey)?;
+    let ephemeral = loop {
+        let mut scalar = SecretBytes::from(vec![0u8; FieldBytesSize::<C>::USIZE]);
+        secure_random(&mut scalar.0)?;
+        // Out-of-range scalars are astronomically rare; just draw again.
+        if let Ok(key) = SecretKey::<C>::from_slice(scalar.expose()) {
+            break key;
+        }
+    };
+    let ephemeral_point = ephemeral.public_key().to_encoded_point(false);
+    let shared = diffie_hellman(ephemeral.to_nonzero_scalar(), recipient.as_affine());
+    Ok((
+        ephemeral_point.as_bytes().to_vec(),
+        SecretBytes::from(shared.raw_secret_bytes().to_vec()),
+    ))
+}
+
+/// ECIES-style wrap of a data key to an EC recipient (e.g. a CNG ECDH key): an ephemeral
+/// software key agrees with the recipient, and the key-encryption key is HKDF-SHA256 over
+/// SHA-256 of the shared x-coordinate, salted with the ephemeral public point.
+pub struct Ecies;
+
+impl Ecies {
+    fn kek(curve: EcCurve, shared_hash: &[u8], ephemeral_point: &[u8]) -> Result<SecretBytes> {
+        let mut kek = SecretBytes::from(vec![0u8; 32]);
+        Hkdf::<Sha256>::new(Some(ephemeral_point), shared_hash)
+            .expand(format!("bwbio-ecies-{curve}").as_bytes(), &mut kek.0)
+            .map_err(|_| CryptoError::Hkdf)?;
+        Ok(kek)
+    }
+
+    /// Layout: `ephemeral point (uncompressed) | nonce (12) | ciphertext+tag`.
+    pub fn wrap(curve: EcCurve, recipient_point: &[u8], data_key: &[u8]) -> Result<Vec<u8>> {
+        let (ephemeral_point, shared) = match curve {
+            EcCurve::P256 => ephemeral_agreement::<p256::NistP256>(recipient_point)?,
+            EcCurve::P384 => ephemeral_agreement::<p384::NistP384>(recipient_point)?,
+            EcCurve::P521 => ephemeral_agreement::<p521::NistP521>(recipient_point)?,
+        };
+        let kek = Self::kek(curve, &Sha256::digest(shared.expose()), &ephemeral_point)?;
+        let nonce = secure_random_array::<12>()?;
+        let ciphertext = Aes256Gcm::new(GenericArray::from_slice(kek.expose()))
+            .encrypt(Nonce::from_slice(&nonce), data_key)
+            .map_err(|_| CryptoError::Encrypt)?;
+        Ok([ephemeral_point.as_slice(), &nonce, &ciphertext].concat())
+    }
+
+    /// `agree` receives the ephemeral point and returns SHA-256 of the shared x-coordinate.
+    pub fn unwrap(
+        curve: EcCurve,
+        wrapped: &[u8],
+        agree: impl FnOnce(&[u8]) -> Result<SecretBytes>,
+    ) -> Result<SecretBytes> {
+        if wrapped.len() < curve.point_len() + 12 + 16 {
+            return Err(CryptoError::TooShort("Wrapped key").into());
+        }
+        let (ephemeral_point, rest) = wrapped.split_at(curve.point_len());
+        let (nonce, ciphertext) = rest.split_at(12);
+        let kek = Self::kek(curve, agree(ephemeral_point)?.expose(), ephemeral_point)?;
+        Aes256Gcm::new(GenericArray::from_slice(kek.expose()))
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map(SecretBytes::from)
+            .map_err(|_| CryptoError::Authentication.into())
+    }
+}
+
+fn self_test_failed(message: impl Into<String>) -> BwbioError {
+    CryptoError::SelfTest(message.into()).into()
+}
+
+/// Known-answer and roundtrip checks for the primitives the host relies on, so a broken build or
+/// dependency is caught before any key material is handled.
+pub fn self_test() -> Result<()> {
+    // AES-256-CBC + HMAC-SHA256 vector: enc key 00..1f, mac key 20..3f, iv 40..4f.
+    const PLAINTEXT: &[u8] = b"bwbio self-test vector";
+    const VECTOR: &str = "2.QEFCQ0RFRkdISUpLTE1OTw==|X6YSrTHeP9P9aGxbkb6r0i0GYY2WoN5pT/TdstddX9M=|vgBez3GaToA1M5pXLVTK5hlAcIi2XjmKReLdF+/VWhA=";
+
+    let key_bytes = (0u8..64).collect::<Vec<_>>();
+    let key = Aes256CbcHmacKey::from_slice(&key_bytes)?;
+    let enc_str = EncString::from_str(VECTOR)?;
+    if key.decrypt(&enc_str)? != PLAINTEXT {
+        return Err(self_test_failed("AES-CBC-HMAC known-answer test failed"));
+    }
+
+    let (iv, data) = (enc_str.iv()?, enc_str.data()?);
+    let expected_mac = enc_str.mac()?.unwrap_or_default();
+    if generate_mac(&key.mac_key, &iv, &data).as_slice() != expected_mac.as_slice() {
+        return Err(self_test_failed("HMAC-SHA256 known-answer test failed"));
+    }
+    let mut bad_mac = expected_mac;
+    bad_mac[0] ^= 1;
+    let tampered = EncString::new(EncType::AesCbc256HmacSha256B64, &data, &iv, Some(&bad_mac));
+    if !matches!(key.decrypt(&tampered), Err(ProtoError::MacMismatch)) {
+        return Err(self_test_failed("Tampered MAC was not rejected"));
+    }
+
+    for enc_type in [EncType::AesCbc256HmacSha256B64, EncType::BwbioAesGcm256B64] {
+        if key.decrypt(&key.encrypt_with(enc_type, PLAINTEXT)?)? != PLAINTEXT {
+            return Err(self_test_failed(format!("{enc_type:?} roundtrip failed")));
+        }
+    }
+
+    // A fixed RSA-1024 key, with bytes 00..1f encrypted by `openssl pkeyutl` under each OAEP
+    // digest; a small modulus keeps the vector short, and OAEP behaves the same at any size.
+    const RSA_KEY: &str = "MIICdgIBADANBgkqhkiG9w0BAQEFAASCAmAwggJcAgEAAoGBAO5NVjiJx3gkImWbzzV/YYZiLFMfl6jjpvTUypxmSpqIzgFnT/CbSRV+C1+aH9G/F4BQWkt2qm9Vp73cK/Ci8hsxgteuFAu83iw+mN3PjYOb1dnGnMAZfr0tWItxSjvUBIOnSz6foO96DKU68jt3vh2AZZxuNNk5Hi6G4MVDwg9LAgMBAAECgYBOzIXxRiYOZSx2mvIGx6tRC4+KoQy+TSD9Hows8WpD9W7hae82KbMeB0LthwJ0khLMXEWvZHrxO8qu7modNVSG5BYm3BYgA20l6Phv9dZq8slAL8NFqUmlBq0FqATeCTLIcr5TdYhFFOmpvfZG0bEZavDEZ1lAHKdEf1zz+ympsQJBAPtHFBtiwWqPtHjTR3cx+eGgaHsjzOA+XMdObgi7IoMALwi1MsLTLd1sT+TC8J45LjwyjNGdQaXcmvgq3QZq3SkCQQDyx9TjWh7wBTAN5eWPXcj1x4vctUk3QF9zI7u/7AH2GyVKUI9LvjcE8DSMUtchM5/Sx8tv93YNDkVJ6wO0GeNTAkA4w6zaJuwUu5QEe444chTGEKuprcO5oLzq1V/sHwJD38XATNbDxlslGzPNNPCQfdBhDvZa4BCVfImRqHU33upJAkEAjiMiZ0ze6gUPgkODZ7Evs311S+0zBU8B0uYryIxDBbQU2/JYQq8FhTWgfjsNyllwC7Z/S3kkuzYMqN8X1vyFEwJASzyS3dBPAoMGQKEbkzyKdk7XdYpXo5IVy25AJFpDSUK/RdCcDQ0dql3Tnj3py12ArK2BJ3j0vzMu9lmMrdgIvg==";
+    const RSA_VECTORS: [(OaepHash, &str); 2] = [
+        (
+            OaepHash::Sha1,
+            "wcatchmDRLIl+Xado5ts1rFITOl9ovbSs1idR3FsEJTLQ/ypjEyC1/ML1uTKZ34pQgN717FYYBw58CNuSyDnzStRvEGzjWmPkAsbU/8aPrHBttqKMtpY8//57ay/GxkGB0LzCCPkVKKYwtDvjwq+KVZy9xXqhJGOoJdcbo8mgaM=",
+        ),
+        (
+            OaepHash::Sha256,
+            "GVTo4zWwaQgCqEl01hr6KgfTDPtXB0Omii8gpUnOs4tVxCN8rZfblbx8gd3HcOlIBlWN2idY77F+9Lk1ekVw9uuOW6PTdCJXjroSlkybCLiYxlefIqICMQjdlnSc2ehMqqi9Fb/EOaThQscpbfeYQZsF0yBJdA4SwEE5dlVVFRE=",
+        ),
+    ];
+
+    let keypair = RsaKeyPair {
+        private_key: RsaPrivateKey::from_pkcs8_der(&base64_decode(RSA_KEY)?)
+            .map_err(|e| self_test_failed(format!("RSA test key can't be loaded: {e}")))?,
+    };
+    let public_key = keypair.public_key_b64()?;
+    for (hash, vector) in RSA_VECTORS {
+        if keypair.decrypt(vector, hash)?.expose() != &key_bytes[..32] {
+            return Err(self_test_failed(format!(
+                "RSA-OAEP ({hash:?}) known-answer test failed"
+            )));
+        }
+        let ciphertext = rsa_encrypt(&public_key, &key_bytes[..32], hash)?;
+        if keypair.decrypt(&ciphertext, hash)?.expose() != &key_bytes[..32] {
+            return Err(self_test_failed(format!(
+                "RSA-OAEP ({hash:?}) roundtrip failed"
+            )));
+        }
+    }
+
+    Ok(())
 }
 
-impl Default for Aes256CbcHmacKey {
-    fn default() -> Self {
-        Self::new()
+impl Drop for Aes256CbcHmacKey {
+    // The AES and AES-GCM key schedules wipe themselves (`zeroize` feature); the keyed HMAC state
+    // has no such hook, so only the raw keys are cleared here.
+    fn drop(&mut self) {
+        self.enc_key.zeroize();
+        self.mac_key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"a message longer than one AES block";
+
+    #[test]
+    fn p256_signature() {
+        use p256::ecdsa::{SigningKey, signature::Signer};
+        let key = SigningKey::from_slice(&[9; 32]).unwrap();
+        let public = key.verifying_key().to_encoded_point(false);
+        let signature: p256::ecdsa::Signature = key.sign(MESSAGE);
+        let der = signature.to_der();
+        verify_p256_signature(public.as_bytes(), MESSAGE, der.as_bytes()).unwrap();
+        assert!(verify_p256_signature(public.as_bytes(), b"tampered", der.as_bytes()).is_err());
+        assert!(verify_p256_signature(public.as_bytes(), MESSAGE, &der.as_bytes()[1..]).is_err());
+        assert!(verify_p256_signature(&[4; 65], MESSAGE, der.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn self_test_passes() {
+        self_test().unwrap();
+    }
+
+    #[test]
+    fn aes_roundtrip() {
+        let key = Aes256CbcHmacKey::new().unwrap();
+        for enc_type in [
+            EncType::AesCbc256B64,
+            EncType::AesCbc256HmacSha256B64,
+            EncType::BwbioAesGcm256B64,
+        ] {
+            let enc_str = key.encrypt_with(enc_type, MESSAGE).unwrap();
+            assert_eq!(key.decrypt(&enc_str).unwrap(), MESSAGE);
+            // Through the string form the extension sends.
+            let parsed = EncString::from_str(&enc_str.to_string()).unwrap();
+            assert_eq!(key.decrypt(&parsed).unwrap(), MESSAGE);
+        }
+    }
+
+    #[test]
+    fn aes_type_0_is_upgraded() {
+        let key = Aes256CbcHmacKey::new().unwrap();
+        let enc_str = key.encrypt_with(EncType::AesCbc256B64, MESSAGE).unwrap();
+        assert_eq!(enc_str.enc_type().unwrap(), EncType::AesCbc256HmacSha256B64);
+    }
+
+    #[test]
+    fn aes_key_roundtrip() {
+        let key = Aes256CbcHmacKey::new().unwrap();
+        let copy = Aes256CbcHmacKey::from_slice(key.to_vec().expose()).unwrap();
+        for enc_type in [EncType::AesCbc256HmacSha256B64, EncType::BwbioAesGcm256B64] {
+            let enc_str = key.encrypt_with(enc_type, MESSAGE).unwrap();
+            assert_eq!(copy.decrypt(&enc_str).unwrap(), MESSAGE);
+        }
+        assert!(Aes256CbcHmacKey::from_slice(&[0; 32]).is_err());
+    }
+
+    #[test]
+    fn aes_rejects_other_key() {
+        let key = Aes256CbcHmacKey::new().unwrap();
+        let other = Aes256CbcHmacKey::new().unwrap();
+        let cbc = key.encrypt(MESSAGE).unwrap();
+        assert!(matches!(other.decrypt(&cbc), Err(ProtoError::MacMismatch)));
+        let gcm = key.encrypt_gcm(MESSAGE).unwrap();
+        assert!(matches!(other.decrypt(&gcm), Err(ProtoError::MacMismatch)));
+    }
+
+    #[test]
+    fn aes_rejects_bad_iv_length() {
+        let key = Aes256CbcHmacKey::new().unwrap();
+        let enc_str = key.encrypt(MESSAGE).unwrap();
+        let short_iv = EncString::new(
+            EncType::AesCbc256HmacSha256B64,
+            &enc_str.data().unwrap(),
+            &[0; 8],
+            enc_str.mac().unwrap().as_deref(),
+        );
+        assert!(matches!(
+            key.decrypt(&short_iv),
+            Err(ProtoError::InvalidIvLength(8))
+        ));
+    }
+
+    #[test]
+    fn master_key_stretches_to_cbc_hmac_key() {
+        let master_key = derive_master_key(
+            "password",
+            " User@Example.com ",
+            Kdf::Pbkdf2 { iterations: 1000 },
+        )
+        .unwrap();
+        // The email is trimmed and lowercased before salting.
+        let same = derive_master_key(
+            "password",
+            "user@example.com",
+            Kdf::Pbkdf2 { iterations: 1000 },
+        )
+        .unwrap();
+        assert_eq!(master_key.expose(), same.expose());
+        assert_eq!(master_key.expose().len(), 32);
+        assert_eq!(
+            stretch_master_key(&master_key)
+                .unwrap()
+                .to_vec()
+                .expose()
+                .len(),
+            64
+        );
+    }
+
+    #[test]
+    fn rsa_oaep_roundtrip() {
+        let keypair = RsaKeyPair::generate(1024).unwrap();
+        let public_key = keypair.public_key_b64().unwrap();
+        for hash in [OaepHash::Sha1, OaepHash::Sha256] {
+            let ciphertext = rsa_encrypt(&public_key, MESSAGE, hash).unwrap();
+            assert_eq!(
+                keypair.decrypt(&ciphertext, hash).unwrap().expose(),
+                MESSAGE
+            );
+        }
+        let ciphertext = rsa_encrypt(&public_key, MESSAGE, OaepHash::Sha1).unwrap();
+        assert!(keypair.decrypt(&ciphertext, OaepHash::Sha256).is_err());
+    }
+
+    fn xor_wrap(data_key: &[u8]) -> Result<Vec<u8>> {
+        Ok(data_key.iter().map(|b| b ^ 0x5a).collect())
+    }
+
+    #[test]
+    fn hybrid_envelope_roundtrip() {
+        let envelope = HybridEnvelope::seal(MESSAGE, xor_wrap).unwrap();
+        let bytes = envelope.to_bytes();
+        let opened = HybridEnvelope::from_bytes(&bytes)
+            .unwrap()
+            .open(|wrapped| xor_wrap(wrapped).map(SecretBytes::from))
+            .unwrap();
+        assert_eq!(opened.expose(), MESSAGE);
+    }
+
+    #[test]
+    fn hybrid_envelope_rejects_tampering() {
+        let mut bytes = HybridEnvelope::seal(MESSAGE, xor_wrap).unwrap().to_bytes();
+        *bytes.last_mut().unwrap() ^= 1;
+        let result = HybridEnvelope::from_bytes(&bytes)
+            .unwrap()
+            .open(|wrapped| xor_wrap(wrapped).map(SecretBytes::from));
+        assert!(matches!(
+            result,
+            Err(BwbioError::Crypto(CryptoError::Authentication))
+        ));
+        assert!(HybridEnvelope::from_bytes(&bytes[..20]).is_err());
+    }
+
+    #[test]
+    fn ecies_roundtrip() {
+        let recipient = SecretKey::<p256::NistP256>::from_slice(&[7; 32]).unwrap();
+        let recipient_point = recipient.public_key().to_encoded_point(false);
+        let data_key = [9; 32];
+        let wrapped = Ecies::wrap(EcCurve::P256, recipient_point.as_bytes(), &data_key).unwrap();
+        let agree = |ephemeral: &[u8]| {
+            let ephemeral = PublicKey::<p256::NistP256>::from_sec1_bytes(ephemeral)
+                .map_err(|_| CryptoError::InvalidPublicKey)?;
+            let shared = diffie_hellman(recipient.to_nonzero_scalar(), ephemeral.as_affine());
+            Ok(SecretBytes::from(
+                Sha256::digest(shared.raw_secret_bytes()).to_vec(),
+            ))
+        };
+        let unwrapped = Ecies::unwrap(EcCurve::P256, &wrapped, agree).unwrap();
+        assert_eq!(unwrapped.expose(), data_key);
+
+        let mut tampered = wrapped;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Ecies::unwrap(EcCurve::P256, &tampered, agree).is_err());
     }
 }
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::audit::AuditEntry;
+use crate::bio::biometrics_details;
+use crate::install::{DetectedBrowser, detect_browsers};
+use crate::kmgr::{KeyManager, StoredKey};
+use crate::log::format_utc;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Audit entries shown under "Recent activity".
+const RECENT_EVENTS: usize = 10;
+
+/// What the user asked for when leaving the dashboard. Anything that prompts runs outside of
+/// it, with the dialoguer menus, and comes back to a fresh dashboard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DashboardAction {
+    /// The classic management menu.
+    Menu,
+    Import,
+    Delete(String),
+    Exit,
+}
+
+/// Everything the dashboard shows, read once per refresh.
+struct Snapshot {
+    protection: String,
+    biometrics: String,
+    keys: Result<Vec<StoredKey>, String>,
+    browsers: Vec<DetectedBrowser>,
+    events: Vec<AuditEntry>,
+}
+
+impl Snapshot {
+    fn load(kmgr: &KeyManager) -> Self {
+        let mut events = kmgr.audit_log().entries().unwrap_or_default();
+        events.reverse();
+        events.truncate(RECENT_EVENTS);
+        Self {
+            protection: kmgr.protection(),
+            biometrics: biometrics_details().status.to_string(),
+            keys: kmgr.list_keys().map_err(|e| e.to_string()),
+            // Browsers that aren't installed only matter when something is registered for them.
+            browsers: detect_browsers()
+                .into_iter()
+                .filter(|b| b.installed.is_some() || b.target.is_some())
+                .collect(),
+            events,
+        }
+    }
+
+    fn keys(&self) -> &[StoredKey] {
+        self.keys.as_deref().unwrap_or_default()
+    }
+}
+
+/// Shows stored keys, Windows Hello, browser registrations and recent audit events until a key
+/// asks for something else. Fails when the console can't be switched to full screen, in which
+/// case the menus are the fallback.
+pub fn run(kmgr: &KeyManager) -> io::Result<DashboardAction> {
+    let mut terminal = ratatui::try_init()?;
+    let action = event_loop(&mut terminal, kmgr);
+    ratatui::restore();
+    action
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, kmgr: &KeyManager) -> io::Result<DashboardAction> {
+    let mut snapshot = Snapshot::load(kmgr);
+    let mut selected = ListState::default().with_selected(Some(0));
+    loop {
+        terminal.draw(|frame| draw(frame, &snapshot, &mut selected))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => selected.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => selected.select_next(),
+            KeyCode::Char('r') => snapshot = Snapshot::load(kmgr),
+            KeyCode::Char('i') => return Ok(DashboardAction::Import),
+            KeyCode::Char('d') | KeyCode::Delete => {
+                if let Some(key) = selected.selected().and_then(|i| snapshot.keys().get(i)) {
+                    return Ok(DashboardAction::Delete(key.user_id.clone()));
+                }
+            }
+            KeyCode::Char('m') | KeyCode::Enter => return Ok(DashboardAction::Menu),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(DashboardAction::Exit),
+            _ => {}
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, snapshot: &Snapshot, selected: &mut ListState) {
+    let [header, main, bottom, footer] = Layout::vertical([
+        Constraint::Length(2),
+        Constraint::Min(6),
+        Constraint::Length(RECENT_EVENTS as u16 + 2),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [keys_area, details_area] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(main);
+    let [browsers_area, events_area] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(bottom);
+
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(format!("bwbio {}", env!("CARGO_PKG_VERSION")).bold()),
+            Line::from(format!(
+                "Keys protected by: {}   Windows Hello: {}",
+                snapshot.protection, snapshot.biometrics
+            )),
+        ]),
+        header,
+    );
+
+    let keys_block = Block::bordered().title(" Stored keys ");
+    match &snapshot.keys {
+        Ok(keys) if keys.is_empty() => frame.render_widget(
+            Paragraph::new("No keys stored; press i to import one.").block(keys_block),
+            keys_area,
+        ),
+        Ok(keys) => frame.render_stateful_widget(
+            List::new(keys.iter().map(|k| k.user_id.as_str()))
+                .block(keys_block)
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+                .highlight_symbol("> "),
+            keys_area,
+            selected,
+        ),
+        Err(e) => frame.render_widget(
+            Paragraph::new(format!("Failed to list keys: {e}"))
+                .wrap(Wrap { trim: true })
+                .block(keys_block),
+            keys_area,
+        ),
+    }
+
+    let details = selected
+        .selected()
+        .and_then(|i| snapshot.keys().get(i))
+        .map(key_details)
+        .unwrap_or_default();
+    frame.render_widget(
+        Paragraph::new(details)
+            .wrap(Wrap { trim: true })
+            .block(Block::bordered().title(" Details ")),
+        details_area,
+    );
+
+    let browsers = snapshot
+        .browsers
+        .iter()
+        .map(|b| match &b.target {
+            Some(target) => Line::from(format!("{}: {target}", b.name)),
+            None => Line::from(format!("{}: not registered", b.name)),
+        })
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        Paragraph::new(browsers)
+            .wrap(Wrap { trim: true })
+            .block(Block::bordered().title(" Browsers ")),
+        browsers_area,
+    );
+
+    let events = snapshot
+        .events
+        .iter()
+        .map(|e| {
+            let time = format_utc(UNIX_EPOCH + Duration::from_secs(e.time));
+            let error = e
+                .error
+                .as_deref()
+                .map(|e| format!(": {e}"))
+                .unwrap_or_default();
+            Line::from(format!(
+                "{time}  {} {} ({}){error}",
+                e.action, e.user_id, e.source
+            ))
+        })
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        Paragraph::new(events).block(Block::bordered().title(" Recent activity ")),
+        events_area,
+    );
+
+    frame.render_widget(
+        Paragraph::new("↑/↓ select   i import   d delete   r refresh   m menu   q quit").dim(),
+        footer,
+    );
+}
+
+fn key_details(key: &StoredKey) -> Vec<Line<'static>> {
+    let time = |secs: u64| format_utc(UNIX_EPOCH + Duration::from_secs(secs));
+    let metadata = &key.metadata;
+    let mut lines = vec![Line::from(key.user_id.clone().bold())];
+    if let Some(email) = &metadata.email {
+        lines.push(Line::from(format!("Email: {email}")));
+    }
+    if let Some(server_url) = &metadata.server_url {
+        lines.push(Line::from(format!("Server: {server_url}")));
+    }
+    if let Some(imported_at) = metadata.imported_at {
+        lines.push(Line::from(format!("Imported: {}", time(imported_at))));
+    }
+    lines.push(Line::from(format!(
+        "Last used: {}",
+        metadata
+            .last_exported_at
+            .map(time)
+            .unwrap_or("never".into())
+    )));
+    if let Some(days) = metadata.expire_after_days {
+        let action = if metadata.delete_when_expired {
+            "Deleted"
+        } else {
+            "Refused"
+        };
+        lines.push(Line::from(format!("{action} after {days} unused days")));
+    }
+    lines
+}
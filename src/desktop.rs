@@ -0,0 +1,309 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::crypto::{
+    Aes256CbcHmacKey, Kdf, SecretBytes, SecretString, base64_decode, base64_encode,
+    derive_master_key, stretch_master_key,
+};
+use crate::error::Result;
+use crate::proto::{EncString, EncType};
+use serde_json::{Value, from_slice, json};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fs::read,
+    io,
+    path::{Path, PathBuf},
+    ptr::null_mut,
+    slice,
+};
+use thiserror::Error;
+use windows::{
+    Security::{
+        Credentials::{KeyCredentialManager, KeyCredentialStatus},
+        Cryptography::CryptographicBuffer,
+    },
+    Win32::{
+        Foundation::ERROR_NOT_FOUND,
+        Security::Credentials::{
+            CRED_TYPE_GENERIC, CREDENTIALW, CredEnumerateW, CredFree, CredReadW,
+        },
+    },
+    core::{Array, HSTRING},
+};
+
+/// Credential Manager service under which Bitwarden desktop keeps biometric unlock keys, as
+/// `Bitwarden_biometric/<userId>_user_biometric`.
+const BIOMETRIC_SERVICE: &str = "Bitwarden_biometric";
+const BIOMETRIC_ACCOUNT_SUFFIX: &str = "_user_biometric";
+/// Windows Hello key credential whose signatures Bitwarden desktop derives its biometric keys from.
+const BIOMETRIC_KEY_CREDENTIAL: &str = "Bitwarden";
+
+#[derive(Debug, Error)]
+pub enum DesktopError {
+    /// Credential Manager or Windows Hello refused.
+    #[error(transparent)]
+    Os(#[from] windows::core::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Invalid data.json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Wrong master password or unreadable user key")]
+    WrongMasterPassword,
+    #[error("Bitwarden desktop has no biometric unlock key for {0}")]
+    NoBiometricKey(String),
+    #[error("Bitwarden desktop's Windows Hello credential is not available")]
+    CredentialUnavailable,
+    #[error("Windows Hello verification failed")]
+    VerificationFailed,
+    #[error("Unrecognized biometric key format for {0}")]
+    UnrecognizedKeyFormat(String),
+    #[error(
+        "Couldn't decrypt the biometric key for {0}; if \"require password on app start\" is on, import with the master password instead"
+    )]
+    BiometricKeyUndecryptable(String),
+}
+
+/// Location of the Bitwarden desktop app's state file for the current user.
+pub fn default_data_path() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(|p| PathBuf::from(p).join("Bitwarden").join("data.json"))
+}
+
+/// An account logged into Bitwarden desktop, with what is needed to recover its user key.
+pub struct DesktopAccount {
+    user_id: String,
+    email: String,
+    server_url: Option<String>,
+    kdf: Kdf,
+    encrypted_user_key: EncString,
+}
+
+impl DesktopAccount {
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    /// Web vault the account is logged into, if desktop recorded one.
+    pub fn server_url(&self) -> Option<&str> {
+        self.server_url.as_deref()
+    }
+
+    /// Derives the master key from `master_password` and decrypts the user key with it.
+    pub fn decrypt_user_key(&self, master_password: &str) -> Result<SecretString> {
+        let master_key = derive_master_key(master_password, &self.email, self.kdf)?;
+        let user_key = stretch_master_key(&master_key)?
+            .decrypt(&self.encrypted_user_key)
+            .map(SecretBytes::from)
+            .map_err(|_| DesktopError::WrongMasterPassword)?;
+        Ok(SecretString::from(base64_encode(user_key.expose())))
+    }
+}
+
+fn parse_kdf(
+    kdf_type: Option<u64>,
+    iterations: Option<u64>,
+    memory: Option<u64>,
+    parallelism: Option<u64>,
+) -> Option<Kdf> {
+    let iterations = u32::try_from(iterations?).ok()?;
+    match kdf_type? {
+        0 => Some(Kdf::Pbkdf2 { iterations }),
+        1 => Some(Kdf::Argon2id {
+            iterations,
+            memory_mib: u32::try_from(memory?).ok()?,
+            parallelism: u32::try_from(parallelism?).ok()?,
+        }),
+        _ => None,
+    }
+}
+
+/// User ids with biometric unlock enabled in Bitwarden desktop.
+pub fn biometric_user_ids() -> Result<Vec<String>> {
+    let filter = HSTRING::from(format!("{BIOMETRIC_SERVICE}/*"));
+    let mut count = 0u32;
+    let mut credentials: *mut *mut CREDENTIALW = null_mut();
+    unsafe {
+        match CredEnumerateW(&filter, None, &mut count, &mut credentials) {
+            Ok(_) => {}
+            Err(e) if e.code() == ERROR_NOT_FOUND.to_hresult() => return Ok(Vec::new()),
+            Err(e) => return Err(DesktopError::from(e).into()),
+        }
+        let user_ids = slice::from_raw_parts(credentials, count as usize)
+            .iter()
+            .filter_map(|&c| (*c).TargetName.to_string().ok())
+            .filter_map(|target| {
+                target
+                    .strip_prefix(BIOMETRIC_SERVICE)?
+                    .strip_prefix('/')?
+                    .strip_suffix(BIOMETRIC_ACCOUNT_SUFFIX)
+                    .map(str::to_string)
+            })
+            .collect();
+        CredFree(credentials as *const _);
+        Ok(user_ids)
+    }
+}
+
+/// Bitwarden desktop writes credential blobs as UTF-16; other writers use UTF-8.
+fn read_biometric_credential(user_id: &str) -> Result<SecretString> {
+    let target = HSTRING::from(format!(
+        "{BIOMETRIC_SERVICE}/{user_id}{BIOMETRIC_ACCOUNT_SUFFIX}"
+    ));
+    let mut credential: *mut CREDENTIALW = null_mut();
+    unsafe {
+        match CredReadW(&target, CRED_TYPE_GENERIC, None, &mut credential) {
+            Ok(_) => {}
+            Err(e) if e.code() == ERROR_NOT_FOUND.to_hresult() => {
+                return Err(DesktopError::NoBiometricKey(user_id.to_string()).into());
+            }
+            Err(e) => return Err(DesktopError::from(e).into()),
+        }
+        let c = &*credential;
+        let blob = SecretBytes::from(
+            slice::from_raw_parts(c.CredentialBlob, c.CredentialBlobSize as usize).to_vec(),
+        );
+        CredFree(credential as *const _);
+        // The stored values are ASCII, so UTF-16 shows up as every second byte being zero.
+        let bytes = blob.expose();
+        if bytes.len() % 2 == 0 && bytes.iter().skip(1).step_by(2).all(|&b| b == 0) {
+            let ascii = bytes.iter().step_by(2).copied().collect::<Vec<_>>();
+            SecretBytes::from(ascii).into_secret_string()
+        } else {
+            blob.into_secret_string()
+        }
+    }
+}
+
+/// Signs `challenge` with Bitwarden desktop's Windows Hello key credential (prompting the
+/// user) and hashes the signature into the key material desktop encrypts with.
+fn derive_os_key_part(challenge: &[u8]) -> Result<String, DesktopError> {
+    let retrieval =
+        KeyCredentialManager::OpenAsync(&HSTRING::from(BIOMETRIC_KEY_CREDENTIAL))?.get()?;
+    if retrieval.Status()? != KeyCredentialStatus::Success {
+        return Err(DesktopError::CredentialUnavailable);
+    }
+    let challenge = CryptographicBuffer::CreateFromByteArray(challenge)?;
+    let operation = retrieval.Credential()?.RequestSignAsync(&challenge)?;
+    let signature = operation.get()?;
+    if signature.Status()? != KeyCredentialStatus::Success {
+        return Err(DesktopError::VerificationFailed);
+    }
+    let mut signature_bytes = Array::<u8>::new();
+    CryptographicBuffer::CopyToByteArray(&signature.Result()?, &mut signature_bytes)?;
+    Ok(base64_encode(&Sha256::digest(&*signature_bytes)))
+}
+
+/// The user key Bitwarden desktop stored for biometric unlock (Require Windows Hello). Keys
+/// protected with "require password on app start" also need a client key half that only the
+/// desktop app holds and can't be migrated this way.
+pub fn read_biometric_key(user_id: &str) -> Result<SecretString> {
+    let stored = read_biometric_credential(user_id)?;
+    // Older desktop releases stored the key itself.
+    let Ok(enc_str) = stored.expose().parse::<EncString>() else {
+        base64_decode(stored.expose())
+            .map_err(|_| DesktopError::UnrecognizedKeyFormat(user_id.to_string()))?;
+        return Ok(stored);
+    };
+    if enc_str.enc_type()? != EncType::AesCbc256B64 {
+        return Err(DesktopError::UnrecognizedKeyFormat(user_id.to_string()).into());
+    }
+    let os_key_part = SecretString::from(derive_os_key_part(&enc_str.iv()?)?);
+    let mut key = SecretBytes::from(vec![0u8; 64]);
+    key.expose_mut()[..32].copy_from_slice(&Sha256::digest(os_key_part.expose().as_bytes()));
+    Aes256CbcHmacKey::from_slice(key.expose())?
+        .decrypt(&enc_str)
+        .map(SecretBytes::from)
+        .map_err(|_| DesktopError::BiometricKeyUndecryptable(user_id.to_string()))?
+        .into_secret_string()
+}
+
+/// Server URL from a desktop environment entry: the self-hosted base URL, or the cloud vault for
+/// the `US`/`EU` regions.
+fn parse_server_url(environment: &Value) -> Option<String> {
+    let urls = &environment["urls"];
+    if let Some(url) = urls["base"]
+        .as_str()
+        .or(urls["webVault"].as_str())
+        .filter(|url| !url.is_empty())
+    {
+        return Some(url.trim_end_matches('/').to_string());
+    }
+    match environment["region"].as_str()? {
+        "US" => Some("https://vault.bitwarden.com".to_string()),
+        "EU" => Some("https://vault.bitwarden.eu".to_string()),
+        _ => None,
+    }
+}
+
+/// Reads every account with a master-password-protected user key from a desktop `data.json`.
+pub fn read_accounts(path: &Path) -> Result<Vec<DesktopAccount>> {
+    let data = read(path)
+        .map_err(DesktopError::from)
+        .and_then(|data| Ok(from_slice::<Value>(&data)?))?;
+    let mut accounts = Vec::new();
+
+    if let Some(global) = data
+        .get("global_account_accounts")
+        .and_then(Value::as_object)
+    {
+        // Current layout: flat `global_*` and `user_<id>_*` state entries.
+        for (user_id, info) in global {
+            let kdf_config = &data[format!("user_{user_id}_kdfConfig_kdfConfig")];
+            let kdf = parse_kdf(
+                kdf_config["kdfType"].as_u64(),
+                kdf_config["iterations"].as_u64(),
+                kdf_config["memory"].as_u64(),
+                kdf_config["parallelism"].as_u64(),
+            );
+            let user_key = data[format!("user_{user_id}_masterPassword_masterKeyEncryptedUserKey")]
+                .as_str()
+                .and_then(|s| s.parse().ok());
+            if let (Some(email), Some(kdf), Some(encrypted_user_key)) =
+                (info["email"].as_str(), kdf, user_key)
+            {
+                accounts.push(DesktopAccount {
+                    user_id: user_id.clone(),
+                    email: email.to_string(),
+                    server_url: parse_server_url(
+                        &data[format!("user_{user_id}_environment_environment")],
+                    ),
+                    kdf,
+                    encrypted_user_key,
+                });
+            }
+        }
+    } else if let Some(user_ids) = data.get("authenticatedAccounts").and_then(Value::as_array) {
+        // Legacy layout: one object per user id holding `profile` and `keys`.
+        for user_id in user_ids.iter().filter_map(Value::as_str) {
+            let profile = &data[user_id]["profile"];
+            let kdf = parse_kdf(
+                profile["kdfType"].as_u64(),
+                profile["kdfIterations"].as_u64(),
+                profile["kdfMemory"].as_u64(),
+                profile["kdfParallelism"].as_u64(),
+            );
+            let user_key = data[user_id]["keys"]["masterKeyEncryptedUserKey"]
+                .as_str()
+                .and_then(|s| s.parse().ok());
+            if let (Some(email), Some(kdf), Some(encrypted_user_key)) =
+                (profile["email"].as_str(), kdf, user_key)
+            {
+                accounts.push(DesktopAccount {
+                    user_id: user_id.to_string(),
+                    email: email.to_string(),
+                    server_url: parse_server_url(&json!({
+                        "urls": data[user_id]["settings"]["environmentUrls"]
+                    })),
+                    kdf,
+                    encrypted_user_key,
+                });
+            }
+        }
+    }
+
+    Ok(accounts)
+}
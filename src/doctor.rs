@@ -0,0 +1,313 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::bio::biometrics_details;
+use crate::cng::{CngProvider, CngProviderKind};
+use crate::config::config;
+use crate::crypto::self_test;
+use crate::install::{
+    EXE_NAME, NATIVE_MESSAGING_HOSTS, NativeMessagingHost, default_install_dir, desktop_app_path,
+    desktop_conflicts, recorded_exe_hash, registered_manifest, sha256_hex,
+};
+use crate::keystore::open_key_store;
+use crate::kmgr::{KeyManager, active_cng_key_name, machine_key_selected};
+use crate::proto::BiometricsStatus;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    env,
+    fmt::{self, Display, Formatter},
+    fs::{create_dir_all, read, read_to_string, remove_file, write},
+    path::{Path, PathBuf},
+};
+use windows_strings::HSTRING;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but not the way bwbio is meant to be set up.
+    Warn,
+    Fail,
+}
+
+impl Display for CheckStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+        })
+    }
+}
+
+/// One line of the `doctor` report.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.name, self.detail)
+    }
+}
+
+/// Checks everything an unlock depends on. Nothing is created or changed, apart from a probe
+/// file in the key directory that is removed again.
+pub fn run(kmgr: &KeyManager, key_name: HSTRING, key_dir: &Path) -> Vec<Check> {
+    let mut checks = vec![match self_test() {
+        Ok(_) => Check::new("Crypto self-test", CheckStatus::Pass, "passed"),
+        Err(e) => Check::new("Crypto self-test", CheckStatus::Fail, e.to_string()),
+    }];
+    checks.extend(key_protection(key_name, key_dir));
+    checks.push(windows_hello());
+    checks.extend(
+        biometrics_details()
+            .notes()
+            .into_iter()
+            .map(|note| Check::new("Windows Hello", CheckStatus::Warn, note)),
+    );
+    checks.push(key_directory(key_dir));
+    checks.push(match kmgr.list_keys() {
+        Ok(keys) if keys.is_empty() => Check::new(
+            "Stored keys",
+            CheckStatus::Warn,
+            "none; import a key before unlocking",
+        ),
+        Ok(keys) => Check::new("Stored keys", CheckStatus::Pass, format!("{}", keys.len())),
+        Err(e) => Check::new("Stored keys", CheckStatus::Fail, e.to_string()),
+    });
+    checks.push(installed_executable());
+    checks.push(desktop_app());
+    // Browsers that aren't installed only matter when something is registered for them.
+    checks.extend(
+        NATIVE_MESSAGING_HOSTS
+            .iter()
+            .filter(|host| host.installed().is_some() || registered_manifest(host).is_some())
+            .map(manifest_registration),
+    );
+    checks
+}
+
+/// TPM, key storage provider and CNG key; looked up without opening the key, which would create
+/// it when missing.
+fn key_protection(key_name: HSTRING, key_dir: &Path) -> Vec<Check> {
+    let mut checks = vec![if CngProvider::tpm_available() {
+        Check::new(
+            "TPM",
+            CheckStatus::Pass,
+            "Platform Crypto Provider available",
+        )
+    } else {
+        Check::new(
+            "TPM",
+            CheckStatus::Warn,
+            if config().allow_software_ksp() {
+                "not available; keys use the software key provider (allow_software_ksp)"
+            } else {
+                "not available; the software key provider needs allow_software_ksp"
+            },
+        )
+    }];
+    let provider = match CngProvider::new() {
+        Ok(p) if machine_key_selected() => p.machine_scope(),
+        Ok(p) => p,
+        Err(e) => {
+            checks.push(Check::new(
+                "Key storage provider",
+                CheckStatus::Fail,
+                e.to_string(),
+            ));
+            return checks;
+        }
+    };
+    checks.push(Check::new(
+        "Key storage provider",
+        if provider.kind() == CngProviderKind::Software {
+            CheckStatus::Warn
+        } else {
+            CheckStatus::Pass
+        },
+        provider.kind().to_string(),
+    ));
+    let store = match open_key_store(key_dir.to_path_buf()) {
+        Ok(store) => store,
+        Err(e) => {
+            checks.push(Check::new("Key store", CheckStatus::Fail, e.to_string()));
+            return checks;
+        }
+    };
+    let key_name = active_cng_key_name(&*store, key_name);
+    checks.push(match provider.enum_keys() {
+        Ok(keys) if keys.iter().any(|k| k.name == key_name.to_string_lossy()) => {
+            Check::new("CNG key", CheckStatus::Pass, format!("{key_name} present"))
+        }
+        Ok(_) => Check::new(
+            "CNG key",
+            CheckStatus::Warn,
+            format!("{key_name} missing; stored keys can't be unwrapped until it is restored"),
+        ),
+        Err(e) => Check::new("CNG key", CheckStatus::Fail, e.to_string()),
+    });
+    checks
+}
+
+fn windows_hello() -> Check {
+    match biometrics_details().status {
+        BiometricsStatus::Available => Check::new("Windows Hello", CheckStatus::Pass, "available"),
+        status => Check::new("Windows Hello", CheckStatus::Fail, status.to_string()),
+    }
+}
+
+/// Whether the key directory (which also holds the lock file and audit log whatever
+/// `BW_KEY_STORE` says) can be written.
+fn key_directory(key_dir: &Path) -> Check {
+    let probe = key_dir.join(".doctor");
+    let writable = create_dir_all(key_dir)
+        .and_then(|_| write(&probe, b""))
+        .and_then(|_| remove_file(&probe));
+    match writable {
+        Ok(_) => Check::new(
+            "Key directory",
+            CheckStatus::Pass,
+            format!("{} is writable", key_dir.display()),
+        ),
+        Err(e) => Check::new(
+            "Key directory",
+            CheckStatus::Fail,
+            format!("{}: {e}", key_dir.display()),
+        ),
+    }
+}
+
+/// Whether the installed executable still has the hash recorded when it was installed or
+/// updated.
+fn installed_executable() -> Check {
+    let name = "Installed executable";
+    let exe = match default_install_dir() {
+        Ok(dir) => dir.join(EXE_NAME),
+        Err(e) => return Check::new(name, CheckStatus::Warn, e.to_string()),
+    };
+    if !exe.is_file() {
+        return Check::new(
+            name,
+            CheckStatus::Warn,
+            format!("{} doesn't exist", exe.display()),
+        );
+    }
+    let Some(recorded) = recorded_exe_hash() else {
+        return Check::new(
+            name,
+            CheckStatus::Warn,
+            "no hash recorded; reinstall bwbio to record one",
+        );
+    };
+    match read(&exe) {
+        Ok(bytes) if sha256_hex(&bytes) == recorded => Check::new(
+            name,
+            CheckStatus::Pass,
+            format!("{} matches the hash recorded at install", exe.display()),
+        ),
+        Ok(_) => Check::new(
+            name,
+            CheckStatus::Fail,
+            format!(
+                "{} changed since it was installed; it may be damaged or tampered with, reinstall bwbio",
+                exe.display()
+            ),
+        ),
+        Err(e) => Check::new(name, CheckStatus::Fail, format!("{}: {e}", exe.display())),
+    }
+}
+
+/// Whether Bitwarden desktop's native messaging host is registered for an installed browser,
+/// which then starts the desktop app instead of bwbio.
+fn desktop_app() -> Check {
+    let conflicts = desktop_conflicts();
+    if !conflicts.is_empty() {
+        let ids = conflicts.iter().map(|h| h.id).collect::<Vec<_>>().join(",");
+        return Check::new(
+            "Bitwarden desktop",
+            CheckStatus::Warn,
+            format!(
+                "registered for {ids}, which start it instead of bwbio; `bwbio register --browser {ids}` takes over"
+            ),
+        );
+    }
+    match desktop_app_path() {
+        Some(exe) => Check::new(
+            "Bitwarden desktop",
+            CheckStatus::Pass,
+            format!(
+                "installed at {}, not registered for any browser",
+                exe.display()
+            ),
+        ),
+        None => Check::new("Bitwarden desktop", CheckStatus::Pass, "not installed"),
+    }
+}
+
+/// Whether the browser finds a manifest at its registry key, and the manifest points at an
+/// existing bwbio executable.
+fn manifest_registration(host: &NativeMessagingHost) -> Check {
+    let name = format!("{} manifest", host.browser);
+    let Some(manifest_path) = registered_manifest(host) else {
+        return Check::new(name, CheckStatus::Warn, "not registered");
+    };
+    let manifest_path = manifest_path.display().to_string();
+    let manifest = match read_to_string(&manifest_path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| serde_json::from_str::<Value>(&s).map_err(|e| e.to_string()))
+    {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            return Check::new(
+                name,
+                CheckStatus::Fail,
+                format!("{manifest_path} can't be read: {e}"),
+            );
+        }
+    };
+    let Some(exe) = manifest["path"].as_str().map(PathBuf::from) else {
+        return Check::new(
+            name,
+            CheckStatus::Fail,
+            format!("{manifest_path} has no executable path"),
+        );
+    };
+    if !exe.is_file() {
+        return Check::new(
+            name,
+            CheckStatus::Fail,
+            format!(
+                "{manifest_path} points at {}, which doesn't exist",
+                exe.display()
+            ),
+        );
+    }
+    let current = env::current_exe().ok().and_then(|p| p.canonicalize().ok());
+    if exe.canonicalize().ok() != current {
+        return Check::new(
+            name,
+            CheckStatus::Warn,
+            format!(
+                "{manifest_path} points at another executable: {}",
+                exe.display()
+            ),
+        );
+    }
+    Check::new(name, CheckStatus::Pass, manifest_path)
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::browser::HostError;
+#[cfg(windows)]
+use crate::cng::CngError;
+use crate::config::ConfigError;
+use crate::crypto::CryptoError;
+#[cfg(windows)]
+use crate::desktop::DesktopError;
+use crate::keystore::StoreError;
+use crate::kmgr::KeyManagerError;
+use crate::presence::PresenceError;
+use crate::proto::ProtoError;
+#[cfg(target_os = "linux")]
+use crate::tpm2::Tpm2Error;
+#[cfg(target_os = "linux")]
+use crate::xdg::XdgError;
+use std::io;
+use thiserror::Error;
+
+/// Error of the library APIs, by the part of bwbio it comes from, so callers can match on the
+/// cause rather than its message. Only the front ends (command line, TUI, installer and updater)
+/// and the helpers only they use work with `anyhow` errors.
+#[derive(Debug, Error)]
+pub enum BwbioError {
+    #[cfg(windows)]
+    #[error(transparent)]
+    Cng(#[from] CngError),
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error(transparent)]
+    Proto(#[from] ProtoError),
+    /// The key store, key files and audit log.
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Bio(#[from] PresenceError),
+    #[error(transparent)]
+    Key(#[from] KeyManagerError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    /// The native messaging host.
+    #[error(transparent)]
+    Host(#[from] HostError),
+    /// Keys and accounts read from Bitwarden desktop.
+    #[cfg(windows)]
+    #[error(transparent)]
+    Desktop(#[from] DesktopError),
+    #[cfg(target_os = "linux")]
+    #[error(transparent)]
+    Tpm2(#[from] Tpm2Error),
+    /// Browser registration on Linux.
+    #[cfg(target_os = "linux")]
+    #[error(transparent)]
+    Xdg(#[from] XdgError),
+}
+
+pub type Result<T, E = BwbioError> = std::result::Result<T, E>;
+
+impl BwbioError {
+    /// Whether the user failed or dismissed a prompt: bwbio's own Windows Hello check, or the
+    /// KSP's for a gesture-bound key.
+    pub fn is_auth_failure(&self) -> bool {
+        match self {
+            BwbioError::Bio(PresenceError::Failed) => true,
+            #[cfg(windows)]
+            BwbioError::Bio(PresenceError::TimedOut(_)) => true,
+            #[cfg(windows)]
+            BwbioError::Cng(e) => e.is_cancelled(),
+            _ => false,
+        }
+    }
+}
+
+/// Plain Windows errors are NCrypt's; modules calling other Windows APIs wrap them in their own
+/// error first.
+#[cfg(windows)]
+impl From<windows::core::Error> for BwbioError {
+    fn from(e: windows::core::Error) -> Self {
+        BwbioError::Cng(CngError::Os(e))
+    }
+}
+
+/// Files bwbio keeps for itself (key files, lock, audit log, log, backups) belong to the key store.
+impl From<io::Error> for BwbioError {
+    fn from(e: io::Error) -> Self {
+        BwbioError::Store(StoreError::Io(e))
+    }
+}
+
+impl From<serde_json::Error> for BwbioError {
+    fn from(e: serde_json::Error) -> Self {
+        BwbioError::Store(StoreError::Json(e))
+    }
+}
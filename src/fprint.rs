@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::presence::BiometricVerifier;
+use crate::proto::BiometricsStatus;
+use std::{env, process::Command};
+use tracing::warn;
+
+/// Fingerprint matching through fprintd's command line tools. The reader has no screen of its
+/// own, so the prompt's reason is shown as a desktop notification when `notify-send` exists.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fprintd;
+
+/// The user bwbio runs as, whose enrolled fingers are matched.
+fn user_name() -> Option<String> {
+    env::var("USER").ok().filter(|user| !user.is_empty())
+}
+
+/// Returns true when bwbio was started over SSH, where nobody can touch the local reader.
+pub fn is_remote_session() -> bool {
+    env::var_os("SSH_CONNECTION").is_some()
+}
+
+impl BiometricVerifier for Fprintd {
+    fn status(&self) -> BiometricsStatus {
+        if is_remote_session() {
+            return BiometricsStatus::RemoteSession;
+        }
+        let Some(user) = user_name() else {
+            return BiometricsStatus::PlatformUnsupported;
+        };
+        let Ok(output) = Command::new("fprintd-list").arg(&user).output() else {
+            return BiometricsStatus::PlatformUnsupported;
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() || stdout.contains("No devices available") {
+            BiometricsStatus::HardwareUnavailable
+        } else if stdout.contains("has no fingers enrolled") {
+            BiometricsStatus::NotEnrolled
+        } else {
+            BiometricsStatus::Available
+        }
+    }
+
+    fn authenticate(&self, reason: &str) -> bool {
+        let _ = Command::new("notify-send")
+            .args(["--app-name=bwbio", reason, "Touch the fingerprint reader"])
+            .status();
+        match Command::new("fprintd-verify").output() {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).contains("verify-match"),
+            Err(e) => {
+                warn!("Failed to run fprintd-verify: {e}");
+                false
+            }
+        }
+    }
+}
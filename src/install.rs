@@ -0,0 +1,1040 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::browser::{CHROMIUM_EXTENSION_ORIGINS, FIREFOX_EXTENSION_ID};
+use crate::cng::CngProvider;
+use crate::config::{Config, config};
+use crate::keystore::open_key_store;
+use crate::kmgr::{active_cng_key_name, delete_cng_key, machine_key_selected};
+use crate::progress::Progress;
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Serialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fmt::{self, Display, Formatter},
+    fs::{
+        canonicalize, copy, create_dir_all, read, read_dir, read_to_string, remove_dir_all,
+        remove_file, rename, write,
+    },
+    io::ErrorKind,
+    os::windows::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    str::FromStr,
+};
+use thiserror::Error;
+use tracing::info;
+use windows::Win32::Foundation::{CloseHandle, E_ACCESSDENIED};
+use windows::Win32::Storage::FileSystem::{MOVEFILE_DELAY_UNTIL_REBOOT, MoveFileExW};
+use windows::Win32::System::Com::{
+    CLSCTX_INPROC_SERVER, COINIT_APARTMENTTHREADED, CoCreateInstance, CoInitializeEx,
+    CoUninitialize, IPersistFile,
+};
+use windows::Win32::System::Threading::{
+    CREATE_NO_WINDOW, GetExitCodeProcess, INFINITE, WaitForSingleObject,
+};
+use windows::Win32::UI::Shell::{
+    IShellLinkW, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW, ShellExecuteExW, ShellLink,
+};
+use windows::Win32::UI::WindowsAndMessaging::SW_HIDE;
+use windows::core::{Interface, PCWSTR, w};
+use windows_registry::{CURRENT_USER, Key, LOCAL_MACHINE};
+use windows_strings::HSTRING;
+
+pub const MANIFEST_NAME: &str = "chrome.json";
+pub const FIREFOX_MANIFEST_NAME: &str = "firefox.json";
+pub const EXE_NAME: &str = "bwbio.exe";
+
+/// Browsers sharing a native messaging manifest format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowserFamily {
+    #[default]
+    Chromium,
+    Firefox,
+}
+
+impl BrowserFamily {
+    /// File name of the manifest written next to the installed executable.
+    pub fn manifest_name(self) -> &'static str {
+        match self {
+            BrowserFamily::Chromium => MANIFEST_NAME,
+            BrowserFamily::Firefox => FIREFOX_MANIFEST_NAME,
+        }
+    }
+}
+
+impl FromStr for BrowserFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "chromium" | "chrome" | "edge" => Ok(BrowserFamily::Chromium),
+            "firefox" => Ok(BrowserFamily::Firefox),
+            _ => Err(anyhow!(
+                "Unknown browser family {s:?}; expected chromium or firefox"
+            )),
+        }
+    }
+}
+
+/// A browser that loads native messaging hosts from a manifest registered under `HKCU`.
+/// Browsers without a key of their own read Chrome's, so registering them registers Chrome too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NativeMessagingHost {
+    /// Name used by `--browser`.
+    pub id: &'static str,
+    pub browser: &'static str,
+    pub key_path: &'static str,
+    /// Executable name under `App Paths`, used to find out whether the browser is installed.
+    pub exe: &'static str,
+    pub family: BrowserFamily,
+}
+
+const CHROME_KEY_PATH: &str = "software\\google\\chrome\\nativemessaginghosts\\com.8bit.bitwarden";
+
+pub const NATIVE_MESSAGING_HOSTS: [NativeMessagingHost; 7] = [
+    NativeMessagingHost {
+        id: "chrome",
+        browser: "Google Chrome",
+        key_path: CHROME_KEY_PATH,
+        exe: "chrome.exe",
+        family: BrowserFamily::Chromium,
+    },
+    NativeMessagingHost {
+        id: "edge",
+        browser: "Microsoft Edge",
+        key_path: "software\\microsoft\\edge\\nativemessaginghosts\\com.8bit.bitwarden",
+        exe: "msedge.exe",
+        family: BrowserFamily::Chromium,
+    },
+    NativeMessagingHost {
+        id: "brave",
+        browser: "Brave",
+        key_path: "software\\bravesoftware\\brave-browser\\nativemessaginghosts\\com.8bit.bitwarden",
+        exe: "brave.exe",
+        family: BrowserFamily::Chromium,
+    },
+    NativeMessagingHost {
+        id: "vivaldi",
+        browser: "Vivaldi",
+        key_path: CHROME_KEY_PATH,
+        exe: "vivaldi.exe",
+        family: BrowserFamily::Chromium,
+    },
+    NativeMessagingHost {
+        id: "opera",
+        browser: "Opera",
+        key_path: CHROME_KEY_PATH,
+        exe: "opera.exe",
+        family: BrowserFamily::Chromium,
+    },
+    NativeMessagingHost {
+        id: "chromium",
+        browser: "Chromium",
+        key_path: "software\\chromium\\nativemessaginghosts\\com.8bit.bitwarden",
+        exe: "chromium.exe",
+        family: BrowserFamily::Chromium,
+    },
+    NativeMessagingHost {
+        id: "firefox",
+        browser: "Mozilla Firefox",
+        key_path: "software\\mozilla\\nativemessaginghosts\\com.8bit.bitwarden",
+        exe: "firefox.exe",
+        family: BrowserFamily::Firefox,
+    },
+];
+
+impl NativeMessagingHost {
+    /// The browser executable from its `App Paths` entry, `None` when it isn't installed.
+    pub fn installed(&self) -> Option<PathBuf> {
+        let app_path = format!(
+            "software\\microsoft\\windows\\currentversion\\app paths\\{}",
+            self.exe
+        );
+        [CURRENT_USER, LOCAL_MACHINE]
+            .iter()
+            .find_map(|root| root.open(&app_path).and_then(|k| k.get_string("")).ok())
+            .map(|s| PathBuf::from(s.trim_matches('"')))
+    }
+}
+
+/// What a registered `com.8bit.bitwarden` manifest starts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind", content = "path")]
+pub enum ManifestTarget {
+    Bwbio(PathBuf),
+    /// The proxy shipped with the official Bitwarden desktop app.
+    BitwardenDesktop(PathBuf),
+    Other(PathBuf),
+    /// The manifest is missing, isn't JSON or has no `path`.
+    Unreadable,
+}
+
+impl Display for ManifestTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestTarget::Bwbio(path) => write!(f, "bwbio ({})", path.display()),
+            ManifestTarget::BitwardenDesktop(path) => {
+                write!(f, "Bitwarden desktop ({})", path.display())
+            }
+            ManifestTarget::Other(path) => write!(f, "unknown host {}", path.display()),
+            ManifestTarget::Unreadable => f.write_str("unreadable manifest"),
+        }
+    }
+}
+
+/// One browser as reported by `browsers`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DetectedBrowser {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub family: BrowserFamily,
+    /// The browser executable, `None` when it isn't installed.
+    pub installed: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub target: Option<ManifestTarget>,
+}
+
+/// Every browser in [`NATIVE_MESSAGING_HOSTS`] with its `com.8bit.bitwarden` registration,
+/// looked up per user first and machine-wide second, as the browsers do.
+pub fn detect_browsers() -> Vec<DetectedBrowser> {
+    NATIVE_MESSAGING_HOSTS
+        .iter()
+        .map(|host| {
+            let manifest = [CURRENT_USER, LOCAL_MACHINE]
+                .iter()
+                .find_map(|root| root.open(host.key_path).and_then(|k| k.get_string("")).ok())
+                .map(PathBuf::from);
+            DetectedBrowser {
+                id: host.id,
+                name: host.browser,
+                family: host.family,
+                installed: host.installed(),
+                target: manifest.as_deref().map(manifest_target),
+                manifest,
+            }
+        })
+        .collect()
+}
+
+/// The Bitwarden desktop executable, from a per-user or machine-wide install.
+pub fn desktop_app_path() -> Option<PathBuf> {
+    [("LOCALAPPDATA", "Programs"), ("ProgramFiles", "")]
+        .iter()
+        .filter_map(|(var, sub)| env::var_os(var).map(|dir| PathBuf::from(dir).join(sub)))
+        .map(|dir| dir.join("Bitwarden").join("Bitwarden.exe"))
+        .find(|exe| exe.is_file())
+}
+
+/// Installed browsers that start Bitwarden desktop instead of bwbio. Registering bwbio for
+/// them takes over; the desktop registration is backed up and comes back on unregister.
+pub fn desktop_conflicts() -> Vec<NativeMessagingHost> {
+    detect_browsers()
+        .into_iter()
+        .filter(|b| {
+            b.installed.is_some() && matches!(b.target, Some(ManifestTarget::BitwardenDesktop(_)))
+        })
+        .filter_map(|b| NATIVE_MESSAGING_HOSTS.into_iter().find(|h| h.id == b.id))
+        .collect()
+}
+
+fn manifest_target(manifest: &Path) -> ManifestTarget {
+    let Some(exe) = read_to_string(manifest)
+        .ok()
+        .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        .and_then(|m| m["path"].as_str().map(PathBuf::from))
+    else {
+        return ManifestTarget::Unreadable;
+    };
+    // Relative paths are relative to the manifest.
+    let exe = match manifest.parent() {
+        Some(dir) if exe.is_relative() => dir.join(exe),
+        _ => exe,
+    };
+    let file_name = exe
+        .file_name()
+        .map(|n| n.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let current = env::current_exe().ok().and_then(|p| p.canonicalize().ok());
+    if file_name == EXE_NAME || (current.is_some() && exe.canonicalize().ok() == current) {
+        ManifestTarget::Bwbio(exe)
+    } else if file_name == "desktop_proxy.exe" || file_name == "bitwarden.exe" {
+        ManifestTarget::BitwardenDesktop(exe)
+    } else {
+        ManifestTarget::Other(exe)
+    }
+}
+
+/// The directory recorded by the last install, or `%LOCALAPPDATA%\bwbio`.
+pub fn default_install_dir() -> Result<PathBuf> {
+    if let Some(dir) = &config().install_dir {
+        return Ok(dir.clone());
+    }
+    standard_install_dir()
+}
+
+/// `%LOCALAPPDATA%\bwbio`.
+pub fn standard_install_dir() -> Result<PathBuf> {
+    env::var_os("LOCALAPPDATA")
+        .map(|s| PathBuf::from(s).join("bwbio"))
+        .ok_or_else(|| anyhow!("LOCALAPPDATA not set. Cannot determine install path."))
+}
+
+/// `BW_KEY_DIR`, the configured `key_dir`, or the `keys` directory next to the installed
+/// executable.
+pub fn key_dir_for(install_dir: &Path) -> PathBuf {
+    config().key_dir(install_dir.join("keys"))
+}
+
+/// Browsers named in a comma separated list such as `chrome,edge`. When `list` is `None`: the
+/// configured `browsers`, else the installed browsers, else Chrome and Edge.
+pub fn select_browsers(list: Option<&str>) -> Result<Vec<NativeMessagingHost>> {
+    let configured = config().browsers.as_ref().map(|b| b.join(","));
+    let Some(list) = list.or(configured.as_deref()) else {
+        let installed = NATIVE_MESSAGING_HOSTS
+            .into_iter()
+            .filter(|host| host.installed().is_some())
+            .collect::<Vec<_>>();
+        if installed.is_empty() {
+            return Ok(NATIVE_MESSAGING_HOSTS[..2].to_vec());
+        }
+        return Ok(installed);
+    };
+    list.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            NATIVE_MESSAGING_HOSTS
+                .iter()
+                .find(|h| h.id.eq_ignore_ascii_case(id))
+                .copied()
+                .ok_or_else(|| {
+                    let known = NATIVE_MESSAGING_HOSTS.map(|h| h.id).join(", ");
+                    anyhow!("Unknown browser {id:?}; expected one of {known}")
+                })
+        })
+        .collect()
+}
+
+/// Records `hosts` as the configured `browsers`, so later registration and `unregister` use the
+/// same browsers.
+pub fn remember_browsers(hosts: &[NativeMessagingHost]) -> Result<()> {
+    let ids = hosts.iter().map(|h| h.id).collect::<Vec<_>>().join(",");
+    Ok(Config::update("browsers", Some(&ids))?)
+}
+
+/// The manifest `host` currently loads bwbio's host name from, if it is registered.
+pub fn registered_manifest(host: &NativeMessagingHost) -> Option<PathBuf> {
+    CURRENT_USER
+        .open(host.key_path)
+        .and_then(|key| key.get_string(""))
+        .ok()
+        .map(PathBuf::from)
+}
+
+fn display_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    path.strip_prefix(r"\\?\").unwrap_or(&path).to_string()
+}
+
+/// Points each browser in `hosts` at the manifest for its family in `install_dir`. Returns the
+/// ids of the browsers registered; failures for single browsers are only warned about.
+pub fn register_native_messaging_manifest(
+    install_dir: &Path,
+    hosts: &[NativeMessagingHost],
+) -> Result<Vec<&'static str>> {
+    register_native_messaging_manifest_in(install_dir, hosts, RegistryScope::User)
+}
+
+/// Registers the manifests in `install_dir` with each browser in `hosts` under `scope`. Only
+/// per-user registrations back up what they replace. Fails with [`RegistryAccessDenied`] when
+/// a key can't be written without elevation.
+pub fn register_native_messaging_manifest_in(
+    install_dir: &Path,
+    hosts: &[NativeMessagingHost],
+    scope: RegistryScope,
+) -> Result<Vec<&'static str>> {
+    if hosts.is_empty() {
+        eprintln!(
+            "Warning: no browsers selected. Manually register the manifests in {} if needed.",
+            install_dir.display()
+        );
+    }
+
+    let mut registered = Vec::new();
+    let mut denied = Vec::new();
+    let mut failed = Vec::new();
+    for host in hosts {
+        let manifest_path = install_dir.join(host.family.manifest_name());
+        let manifest_abs = canonicalize(&manifest_path)
+            .with_context(|| format!("Failed to canonicalize {}", manifest_path.display()))?;
+        let manifest_str = display_path(&manifest_abs);
+        if scope == RegistryScope::User
+            && let Err(e) = backup_registration(host)
+        {
+            eprintln!(
+                "Warning: failed to back up the registration of {}: {e}",
+                host.browser
+            );
+        }
+        let key_name = format!("{scope}\\{}", host.key_path);
+        match scope
+            .root()
+            .create(host.key_path)
+            .and_then(|key| key.set_string("", &manifest_str))
+        {
+            Ok(_) => registered.push(host.id),
+            Err(e) if e.code() == E_ACCESSDENIED => {
+                if !denied.contains(&key_name) {
+                    denied.push(key_name);
+                }
+            }
+            Err(e) => failed.push(format!("{key_name}: {e}")),
+        }
+    }
+
+    if !denied.is_empty() {
+        return Err(RegistryAccessDenied { keys: denied }.into());
+    }
+    if !failed.is_empty() {
+        bail!("Failed to write {}", failed.join("; "));
+    }
+    info!(browsers = ?registered, %scope, "Registered");
+    Ok(registered)
+}
+
+/// Removes the machine-wide registrations in `hosts` that start bwbio. Fails with
+/// [`RegistryAccessDenied`] when not elevated.
+pub fn unregister_machine_manifest(hosts: &[NativeMessagingHost]) -> Result<Vec<&'static str>> {
+    let mut removed_keys = Vec::new();
+    let mut removed = Vec::new();
+    for host in hosts {
+        let targets_bwbio = LOCAL_MACHINE
+            .open(host.key_path)
+            .and_then(|key| key.get_string(""))
+            .is_ok_and(|manifest| {
+                matches!(
+                    manifest_target(Path::new(&manifest)),
+                    ManifestTarget::Bwbio(_) | ManifestTarget::Unreadable
+                )
+            });
+        if targets_bwbio && !removed_keys.contains(&host.key_path) {
+            match LOCAL_MACHINE.remove_tree(host.key_path) {
+                Ok(_) => removed_keys.push(host.key_path),
+                Err(e) if e.code() == E_ACCESSDENIED => {
+                    return Err(RegistryAccessDenied {
+                        keys: vec![format!("{}\\{}", RegistryScope::Machine, host.key_path)],
+                    }
+                    .into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if removed_keys.contains(&host.key_path) {
+            removed.push(host.id);
+        }
+    }
+    Ok(removed)
+}
+
+/// Where registrations replaced by bwbio are kept, as values named by the browser's key path.
+/// Where browser registrations are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RegistryScope {
+    /// `HKEY_CURRENT_USER`, which bwbio normally uses.
+    #[default]
+    User,
+    /// `HKEY_LOCAL_MACHINE`, for every user of the PC; needs an elevated process.
+    Machine,
+}
+
+impl RegistryScope {
+    fn root(self) -> &'static Key {
+        match self {
+            RegistryScope::User => CURRENT_USER,
+            RegistryScope::Machine => LOCAL_MACHINE,
+        }
+    }
+}
+
+impl Display for RegistryScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RegistryScope::User => "HKEY_CURRENT_USER",
+            RegistryScope::Machine => "HKEY_LOCAL_MACHINE",
+        })
+    }
+}
+
+/// Registry keys bwbio wasn't allowed to write, typically because of policy or because they are
+/// machine-wide; an elevated process may be.
+#[derive(Debug, Error)]
+#[error("Access to {} was denied", .keys.join(", "))]
+pub struct RegistryAccessDenied {
+    pub keys: Vec<String>,
+}
+
+/// Runs `bwbio register` for `hosts` elevated, through the UAC prompt, and waits for it.
+pub fn register_elevated(
+    install_dir: &Path,
+    hosts: &[NativeMessagingHost],
+    scope: RegistryScope,
+) -> Result<()> {
+    let mut args = vec![
+        "register".to_string(),
+        "--dir".to_string(),
+        display_path(install_dir),
+        "--browser".to_string(),
+        hosts.iter().map(|h| h.id).collect::<Vec<_>>().join(","),
+    ];
+    if scope == RegistryScope::Machine {
+        args.push("--machine".to_string());
+    }
+    if !run_elevated(&args)? {
+        bail!("The elevated registration failed");
+    }
+    Ok(())
+}
+
+/// Runs this executable with `args` in an elevated process, without a window, and waits for it.
+/// Returns whether it exited successfully; declining the UAC prompt is an error.
+fn run_elevated(args: &[String]) -> Result<bool> {
+    let exe = env::current_exe().context("Failed to get current exe path")?;
+    let params = args
+        .iter()
+        .map(|a| {
+            if a.contains(' ') {
+                format!("\"{a}\"")
+            } else {
+                a.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let file = HSTRING::from(exe.as_os_str());
+    let params = HSTRING::from(params);
+    let mut info = SHELLEXECUTEINFOW {
+        cbSize: size_of::<SHELLEXECUTEINFOW>() as u32,
+        fMask: SEE_MASK_NOCLOSEPROCESS,
+        lpVerb: w!("runas"),
+        lpFile: PCWSTR(file.as_ptr()),
+        lpParameters: PCWSTR(params.as_ptr()),
+        nShow: SW_HIDE.0,
+        ..Default::default()
+    };
+    unsafe {
+        ShellExecuteExW(&mut info).context("Elevation was declined or failed")?;
+        WaitForSingleObject(info.hProcess, INFINITE);
+        let mut code = 1;
+        let result = GetExitCodeProcess(info.hProcess, &mut code);
+        let _ = CloseHandle(info.hProcess);
+        result.context("Failed to get the elevated process's exit code")?;
+        Ok(code == 0)
+    }
+}
+
+const MANIFEST_BACKUP_KEY: &str = "software\\bwbio\\manifestbackup";
+const INSTALL_KEY: &str = "software\\bwbio";
+const EXE_HASH_VALUE: &str = "ExeSha256";
+const AUTOSTART_KEY: &str = "software\\microsoft\\windows\\currentversion\\run";
+const AUTOSTART_VALUE: &str = "bwbio";
+const SHORTCUT_NAME: &str = "bwbio.lnk";
+
+/// Lowercase hex SHA-256 of `bytes`.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Remembers the SHA-256 of the installed executable, for `doctor` to notice when it changes.
+pub fn record_exe_hash(hash: &str) -> windows::core::Result<()> {
+    CURRENT_USER
+        .create(INSTALL_KEY)?
+        .set_string(EXE_HASH_VALUE, hash)
+}
+
+/// The hash recorded by the last install or update.
+pub fn recorded_exe_hash() -> Option<String> {
+    CURRENT_USER
+        .open(INSTALL_KEY)
+        .and_then(|key| key.get_string(EXE_HASH_VALUE))
+        .ok()
+}
+
+/// The Start Menu shortcut to the management menu, whether or not it exists.
+pub fn start_menu_shortcut() -> Result<PathBuf> {
+    env::var_os("APPDATA")
+        .map(|s| {
+            PathBuf::from(s)
+                .join("Microsoft\\Windows\\Start Menu\\Programs")
+                .join(SHORTCUT_NAME)
+        })
+        .ok_or_else(|| anyhow!("APPDATA not set"))
+}
+
+/// Creates (or replaces) the Start Menu shortcut, opening the management menu of `exe`.
+pub fn create_start_menu_shortcut(exe: &Path) -> Result<PathBuf> {
+    let path = start_menu_shortcut()?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).context("Failed to create the Start Menu folder")?;
+    }
+    unsafe {
+        // Fails when COM was already set up differently on this thread, which works just as well.
+        let initialized = CoInitializeEx(None, COINIT_APARTMENTTHREADED).is_ok();
+        let saved = (|| {
+            let link: IShellLinkW = CoCreateInstance(&ShellLink, None, CLSCTX_INPROC_SERVER)?;
+            link.SetPath(&HSTRING::from(display_path(exe)))?;
+            if let Some(dir) = exe.parent() {
+                link.SetWorkingDirectory(&HSTRING::from(display_path(dir)))?;
+            }
+            link.SetDescription(w!("Manage bwbio keys and browser integration"))?;
+            link.cast::<IPersistFile>()?
+                .Save(&HSTRING::from(display_path(&path)), true)
+        })();
+        if initialized {
+            CoUninitialize();
+        }
+        saved.context("Failed to create the Start Menu shortcut")?;
+    }
+    Ok(path)
+}
+
+/// Removes the Start Menu shortcut. Returns whether there was one.
+pub fn remove_start_menu_shortcut() -> Result<bool> {
+    match remove_file(start_menu_shortcut()?) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).context("Failed to remove the Start Menu shortcut"),
+    }
+}
+
+/// The command line Windows runs at logon for bwbio, if it is set to.
+pub fn autostart_entry() -> Option<String> {
+    CURRENT_USER
+        .open(AUTOSTART_KEY)
+        .and_then(|key| key.get_string(AUTOSTART_VALUE))
+        .ok()
+}
+
+/// Starts `exe agent` at logon, for the background agent.
+pub fn enable_autostart(exe: &Path) -> windows::core::Result<()> {
+    CURRENT_USER
+        .create(AUTOSTART_KEY)?
+        .set_string(AUTOSTART_VALUE, format!("\"{}\" agent", display_path(exe)))
+}
+
+/// Stops bwbio from starting at logon. Returns whether it was set to.
+pub fn disable_autostart() -> windows::core::Result<bool> {
+    if autostart_entry().is_none() {
+        return Ok(false);
+    }
+    CURRENT_USER
+        .create(AUTOSTART_KEY)?
+        .remove_value(AUTOSTART_VALUE)?;
+    Ok(true)
+}
+
+/// Saves the registration of `host` unless it is missing or bwbio's own, so unregistering can
+/// give the browser back to Bitwarden desktop (or whatever else was registered).
+fn backup_registration(host: &NativeMessagingHost) -> windows::core::Result<()> {
+    let Some(existing) = registered_manifest(host) else {
+        return Ok(());
+    };
+    if matches!(
+        manifest_target(&existing),
+        ManifestTarget::Bwbio(_) | ManifestTarget::Unreadable
+    ) {
+        return Ok(());
+    }
+    CURRENT_USER
+        .create(MANIFEST_BACKUP_KEY)?
+        .set_string(host.key_path, display_path(&existing))
+}
+
+/// The registration bwbio replaced for `host`, if any.
+pub fn backed_up_registration(host: &NativeMessagingHost) -> Option<PathBuf> {
+    CURRENT_USER
+        .open(MANIFEST_BACKUP_KEY)
+        .and_then(|key| key.get_string(host.key_path))
+        .ok()
+        .map(PathBuf::from)
+}
+
+/// Puts the backed up registration of `host` back in place of bwbio's, or removes bwbio's when
+/// there is none.
+fn restore_registration(host: &NativeMessagingHost) -> windows::core::Result<()> {
+    let Some(backup) = backed_up_registration(host) else {
+        return CURRENT_USER.remove_tree(host.key_path);
+    };
+    CURRENT_USER
+        .create(host.key_path)?
+        .set_string("", display_path(&backup))?;
+    CURRENT_USER
+        .open(MANIFEST_BACKUP_KEY)?
+        .remove_value(host.key_path)
+}
+
+/// Removes the registration of each browser in `hosts` that starts bwbio (or a manifest that is
+/// gone), restoring the registration bwbio replaced if there was one; Bitwarden desktop's own
+/// registration is left alone. Returns the ids of the browsers that were registered.
+pub fn unregister_native_messaging_manifest(hosts: &[NativeMessagingHost]) -> Vec<&'static str> {
+    let mut removed_keys = Vec::new();
+    let mut removed = Vec::new();
+    for host in hosts {
+        // Browsers sharing Chrome's key are removed along with it.
+        if !removed_keys.contains(&host.key_path)
+            && registered_manifest(host).is_some_and(|manifest| {
+                matches!(
+                    manifest_target(&manifest),
+                    ManifestTarget::Bwbio(_) | ManifestTarget::Unreadable
+                )
+            })
+            && restore_registration(host).is_ok()
+        {
+            removed_keys.push(host.key_path);
+        }
+        if removed_keys.contains(&host.key_path) {
+            removed.push(host.id);
+        }
+    }
+
+    if removed.is_empty() {
+        eprintln!(
+            "Warning: no registry values removed (no supported browsers detected or already unregistered)"
+        );
+    } else {
+        info!(browsers = ?removed, "Unregistered");
+    }
+    removed
+}
+
+/// The `com.8bit.bitwarden` manifest starting `exe`, allowing the Bitwarden extensions of
+/// `family`.
+pub fn native_messaging_manifest(exe: &Path, family: BrowserFamily) -> Value {
+    let mut manifest = json!({
+        "name": "com.8bit.bitwarden",
+        "description": "Bitwarden desktop <-> browser bridge",
+        "path": display_path(exe),
+        "type": "stdio",
+    });
+    match family {
+        BrowserFamily::Chromium => manifest["allowed_origins"] = json!(CHROMIUM_EXTENSION_ORIGINS),
+        BrowserFamily::Firefox => manifest["allowed_extensions"] = json!([FIREFOX_EXTENSION_ID]),
+    }
+    manifest
+}
+
+/// Copies the running executable into `install_dir`, writes the manifests next to it and
+/// registers them with `hosts`, showing each step on `progress`. Returns the installed
+/// executable.
+pub fn perform_install(
+    install_dir: &Path,
+    hosts: &[NativeMessagingHost],
+    progress: &Progress,
+) -> Result<PathBuf> {
+    progress.step(&format!("Copying {EXE_NAME} to {}", install_dir.display()));
+    create_dir_all(install_dir).context("Failed to create install directory")?;
+
+    let current_exe = env::current_exe().context("Failed to get current exe path")?;
+    let target_exe = install_dir.join(EXE_NAME);
+    let hash = read(&current_exe)
+        .map(|exe| sha256_hex(&exe))
+        .context("Failed to read the running exe")?;
+    if current_exe.canonicalize().ok() != target_exe.canonicalize().ok() {
+        copy(&current_exe, &target_exe).context("Failed to copy exe to target location")?;
+        let copied = read(&target_exe).context("Failed to read the copied exe")?;
+        if sha256_hex(&copied) != hash {
+            let _ = remove_file(&target_exe);
+            bail!(
+                "The copy of {} in {} doesn't match the original and was removed",
+                current_exe.display(),
+                install_dir.display()
+            );
+        }
+    }
+    if let Err(e) = record_exe_hash(&hash) {
+        eprintln!("Warning: failed to record the executable's hash: {e}");
+    }
+    let target_exe = canonicalize(&target_exe).unwrap_or(target_exe);
+
+    progress.step("Writing the manifests");
+    write_manifests(install_dir, &target_exe)?;
+
+    // Only a non-standard directory is recorded, so the config file stays empty by default.
+    let recorded = standard_install_dir()
+        .is_ok_and(|dir| dir != install_dir)
+        .then(|| display_path(&canonicalize(install_dir).unwrap_or(install_dir.to_path_buf())));
+    if let Err(e) = Config::update("install_dir", recorded.as_deref()) {
+        eprintln!("Warning: failed to record the install directory: {e:#}");
+    }
+
+    // Last, so everything else is in place when only this step needs to be retried elevated.
+    progress.step("Registering with the browsers");
+    register_native_messaging_manifest(install_dir, hosts)
+        .context("Failed to write registry entries")?;
+
+    info!(dir = %install_dir.display(), version = env!("CARGO_PKG_VERSION"), "Installed");
+    Ok(target_exe)
+}
+
+/// Writes the manifest of each browser family into `install_dir`, pointing at `exe`.
+pub fn write_manifests(install_dir: &Path, exe: &Path) -> Result<()> {
+    for family in [BrowserFamily::Chromium, BrowserFamily::Firefox] {
+        let manifest = native_messaging_manifest(exe, family);
+        write(
+            install_dir.join(family.manifest_name()),
+            manifest.to_string(),
+        )
+        .context("Failed to write manifest")?;
+    }
+    Ok(())
+}
+
+/// An installation other than the one at `default_install_dir()`: the recorded install
+/// directory, `%LOCALAPPDATA%\bwbio` or the directory a browser registration points at,
+/// whichever still holds bwbio.
+pub fn find_existing_install() -> Option<PathBuf> {
+    let registered = detect_browsers()
+        .into_iter()
+        .filter_map(|b| match b.target {
+            Some(ManifestTarget::Bwbio(exe)) => exe.parent().map(Path::to_path_buf),
+            _ => None,
+        });
+    config()
+        .install_dir
+        .clone()
+        .into_iter()
+        .chain(standard_install_dir().ok())
+        .chain(registered)
+        .find(|dir| is_installed(dir))
+}
+
+/// Whether `a` and `b` name the same directory, which need not exist.
+pub fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Installs to `to` in place of the installation in `from`, moving the stored keys along when
+/// they live there. The browser registrations and the recorded install directory follow the
+/// new install; the CNG key and a key directory configured elsewhere are left as they are.
+pub fn migrate_install(
+    from: &Path,
+    to: &Path,
+    hosts: &[NativeMessagingHost],
+    progress: &Progress,
+) -> Result<PathBuf> {
+    let from_keys = key_dir_for(from);
+    let to_keys = if from_keys == from.join("keys") {
+        let to_keys = to.join("keys");
+        if from_keys.exists() {
+            progress.step("Moving the stored keys");
+            move_dir(&from_keys, &to_keys).context("Failed to move the stored keys")?;
+        }
+        to_keys
+    } else {
+        from_keys
+    };
+    // Uninstalling `from` removes these, so they are recreated for the new executable.
+    let had_shortcut = start_menu_shortcut().is_ok_and(|path| path.exists());
+    let had_autostart = autostart_entry().is_some();
+    let exe = perform_install(to, hosts, progress)?;
+    progress.step(&format!("Removing the installation in {}", from.display()));
+    perform_uninstall(
+        from,
+        &to_keys,
+        UninstallOptions {
+            keep_keys: true,
+            keep_registration: true,
+            ..Default::default()
+        },
+    )
+    .context("Failed to remove the previous installation")?;
+    if had_shortcut {
+        create_start_menu_shortcut(&exe)?;
+    }
+    if had_autostart {
+        enable_autostart(&exe).context("Failed to move the logon autostart entry")?;
+    }
+    Ok(exe)
+}
+
+/// Renames `from` to `to`, or copies it and removes the original when they are on different
+/// volumes.
+fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        bail!("{} already exists", to.display());
+    }
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent)?;
+    }
+    if rename(from, to).is_ok() {
+        return Ok(());
+    }
+    if let Err(e) = copy_dir(from, to) {
+        let _ = remove_dir_all(to);
+        return Err(e);
+    }
+    remove_dir_all(from)?;
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    create_dir_all(to)?;
+    for entry in read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else {
+            copy(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes `path`, a file or a whole directory, once this process has exited. A hidden
+/// PowerShell waits for the exit; if it can't be started, a file is queued for deletion at the
+/// next reboot instead, which only works elevated.
+fn delete_after_exit(path: &Path) {
+    let path = display_path(path);
+    let script = format!(
+        "Wait-Process -Id {} -ErrorAction SilentlyContinue; Remove-Item -LiteralPath '{}' -Recurse -Force",
+        process::id(),
+        path.replace('\'', "''")
+    );
+    let spawned = Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .creation_flags(CREATE_NO_WINDOW.0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if spawned.is_err()
+        && unsafe {
+            MoveFileExW(
+                &HSTRING::from(&path),
+                PCWSTR::null(),
+                MOVEFILE_DELAY_UNTIL_REBOOT,
+            )
+        }
+        .is_err()
+    {
+        eprintln!("Warning: {path} is left behind; delete it by hand.");
+    }
+}
+
+/// What [`perform_uninstall`] leaves in place; by default nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UninstallOptions {
+    /// The stored keys, and with them the CNG key that wraps them. A key directory inside the
+    /// install directory is left behind for a later reinstall.
+    pub keep_keys: bool,
+    /// Only the CNG key.
+    pub keep_cng_key: bool,
+    /// The browser registrations, for when another install has taken them over.
+    pub keep_registration: bool,
+}
+
+pub fn is_installed(install_dir: &Path) -> bool {
+    install_dir.join(EXE_NAME).exists() || install_dir.join(MANIFEST_NAME).exists()
+}
+
+/// Unregisters bwbio and removes the installation along with the stored keys and the CNG key
+/// wrapping them, except what `options` keeps. Whatever is already gone is skipped.
+pub fn perform_uninstall(
+    install_dir: &Path,
+    key_dir: &Path,
+    options: UninstallOptions,
+) -> Result<()> {
+    let keep_keys = options.keep_keys;
+    info!(dir = %install_dir.display(), ?options, "Uninstalling");
+    if !options.keep_registration {
+        unregister_native_messaging_manifest(&NATIVE_MESSAGING_HOSTS);
+    }
+
+    // Read before the key store goes, as it records the key name after a rotation.
+    let store = open_key_store(key_dir.to_path_buf())?;
+    let key_name = active_cng_key_name(&*store, config().cng_key_name());
+
+    if !keep_keys && let Err(e) = store.remove_all() {
+        eprintln!("Warning: failed to remove stored keys: {e}");
+    }
+
+    for name in [MANIFEST_NAME, FIREFOX_MANIFEST_NAME] {
+        let manifest_path = install_dir.join(name);
+        if manifest_path.exists()
+            && let Err(e) = remove_file(&manifest_path)
+        {
+            eprintln!("Warning: failed to remove {name}: {e}");
+        }
+    }
+
+    // The running executable can't be deleted, only moved out of the way and deleted once this
+    // process has exited.
+    let installed_exe = install_dir.join(EXE_NAME);
+    let removed_exe = match env::current_exe() {
+        Ok(cur)
+            if installed_exe.exists()
+                && cur.canonicalize().ok() == installed_exe.canonicalize().ok() =>
+        {
+            let moved = env::temp_dir().join(format!("bwbio_uninstall_{}.exe", process::id()));
+            rename(&cur, &moved)
+                .context("Failed to move exe to temp")
+                .map(|_| delete_after_exit(&moved))
+        }
+        _ if installed_exe.exists() => remove_file(&installed_exe).context("Failed to remove exe"),
+        _ => Ok(()),
+    };
+    let keep_dir = keep_keys && key_dir.starts_with(install_dir);
+    if let Err(e) = removed_exe {
+        if keep_dir {
+            eprintln!("Warning: {e:#}");
+        } else {
+            // Typically a temp directory on another volume; the whole directory goes instead.
+            delete_after_exit(install_dir);
+        }
+    } else if !keep_dir
+        && install_dir.exists()
+        && let Err(e) = remove_dir_all(install_dir)
+    {
+        eprintln!("Warning: failed to remove install directory: {e}");
+    }
+
+    if let Ok(key) = CURRENT_USER.open(INSTALL_KEY) {
+        let _ = key.remove_value(EXE_HASH_VALUE);
+    }
+    if let Err(e) = remove_start_menu_shortcut() {
+        eprintln!("Warning: {e:#}");
+    }
+    if let Err(e) = disable_autostart() {
+        eprintln!("Warning: failed to remove the logon autostart entry: {e}");
+    }
+
+    if config().install_dir.as_deref() == Some(install_dir)
+        && let Err(e) = Config::update("install_dir", None)
+    {
+        eprintln!("Warning: failed to forget the install directory: {e:#}");
+    }
+
+    // A machine-wide key is shared with other users and is left to the administrator.
+    if !keep_keys
+        && !options.keep_cng_key
+        && !machine_key_selected()
+        && let Ok(provider) = CngProvider::new()
+        && let Ok(key) = provider.open_key(key_name)
+        && let Err(e) = delete_cng_key(key)
+    {
+        eprintln!("Warning: failed to delete CNG key: {e}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,370 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::crypto::EcCurve;
+use crate::error::Result;
+use crate::keystore::StoreError;
+use std::fmt::{self, Display, Formatter};
+
+const MAGIC: &[u8] = b"BWBK";
+/// Version 1 has no MAC; version 2 adds one after the key name, version 3's MAC also covers
+/// the user id, so files can't be swapped between accounts, and version 4 records the key
+/// provider.
+pub const VERSION: u8 = 4;
+const MAC_LEN: usize = 32;
+
+/// How the data key inside a key file is wrapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapAlgorithm {
+    /// RSA-OAEP with SHA-256 by the CNG key.
+    RsaOaep,
+    /// ECIES against the CNG ECDH key.
+    Ecies(EcCurve),
+    /// A software wrap with no hardware key behind it; written by the former passphrase
+    /// backend and by test wrappers.
+    Passphrase,
+    /// A `systemd-creds` credential sealed to the TPM2.
+    Tpm2,
+}
+
+impl WrapAlgorithm {
+    fn to_byte(self) -> u8 {
+        match self {
+            WrapAlgorithm::RsaOaep => 1,
+            WrapAlgorithm::Ecies(EcCurve::P256) => 2,
+            WrapAlgorithm::Ecies(EcCurve::P384) => 3,
+            WrapAlgorithm::Ecies(EcCurve::P521) => 4,
+            WrapAlgorithm::Passphrase => 5,
+            WrapAlgorithm::Tpm2 => 6,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            1 => Ok(WrapAlgorithm::RsaOaep),
+            2 => Ok(WrapAlgorithm::Ecies(EcCurve::P256)),
+            3 => Ok(WrapAlgorithm::Ecies(EcCurve::P384)),
+            4 => Ok(WrapAlgorithm::Ecies(EcCurve::P521)),
+            5 => Ok(WrapAlgorithm::Passphrase),
+            6 => Ok(WrapAlgorithm::Tpm2),
+            other => Err(StoreError::UnknownWrapAlgorithm(other).into()),
+        }
+    }
+}
+
+/// What holds the key that wrapped the data key, recorded from version 4 on so a later run can
+/// tell that the keys need the TPM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyProvider {
+    /// A key with no hardware behind it, such as one in the Software KSP.
+    Software,
+    /// A key sealed to the TPM: the Platform Crypto Provider or `systemd-creds`.
+    Tpm,
+}
+
+impl KeyProvider {
+    fn to_byte(provider: Option<Self>) -> u8 {
+        match provider {
+            None => 0,
+            Some(KeyProvider::Software) => 1,
+            Some(KeyProvider::Tpm) => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Option<Self>> {
+        match b {
+            0 => Ok(None),
+            1 => Ok(Some(KeyProvider::Software)),
+            2 => Ok(Some(KeyProvider::Tpm)),
+            other => Err(StoreError::UnknownKeyProvider(other).into()),
+        }
+    }
+}
+
+impl Display for KeyProvider {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyProvider::Software => "software",
+            KeyProvider::Tpm => "TPM",
+        })
+    }
+}
+
+/// `RSA-OAEP with CNG key 'bw-bio'`, or just the algorithm when no CNG key is involved.
+pub fn describe_wrap(algorithm: WrapAlgorithm, key_name: &str) -> String {
+    if key_name.is_empty() {
+        algorithm.to_string()
+    } else {
+        format!("{algorithm} with CNG key '{key_name}'")
+    }
+}
+
+impl Display for WrapAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WrapAlgorithm::RsaOaep => f.write_str("RSA-OAEP"),
+            WrapAlgorithm::Ecies(curve) => write!(f, "ECIES ({curve})"),
+            WrapAlgorithm::Passphrase => f.write_str("passphrase"),
+            WrapAlgorithm::Tpm2 => f.write_str("TPM2"),
+        }
+    }
+}
+
+/// A stored key: a header describing how it was wrapped, followed by the hybrid envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyFile {
+    pub algorithm: WrapAlgorithm,
+    /// Name of the CNG key that wrapped the data key; empty for software and TPM2 wrapping.
+    pub key_name: String,
+    /// Where the wrapping key lives; `None` for files older than version 4, or when the wrapper
+    /// can't tell.
+    pub provider: Option<KeyProvider>,
+    /// Format version, which decides what the MAC covers. Files without a MAC are version 1.
+    pub version: u8,
+    /// HMAC-SHA256 over [`KeyFile::mac_input`]; `None` for version 1 files.
+    pub mac: Option<[u8; MAC_LEN]>,
+    pub envelope: Vec<u8>,
+}
+
+impl KeyFile {
+    /// Whether `bytes` start with the key file header. Files from before the header hold a bare
+    /// envelope or RSA block.
+    pub fn has_header(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    fn name_bytes(&self) -> &[u8] {
+        &self.key_name.as_bytes()[..self.key_name.len().min(u8::MAX as usize)]
+    }
+
+    /// Everything the MAC covers: the header, key name, provider from version 4 on, `user_id`
+    /// from version 3 on, and the envelope.
+    pub fn mac_input(&self, user_id: &str) -> Vec<u8> {
+        let name = self.name_bytes();
+        let mut input = [
+            MAGIC,
+            &[self.version, self.algorithm.to_byte(), name.len() as u8],
+            name,
+        ]
+        .concat();
+        if self.version >= 4 {
+            input.push(KeyProvider::to_byte(self.provider));
+        }
+        if self.version >= 3 {
+            let user_id = &user_id.as_bytes()[..user_id.len().min(u8::MAX as usize)];
+            input.push(user_id.len() as u8);
+            input.extend_from_slice(user_id);
+        }
+        input.extend_from_slice(&self.envelope);
+        input
+    }
+
+    /// Layout: `"BWBK" | version (4) | algorithm (1) | name_len (1) | key name | provider (1) |
+    /// MAC (32) | envelope`. Files without a MAC are written as version 1, which lacks the
+    /// provider and MAC fields; versions 2 and 3 lack the provider.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let name = self.name_bytes();
+        let (version, mac) = match &self.mac {
+            Some(mac) => (self.version, &mac[..]),
+            None => (1, &[][..]),
+        };
+        let provider = if version >= 4 {
+            &[KeyProvider::to_byte(self.provider)][..]
+        } else {
+            &[]
+        };
+        [
+            MAGIC,
+            &[version, self.algorithm.to_byte(), name.len() as u8],
+            name,
+            provider,
+            mac,
+            &self.envelope,
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let Some(rest) = bytes.strip_prefix(MAGIC) else {
+            return Err(StoreError::MissingHeader.into());
+        };
+        let [version, algorithm, name_len, rest @ ..] = rest else {
+            return Err(StoreError::TruncatedHeader.into());
+        };
+        if !(1..=VERSION).contains(version) {
+            return Err(StoreError::UnsupportedVersion(*version).into());
+        }
+        let Some((name, rest)) = rest.split_at_checked(*name_len as usize) else {
+            return Err(StoreError::TruncatedHeader.into());
+        };
+        let (provider, rest) = match rest {
+            [provider, rest @ ..] if *version >= 4 => (KeyProvider::from_byte(*provider)?, rest),
+            [] if *version >= 4 => return Err(StoreError::TruncatedHeader.into()),
+            rest => (None, rest),
+        };
+        let (mac, envelope) = if *version >= 2 {
+            let Some((mac, envelope)) = rest.split_first_chunk::<MAC_LEN>() else {
+                return Err(StoreError::TruncatedHeader.into());
+            };
+            (Some(*mac), envelope)
+        } else {
+            (None, rest)
+        };
+        Ok(Self {
+            algorithm: WrapAlgorithm::from_byte(*algorithm)?,
+            key_name: String::from_utf8_lossy(name).into_owned(),
+            provider,
+            version: *version,
+            mac,
+            envelope: envelope.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::BwbioError;
+
+    const ENVELOPE: &[u8] = b"envelope bytes";
+    const MAC: [u8; MAC_LEN] = [0xaa; MAC_LEN];
+
+    fn key_file(version: u8, mac: Option<[u8; MAC_LEN]>) -> KeyFile {
+        KeyFile {
+            algorithm: WrapAlgorithm::RsaOaep,
+            key_name: "bw-bio".to_string(),
+            provider: (version >= 4).then_some(KeyProvider::Tpm),
+            version,
+            mac,
+            envelope: ENVELOPE.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_v1() {
+        let bytes = [b"BWBK\x01\x01\x06bw-bio".as_slice(), ENVELOPE].concat();
+        assert!(KeyFile::has_header(&bytes));
+        assert_eq!(KeyFile::from_bytes(&bytes).unwrap(), key_file(1, None));
+        assert_eq!(key_file(1, None).to_bytes(), bytes);
+    }
+
+    #[test]
+    fn parses_v2_and_v3() {
+        for version in [2, 3] {
+            let bytes = [b"BWBK".as_slice(), &[version, 2, 0], &MAC, ENVELOPE].concat();
+            let file = KeyFile::from_bytes(&bytes).unwrap();
+            assert_eq!(file.version, version);
+            assert_eq!(file.algorithm, WrapAlgorithm::Ecies(EcCurve::P256));
+            assert_eq!(file.key_name, "");
+            assert_eq!(file.provider, None);
+            assert_eq!(file.mac, Some(MAC));
+            assert_eq!(file.envelope, ENVELOPE);
+            assert_eq!(file.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn parses_v4() {
+        let bytes = [b"BWBK\x04\x01\x06bw-bio\x02".as_slice(), &MAC, ENVELOPE].concat();
+        assert_eq!(KeyFile::from_bytes(&bytes).unwrap(), key_file(4, Some(MAC)));
+        assert_eq!(key_file(4, Some(MAC)).to_bytes(), bytes);
+    }
+
+    #[test]
+    fn roundtrips_every_algorithm() {
+        for algorithm in [
+            WrapAlgorithm::RsaOaep,
+            WrapAlgorithm::Ecies(EcCurve::P256),
+            WrapAlgorithm::Ecies(EcCurve::P384),
+            WrapAlgorithm::Ecies(EcCurve::P521),
+            WrapAlgorithm::Passphrase,
+            WrapAlgorithm::Tpm2,
+        ] {
+            for provider in [None, Some(KeyProvider::Software), Some(KeyProvider::Tpm)] {
+                let file = KeyFile {
+                    algorithm,
+                    provider,
+                    ..key_file(VERSION, Some(MAC))
+                };
+                assert_eq!(KeyFile::from_bytes(&file.to_bytes()).unwrap(), file);
+            }
+        }
+    }
+
+    #[test]
+    fn without_mac_writes_v1() {
+        let file = KeyFile {
+            provider: Some(KeyProvider::Tpm),
+            ..key_file(VERSION, None)
+        };
+        let parsed = KeyFile::from_bytes(&file.to_bytes()).unwrap();
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.provider, None);
+        assert_eq!(parsed.envelope, ENVELOPE);
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let rejects = |bytes: &[u8]| match KeyFile::from_bytes(bytes) {
+            Err(BwbioError::Store(e)) => e,
+            other => panic!("expected a store error, got {other:?}"),
+        };
+        assert!(matches!(rejects(b"envelope"), StoreError::MissingHeader));
+        assert!(matches!(
+            rejects(b"BWBK\x03\x01"),
+            StoreError::TruncatedHeader
+        ));
+        assert!(matches!(
+            rejects(b"BWBK\x01\x01\x09short"),
+            StoreError::TruncatedHeader
+        ));
+        assert!(matches!(
+            rejects(b"BWBK\x03\x01\x00short"),
+            StoreError::TruncatedHeader
+        ));
+        assert!(matches!(
+            rejects(b"BWBK\x04\x01\x00"),
+            StoreError::TruncatedHeader
+        ));
+        assert!(matches!(
+            rejects(b"BWBK\x00\x01\x00"),
+            StoreError::UnsupportedVersion(0)
+        ));
+        assert!(matches!(
+            rejects(&[b"BWBK".as_slice(), &[VERSION + 1, 1, 0], &MAC].concat()),
+            StoreError::UnsupportedVersion(_)
+        ));
+        assert!(matches!(
+            rejects(b"BWBK\x01\x07\x00"),
+            StoreError::UnknownWrapAlgorithm(7)
+        ));
+        assert!(matches!(
+            rejects(&[b"BWBK\x04\x01\x00\x03".as_slice(), &MAC].concat()),
+            StoreError::UnknownKeyProvider(3)
+        ));
+    }
+
+    #[test]
+    fn mac_input_covers_user_id_from_v3() {
+        let v2 = key_file(2, Some(MAC));
+        assert_eq!(v2.mac_input("alice"), v2.mac_input("bob"));
+        let v3 = key_file(3, Some(MAC));
+        assert_ne!(v3.mac_input("alice"), v3.mac_input("bob"));
+    }
+
+    #[test]
+    fn mac_input_covers_provider_from_v4() {
+        let tpm = key_file(4, Some(MAC));
+        let software = KeyFile {
+            provider: Some(KeyProvider::Software),
+            ..tpm.clone()
+        };
+        assert_ne!(tpm.mac_input("alice"), software.mac_input("alice"));
+        let v3 = key_file(3, Some(MAC));
+        let v3_software = KeyFile {
+            provider: Some(KeyProvider::Software),
+            ..v3.clone()
+        };
+        assert_eq!(v3.mac_input("alice"), v3_software.mac_input("alice"));
+    }
+}
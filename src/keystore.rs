@@ -0,0 +1,385 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::config::config;
+use crate::error::Result;
+use std::{
+    fs::{File, OpenOptions, create_dir_all, read, read_dir, remove_dir_all, remove_file, rename},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+#[cfg(windows)]
+use std::{ptr::null_mut, slice};
+use thiserror::Error;
+#[cfg(windows)]
+use windows::Win32::{
+    Foundation::{ERROR_FILE_NOT_FOUND, ERROR_NOT_FOUND},
+    Security::Credentials::{
+        CRED_FLAGS, CRED_MAX_CREDENTIAL_BLOB_SIZE, CRED_PERSIST_LOCAL_MACHINE, CRED_TYPE_GENERIC,
+        CREDENTIALW, CredDeleteW, CredEnumerateW, CredFree, CredReadW, CredWriteW,
+    },
+};
+#[cfg(windows)]
+use windows::core::PWSTR;
+#[cfg(windows)]
+use windows_registry::{CURRENT_USER, Key, Type};
+#[cfg(windows)]
+use windows_strings::HSTRING;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The registry or Credential Manager refused.
+    #[cfg(windows)]
+    #[error(transparent)]
+    Os(#[from] windows::core::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Key store entry {0} does not exist")]
+    MissingEntry(String),
+    #[error("Unknown key store: {0}")]
+    UnknownStore(String),
+    #[error("{name} is {len} bytes, more than the Credential Manager holds")]
+    TooLarge { name: String, len: usize },
+    #[error("Missing key file header")]
+    MissingHeader,
+    #[error("Key file header truncated")]
+    TruncatedHeader,
+    #[error("Unsupported key file format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown key wrap algorithm {0}")]
+    UnknownWrapAlgorithm(u8),
+    #[error("Unknown key provider {0}")]
+    UnknownKeyProvider(u8),
+}
+
+/// Storage for the wrapped keys and their metadata, as named binary entries. Entry names are
+/// user ids plus the bookkeeping entries [`crate::kmgr::KeyManager`] hides from listings.
+pub trait KeyStore: Send + Sync {
+    /// Names of all entries.
+    fn list(&self) -> Result<Vec<String>>;
+    /// Contents of `name`, or `None` if there is no such entry.
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>>;
+    /// Creates or replaces `name`. A crash must leave either the old or the new contents.
+    fn write(&self, name: &str, data: &[u8]) -> Result<()>;
+    /// Removes `name`; removing a missing entry is not an error.
+    fn delete(&self, name: &str) -> Result<()>;
+    /// Removes every entry along with the store itself.
+    fn remove_all(&self) -> Result<()>;
+
+    /// Moves `from` over `to`.
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let Some(data) = self.read(from)? else {
+            return Err(StoreError::MissingEntry(from.to_string()).into());
+        };
+        self.write(to, &data)?;
+        self.delete(from)
+    }
+
+    fn exists(&self, name: &str) -> Result<bool> {
+        Ok(self.read(name)?.is_some())
+    }
+}
+
+/// Which [`KeyStore`] holds the keys, chosen with `BW_KEY_STORE` or `key_store`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyStoreKind {
+    /// One file per entry in the key directory.
+    #[default]
+    File,
+    /// Binary values under `HKCU\Software\bwbio\Keys`.
+    #[cfg(windows)]
+    Registry,
+    /// Generic credentials in the Windows Credential Manager.
+    #[cfg(windows)]
+    CredentialManager,
+}
+
+impl FromStr for KeyStoreKind {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, StoreError> {
+        match s.to_ascii_lowercase().as_str() {
+            "file" => Ok(KeyStoreKind::File),
+            #[cfg(windows)]
+            "registry" => Ok(KeyStoreKind::Registry),
+            #[cfg(windows)]
+            "credman" | "credential-manager" => Ok(KeyStoreKind::CredentialManager),
+            other => Err(StoreError::UnknownStore(other.to_string())),
+        }
+    }
+}
+
+/// The key store selected by `BW_KEY_STORE` or the configured `key_store` (default: files in
+/// `bw_key_directory`).
+pub fn open_key_store(bw_key_directory: PathBuf) -> Result<Box<dyn KeyStore>> {
+    Ok(match config().key_store()? {
+        KeyStoreKind::File => Box::new(FileStore::new(bw_key_directory)),
+        #[cfg(windows)]
+        KeyStoreKind::Registry => Box::new(RegistryStore),
+        #[cfg(windows)]
+        KeyStoreKind::CredentialManager => Box::new(CredentialStore),
+    })
+}
+
+/// Advisory lock serializing changes to the key store between the browser host and the CLI/TUI.
+/// It is taken on `.lock` in the key directory whichever backend holds the keys.
+pub struct StoreLock {
+    path: PathBuf,
+}
+
+impl StoreLock {
+    pub fn new(bw_key_directory: &Path) -> Self {
+        Self {
+            path: bw_key_directory.join(".lock"),
+        }
+    }
+
+    /// Blocks until no other process holds the lock. It is released when the file is dropped.
+    pub fn acquire(&self) -> Result<File> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&self.path)?;
+        file.lock()?;
+        Ok(file)
+    }
+}
+
+/// One file per entry in a directory, written via a temporary file, fsync and rename.
+pub struct FileStore {
+    directory: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+}
+
+impl KeyStore for FileStore {
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        if self.directory.exists() {
+            for entry in read_dir(&self.directory)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file()
+                    && let Some(name) = entry.file_name().to_str()
+                    && !name.ends_with(".tmp")
+                {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match read(self.directory.join(name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        create_dir_all(&self.directory)?;
+        let path = self.directory.join(name);
+        let tmp_path = self.directory.join(format!(".{name}.tmp"));
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| rename(&tmp_path, path));
+        if result.is_err() {
+            let _ = remove_file(&tmp_path);
+        }
+        Ok(result?)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match remove_file(self.directory.join(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_all(&self) -> Result<()> {
+        if self.directory.exists() {
+            remove_dir_all(&self.directory)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        Ok(rename(self.directory.join(from), self.directory.join(to))?)
+    }
+}
+
+#[cfg(windows)]
+const REGISTRY_PATH: &str = "Software\\bwbio\\Keys";
+
+/// `REG_BINARY` values under `HKCU\Software\bwbio\Keys`. Single value writes are atomic.
+#[cfg(windows)]
+pub struct RegistryStore;
+
+#[cfg(windows)]
+impl RegistryStore {
+    fn key(&self) -> Result<Key> {
+        Ok(CURRENT_USER
+            .create(REGISTRY_PATH)
+            .map_err(StoreError::from)?)
+    }
+}
+
+#[cfg(windows)]
+impl KeyStore for RegistryStore {
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(self
+            .key()?
+            .values()
+            .map_err(StoreError::from)?
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.key()?.get_value(name) {
+            Ok(value) => Ok(Some(value.to_vec())),
+            Err(e) if e.code() == ERROR_FILE_NOT_FOUND.to_hresult() => Ok(None),
+            Err(e) => Err(StoreError::from(e).into()),
+        }
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        Ok(self
+            .key()?
+            .set_bytes(name, Type::Bytes, data)
+            .map_err(StoreError::from)?)
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match self.key()?.remove_value(name) {
+            Err(e) if e.code() != ERROR_FILE_NOT_FOUND.to_hresult() => {
+                Err(StoreError::from(e).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_all(&self) -> Result<()> {
+        match CURRENT_USER.remove_tree(REGISTRY_PATH) {
+            Err(e) if e.code() != ERROR_FILE_NOT_FOUND.to_hresult() => {
+                Err(StoreError::from(e).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(windows)]
+const CREDENTIAL_PREFIX: &str = "bwbio:";
+
+/// Generic credentials named `bwbio:<entry>` in the Windows Credential Manager, which DPAPI
+/// protects per user and machine. Entries are limited to 2560 bytes.
+#[cfg(windows)]
+pub struct CredentialStore;
+
+#[cfg(windows)]
+impl CredentialStore {
+    fn target(name: &str) -> HSTRING {
+        HSTRING::from(format!("{CREDENTIAL_PREFIX}{name}"))
+    }
+}
+
+#[cfg(windows)]
+impl KeyStore for CredentialStore {
+    fn list(&self) -> Result<Vec<String>> {
+        let filter = HSTRING::from(format!("{CREDENTIAL_PREFIX}*"));
+        let mut count = 0u32;
+        let mut credentials: *mut *mut CREDENTIALW = null_mut();
+        unsafe {
+            match CredEnumerateW(&filter, None, &mut count, &mut credentials) {
+                Ok(_) => {}
+                Err(e) if e.code() == ERROR_NOT_FOUND.to_hresult() => return Ok(Vec::new()),
+                Err(e) => return Err(StoreError::from(e).into()),
+            }
+            let names = slice::from_raw_parts(credentials, count as usize)
+                .iter()
+                .filter_map(|&c| (*c).TargetName.to_string().ok())
+                .filter_map(|target| target.strip_prefix(CREDENTIAL_PREFIX).map(str::to_string))
+                .collect();
+            CredFree(credentials as *const _);
+            Ok(names)
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let mut credential: *mut CREDENTIALW = null_mut();
+        unsafe {
+            match CredReadW(
+                &Self::target(name),
+                CRED_TYPE_GENERIC,
+                None,
+                &mut credential,
+            ) {
+                Ok(_) => {}
+                Err(e) if e.code() == ERROR_NOT_FOUND.to_hresult() => return Ok(None),
+                Err(e) => return Err(StoreError::from(e).into()),
+            }
+            let c = &*credential;
+            let data = slice::from_raw_parts(c.CredentialBlob, c.CredentialBlobSize as usize);
+            let data = data.to_vec();
+            CredFree(credential as *const _);
+            Ok(Some(data))
+        }
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        if data.len() > CRED_MAX_CREDENTIAL_BLOB_SIZE as usize {
+            return Err(StoreError::TooLarge {
+                name: name.to_string(),
+                len: data.len(),
+            }
+            .into());
+        }
+        let target = Self::target(name);
+        let user_name = HSTRING::from("bwbio");
+        let credential = CREDENTIALW {
+            Flags: CRED_FLAGS(0),
+            Type: CRED_TYPE_GENERIC,
+            TargetName: PWSTR(target.as_ptr() as *mut _),
+            CredentialBlobSize: data.len() as u32,
+            CredentialBlob: data.as_ptr() as *mut _,
+            Persist: CRED_PERSIST_LOCAL_MACHINE,
+            UserName: PWSTR(user_name.as_ptr() as *mut _),
+            ..Default::default()
+        };
+        unsafe { Ok(CredWriteW(&credential, 0).map_err(StoreError::from)?) }
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        unsafe {
+            match CredDeleteW(&Self::target(name), CRED_TYPE_GENERIC, None) {
+                Err(e) if e.code() != ERROR_NOT_FOUND.to_hresult() => {
+                    Err(StoreError::from(e).into())
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn remove_all(&self) -> Result<()> {
+        for name in self.list()? {
+            self.delete(&name)?;
+        }
+        Ok(())
+    }
+}
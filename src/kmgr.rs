@@ -1,98 +1,1980 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
 
-use crate::cng::default_key_name;
-use crate::cng::{CngKey, CngProvider};
-use anyhow::Result;
+use crate::audit::{AuditAction, AuditEntry, AuditLog, AuditSource};
+#[cfg(windows)]
+use crate::cng::{
+    CngKey, CngKeyAlgorithm, CngKeyOptions, CngPadding, CngProvider, CngProviderKind,
+};
+use crate::config::{check_config, config};
+#[cfg(windows)]
+use crate::crypto::Ecies;
+use crate::crypto::{
+    CryptoError, HybridEnvelope, PassphraseWrap, SecretBytes, SecretString, dpapi_protect,
+    dpapi_unprotect, generate_mac, secure_random,
+};
+#[cfg(windows)]
+use crate::desktop::{DesktopAccount, default_data_path, read_accounts, read_biometric_key};
+use crate::error::{BwbioError, Result};
+use crate::keyfile::{self, KeyFile, KeyProvider, WrapAlgorithm, describe_wrap};
+use crate::keystore::{KeyStore, StoreLock, open_key_store};
+use crate::presence::Presence;
+#[cfg(target_os = "linux")]
+use crate::presence::platform_verifier;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::{BTreeMap, HashMap},
     env::current_exe,
-    fs::{create_dir_all, read, read_dir, remove_file, write},
-    path::PathBuf,
+    fs::{read, write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, OnceLock, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tracing::{info, warn};
+#[cfg(windows)]
 use windows_strings::HSTRING;
+use zeroize::Zeroize;
 
-pub struct KeyManager {
-    cng_provider: CngProvider,
-    cng_key: CngKey,
-    bw_key_directory: PathBuf,
-}
-
-impl Default for KeyManager {
-    fn default() -> Self {
-        Self::new(
-            default_key_name(),
-            current_exe()
-                .expect("Failed to get current executable path")
-                .parent()
-                .expect("Failed to get parent directory")
-                .to_path_buf()
-                .join("keys"),
-        )
+/// Entry naming the CNG key after a rotation. Entries starting with a dot are never user ids.
+const ACTIVE_KEY_ENTRY: &str = ".cng-key";
+/// Entry recording a committed CNG key rotation while its staged files are renamed into place;
+/// see [`Rotation`].
+const ROTATION_ENTRY: &str = ".rotation";
+/// Entry holding the sealed key for the key file MACs.
+const INTEGRITY_ENTRY: &str = ".integrity";
+/// Unix time of the last `bwbio lock`; cached keys from before it are dropped by every process.
+const LOCKED_AT_ENTRY: &str = ".locked-at";
+/// DPAPI-encrypted map of user id to metadata that replaces the `.meta.json` entries when key
+/// names are hashed.
+const INDEX_ENTRY: &str = ".index";
+
+/// Name of the CNG key that wraps the keys in `store`: the one recorded by the last rotation, or
+/// `fallback` if the keys were never rotated.
+#[cfg(windows)]
+pub fn active_cng_key_name(store: &dyn KeyStore, fallback: HSTRING) -> HSTRING {
+    store
+        .read(ACTIVE_KEY_ENTRY)
+        .ok()
+        .flatten()
+        .map(|name| String::from_utf8_lossy(&name).trim().to_string())
+        .filter(|name| !name.is_empty())
+        .map(HSTRING::from)
+        .unwrap_or(fallback)
+}
+
+/// The swap a CNG key rotation still has to make once its keys are re-wrapped into staged
+/// entries. Recorded in [`ROTATION_ENTRY`] before the first rename, so a rotation cut short is
+/// finished by the next key manager instead of leaving keys wrapped by two different CNG keys.
+#[derive(Debug, Serialize, Deserialize)]
+struct Rotation {
+    /// Staged entries and the entries they replace.
+    renames: Vec<(String, String)>,
+    /// The new CNG key, recorded in [`ACTIVE_KEY_ENTRY`] after the renames.
+    cng_key: String,
+    /// The replaced CNG key, deleted once the swap is done in one go.
+    old_cng_key: String,
+}
+
+impl Rotation {
+    /// Records the rotation in `store`, then makes the swap.
+    #[cfg(any(windows, test))]
+    fn commit(&self, store: &dyn KeyStore) -> Result<()> {
+        store.write(ROTATION_ENTRY, &serde_json::to_vec(self)?)?;
+        self.apply(store)
+    }
+
+    /// Renames what is still staged, names the new CNG key active and drops the record.
+    fn apply(&self, store: &dyn KeyStore) -> Result<()> {
+        for (staged, entry) in &self.renames {
+            if store.exists(staged)? {
+                store.rename(staged, entry)?;
+            }
+        }
+        store.write(ACTIVE_KEY_ENTRY, self.cng_key.as_bytes())?;
+        store.delete(ROTATION_ENTRY)
     }
 }
 
-impl KeyManager {
-    pub fn new(cng_key_name: HSTRING, bw_key_directory: PathBuf) -> Self {
-        let cng_provider = CngProvider::new().expect("Failed to create CNG provider");
-        let cng_key = cng_provider
-            .open_key(cng_key_name)
-            .expect("Failed to open CNG key");
-        Self {
-            cng_provider,
-            cng_key,
-            bw_key_directory,
+/// Finishes a rotation an earlier run recorded in `store` but didn't complete. Its old CNG key
+/// is left for `bwbio cng delete`, as nobody is there to confirm deleting it.
+fn finish_rotation(store: &dyn KeyStore, lock: &StoreLock) -> Result<()> {
+    if !store.exists(ROTATION_ENTRY)? {
+        return Ok(());
+    }
+    let _lock = lock.acquire()?;
+    let Some(recorded) = store.read(ROTATION_ENTRY)? else {
+        return Ok(());
+    };
+    let rotation: Rotation = serde_json::from_slice(&recorded)?;
+    rotation.apply(store)?;
+    warn!(
+        to = rotation.cng_key,
+        old = rotation.old_cng_key,
+        "Finished an interrupted CNG key rotation; the old key is left in place"
+    );
+    Ok(())
+}
+
+/// The provider the key files in `store` record: the TPM if any file is sealed to it, so the
+/// keys can't be opened without it, else the software provider if a file records it. `None`
+/// when no file records one (no keys, or only files from before version 4). Unreadable entries
+/// are skipped; their MACs are checked when they are read.
+#[cfg(any(windows, test))]
+fn stored_provider(store: &dyn KeyStore) -> Option<KeyProvider> {
+    let providers = store
+        .list()
+        .unwrap_or_default()
+        .iter()
+        .filter(|name| !name.starts_with('.'))
+        .filter_map(|name| store.read(name).ok().flatten())
+        .filter(|bytes| KeyFile::has_header(bytes))
+        .filter_map(|bytes| KeyFile::from_bytes(&bytes).ok()?.provider)
+        .collect::<Vec<_>>();
+    [KeyProvider::Tpm, KeyProvider::Software]
+        .into_iter()
+        .find(|provider| providers.contains(provider))
+}
+
+/// `BW_KEY_DIR`, the configured `key_dir`, or the `keys` directory next to the executable
+/// (`$XDG_DATA_HOME/bwbio/keys` on Linux, where executables live in shared directories).
+pub fn default_key_dir() -> Result<PathBuf> {
+    #[cfg(target_os = "linux")]
+    if let Ok(data_home) = crate::xdg::data_home() {
+        return Ok(config().key_dir(data_home.join("bwbio").join("keys")));
+    }
+    let exe = current_exe()?;
+    let Some(install_dir) = exe.parent() else {
+        return Err(KeyManagerError::NoParentDirectory(exe).into());
+    };
+    Ok(config().key_dir(install_dir.join("keys")))
+}
+
+#[derive(Debug, Error)]
+pub enum KeyManagerError {
+    #[error("Invalid user id {0:?}: expected letters, digits, '-' or '_' (at most 64)")]
+    InvalidUserId(String),
+    #[error("No key stored for {0}")]
+    KeyNotFound(String),
+    #[error("Key for {user_id} expired after {days} days without use; import it again")]
+    KeyExpired { user_id: String, days: u32 },
+    #[error("Key file integrity check failed for {0}")]
+    IntegrityCheckFailed(String),
+    #[error("Key file for {0} has no MAC, but the key store uses them; import the key again")]
+    UnauthenticatedKeyFile(String),
+    #[error("Key file integrity key is unreadable: {0}")]
+    IntegrityKeyUnreadable(Box<BwbioError>),
+    #[error("Key for {user_id} is wrapped with {stored}, but the current key is {current}")]
+    WrapMismatch {
+        user_id: String,
+        stored: String,
+        current: String,
+    },
+    #[error("Key file predates hybrid wrapping and needs {0}")]
+    LegacyKeyFile(&'static str),
+    #[error("A key is already stored for {0}")]
+    KeyExists(String),
+    #[error("No DPAPI copy stored for {0}")]
+    NoDpapiCopy(String),
+    #[error("{} has no parent directory", .0.display())]
+    NoParentDirectory(PathBuf),
+    #[error("{0} needs the CNG backend")]
+    NeedsCng(&'static str),
+    #[cfg(windows)]
+    #[error("CNG key already uses {0}")]
+    AlgorithmUnchanged(CngKeyAlgorithm),
+    #[cfg(windows)]
+    #[error(
+        "The TPM can't be opened ({0}), but the stored keys are sealed to it; bwbio won't fall back to the software key provider"
+    )]
+    TpmRequired(String),
+    #[cfg(windows)]
+    #[error(
+        "The TPM can't be opened ({0}), and no stored key records the software key provider; set allow_software_ksp to use it"
+    )]
+    SoftwareKspRefused(String),
+    #[error("CNG key is already named {0}")]
+    NameUnchanged(String),
+    #[error("A CNG key named {0} already exists")]
+    CngKeyExists(String),
+    #[error("{} is not a bwbio backup", .0.display())]
+    NotABackup(PathBuf),
+    #[error("Unsupported bwbio backup version")]
+    UnsupportedBackupVersion,
+}
+
+/// User ids name key store entries, so they are limited to what a Bitwarden user id (a UUID)
+/// can contain; anything else could reach outside the key directory.
+pub fn validate_user_id(user_id: &str) -> Result<(), KeyManagerError> {
+    let valid = (1..=64).contains(&user_id.len())
+        && user_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if valid {
+        Ok(())
+    } else {
+        Err(KeyManagerError::InvalidUserId(user_id.to_string()))
+    }
+}
+
+/// Suffix of the metadata entry stored next to each key.
+const METADATA_SUFFIX: &str = ".meta.json";
+/// Suffix of the optional DPAPI-protected second copy of a key.
+const DPAPI_SUFFIX: &str = ".dpapi";
+
+/// Unencrypted details kept next to a key file so listings can show more than the user id.
+/// Times are Unix seconds.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_exported_at: Option<u64>,
+    /// Refuse the key once it has gone unused for this many days.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expire_after_days: Option<u32>,
+    /// Delete an expired key instead of only refusing it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub delete_when_expired: bool,
+}
+
+impl KeyMetadata {
+    /// Whether the key has gone unused for longer than its expiry at `now` (Unix seconds). Keys
+    /// without a recorded import or export time never expire.
+    pub fn is_expired(&self, now: u64) -> bool {
+        match (
+            self.expire_after_days,
+            self.last_exported_at.or(self.imported_at),
+        ) {
+            (Some(days), Some(last_used)) => {
+                now.saturating_sub(last_used) > u64::from(days) * 86400
+            }
+            _ => false,
         }
     }
+}
+
+/// A stored key as listed by [`KeyManager::list_keys`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredKey {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub metadata: KeyMetadata,
+}
+
+/// Result of [`KeyManager::verify_keys`] for one key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "status", content = "detail")]
+pub enum KeyHealth {
+    Healthy,
+    /// The file was damaged or changed since bwbio wrote it.
+    IntegrityFailed(String),
+    /// The current CNG key or TPM2 can't unwrap it, typically after a TPM reset.
+    Undecryptable(String),
+}
+
+/// Magic and version at the start of a backup archive.
+const BACKUP_MAGIC: &[u8] = b"BWBIOBAK";
+const BACKUP_VERSION: u8 = 1;
+
+/// Plaintext content of a backup archive, before passphrase encryption.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BackupEntry {
+    user_id: String,
+    key: String,
+    #[serde(default)]
+    metadata: KeyMetadata,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Returns true when `hash_key_names` (or `BW_HASH_KEY_NAMES`) asks for key entries named by a
+/// hash of the user id.
+pub fn hashed_key_names_selected() -> bool {
+    config().hash_key_names()
+}
+
+/// How long exported keys stay cached, from `BW_KEY_CACHE_SECONDS` or the configured
+/// `key_cache_seconds` (default: no caching).
+pub fn key_cache_ttl() -> Duration {
+    config()
+        .key_cache_seconds()
+        .map(Duration::from_secs)
+        .unwrap_or_default()
+}
 
-    pub fn cng_provider(&self) -> &CngProvider {
-        &self.cng_provider
+struct CachedKey {
+    key: SecretString,
+    /// Unix seconds, compared against [`LOCKED_AT_ENTRY`].
+    cached_at: u64,
+    expires: Instant,
+}
+
+/// Returns true when `dpapi_backup` (or `BW_DPAPI_BACKUP`) asks for a DPAPI-protected second
+/// copy of every key.
+pub fn dpapi_backup_selected() -> bool {
+    config().dpapi_backup()
+}
+
+/// Returns true when `cng_machine_key` (or `BW_CNG_MACHINE_KEY`) selects an
+/// administrator-provisioned machine-wide key.
+pub fn machine_key_selected() -> bool {
+    config().cng_machine_key()
+}
+
+/// Deletes a CNG key, after a Windows Hello check unless `cng_delete_verify` is off.
+#[cfg(windows)]
+pub fn delete_cng_key(key: CngKey) -> Result<()> {
+    if config().cng_delete_verify() {
+        key.delete_verified()
+    } else {
+        key.delete()
     }
+}
 
-    pub fn cng_key(&self) -> &CngKey {
-        &self.cng_key
+/// Options for a newly created CNG key: algorithm and size from `cng_algorithm` (default
+/// RSA-2048), and a KSP-enforced gesture when `cng_gesture` is on.
+#[cfg(windows)]
+pub fn cng_key_options() -> CngKeyOptions {
+    CngKeyOptions {
+        algorithm: config().cng_algorithm(),
+        gesture_bound: config().cng_gesture(),
     }
+}
 
-    pub fn list_keys(&self) -> Result<Vec<String>> {
-        let mut keys = Vec::new();
-        if self.bw_key_directory.exists() {
-            for entry in read_dir(&self.bw_key_directory)? {
-                let entry = entry?;
-                if entry.file_type()?.is_file() {
-                    if let Some(name) = entry.file_name().to_str() {
-                        keys.push(name.to_string());
-                    }
+#[cfg(windows)]
+struct OpenCng {
+    // Fields drop in order, so the key handle is released before its provider.
+    key: CngKey,
+    provider: CngProvider,
+}
+
+/// The CNG key, opened (and created if missing) on first use, so operations that only touch
+/// the key directory never create a TPM key or fail on provider errors.
+#[cfg(windows)]
+pub struct LazyCng {
+    key_name: HSTRING,
+    /// What the stored key files record, from [`stored_provider`]. Without a record the
+    /// Software KSP is only used when `allow_software_ksp` is on.
+    stored: Option<KeyProvider>,
+    opened: OnceLock<OpenCng>,
+}
+
+#[cfg(windows)]
+impl LazyCng {
+    fn new(key_name: HSTRING, stored: Option<KeyProvider>) -> Self {
+        Self {
+            key_name,
+            stored,
+            opened: OnceLock::new(),
+        }
+    }
+
+    fn get(&self) -> Result<&OpenCng> {
+        if let Some(opened) = self.opened.get() {
+            return Ok(opened);
+        }
+        let mut provider = match self.stored {
+            Some(KeyProvider::Tpm) => CngProvider::open(CngProviderKind::Platform)
+                .map_err(|e| KeyManagerError::TpmRequired(e.to_string()))?,
+            Some(KeyProvider::Software) => CngProvider::open(CngProviderKind::Software)?,
+            None => match CngProvider::open(CngProviderKind::Platform) {
+                Ok(provider) => provider,
+                Err(e) if config().allow_software_ksp() => {
+                    warn!("The TPM can't be opened ({e}); using the software key provider");
+                    CngProvider::open(CngProviderKind::Software)?
                 }
+                Err(e) => return Err(KeyManagerError::SoftwareKspRefused(e.to_string()).into()),
+            },
+        };
+        if machine_key_selected() {
+            provider = provider.machine_scope();
+        }
+        let key = provider.open_or_create_key(self.key_name.clone(), cng_key_options())?;
+        Ok(self.opened.get_or_init(|| OpenCng { key, provider }))
+    }
+
+    fn get_mut(&mut self) -> Result<&mut OpenCng> {
+        self.get()?;
+        Ok(self.opened.get_mut().expect("CNG key was just opened"))
+    }
+}
+
+/// Protects the per-file data keys at rest. bwbio's own wrappers are the CNG key ([`LazyCng`]),
+/// and the TPM2 on Linux ([`crate::tpm2::Tpm2KeyWrap`]); [`KeyManager::with_wrapper`] takes any
+/// other, such as the in-memory [`crate::mock::MemoryKeyWrap`].
+pub trait KeyWrapper: Send + Sync {
+    fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>>;
+
+    /// The wrap algorithm and CNG key name recorded in key file headers.
+    fn describe(&self) -> Result<(WrapAlgorithm, String)>;
+
+    /// Also returns whether `wrapped` uses a legacy scheme and should be re-wrapped. Without a
+    /// `presence` token from [`KeyWrapper::with_user_presence`] it checks for the user first.
+    fn unwrap(&self, wrapped: &[u8], presence: Option<&Presence>) -> Result<(SecretBytes, bool)>;
+
+    /// Runs `f` once after a single user presence check showing `reason`, handing it the token
+    /// that lets its unwraps skip their own check; see [`Presence::scope`].
+    fn with_user_presence(
+        &self,
+        reason: &str,
+        f: &mut dyn FnMut(&Presence) -> Result<()>,
+    ) -> Result<()>;
+
+    /// Human-readable description of what protects the stored keys.
+    fn protection(&self) -> String;
+
+    /// Where the wrapping key lives, recorded in key file headers; `None` when unknown.
+    fn provider(&self) -> Result<Option<KeyProvider>> {
+        Ok(None)
+    }
+}
+
+#[cfg(windows)]
+impl KeyWrapper for LazyCng {
+    fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>> {
+        let key = &self.get()?.key;
+        match key.algorithm()? {
+            CngKeyAlgorithm::Rsa { .. } => key.encrypt(data_key),
+            CngKeyAlgorithm::Ecdh(curve) => {
+                Ecies::wrap(curve, &key.export_ecc_public_key()?, data_key)
+            }
+        }
+    }
+
+    fn describe(&self) -> Result<(WrapAlgorithm, String)> {
+        let key = &self.get()?.key;
+        let algorithm = match key.algorithm()? {
+            CngKeyAlgorithm::Rsa { .. } => WrapAlgorithm::RsaOaep,
+            CngKeyAlgorithm::Ecdh(curve) => WrapAlgorithm::Ecies(curve),
+        };
+        Ok((algorithm, key.name()?.to_string_lossy()))
+    }
+
+    fn unwrap(&self, wrapped: &[u8], presence: Option<&Presence>) -> Result<(SecretBytes, bool)> {
+        let OpenCng { key, provider } = self.get()?;
+        match key.algorithm()? {
+            CngKeyAlgorithm::Rsa { .. } => {
+                let (data_key, padding) = key.decrypt(wrapped, presence)?;
+                Ok((data_key, padding == CngPadding::Pkcs1))
+            }
+            CngKeyAlgorithm::Ecdh(curve) => Ecies::unwrap(curve, wrapped, |ephemeral| {
+                key.derive_shared_secret(
+                    &provider.import_ecc_public_key(curve, ephemeral)?,
+                    presence,
+                )
+            })
+            .map(|data_key| (data_key, false)),
+        }
+    }
+
+    fn with_user_presence(
+        &self,
+        reason: &str,
+        f: &mut dyn FnMut(&Presence) -> Result<()>,
+    ) -> Result<()> {
+        self.get()?.key.with_user_presence(reason, f)
+    }
+
+    fn protection(&self) -> String {
+        match self.get() {
+            Ok(OpenCng { key, provider }) => {
+                let algorithm = key
+                    .algorithm()
+                    .map(|a| a.to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                format!("{} ({algorithm})", provider.kind())
+            }
+            Err(e) => format!("CNG key unavailable: {e}"),
+        }
+    }
+
+    fn provider(&self) -> Result<Option<KeyProvider>> {
+        Ok(Some(match self.get()?.provider.kind() {
+            CngProviderKind::Platform => KeyProvider::Tpm,
+            CngProviderKind::Software => KeyProvider::Software,
+        }))
+    }
+}
+
+/// How the per-file data keys are protected at rest.
+pub enum KeyWrap {
+    #[cfg(windows)]
+    Cng(LazyCng),
+    /// The TPM2 on Linux, or a wrapper given to [`KeyManager::with_wrapper`].
+    Software(Box<dyn KeyWrapper>),
+}
+
+impl KeyWrap {
+    fn wrapper(&self) -> &dyn KeyWrapper {
+        match self {
+            #[cfg(windows)]
+            KeyWrap::Cng(cng) => cng,
+            KeyWrap::Software(wrapper) => wrapper.as_ref(),
+        }
+    }
+
+    /// [`KeyWrapper::with_user_presence`] for an `f` that returns a value.
+    fn with_user_presence<T>(
+        &self,
+        reason: &str,
+        f: impl FnOnce(&Presence) -> Result<T>,
+    ) -> Result<T> {
+        let mut f = Some(f);
+        let mut value = None;
+        self.wrapper().with_user_presence(reason, &mut |presence| {
+            if let Some(f) = f.take() {
+                value = Some(f(presence)?);
             }
+            Ok(())
+        })?;
+        Ok(value.expect("KeyWrapper::with_user_presence ran f"))
+    }
+}
+
+pub struct KeyManager {
+    wrap: KeyWrap,
+    store: Box<dyn KeyStore>,
+    lock: StoreLock,
+    audit: AuditLog,
+    source: AuditSource,
+    integrity_key: OnceLock<SecretBytes>,
+    hashed_names: bool,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, CachedKey>>,
+}
+
+impl KeyManager {
+    /// The key manager for [`default_key_dir`] and the configured CNG key name (the TPM2 on
+    /// Linux). CNG and configuration errors are returned instead of panicking.
+    pub fn from_env() -> Result<Self> {
+        let bw_key_directory = default_key_dir()?;
+        #[cfg(windows)]
+        return Self::try_new(config().cng_key_name(), bw_key_directory);
+        #[cfg(target_os = "linux")]
+        return Self::with_tpm2(bw_key_directory);
+        #[cfg(not(any(windows, target_os = "linux")))]
+        {
+            let _ = bw_key_directory;
+            Err(KeyManagerError::NeedsCng("Unlocking").into())
         }
-        Ok(keys)
+    }
+
+    /// Keys live in the store selected by `BW_KEY_STORE`; `bw_key_directory` is used by the
+    /// default file store.
+    #[cfg(windows)]
+    pub fn new(cng_key_name: HSTRING, bw_key_directory: PathBuf) -> Result<Self> {
+        let store = open_key_store(bw_key_directory.clone())?;
+        let lock = StoreLock::new(&bw_key_directory);
+        finish_rotation(&*store, &lock)?;
+        let cng_key_name = active_cng_key_name(&*store, cng_key_name);
+        let stored = stored_provider(&*store);
+        Ok(Self {
+            wrap: KeyWrap::Cng(LazyCng::new(cng_key_name, stored)),
+            store,
+            lock,
+            audit: AuditLog::new(bw_key_directory),
+            source: AuditSource::default(),
+            integrity_key: OnceLock::new(),
+            hashed_names: hashed_key_names_selected(),
+            cache_ttl: key_cache_ttl(),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Like [`KeyManager::new`], but opens (or creates) the CNG key right away so a missing TPM
+    /// or inaccessible key is reported here rather than on the first export.
+    #[cfg(windows)]
+    pub fn try_new(cng_key_name: HSTRING, bw_key_directory: PathBuf) -> Result<Self> {
+        let kmgr = Self::new(cng_key_name, bw_key_directory)?;
+        kmgr.cng_key()?;
+        Ok(kmgr)
+    }
+
+    /// Seals keys to the TPM2 with `systemd-creds`, the Linux counterpart of the CNG key.
+    #[cfg(target_os = "linux")]
+    pub fn with_tpm2(bw_key_directory: PathBuf) -> Result<Self> {
+        let wrapper = crate::tpm2::Tpm2KeyWrap::new(platform_verifier());
+        Self::with_wrapper(Box::new(wrapper), bw_key_directory)
+    }
+
+    /// Wraps keys with `wrapper` instead of the CNG key, such as an in-memory one so the key
+    /// manager can be exercised without a TPM.
+    pub fn with_wrapper(wrapper: Box<dyn KeyWrapper>, bw_key_directory: PathBuf) -> Result<Self> {
+        let store = open_key_store(bw_key_directory.clone())?;
+        let lock = StoreLock::new(&bw_key_directory);
+        finish_rotation(&*store, &lock)?;
+        Ok(Self {
+            wrap: KeyWrap::Software(wrapper),
+            store,
+            lock,
+            audit: AuditLog::new(bw_key_directory),
+            source: AuditSource::default(),
+            integrity_key: OnceLock::new(),
+            hashed_names: hashed_key_names_selected(),
+            cache_ttl: key_cache_ttl(),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Names the part of bwbio that audit log entries are attributed to (default: CLI).
+    pub fn with_audit_source(mut self, source: AuditSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    // Best effort: a full disk or read-only log must not block key operations, but the failure
+    // is logged so a gap in the audit log can be explained.
+    fn record(&self, action: AuditAction, user_id: &str, error: Option<String>) {
+        match &error {
+            Some(error) => warn!(user_id, source = %self.source, "Key {action}: {error}"),
+            None => info!(user_id, source = %self.source, "Key {action}"),
+        }
+        let appended = self.audit.append(&AuditEntry {
+            time: unix_now(),
+            action,
+            user_id: user_id.to_string(),
+            source: self.source,
+            error,
+        });
+        if let Err(e) = appended {
+            warn!(user_id, "Failed to write the audit log: {e}");
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn uses_cng(&self) -> bool {
+        matches!(self.wrap, KeyWrap::Cng(_))
+    }
+
+    /// Opens the CNG provider and key if that hasn't happened yet.
+    #[cfg(windows)]
+    pub fn cng_provider(&self) -> Result<Option<&CngProvider>> {
+        match &self.wrap {
+            KeyWrap::Cng(cng) => Ok(Some(&cng.get()?.provider)),
+            KeyWrap::Software(_) => Ok(None),
+        }
+    }
+
+    /// Opens the CNG key, creating it if missing, if that hasn't happened yet.
+    #[cfg(windows)]
+    pub fn cng_key(&self) -> Result<Option<&CngKey>> {
+        match &self.wrap {
+            KeyWrap::Cng(cng) => Ok(Some(&cng.get()?.key)),
+            KeyWrap::Software(_) => Ok(None),
+        }
+    }
+
+    /// Human-readable description of what protects the stored keys.
+    pub fn protection(&self) -> String {
+        self.wrap.wrapper().protection()
+    }
+
+    pub fn list_keys(&self) -> Result<Vec<StoredKey>> {
+        Ok(self
+            .list_user_ids()?
+            .into_iter()
+            .map(|user_id| StoredKey {
+                metadata: self.key_metadata(&user_id),
+                user_id,
+            })
+            .collect())
+    }
+
+    fn list_user_ids(&self) -> Result<Vec<String>> {
+        if self.hashed_names {
+            return Ok(self.read_index()?.into_keys().collect());
+        }
+        Ok(self
+            .store
+            .list()?
+            .into_iter()
+            // The log and its rotations share the directory; user ids never contain a dot.
+            .filter(|name| {
+                !name.starts_with('.')
+                    && !name.ends_with(METADATA_SUFFIX)
+                    && !name.ends_with(DPAPI_SUFFIX)
+                    && validate_user_id(name).is_ok()
+            })
+            .collect())
+    }
+
+    /// Store entry holding the key for `user_id`: the user id itself, or with `hash_key_names`
+    /// the first 128 bits of its SHA-256 in hex, so the store doesn't reveal which accounts are
+    /// set up.
+    fn key_entry(&self, user_id: &str) -> String {
+        if self.hashed_names {
+            Sha256::digest(user_id.as_bytes())[..16]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect()
+        } else {
+            user_id.to_string()
+        }
+    }
+
+    fn metadata_entry(&self, user_id: &str) -> String {
+        format!("{}{METADATA_SUFFIX}", self.key_entry(user_id))
+    }
+
+    fn dpapi_entry(&self, user_id: &str) -> String {
+        format!("{}{DPAPI_SUFFIX}", self.key_entry(user_id))
+    }
+
+    fn read_index(&self) -> Result<BTreeMap<String, KeyMetadata>> {
+        match self.store.read(INDEX_ENTRY)? {
+            Some(protected) => Ok(serde_json::from_slice(
+                dpapi_unprotect(&protected)?.expose(),
+            )?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    fn write_index(&self, index: &BTreeMap<String, KeyMetadata>) -> Result<()> {
+        let json = SecretBytes::from(serde_json::to_vec(index)?);
+        self.store
+            .write(INDEX_ENTRY, &dpapi_protect(json.expose())?)
+    }
+
+    /// Metadata recorded for `user_id`; empty for keys imported before metadata existed.
+    pub fn key_metadata(&self, user_id: &str) -> KeyMetadata {
+        if validate_user_id(user_id).is_err() {
+            return KeyMetadata::default();
+        }
+        if self.hashed_names {
+            return self
+                .read_index()
+                .ok()
+                .and_then(|mut index| index.remove(user_id))
+                .unwrap_or_default();
+        }
+        self.store
+            .read(&self.metadata_entry(user_id))
+            .ok()
+            .flatten()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn write_metadata(&self, user_id: &str, metadata: &KeyMetadata) -> Result<()> {
+        if self.hashed_names {
+            let mut index = self.read_index()?;
+            index.insert(user_id.to_string(), metadata.clone());
+            return self.write_index(&index);
+        }
+        self.store.write(
+            &self.metadata_entry(user_id),
+            &serde_json::to_vec_pretty(metadata)?,
+        )
+    }
+
+    fn delete_metadata(&self, user_id: &str) -> Result<()> {
+        if self.hashed_names {
+            let mut index = self.read_index()?;
+            if index.remove(user_id).is_some() {
+                self.write_index(&index)?;
+            }
+            return Ok(());
+        }
+        self.store.delete(&self.metadata_entry(user_id))
+    }
+
+    fn update_metadata(&self, user_id: &str, update: impl FnOnce(&mut KeyMetadata)) -> Result<()> {
+        let mut metadata = self.key_metadata(user_id);
+        update(&mut metadata);
+        self.write_metadata(user_id, &metadata)
     }
 
     pub fn import_key(&self, user_id: &str, bw_key: &str) -> Result<()> {
-        create_dir_all(&self.bw_key_directory)?;
-        let encrypted = self.cng_key.encrypt(bw_key.as_bytes())?;
-        let file_path = self.bw_key_directory.join(user_id);
-        write(file_path, encrypted)?;
+        let _lock = self.lock.acquire()?;
+        self.store_key(user_id, bw_key)
+    }
+
+    fn store_key(&self, user_id: &str, bw_key: &str) -> Result<()> {
+        validate_user_id(user_id)?;
+        self.cache().remove(user_id);
+        self.write_key(user_id, bw_key.as_bytes())?;
+        self.write_dpapi_copy(user_id, bw_key.as_bytes())?;
+        self.record(AuditAction::Import, user_id, None);
+        self.update_metadata(user_id, |m| m.imported_at = Some(unix_now()))
+    }
+
+    /// Recovers an account's user key with its master password and imports it.
+    #[cfg(windows)]
+    pub fn import_desktop_account(
+        &self,
+        account: &DesktopAccount,
+        master_password: &str,
+    ) -> Result<()> {
+        let user_key = account.decrypt_user_key(master_password)?;
+        let _lock = self.lock.acquire()?;
+        self.store_key(account.user_id(), user_key.expose())?;
+        self.update_metadata(account.user_id(), |m| {
+            m.email = Some(account.email().to_string());
+            m.server_url = account.server_url().map(str::to_string);
+        })
+    }
+
+    /// Imports the key Bitwarden desktop stored for biometric unlock of `user_id` (Require
+    /// Windows Hello), so desktop users can switch without extracting the key by hand. The
+    /// account email is taken from desktop's `data.json` when it is there.
+    #[cfg(windows)]
+    pub fn import_desktop_biometric(&self, user_id: &str) -> Result<()> {
+        validate_user_id(user_id)?;
+        let user_key = read_biometric_key(user_id)?;
+        let account = default_data_path()
+            .and_then(|path| read_accounts(&path).ok())
+            .and_then(|accounts| accounts.into_iter().find(|a| a.user_id() == user_id));
+        let _lock = self.lock.acquire()?;
+        self.store_key(user_id, user_key.expose())?;
+        self.update_metadata(user_id, |m| {
+            if let Some(account) = &account {
+                m.email = Some(account.email().to_string());
+                m.server_url = account.server_url().map(str::to_string);
+            }
+        })
+    }
+
+    /// Records which server `user_id` belongs to, or forgets it with `None`.
+    pub fn set_server_url(&self, user_id: &str, server_url: Option<&str>) -> Result<()> {
+        validate_user_id(user_id)?;
+        let _lock = self.lock.acquire()?;
+        if !self.store.exists(&self.key_entry(user_id))? {
+            return Err(KeyManagerError::KeyNotFound(user_id.to_string()).into());
+        }
+        self.update_metadata(user_id, |m| {
+            m.server_url = server_url.map(|url| url.trim_end_matches('/').to_string())
+        })
+    }
+
+    /// Keeps the DPAPI copy of `user_id` when `dpapi_backup` is on.
+    fn write_dpapi_copy(&self, user_id: &str, bw_key: &[u8]) -> Result<()> {
+        if dpapi_backup_selected() {
+            self.store
+                .write(&self.dpapi_entry(user_id), &dpapi_protect(bw_key)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn has_dpapi_copy(&self, user_id: &str) -> Result<bool> {
+        validate_user_id(user_id)?;
+        self.store.exists(&self.dpapi_entry(user_id))
+    }
+
+    /// Replaces the stored key for `user_id` with its DPAPI copy, re-wrapped by the current CNG
+    /// key. For keys the CNG key can no longer unwrap, e.g. after a TPM clear.
+    pub fn recover_from_dpapi(&self, user_id: &str) -> Result<()> {
+        validate_user_id(user_id)?;
+        let _lock = self.lock.acquire()?;
+        let Some(protected) = self.store.read(&self.dpapi_entry(user_id))? else {
+            return Err(KeyManagerError::NoDpapiCopy(user_id.to_string()).into());
+        };
+        let bw_key = dpapi_unprotect(&protected)?;
+        self.cache().remove(user_id);
+        self.write_key(user_id, bw_key.expose())?;
+        self.record(AuditAction::Import, user_id, None);
+        Ok(())
+    }
+
+    /// Key for the key file MACs. It is sealed like a stored key in the `.integrity` entry and
+    /// created on first use, so opening it needs the CNG key (or TPM2) and user presence;
+    /// it is cached for the life of the key manager.
+    fn integrity_key(&self, presence: Option<&Presence>) -> Result<&[u8; 32]> {
+        if let Some(key) = self.integrity_key.get() {
+            return Ok(key.expose().try_into().expect("checked when cached"));
+        }
+        let key = match self.store.read(INTEGRITY_ENTRY)? {
+            Some(sealed) => self
+                .open_with(&sealed, presence)
+                .map_err(|e| KeyManagerError::IntegrityKeyUnreadable(Box::new(e)))?,
+            None => {
+                let mut key = SecretBytes::from(vec![0u8; 32]);
+                secure_random(key.expose_mut())?;
+                self.store
+                    .write(INTEGRITY_ENTRY, &self.seal(key.expose())?)?;
+                let key = self.integrity_key.get_or_init(|| key);
+                // From now on files without a MAC are refused, so the ones from before the
+                // integrity key get theirs now.
+                self.stamp_unauthenticated_files(presence)?;
+                return Ok(key.expose().try_into().expect("checked when cached"));
+            }
+        };
+        if key.expose().len() != 32 {
+            return Err(CryptoError::KeyLength(key.expose().len()).into());
+        }
+        let key = self.integrity_key.get_or_init(|| key);
+        Ok(key.expose().try_into().expect("checked when cached"))
+    }
+
+    /// Adds a MAC to every stored key file without one, without decrypting it. Headerless files
+    /// get a header for the current wrap; their envelope stays as it was.
+    fn stamp_unauthenticated_files(&self, presence: Option<&Presence>) -> Result<()> {
+        for user_id in self.list_user_ids()? {
+            let entry = self.key_entry(&user_id);
+            let Some(bytes) = self.store.read(&entry)? else {
+                continue;
+            };
+            let mut file = if KeyFile::has_header(&bytes) {
+                KeyFile::from_bytes(&bytes)?
+            } else {
+                let (algorithm, key_name) = self.wrap.wrapper().describe()?;
+                KeyFile {
+                    algorithm,
+                    key_name,
+                    provider: None,
+                    version: 1,
+                    mac: None,
+                    envelope: bytes,
+                }
+            };
+            if file.mac.is_some() {
+                continue;
+            }
+            file.version = keyfile::VERSION;
+            file.mac = Some(self.key_file_mac(&file, &user_id, presence)?);
+            self.store.write(&entry, &file.to_bytes())?;
+        }
         Ok(())
     }
 
+    /// Whether a key file without a MAC must be refused: the integrity key exists, or another
+    /// file carries a MAC, so the integrity key was removed.
+    fn requires_mac(&self) -> Result<bool> {
+        if self.store.exists(INTEGRITY_ENTRY)? {
+            return Ok(true);
+        }
+        for user_id in self.list_user_ids()? {
+            if let Some(bytes) = self.store.read(&self.key_entry(&user_id))?
+                && KeyFile::has_header(&bytes)
+                && KeyFile::from_bytes(&bytes).is_ok_and(|file| file.mac.is_some())
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn key_file_mac(
+        &self,
+        file: &KeyFile,
+        user_id: &str,
+        presence: Option<&Presence>,
+    ) -> Result<[u8; 32]> {
+        Ok(generate_mac(
+            self.integrity_key(presence)?,
+            &[],
+            &file.mac_input(user_id),
+        ))
+    }
+
+    fn write_key(&self, user_id: &str, bw_key: &[u8]) -> Result<()> {
+        self.write_key_entry(&self.key_entry(user_id), user_id, bw_key)
+    }
+
+    /// Writes the key of `user_id` to `entry`, which differs from its own while staged.
+    fn write_key_entry(&self, entry: &str, user_id: &str, bw_key: &[u8]) -> Result<()> {
+        let (algorithm, key_name) = self.wrap.wrapper().describe()?;
+        let mut file = KeyFile {
+            algorithm,
+            key_name,
+            provider: self.wrap.wrapper().provider()?,
+            version: keyfile::VERSION,
+            mac: None,
+            envelope: self.seal(bw_key)?,
+        };
+        file.mac = Some(self.key_file_mac(&file, user_id, None)?);
+        self.store.write(entry, &file.to_bytes())
+    }
+
+    /// Protects `data` of any length the same way as the stored keys: it is sealed under a fresh
+    /// AES-256-GCM data key and only that key is wrapped by the CNG key or TPM2.
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let envelope = HybridEnvelope::seal(data, |data_key| self.wrap.wrapper().wrap(data_key))?;
+        Ok(envelope.to_bytes())
+    }
+
+    /// Reverses [`KeyManager::seal`] (Require biometrics).
+    pub fn open(&self, sealed: &[u8]) -> Result<SecretBytes> {
+        self.open_with(sealed, None)
+    }
+
+    fn open_with(&self, sealed: &[u8], presence: Option<&Presence>) -> Result<SecretBytes> {
+        HybridEnvelope::from_bytes(sealed)?.open(|wrapped| {
+            self.wrap
+                .wrapper()
+                .unwrap(wrapped, presence)
+                .map(|(data_key, _)| data_key)
+        })
+    }
+
     pub fn check_key_exists(&self, user_id: &str) -> Result<bool> {
-        let file_path = self.bw_key_directory.join(user_id);
-        Ok(file_path.exists())
+        validate_user_id(user_id)?;
+        self.store.exists(&self.key_entry(user_id))
+    }
+
+    pub fn export_key(&self, user_id: &str) -> Result<SecretString> {
+        validate_user_id(user_id)?;
+        // The switches guarding a release, such as biometric-only mode, come from there.
+        check_config()?;
+        // One presence check covers the integrity key and the data key.
+        let result = self.check_expiry(user_id).and_then(|_| {
+            self.wrap
+                .with_user_presence(&self.unlock_reason(&[user_id]), |presence| {
+                    match self.cached_key(user_id) {
+                        Some(bw_key) => Ok(bw_key),
+                        None => self.export_unwrap(user_id, presence),
+                    }
+                })
+        });
+        self.finish_export(user_id, result)
+    }
+
+    /// The Windows Hello message for unlocking `user_ids`, naming each account by its email
+    /// when known.
+    fn unlock_reason(&self, user_ids: &[&str]) -> String {
+        let accounts = user_ids
+            .iter()
+            .map(|&user_id| {
+                self.key_metadata(user_id)
+                    .email
+                    .unwrap_or_else(|| user_id.to_string())
+            })
+            .collect::<Vec<_>>();
+        if let Some(text) = &config().prompt_text {
+            return text.replace("{account}", &accounts.join(", "));
+        }
+        match accounts.as_slice() {
+            [account] => format!("Unlock Bitwarden vault for {account}"),
+            _ => format!("Unlock Bitwarden vaults for {}", accounts.join(", ")),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, HashMap<String, CachedKey>> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
-    pub fn export_key(&self, user_id: &str) -> Result<String> {
-        let file_path = self.bw_key_directory.join(user_id);
-        let encrypted = read(file_path)?;
-        let decrypted = self.cng_key.decrypt(&encrypted)?;
-        let bw_key = String::from_utf8(decrypted)?;
+    /// A key exported within the last `BW_KEY_CACHE_SECONDS` and not since locked. Returning it
+    /// skips the TPM decrypt, not the user presence check.
+    fn cached_key(&self, user_id: &str) -> Option<SecretString> {
+        self.prune_cache();
+        let mut cache = self.cache();
+        let cached = cache.get(user_id)?;
+        let locked_at = self
+            .store
+            .read(LOCKED_AT_ENTRY)
+            .ok()
+            .flatten()
+            .and_then(|s| String::from_utf8(s).ok()?.parse::<u64>().ok());
+        if locked_at.is_some_and(|locked_at| locked_at >= cached.cached_at) {
+            cache.clear();
+            return None;
+        }
+        Some(cached.key.clone())
+    }
+
+    /// Drops (and so wipes) cached keys whose time is up.
+    pub fn prune_cache(&self) {
+        let now = Instant::now();
+        self.cache().retain(|_, cached| cached.expires > now);
+    }
+
+    pub fn key_cache_enabled(&self) -> bool {
+        !self.cache_ttl.is_zero()
+    }
+
+    /// Wipes the cached keys of this and, through the store, every other bwbio process.
+    pub fn lock_keys(&self) -> Result<()> {
+        self.cache().clear();
+        self.store
+            .write(LOCKED_AT_ENTRY, unix_now().to_string().as_bytes())
+    }
+
+    /// Exports several keys after a single user presence check (Require biometrics), for
+    /// unlocks of multiple accounts that arrive together. Each key succeeds or fails on its own.
+    pub fn export_keys(&self, user_ids: &[&str]) -> Result<Vec<Result<SecretString>>> {
+        check_config()?;
+        let exported = self
+            .wrap
+            .with_user_presence(&self.unlock_reason(user_ids), |presence| {
+                Ok(user_ids
+                    .iter()
+                    .map(|&user_id| {
+                        let result = validate_user_id(user_id)
+                            .map_err(Into::into)
+                            .and_then(|_| self.check_expiry(user_id))
+                            .and_then(|_| match self.cached_key(user_id) {
+                                Some(bw_key) => Ok(bw_key),
+                                None => self.export_unwrap(user_id, presence),
+                            });
+                        self.finish_export(user_id, result)
+                    })
+                    .collect())
+            });
+        if let Err(e) = &exported {
+            for user_id in user_ids {
+                self.record(AuditAction::ExportFailed, user_id, Some(e.to_string()));
+            }
+        }
+        exported
+    }
+
+    /// Audits an export attempt and, for a released key, records its use.
+    fn finish_export(&self, user_id: &str, result: Result<SecretString>) -> Result<SecretString> {
+        let bw_key = match result {
+            Ok(bw_key) => bw_key,
+            Err(e) => {
+                self.record(AuditAction::ExportFailed, user_id, Some(e.to_string()));
+                return Err(e);
+            }
+        };
+        self.record(AuditAction::Export, user_id, None);
+        // Cache hits keep their original expiry.
+        if self.key_cache_enabled() {
+            self.cache()
+                .entry(user_id.to_string())
+                .or_insert_with(|| CachedKey {
+                    key: bw_key.clone(),
+                    cached_at: unix_now(),
+                    expires: Instant::now() + self.cache_ttl,
+                });
+        }
+        // Best effort: neither the copy nor the time may block an unlock.
+        let Ok(_lock) = self.lock.acquire() else {
+            return Ok(bw_key);
+        };
+        // Keys imported before `dpapi_backup` was on get their copy once they are unwrapped.
+        if dpapi_backup_selected() && !self.has_dpapi_copy(user_id).unwrap_or(true) {
+            let _ = self.write_dpapi_copy(user_id, bw_key.expose().as_bytes());
+        }
+        let _ = self.update_metadata(user_id, |m| m.last_exported_at = Some(unix_now()));
         Ok(bw_key)
     }
 
+    /// Fails for an expired key, deleting it first if its metadata asks for that.
+    fn check_expiry(&self, user_id: &str) -> Result<()> {
+        let metadata = self.key_metadata(user_id);
+        if !metadata.is_expired(unix_now()) {
+            return Ok(());
+        }
+        if metadata.delete_when_expired {
+            let _lock = self.lock.acquire()?;
+            self.remove_key(user_id)?;
+        }
+        Err(KeyManagerError::KeyExpired {
+            user_id: user_id.to_string(),
+            days: metadata.expire_after_days.unwrap_or_default(),
+        }
+        .into())
+    }
+
+    /// Sets how many days `user_id` may go unused before it is refused (or, with `delete`,
+    /// deleted); `None` removes the expiry.
+    pub fn set_expiry(&self, user_id: &str, days: Option<u32>, delete: bool) -> Result<()> {
+        validate_user_id(user_id)?;
+        let _lock = self.lock.acquire()?;
+        if !self.store.exists(&self.key_entry(user_id))? {
+            return Err(KeyManagerError::KeyNotFound(user_id.to_string()).into());
+        }
+        self.update_metadata(user_id, |m| {
+            m.expire_after_days = days;
+            m.delete_when_expired = days.is_some() && delete;
+        })
+    }
+
+    /// Deletes every expired key whose metadata asks for deletion and returns their user ids.
+    pub fn purge_expired(&self) -> Result<Vec<String>> {
+        let _lock = self.lock.acquire()?;
+        let now = unix_now();
+        let mut purged = Vec::new();
+        for key in self.list_keys()? {
+            if key.metadata.delete_when_expired && key.metadata.is_expired(now) {
+                self.remove_key(&key.user_id)?;
+                purged.push(key.user_id);
+            }
+        }
+        Ok(purged)
+    }
+
+    /// [`KeyManager::unwrap_key`] for exports, which run the unlock prompt without the store
+    /// lock; it is taken only for the re-write, and skipped if the file changed meanwhile.
+    fn export_unwrap(&self, user_id: &str, presence: &Presence) -> Result<SecretString> {
+        let entry = self.key_entry(user_id);
+        let read = self.store.read(&entry)?;
+        let (decrypted, legacy) = self.read_key(user_id, presence)?;
+        if legacy {
+            let _lock = self.lock.acquire()?;
+            if self.store.read(&entry)? == read {
+                self.write_key(user_id, decrypted.expose())?;
+            }
+        }
+        decrypted.into_secret_string()
+    }
+
+    /// The caller holds the store lock.
+    fn unwrap_key(&self, user_id: &str, presence: &Presence) -> Result<SecretString> {
+        let (decrypted, legacy) = self.read_key(user_id, presence)?;
+        // Files from before the header, the MAC, OAEP or hybrid wrapping are re-written once
+        // they decrypt.
+        if legacy {
+            self.write_key(user_id, decrypted.expose())?;
+        }
+        decrypted.into_secret_string()
+    }
+
+    /// Decrypts the key of `user_id` without changing the store. The flag is set for files in
+    /// an older format. Only runs under the `presence` check of the caller.
+    fn read_key(&self, user_id: &str, presence: &Presence) -> Result<(SecretBytes, bool)> {
+        let Some(encrypted) = self.store.read(&self.key_entry(user_id))? else {
+            return Err(KeyManagerError::KeyNotFound(user_id.to_string()).into());
+        };
+        let (encrypted, mut legacy) = if KeyFile::has_header(&encrypted) {
+            let file = KeyFile::from_bytes(&encrypted)?;
+            let (algorithm, key_name) = self.wrap.wrapper().describe()?;
+            if (file.algorithm, file.key_name.as_str()) != (algorithm, key_name.as_str()) {
+                return Err(KeyManagerError::WrapMismatch {
+                    user_id: user_id.to_string(),
+                    stored: describe_wrap(file.algorithm, &file.key_name),
+                    current: describe_wrap(algorithm, &key_name),
+                }
+                .into());
+            }
+            match file.mac {
+                // Version 2 MACs don't cover the user id; those files are re-written as version 3.
+                Some(mac)
+                    if self
+                        .key_file_mac(&file, user_id, Some(presence))?
+                        .ct_eq(&mac)
+                        .into() =>
+                {
+                    let outdated = file.version < keyfile::VERSION;
+                    (file.envelope, outdated)
+                }
+                Some(_) => {
+                    return Err(KeyManagerError::IntegrityCheckFailed(user_id.to_string()).into());
+                }
+                None => (self.unauthenticated(user_id, file.envelope)?, true),
+            }
+        } else {
+            (self.unauthenticated(user_id, encrypted)?, true)
+        };
+        match HybridEnvelope::from_bytes(&encrypted) {
+            Ok(envelope) => {
+                let decrypted = envelope.open(|wrapped| {
+                    // Data keys wrapped with PKCS#1 v1.5 count as legacy too.
+                    let (data_key, is_legacy) =
+                        self.wrap.wrapper().unwrap(wrapped, Some(presence))?;
+                    legacy |= is_legacy;
+                    Ok(data_key)
+                })?;
+                Ok((decrypted, legacy))
+            }
+            // Files written before hybrid wrapping hold a single RSA block, which is shorter than
+            // any envelope.
+            Err(_) => Ok((self.read_legacy_key(&encrypted, presence)?, true)),
+        }
+    }
+
+    /// `envelope` of a file without a MAC, which is only trusted while no file has one; see
+    /// [`KeyManager::requires_mac`].
+    fn unauthenticated(&self, user_id: &str, envelope: Vec<u8>) -> Result<Vec<u8>> {
+        if self.requires_mac()? {
+            return Err(KeyManagerError::UnauthenticatedKeyFile(user_id.to_string()).into());
+        }
+        Ok(envelope)
+    }
+
+    /// Decrypts a key file written before hybrid wrapping with the original RSA CNG key.
+    #[cfg(windows)]
+    fn read_legacy_key(&self, encrypted: &[u8], presence: &Presence) -> Result<SecretBytes> {
+        let Some(cng_key) = self.cng_key()? else {
+            return Err(KeyManagerError::LegacyKeyFile("the CNG backend").into());
+        };
+        if !matches!(cng_key.algorithm()?, CngKeyAlgorithm::Rsa { .. }) {
+            return Err(KeyManagerError::LegacyKeyFile("the original RSA key").into());
+        }
+        let (decrypted, _) = cng_key.decrypt(encrypted, Some(presence))?;
+        Ok(decrypted)
+    }
+
+    #[cfg(not(windows))]
+    fn read_legacy_key(&self, _encrypted: &[u8], _presence: &Presence) -> Result<SecretBytes> {
+        Err(KeyManagerError::LegacyKeyFile("the CNG backend").into())
+    }
+
+    /// Decrypts every stored key after a single user presence check (Require biometrics),
+    /// leaving the store untouched, so a TPM reset or a damaged file shows up before an unlock
+    /// fails.
+    pub fn verify_keys(&self) -> Result<Vec<(String, KeyHealth)>> {
+        let _lock = self.lock.acquire()?;
+        let user_ids = self.list_user_ids()?;
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.wrap
+            .with_user_presence("Verify stored Bitwarden keys", |presence| {
+                Ok(user_ids
+                    .into_iter()
+                    .map(|user_id| {
+                        let health = match self.read_key(&user_id, presence) {
+                            Ok(_) => KeyHealth::Healthy,
+                            Err(
+                                e @ BwbioError::Key(
+                                    KeyManagerError::IntegrityCheckFailed(_)
+                                    | KeyManagerError::UnauthenticatedKeyFile(_),
+                                ),
+                            ) => KeyHealth::IntegrityFailed(format!("{e:#}")),
+                            Err(e) => KeyHealth::Undecryptable(format!("{e:#}")),
+                        };
+                        (user_id, health)
+                    })
+                    .collect())
+            })
+    }
+
+    /// Moves every stored key to a new CNG key of `algorithm`, named after the configured key
+    /// and the algorithm, then deletes the old key, as [`KeyManager::rotate_cng_key`] does. CNG
+    /// keys can't be renamed, so the new name is recorded as the active key. `on_step` hears
+    /// about each step as it starts.
+    #[cfg(windows)]
+    pub fn migrate_cng_key(
+        &mut self,
+        algorithm: CngKeyAlgorithm,
+        on_step: &mut dyn FnMut(&str),
+    ) -> Result<usize> {
+        let _lock = self.lock.acquire()?;
+        let KeyWrap::Cng(cng) = &self.wrap else {
+            return Err(KeyManagerError::NeedsCng("Key migration").into());
+        };
+        let OpenCng { key, provider } = cng.get()?;
+        if key.algorithm()? == algorithm {
+            return Err(KeyManagerError::AlgorithmUnchanged(algorithm).into());
+        }
+        let old_name = key.name()?.to_string_lossy();
+        let existing = provider.enum_keys()?;
+        let mut new_name = format!("{}-{algorithm}", config().cng_key_name());
+        if new_name == old_name || existing.iter().any(|k| k.name == new_name) {
+            new_name = format!("{new_name}-{}", unix_now());
+        }
+        let options = CngKeyOptions {
+            algorithm,
+            gesture_bound: key.is_gesture_bound(),
+        };
+        let count = self.replace_cng_key(&new_name, options, on_step)?;
+        info!(%algorithm, from = old_name, to = new_name, count, "CNG key migrated");
+        Ok(count)
+    }
+
+    /// Moves every stored key to a new CNG key named `new_name` with the same algorithm and
+    /// gesture policy, then deletes the old key. `on_step` hears about each step as it starts.
+    #[cfg(windows)]
+    pub fn rotate_cng_key(
+        &mut self,
+        new_name: &str,
+        on_step: &mut dyn FnMut(&str),
+    ) -> Result<usize> {
+        let _lock = self.lock.acquire()?;
+        let KeyWrap::Cng(cng) = &self.wrap else {
+            return Err(KeyManagerError::NeedsCng("Key rotation").into());
+        };
+        let OpenCng { key, provider } = cng.get()?;
+        let old_name = key.name()?;
+        if old_name.to_string_lossy() == new_name {
+            return Err(KeyManagerError::NameUnchanged(new_name.to_string()).into());
+        }
+        if provider.enum_keys()?.iter().any(|k| k.name == new_name) {
+            return Err(KeyManagerError::CngKeyExists(new_name.to_string()).into());
+        }
+        let options = CngKeyOptions {
+            algorithm: key.algorithm()?,
+            gesture_bound: key.is_gesture_bound(),
+        };
+        let count = self.replace_cng_key(new_name, options, on_step)?;
+        info!(from = %old_name, to = new_name, count, "CNG key rotated");
+        Ok(count)
+    }
+
+    /// Re-wraps every stored key with a new CNG key created as `new_name` and deletes the old
+    /// one. The keys are unwrapped after a single biometric prompt, re-wrapped into temporary
+    /// entries and only then renamed over the originals, so a failure before the swap leaves the
+    /// store and the old key untouched. The swap is recorded first and finished by the next key
+    /// manager if a rename fails; see [`Rotation`]. The caller holds the store lock.
+    #[cfg(windows)]
+    fn replace_cng_key(
+        &mut self,
+        new_name: &str,
+        options: CngKeyOptions,
+        on_step: &mut dyn FnMut(&str),
+    ) -> Result<usize> {
+        let KeyWrap::Cng(cng) = &self.wrap else {
+            return Err(KeyManagerError::NeedsCng("Replacing the CNG key").into());
+        };
+        let OpenCng { key, provider } = cng.get()?;
+        on_step("Unwrapping the stored keys (confirm with Windows Hello)");
+        let reason = "Re-wrap your Bitwarden keys with a new key";
+        let keys = key.with_user_presence(reason, |presence| {
+            // Loaded before the swap, while the old key can still open it.
+            self.integrity_key(Some(presence))?;
+            self.list_user_ids()?
+                .into_iter()
+                .map(|user_id| Ok((self.unwrap_key(&user_id, presence)?, user_id)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        let algorithm = options.algorithm;
+        on_step(&format!("Creating CNG key {new_name} ({algorithm})"));
+        let new_key = provider.create_key(HSTRING::from(new_name), options)?;
+        let KeyWrap::Cng(cng) = &mut self.wrap else {
+            unreachable!();
+        };
+        let old_key = std::mem::replace(&mut cng.get_mut()?.key, new_key);
+        let old_name = old_key.name()?;
+
+        on_step(&format!("Re-wrapping {} key(s)", keys.len()));
+        let mut renames = keys
+            .iter()
+            .map(|(_, user_id)| {
+                let entry = self.key_entry(user_id);
+                (format!(".{entry}.rotate"), entry)
+            })
+            .collect::<Vec<_>>();
+        let written = keys
+            .iter()
+            .zip(&renames)
+            .try_for_each(|((bw_key, user_id), (staged, _))| {
+                self.write_key_entry(staged, user_id, bw_key.expose().as_bytes())
+            })
+            .and_then(|_| {
+                // Loaded under the old key above; re-sealed for the new one.
+                if let Some(key) = self.integrity_key.get() {
+                    let staged = format!("{INTEGRITY_ENTRY}.rotate");
+                    self.store.write(&staged, &self.seal(key.expose())?)?;
+                    renames.push((staged, INTEGRITY_ENTRY.to_string()));
+                }
+                Ok(())
+            });
+        if let Err(e) = written {
+            for (staged, _) in &renames {
+                let _ = self.store.delete(staged);
+            }
+            if let KeyWrap::Cng(cng) = &mut self.wrap
+                && let Some(opened) = cng.opened.get_mut()
+            {
+                let _ = std::mem::replace(&mut opened.key, old_key).delete();
+            }
+            return Err(e);
+        }
+        on_step("Replacing the stored keys");
+        Rotation {
+            renames,
+            cng_key: new_name.to_string(),
+            old_cng_key: old_name.to_string_lossy(),
+        }
+        .commit(&*self.store)?;
+        on_step(&format!("Deleting CNG key {old_name}"));
+        old_key.delete()?;
+        Ok(keys.len())
+    }
+
+    /// Writes every stored key, unwrapped after a single biometric prompt, together with its
+    /// metadata to `path` as one archive encrypted under `passphrase` (Argon2id, AES-256-GCM).
+    /// The archive doesn't depend on the TPM, so it survives a TPM clear or a new machine.
+    pub fn backup(&self, path: &Path, passphrase: SecretString) -> Result<usize> {
+        let _lock = self.lock.acquire()?;
+        let unwrap_all = |presence: &Presence| {
+            self.list_user_ids()?
+                .into_iter()
+                .map(|user_id| {
+                    Ok(BackupEntry {
+                        key: self.unwrap_key(&user_id, presence)?.expose().to_string(),
+                        metadata: self.key_metadata(&user_id),
+                        user_id,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let mut entries = self
+            .wrap
+            .with_user_presence("Back up your Bitwarden keys", unwrap_all)?;
+        let plaintext = SecretBytes::from(serde_json::to_vec(&entries)?);
+        for entry in &mut entries {
+            entry.key.zeroize();
+        }
+        let sealed = PassphraseWrap::new(passphrase).wrap(plaintext.expose())?;
+        write(path, [BACKUP_MAGIC, &[BACKUP_VERSION], &sealed].concat())?;
+        Ok(entries.len())
+    }
+
+    /// Imports every key from a [`KeyManager::backup`] archive, replacing stored keys with the
+    /// same user id.
+    pub fn restore(&self, path: &Path, passphrase: SecretString) -> Result<usize> {
+        let _lock = self.lock.acquire()?;
+        let archive = read(path)?;
+        let Some(sealed) = archive.strip_prefix(BACKUP_MAGIC) else {
+            return Err(KeyManagerError::NotABackup(path.to_path_buf()).into());
+        };
+        let Some((&BACKUP_VERSION, sealed)) = sealed.split_first() else {
+            return Err(KeyManagerError::UnsupportedBackupVersion.into());
+        };
+        let plaintext = PassphraseWrap::new(passphrase).unwrap(sealed)?;
+        let mut entries = serde_json::from_slice::<Vec<BackupEntry>>(plaintext.expose())?;
+        self.cache().clear();
+        // Check every id first so a bad archive doesn't leave a partial restore.
+        let result = entries
+            .iter()
+            .try_for_each(|entry| validate_user_id(&entry.user_id))
+            .map_err(Into::into)
+            .and_then(|_| {
+                entries.iter().try_for_each(|entry| {
+                    self.write_key(&entry.user_id, entry.key.as_bytes())?;
+                    self.write_dpapi_copy(&entry.user_id, entry.key.as_bytes())?;
+                    self.record(AuditAction::Import, &entry.user_id, None);
+                    self.write_metadata(&entry.user_id, &entry.metadata)
+                })
+            });
+        for entry in &mut entries {
+            entry.key.zeroize();
+        }
+        result.map(|_| entries.len())
+    }
+
     pub fn delete_key(&self, user_id: &str) -> Result<()> {
-        let file_path = self.bw_key_directory.join(user_id);
-        if file_path.exists() {
-            remove_file(file_path)?;
+        let _lock = self.lock.acquire()?;
+        self.remove_key(user_id)
+    }
+
+    /// Moves the key of `old_id`, with its metadata and DPAPI copy, to `new_id`: for accounts
+    /// whose id changed in a server migration or keys imported under the wrong id. The key
+    /// file's MAC covers the id, so it is checked and computed again, but the key isn't
+    /// decrypted.
+    pub fn rename_key(&self, old_id: &str, new_id: &str) -> Result<()> {
+        validate_user_id(old_id)?;
+        validate_user_id(new_id)?;
+        let _lock = self.lock.acquire()?;
+        let Some(key) = self.store.read(&self.key_entry(old_id))? else {
+            return Err(KeyManagerError::KeyNotFound(old_id.to_string()).into());
+        };
+        if self.store.exists(&self.key_entry(new_id))? {
+            return Err(KeyManagerError::KeyExists(new_id.to_string()).into());
+        }
+        let key = self.rebind_key_file(key, old_id, new_id)?;
+        let metadata = self.key_metadata(old_id);
+        let dpapi_copy = self.store.read(&self.dpapi_entry(old_id))?;
+        // Everything is written before the old entries go, so a failure leaves two copies
+        // rather than none.
+        self.store.write(&self.key_entry(new_id), &key)?;
+        if let Some(dpapi_copy) = dpapi_copy {
+            self.store.write(&self.dpapi_entry(new_id), &dpapi_copy)?;
         }
+        self.write_metadata(new_id, &metadata)?;
+        self.cache().remove(old_id);
+        self.store.delete(&self.key_entry(old_id))?;
+        self.store.delete(&self.dpapi_entry(old_id))?;
+        self.delete_metadata(old_id)?;
+        self.record(AuditAction::Rename, old_id, None);
+        self.record(AuditAction::Rename, new_id, None);
         Ok(())
     }
+
+    /// The key file `bytes` of `old_id` with its MAC computed for `new_id`, after checking the
+    /// old one.
+    fn rebind_key_file(&self, bytes: Vec<u8>, old_id: &str, new_id: &str) -> Result<Vec<u8>> {
+        if !KeyFile::has_header(&bytes) {
+            return self.unauthenticated(old_id, bytes);
+        }
+        let mut file = KeyFile::from_bytes(&bytes)?;
+        let Some(mac) = file.mac else {
+            return self.unauthenticated(old_id, bytes);
+        };
+        if self.key_file_mac(&file, old_id, None)?.ct_ne(&mac).into() {
+            return Err(KeyManagerError::IntegrityCheckFailed(old_id.to_string()).into());
+        }
+        file.version = keyfile::VERSION;
+        file.mac = Some(self.key_file_mac(&file, new_id, None)?);
+        Ok(file.to_bytes())
+    }
+
+    fn remove_key(&self, user_id: &str) -> Result<()> {
+        validate_user_id(user_id)?;
+        self.cache().remove(user_id);
+        self.store.delete(&self.key_entry(user_id))?;
+        self.record(AuditAction::Delete, user_id, None);
+        self.store.delete(&self.dpapi_entry(user_id))?;
+        self.delete_metadata(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::FileStore;
+    use crate::mock::{MemoryKeyWrap, MockVerifier, TempKeyDir};
+    use crate::presence::DEFAULT_REASON;
+    use std::io;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    const ALICE: &str = "alice";
+    const BOB: &str = "bob";
+    const KEY: &str = "dXNlciBrZXkgb2YgYWxpY2U=";
+
+    fn key_manager(verifier: &MockVerifier) -> (KeyManager, TempKeyDir) {
+        let dir = TempKeyDir::new();
+        let wrap = MemoryKeyWrap::new(Box::new(verifier.clone())).unwrap();
+        let kmgr = KeyManager::with_wrapper(Box::new(wrap), dir.path().to_path_buf()).unwrap();
+        (kmgr, dir)
+    }
+
+    fn key_error(result: Result<SecretString>) -> KeyManagerError {
+        match result {
+            Err(BwbioError::Key(e)) => e,
+            Err(e) => panic!("expected a key manager error, got {e}"),
+            Ok(_) => panic!("expected a key manager error, got a key"),
+        }
+    }
+
+    fn read_file(kmgr: &KeyManager, user_id: &str) -> KeyFile {
+        let bytes = kmgr.store.read(&kmgr.key_entry(user_id)).unwrap().unwrap();
+        KeyFile::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn import_export_roundtrip() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        assert!(kmgr.check_key_exists(ALICE).unwrap());
+        assert!(!kmgr.check_key_exists(BOB).unwrap());
+        assert_eq!(
+            kmgr.list_keys()
+                .unwrap()
+                .into_iter()
+                .map(|key| key.user_id)
+                .collect::<Vec<_>>(),
+            [ALICE]
+        );
+
+        let prompts = verifier.prompts().len();
+        assert_eq!(kmgr.export_key(ALICE).unwrap().expose(), KEY);
+        // The integrity key and the data key are unwrapped under a single prompt.
+        assert_eq!(
+            verifier.prompts()[prompts..],
+            ["Unlock Bitwarden vault for alice"]
+        );
+
+        let file = read_file(&kmgr, ALICE);
+        assert_eq!(file.version, keyfile::VERSION);
+        assert_eq!(file.algorithm, WrapAlgorithm::Passphrase);
+        assert!(file.mac.is_some());
+    }
+
+    #[test]
+    fn export_refused_without_presence() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        verifier.set_approve(false);
+        assert!(kmgr.export_key(ALICE).unwrap_err().is_auth_failure());
+        verifier.set_approve(true);
+        assert_eq!(kmgr.export_key(ALICE).unwrap().expose(), KEY);
+    }
+
+    #[test]
+    fn export_keys_prompts_once() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        kmgr.import_key(BOB, "Ym9i").unwrap();
+        let prompts = verifier.prompts().len();
+        let keys = kmgr.export_keys(&[ALICE, BOB, "carol"]).unwrap();
+        assert_eq!(verifier.prompts().len(), prompts + 1);
+        assert_eq!(keys[0].as_ref().unwrap().expose(), KEY);
+        assert_eq!(keys[1].as_ref().unwrap().expose(), "Ym9i");
+        assert!(matches!(
+            keys[2],
+            Err(BwbioError::Key(KeyManagerError::KeyNotFound(_)))
+        ));
+    }
+
+    #[test]
+    fn missing_and_deleted_keys() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        assert!(matches!(
+            key_error(kmgr.export_key(ALICE)),
+            KeyManagerError::KeyNotFound(_)
+        ));
+        kmgr.import_key(ALICE, KEY).unwrap();
+        kmgr.delete_key(ALICE).unwrap();
+        assert!(!kmgr.check_key_exists(ALICE).unwrap());
+        assert!(matches!(
+            key_error(kmgr.export_key(ALICE)),
+            KeyManagerError::KeyNotFound(_)
+        ));
+        assert!(matches!(
+            key_error(kmgr.export_key("../alice")),
+            KeyManagerError::InvalidUserId(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_swapped_user_id() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        kmgr.import_key(BOB, "Ym9i").unwrap();
+        let alice = kmgr.store.read(&kmgr.key_entry(ALICE)).unwrap().unwrap();
+        kmgr.store.write(&kmgr.key_entry(BOB), &alice).unwrap();
+        assert!(matches!(
+            key_error(kmgr.export_key(BOB)),
+            KeyManagerError::IntegrityCheckFailed(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_file() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        let mut file = read_file(&kmgr, ALICE);
+        *file.envelope.last_mut().unwrap() ^= 1;
+        kmgr.store
+            .write(&kmgr.key_entry(ALICE), &file.to_bytes())
+            .unwrap();
+        assert!(matches!(
+            key_error(kmgr.export_key(ALICE)),
+            KeyManagerError::IntegrityCheckFailed(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_file_without_mac() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        kmgr.import_key(BOB, "Ym9i").unwrap();
+        let file = KeyFile {
+            mac: None,
+            ..read_file(&kmgr, BOB)
+        };
+        kmgr.store
+            .write(&kmgr.key_entry(BOB), &file.to_bytes())
+            .unwrap();
+        assert!(matches!(
+            key_error(kmgr.export_key(BOB)),
+            KeyManagerError::UnauthenticatedKeyFile(_)
+        ));
+        assert_eq!(kmgr.export_key(ALICE).unwrap().expose(), KEY);
+    }
+
+    #[test]
+    fn upgrades_version_2_file() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        let mut file = KeyFile {
+            version: 2,
+            ..read_file(&kmgr, ALICE)
+        };
+        file.mac = Some(kmgr.key_file_mac(&file, ALICE, None).unwrap());
+        kmgr.store
+            .write(&kmgr.key_entry(ALICE), &file.to_bytes())
+            .unwrap();
+
+        assert_eq!(kmgr.export_key(ALICE).unwrap().expose(), KEY);
+        let upgraded = read_file(&kmgr, ALICE);
+        assert_eq!(upgraded.version, keyfile::VERSION);
+        assert_eq!(
+            upgraded.mac,
+            Some(kmgr.key_file_mac(&upgraded, ALICE, None).unwrap())
+        );
+        assert_eq!(kmgr.export_key(ALICE).unwrap().expose(), KEY);
+    }
+
+    #[test]
+    fn rename_rebinds_mac() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        kmgr.import_key(BOB, "Ym9i").unwrap();
+        assert!(matches!(
+            kmgr.rename_key(ALICE, BOB),
+            Err(BwbioError::Key(KeyManagerError::KeyExists(_)))
+        ));
+        kmgr.rename_key(ALICE, "carol").unwrap();
+        assert!(!kmgr.check_key_exists(ALICE).unwrap());
+        assert_eq!(kmgr.export_key("carol").unwrap().expose(), KEY);
+    }
+
+    #[test]
+    fn other_wrapper_cannot_open_keys() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        let wrap = MemoryKeyWrap::new(Box::new(verifier.clone())).unwrap();
+        let other = KeyManager::with_wrapper(Box::new(wrap), dir.path().to_path_buf()).unwrap();
+        assert!(matches!(
+            key_error(other.export_key(ALICE)),
+            KeyManagerError::IntegrityKeyUnreadable(_)
+        ));
+    }
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        let data = vec![42; 4096];
+        let sealed = kmgr.seal(&data).unwrap();
+        assert_eq!(kmgr.open(&sealed).unwrap().expose(), data);
+    }
+
+    /// A file store whose renames of `fail` fail while `failing` is set.
+    struct FlakyStore {
+        inner: FileStore,
+        fail: String,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl KeyStore for FlakyStore {
+        fn list(&self) -> Result<Vec<String>> {
+            self.inner.list()
+        }
+
+        fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+            self.inner.read(name)
+        }
+
+        fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+            self.inner.write(name, data)
+        }
+
+        fn delete(&self, name: &str) -> Result<()> {
+            self.inner.delete(name)
+        }
+
+        fn remove_all(&self) -> Result<()> {
+            self.inner.remove_all()
+        }
+
+        fn rename(&self, from: &str, to: &str) -> Result<()> {
+            if from == self.fail && self.failing.load(Ordering::Relaxed) {
+                return Err(io::Error::other("rename failed").into());
+            }
+            self.inner.rename(from, to)
+        }
+    }
+
+    #[test]
+    fn interrupted_rotation_is_finished() {
+        let verifier = MockVerifier::approving();
+        let (mut kmgr, dir) = key_manager(&verifier);
+        kmgr.import_key(ALICE, KEY).unwrap();
+        kmgr.import_key(BOB, KEY).unwrap();
+        const NEW_KEY: &str = "bmV3IHVzZXIga2V5";
+        let renames = [ALICE, BOB]
+            .map(|user_id| {
+                let staged = format!(".{user_id}.rotate");
+                kmgr.write_key_entry(&staged, user_id, NEW_KEY.as_bytes())
+                    .unwrap();
+                (staged, user_id.to_string())
+            })
+            .to_vec();
+
+        let failing = Arc::new(AtomicBool::new(true));
+        kmgr.store = Box::new(FlakyStore {
+            inner: FileStore::new(dir.path().to_path_buf()),
+            fail: format!(".{BOB}.rotate"),
+            failing: failing.clone(),
+        });
+        let rotation = Rotation {
+            renames,
+            cng_key: "new-key".to_string(),
+            old_cng_key: "old-key".to_string(),
+        };
+        assert!(rotation.commit(&*kmgr.store).is_err());
+        assert!(kmgr.store.exists(ROTATION_ENTRY).unwrap());
+        assert!(!kmgr.store.exists(ACTIVE_KEY_ENTRY).unwrap());
+        assert_eq!(kmgr.export_key(ALICE).unwrap().expose(), NEW_KEY);
+        assert_eq!(kmgr.export_key(BOB).unwrap().expose(), KEY);
+
+        // Still failing: the next key manager refuses to start on a half-swapped store.
+        assert!(finish_rotation(&*kmgr.store, &kmgr.lock).is_err());
+        failing.store(false, Ordering::Relaxed);
+        finish_rotation(&*kmgr.store, &kmgr.lock).unwrap();
+        assert!(!kmgr.store.exists(ROTATION_ENTRY).unwrap());
+        assert!(!kmgr.store.exists(&format!(".{BOB}.rotate")).unwrap());
+        assert_eq!(
+            kmgr.store.read(ACTIVE_KEY_ENTRY).unwrap().unwrap(),
+            b"new-key"
+        );
+        assert_eq!(kmgr.export_key(BOB).unwrap().expose(), NEW_KEY);
+    }
+
+    #[test]
+    fn stored_provider_prefers_the_tpm() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        assert_eq!(stored_provider(&*kmgr.store), None);
+
+        // Files from before version 4, or from a wrapper that doesn't say, record nothing.
+        kmgr.import_key(ALICE, KEY).unwrap();
+        assert_eq!(stored_provider(&*kmgr.store), None);
+
+        let mut file = read_file(&kmgr, ALICE);
+        for provider in [KeyProvider::Software, KeyProvider::Tpm] {
+            file.provider = Some(provider);
+            kmgr.store
+                .write(&kmgr.key_entry(&format!("{provider:?}")), &file.to_bytes())
+                .unwrap();
+            assert_eq!(stored_provider(&*kmgr.store), Some(provider));
+        }
+    }
+
+    #[test]
+    fn presence_covers_only_its_own_scope() {
+        let verifier = MockVerifier::approving();
+        let (kmgr, _dir) = key_manager(&verifier);
+        let sealed = kmgr.seal(b"data").unwrap();
+        let prompts = verifier.prompts().len();
+
+        // Another thread's unwrap during the scope asks for the user itself.
+        kmgr.wrap
+            .with_user_presence("scope", |presence| {
+                kmgr.open_with(&sealed, Some(presence))?;
+                std::thread::scope(|s| s.spawn(|| kmgr.open(&sealed)).join().unwrap())?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(verifier.prompts()[prompts..], ["scope", DEFAULT_REASON]);
+
+        // So does any unwrap after a scope that panicked.
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            kmgr.wrap
+                .with_user_presence("panics", |_| -> Result<()> { panic!("in scope") })
+        }));
+        assert!(panicked.is_err());
+        kmgr.open(&sealed).unwrap();
+        assert_eq!(
+            verifier.prompts()[prompts + 2..],
+            ["panics", DEFAULT_REASON]
+        );
+    }
 }
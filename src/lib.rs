@@ -1,11 +1,48 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
 
+pub mod error;
+#[cfg(windows)]
 pub mod cng;
 pub mod kmgr;
+pub mod keystore;
+pub mod keyfile;
+pub mod audit;
+pub mod log;
+pub mod config;
+#[cfg(windows)]
 pub mod bio;
+pub mod presence;
 pub mod proto;
 pub mod crypto;
+#[cfg(windows)]
+pub mod desktop;
+#[cfg(target_os = "linux")]
+pub mod tpm2;
+#[cfg(target_os = "linux")]
+pub mod fprint;
+#[cfg(target_os = "linux")]
+pub mod polkit;
+#[cfg(target_os = "linux")]
+pub mod xdg;
 pub mod browser;
+pub mod bench;
+pub mod mock;
+#[cfg(all(windows, feature = "cli"))]
+pub mod clipboard;
+#[cfg(all(windows, feature = "cli"))]
 pub mod cli;
+#[cfg(all(windows, feature = "cli"))]
+pub mod doctor;
+#[cfg(all(target_os = "linux", feature = "cli"))]
+pub mod linux;
+#[cfg(all(windows, feature = "installer"))]
+pub mod install;
+#[cfg(all(windows, feature = "installer"))]
+pub mod progress;
+#[cfg(all(windows, feature = "installer"))]
+pub mod update;
+#[cfg(all(windows, feature = "tui"))]
+pub mod dashboard;
+#[cfg(all(windows, feature = "tui"))]
 pub mod tui;
\ No newline at end of file
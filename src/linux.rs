@@ -0,0 +1,228 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::{
+    config::{Overrides, check_config, set_overrides},
+    crypto::SecretString,
+    kmgr::{KeyManager, default_key_dir},
+    log::init_logging,
+    polkit::{POLICY, POLICY_PATH},
+    presence::platform_verifier,
+    xdg::{XDG_HOSTS, register_manifests, select_hosts, unregister_manifests},
+};
+use anyhow::{Context, Result, bail};
+use argh::FromArgs;
+use dialoguer::Password;
+use std::{
+    env,
+    io::{self, IsTerminal, Read},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Key management command line tool
+struct LinuxCmd {
+    /// store keys in this directory (overrides BW_KEY_DIR and the config file)
+    #[argh(option)]
+    key_dir: Option<PathBuf>,
+    #[argh(subcommand)]
+    cmd: Command,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum Command {
+    List(ListCmd),
+    Import(ImportCmd),
+    Delete(DeleteCmd),
+    Status(StatusCmd),
+    Register(RegisterCmd),
+    Unregister(UnregisterCmd),
+    PolkitPolicy(PolkitPolicyCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List all keys
+#[argh(subcommand, name = "list")]
+struct ListCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Import key
+#[argh(subcommand, name = "import")]
+struct ImportCmd {
+    /// user id
+    #[argh(positional)]
+    user_id: String,
+    /// read the key from standard input instead of prompting
+    #[argh(switch)]
+    stdin: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Delete key
+#[argh(subcommand, name = "delete")]
+struct DeleteCmd {
+    /// user id
+    #[argh(positional)]
+    user_id: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show how keys are protected, how the user is verified and which browsers are registered
+#[argh(subcommand, name = "status")]
+struct StatusCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Write the native messaging manifests, for every browser that has been run unless given
+#[argh(subcommand, name = "register")]
+struct RegisterCmd {
+    /// browser to register with: chrome, chromium, edge, brave, vivaldi or firefox (repeatable)
+    #[argh(option)]
+    browser: Vec<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Remove the native messaging manifests of every browser
+#[argh(subcommand, name = "unregister")]
+struct UnregisterCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print the polkit policy used when there is no fingerprint reader, for installing as root
+#[argh(subcommand, name = "polkit-policy")]
+struct PolkitPolicyCmd {}
+
+/// Whether the browser started bwbio: Chromium passes the extension's origin, Firefox the
+/// manifest path and the extension id.
+pub fn started_by_browser(args: &[String]) -> bool {
+    match args {
+        [_, origin, ..] if origin.starts_with("chrome-extension://") => true,
+        [_, manifest, _extension] => manifest.ends_with(".json"),
+        _ => false,
+    }
+}
+
+pub fn linux_cli() -> ExitCode {
+    let strings = env::args().collect::<Vec<_>>();
+    let name = Path::new(&strings[0])
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or(&strings[0]);
+    let args: Vec<&str> = strings[1..].iter().map(String::as_str).collect();
+    let cmd = match LinuxCmd::from_args(&[name], &args) {
+        Ok(cmd) => cmd,
+        Err(early_exit) if early_exit.status.is_ok() => {
+            println!("{}", early_exit.output);
+            return ExitCode::SUCCESS;
+        }
+        Err(early_exit) => {
+            eprintln!(
+                "{}\nRun {name} --help for more information.",
+                early_exit.output
+            );
+            return ExitCode::from(2);
+        }
+    };
+    set_overrides(Overrides {
+        key_dir: cmd.key_dir,
+        cng_key_name: None,
+    });
+    if let Ok(dir) = default_key_dir() {
+        init_logging(dir);
+    }
+    match run(cmd.cmd) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Command) -> Result<()> {
+    // A config file that doesn't load would quietly turn its switches off.
+    check_config()?;
+    match command {
+        Command::List(_) => {
+            let keys = KeyManager::from_env()?.list_keys()?;
+            if keys.is_empty() {
+                println!("No keys stored");
+            }
+            for key in keys {
+                println!("{}", key.user_id);
+            }
+        }
+        Command::Import(cmd) => {
+            let key = read_import_key(cmd.stdin)?;
+            KeyManager::from_env()?
+                .import_key(&cmd.user_id, key.expose())
+                .context("Failed to import key")?;
+            println!("Imported key for {}", cmd.user_id);
+        }
+        Command::Delete(cmd) => {
+            KeyManager::from_env()?.delete_key(&cmd.user_id)?;
+            println!("Deleted key for {}", cmd.user_id);
+        }
+        Command::Status(_) => status()?,
+        Command::Register(cmd) => {
+            let exe = env::current_exe().context("Failed to get current exe path")?;
+            let hosts = select_hosts(&cmd.browser)?;
+            let registered = register_manifests(&exe, &hosts)?;
+            println!("Registered with {}", registered.join(", "));
+        }
+        Command::Unregister(_) => {
+            let removed = unregister_manifests();
+            if removed.is_empty() {
+                println!("No manifests were registered");
+            } else {
+                println!("Unregistered from {}", removed.join(", "));
+            }
+        }
+        Command::PolkitPolicy(_) => {
+            eprintln!("Install as {POLICY_PATH}");
+            print!("{POLICY}");
+        }
+    }
+    Ok(())
+}
+
+fn status() -> Result<()> {
+    let kmgr = KeyManager::from_env()?;
+    println!("Key directory: {}", default_key_dir()?.display());
+    println!("Protection:    {}", kmgr.protection());
+    println!("Verification:  {:?}", platform_verifier().status());
+    for host in XDG_HOSTS {
+        let registered = host.manifest_path().is_ok_and(|path| path.exists());
+        let state = if registered {
+            "registered"
+        } else if host.is_installed() {
+            "not registered"
+        } else {
+            "not found"
+        };
+        println!("{:<16}{state}", host.browser);
+    }
+    Ok(())
+}
+
+/// The key to import from stdin, or a hidden prompt.
+fn read_import_key(stdin: bool) -> Result<SecretString> {
+    let key = if stdin {
+        let mut key = String::new();
+        io::stdin().read_to_string(&mut key)?;
+        SecretString::from(key)
+    } else if io::stdin().is_terminal() {
+        SecretString::from(
+            Password::new()
+                .with_prompt("User key (base64)")
+                .interact()?,
+        )
+    } else {
+        bail!("No terminal to prompt on; pass the key with --stdin")
+    };
+    let trimmed = key.expose().trim();
+    if trimmed.is_empty() {
+        bail!("The key is empty");
+    }
+    Ok(SecretString::from(trimmed.to_string()))
+}
@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::config::{ConfigError, config};
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fmt::{self, Display, Formatter, Write as _},
+    fs::{OpenOptions, create_dir_all, metadata, read_to_string, remove_file, rename},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Size past which the log is rotated before the next line is written.
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+/// Rotated logs kept next to it, `bwbio.log.1` being the newest.
+const ROTATED_LOGS: u32 = 3;
+/// Field names (or `_`-suffixes of them, as in `user_key`) whose values are never written.
+const SECRET_FIELDS: [&str; 6] = ["key", "password", "passphrase", "secret", "token", "pin"];
+
+/// Severity of a log line, most severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        })
+    }
+}
+
+impl From<&Level> for LogLevel {
+    fn from(level: &Level) -> Self {
+        match *level {
+            Level::ERROR => LogLevel::Error,
+            Level::WARN => LogLevel::Warn,
+            Level::INFO => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, ConfigError> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(ConfigError::UnknownLogLevel(s.to_string())),
+        }
+    }
+}
+
+/// Plain text log of the native messaging host, the CLI and the installer, one
+/// `<time>  <LEVEL>  <module>: <message> <fields>` line per `tracing` event. Lives at
+/// the configured `log_file` (or `BW_LOG_FILE`), or `bwbio.log` in the key directory, and is rotated to `bwbio.log.1` once it
+/// passes 1 MiB; lines below `BW_LOG_LEVEL` or the configured `log_level` (default `info`) are
+/// dropped. Keys and messages are never logged, and fields named like secrets are redacted.
+pub struct Log {
+    path: PathBuf,
+    level: LogLevel,
+}
+
+impl Log {
+    pub fn new(bw_key_directory: PathBuf) -> Self {
+        let path = config()
+            .log_file()
+            .unwrap_or_else(|| bw_key_directory.join("bwbio.log"));
+        let level = config().log_level().unwrap_or(LogLevel::Info);
+        Self { path, level }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a line. Best effort: a log that can't be written must not stop the host.
+    pub fn write(&self, level: LogLevel, message: impl Display) {
+        if level > self.level {
+            return;
+        }
+        let line = format!(
+            "{}  {level:<5}  {}\n",
+            format_utc(SystemTime::now()),
+            message.to_string().replace(['\r', '\n'], " ")
+        );
+        if let Some(parent) = self.path.parent() {
+            let _ = create_dir_all(parent);
+        }
+        self.rotate();
+        // A single write of a whole line, so concurrent hosts don't interleave lines.
+        let _ = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(line.as_bytes()));
+    }
+
+    /// Shifts `bwbio.log` to `bwbio.log.1` (and so on, dropping the oldest) once it is too big.
+    fn rotate(&self) {
+        if !metadata(&self.path).is_ok_and(|m| m.len() >= MAX_LOG_BYTES) {
+            return;
+        }
+        let _ = remove_file(self.rotated(ROTATED_LOGS));
+        for n in (1..ROTATED_LOGS).rev() {
+            let _ = rename(self.rotated(n), self.rotated(n + 1));
+        }
+        // Another process may have rotated it already; the next write starts a new file either way.
+        let _ = rename(&self.path, self.rotated(1));
+    }
+
+    /// The whole log; empty when nothing was logged yet.
+    pub fn read(&self) -> Result<String> {
+        match read_to_string(&self.path) {
+            Ok(s) => Ok(s),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Deletes the log and the rotated ones.
+    pub fn clear(&self) -> Result<()> {
+        for path in
+            std::iter::once(self.path.clone()).chain((1..=ROTATED_LOGS).map(|n| self.rotated(n)))
+        {
+            match remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// `bwbio.log.<n>`.
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+}
+
+/// Sends the `tracing` events of every module to the log in `key_dir`. Only the first call has
+/// an effect.
+pub fn init_logging(key_dir: PathBuf) {
+    let _ = tracing_subscriber::registry()
+        .with(LogLayer(Log::new(key_dir)))
+        .try_init();
+}
+
+/// Writes events to a [`Log`].
+struct LogLayer(Log);
+
+impl<S: Subscriber> Layer<S> for LogLayer {
+    fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
+        LogLevel::from(metadata.level()) <= self.0.level
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = LineFields::default();
+        event.record(&mut fields);
+        let module = metadata.target().trim_start_matches("bwbio::");
+        self.0.write(
+            metadata.level().into(),
+            format_args!("{module}: {}{}", fields.message, fields.rest),
+        );
+    }
+}
+
+/// The message of an event and its other fields as ` name=value`, secrets redacted.
+#[derive(Default)]
+struct LineFields {
+    message: String,
+    rest: String,
+}
+
+impl LineFields {
+    fn push(&mut self, field: &Field, value: impl Display) {
+        let name = field.name();
+        if name == "message" {
+            self.message = value.to_string();
+        } else if is_secret(name) {
+            let _ = write!(self.rest, " {name}=[redacted]");
+        } else {
+            let _ = write!(self.rest, " {name}={value}");
+        }
+    }
+}
+
+impl Visit for LineFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format_args!("{value:?}"));
+    }
+}
+
+fn is_secret(name: &str) -> bool {
+    SECRET_FIELDS
+        .iter()
+        .any(|s| name == *s || name.strip_suffix(s).is_some_and(|n| n.ends_with('_')))
+}
+
+/// The level of a line written by [`Log::write`].
+pub fn line_level(line: &str) -> Option<LogLevel> {
+    // `YYYY-MM-DD HH:MM:SS UTC  LEVEL  message`
+    line.split_whitespace().nth(3)?.parse().ok()
+}
+
+/// Formats `time` as `YYYY-MM-DD HH:MM:SS UTC`.
+pub(crate) fn format_utc(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
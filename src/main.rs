@@ -1,10 +1,18 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
 
-use bwbio::{browser::launch_native_messaging, cli::kmgr_cli, tui::tui_cli};
+#[cfg(any(windows, target_os = "linux"))]
+use bwbio::browser::launch_native_messaging;
+#[cfg(target_os = "linux")]
+use bwbio::linux::{linux_cli, started_by_browser};
+#[cfg(windows)]
+use bwbio::{cli::kmgr_cli, tui::tui_cli};
+use std::process::ExitCode;
+#[cfg(windows)]
 use std::{env::args, process::exit};
 
-fn main() {
+#[cfg(windows)]
+fn main() -> ExitCode {
     if args()
         .collect::<Vec<_>>()
         .get(1)
@@ -14,12 +22,33 @@ fn main() {
             eprintln!("Error launching native messaging: {e}");
             exit(1);
         });
-        return;
+        return ExitCode::SUCCESS;
     }
 
     if args().count() == 1 {
         tui_cli();
+        ExitCode::SUCCESS
     } else {
-        kmgr_cli();
+        kmgr_cli()
     }
 }
+
+#[cfg(target_os = "linux")]
+fn main() -> ExitCode {
+    if started_by_browser(&std::env::args().collect::<Vec<_>>()) {
+        if let Err(e) = launch_native_messaging() {
+            eprintln!("Error launching native messaging: {e}");
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+    linux_cli()
+}
+
+/// Only the library builds elsewhere, so the core can be developed and tested off Windows and
+/// Linux.
+#[cfg(not(any(windows, target_os = "linux")))]
+fn main() -> ExitCode {
+    eprintln!("bwbio runs on Windows and Linux only");
+    ExitCode::FAILURE
+}
@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::crypto::{CryptoError, SecretBytes, secure_random};
+use crate::error::Result;
+use crate::keyfile::WrapAlgorithm;
+use crate::kmgr::KeyWrapper;
+use crate::presence::{BiometricVerifier, DEFAULT_REASON, Presence};
+use crate::proto::BiometricsStatus;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead, aes::cipher::generic_array::GenericArray};
+use std::{
+    env,
+    fs::remove_dir_all,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+#[derive(Debug)]
+struct VerifierState {
+    status: Mutex<BiometricsStatus>,
+    approve: AtomicBool,
+    prompts: Mutex<Vec<String>>,
+}
+
+/// A [`BiometricVerifier`] that answers every prompt as told, for tests on machines without
+/// Windows Hello. Clones share their state, so a test can keep one after handing another to the
+/// key manager or the host.
+#[derive(Debug, Clone)]
+pub struct MockVerifier(Arc<VerifierState>);
+
+impl MockVerifier {
+    /// Biometrics available, every prompt approved.
+    pub fn approving() -> Self {
+        Self(Arc::new(VerifierState {
+            status: Mutex::new(BiometricsStatus::Available),
+            approve: AtomicBool::new(true),
+            prompts: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// Biometrics available, every prompt refused.
+    pub fn refusing() -> Self {
+        let verifier = Self::approving();
+        verifier.set_approve(false);
+        verifier
+    }
+
+    pub fn set_status(&self, status: BiometricsStatus) {
+        *self.0.status.lock().unwrap_or_else(PoisonError::into_inner) = status;
+    }
+
+    pub fn set_approve(&self, approve: bool) {
+        self.0.approve.store(approve, Ordering::Relaxed);
+    }
+
+    /// The messages of the prompts shown so far, oldest first.
+    pub fn prompts(&self) -> Vec<String> {
+        self.0
+            .prompts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl BiometricVerifier for MockVerifier {
+    fn status(&self) -> BiometricsStatus {
+        *self.0.status.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn authenticate(&self, reason: &str) -> bool {
+        self.0
+            .prompts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(reason.to_string());
+        self.0.approve.load(Ordering::Relaxed)
+    }
+}
+
+/// A [`KeyWrapper`] sealing data keys with AES-256-GCM under a random key that only lives in
+/// memory, asking `verifier` for presence the way the TPM2 backend does. Keys it wrapped
+/// can't be unwrapped by another instance.
+pub struct MemoryKeyWrap {
+    key: SecretBytes,
+    verifier: Box<dyn BiometricVerifier>,
+    unwraps: AtomicUsize,
+}
+
+impl MemoryKeyWrap {
+    pub fn new(verifier: Box<dyn BiometricVerifier>) -> Result<Self> {
+        let mut key = SecretBytes::from(vec![0u8; 32]);
+        secure_random(key.expose_mut())?;
+        Ok(Self {
+            key,
+            verifier,
+            unwraps: AtomicUsize::new(0),
+        })
+    }
+
+    /// How many data keys were unwrapped so far.
+    pub fn unwraps(&self) -> usize {
+        self.unwraps.load(Ordering::Relaxed)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(GenericArray::from_slice(self.key.expose()))
+    }
+}
+
+impl KeyWrapper for MemoryKeyWrap {
+    /// Layout: `nonce (12) | ciphertext+tag`.
+    fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; 12];
+        secure_random(&mut nonce)?;
+        let ciphertext = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), data_key)
+            .map_err(|_| CryptoError::Encrypt)?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Recorded as a software wrap with no CNG key behind it.
+    fn describe(&self) -> Result<(WrapAlgorithm, String)> {
+        Ok((WrapAlgorithm::Passphrase, String::new()))
+    }
+
+    fn unwrap(&self, wrapped: &[u8], presence: Option<&Presence>) -> Result<(SecretBytes, bool)> {
+        if presence.is_none() {
+            self.verifier.verify_presence(DEFAULT_REASON)?;
+        }
+        if wrapped.len() < 12 + 16 {
+            return Err(CryptoError::TooShort("Wrapped key").into());
+        }
+        let (nonce, ciphertext) = wrapped.split_at(12);
+        let data_key = self
+            .cipher()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError::Authentication)?;
+        self.unwraps.fetch_add(1, Ordering::Relaxed);
+        Ok((SecretBytes::from(data_key), false))
+    }
+
+    fn with_user_presence(
+        &self,
+        reason: &str,
+        f: &mut dyn FnMut(&Presence) -> Result<()>,
+    ) -> Result<()> {
+        Presence::scope(|| Ok(self.verifier.verify_presence(reason)?), f)
+    }
+
+    fn protection(&self) -> String {
+        "In-memory key (no TPM, for tests)".to_string()
+    }
+}
+
+/// A key directory under the system temp directory, removed with everything in it on drop.
+#[derive(Debug)]
+pub struct TempKeyDir(PathBuf);
+
+impl TempKeyDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        Self(env::temp_dir().join(format!("bwbio-test-{}-{id}", process::id())))
+    }
+
+    /// A directory with a fixed name, emptied first; for one held in a static, which is never
+    /// dropped, so each run reuses it instead of leaving another behind.
+    pub fn named(name: &str) -> Self {
+        let dir = Self(env::temp_dir().join(format!("bwbio-test-{name}")));
+        let _ = remove_dir_all(&dir.0);
+        dir
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Default for TempKeyDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempKeyDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::fprint::is_remote_session;
+use crate::presence::BiometricVerifier;
+use crate::proto::BiometricsStatus;
+use std::process::{self, Command, Stdio};
+use tracing::warn;
+
+/// The polkit action bwbio checks; [`POLICY`] declares it.
+pub const ACTION_ID: &str = "com.github.aalivexy.bwbio.unlock";
+
+/// Where an administrator installs [`POLICY`].
+pub const POLICY_PATH: &str = "/usr/share/polkit-1/actions/com.github.aalivexy.bwbio.policy";
+
+/// Declares [`ACTION_ID`], which asks active local users for their own password (or whatever
+/// their PAM stack accepts, such as a fingerprint) every time.
+pub const POLICY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE policyconfig PUBLIC "-//freedesktop//DTD PolicyKit Policy Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/PolicyKit/1/policyconfig.dtd">
+<policyconfig>
+  <action id="com.github.aalivexy.bwbio.unlock">
+    <description>Unlock Bitwarden</description>
+    <message>Authentication is required to unlock Bitwarden in the browser</message>
+    <defaults>
+      <allow_any>no</allow_any>
+      <allow_inactive>no</allow_inactive>
+      <allow_active>auth_self</allow_active>
+    </defaults>
+  </action>
+</policyconfig>
+"#;
+
+/// User verification through the desktop's polkit agent, for machines without a fingerprint
+/// reader. The agent shows the message from [`POLICY`]; polkit has no way to pass the prompt's
+/// reason along.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Polkit;
+
+impl BiometricVerifier for Polkit {
+    fn status(&self) -> BiometricsStatus {
+        if is_remote_session() {
+            return BiometricsStatus::RemoteSession;
+        }
+        let installed = Command::new("pkaction")
+            .args(["--action-id", ACTION_ID])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        match installed {
+            Ok(status) if status.success() => BiometricsStatus::Available,
+            _ => BiometricsStatus::PlatformUnsupported,
+        }
+    }
+
+    fn authenticate(&self, _reason: &str) -> bool {
+        let result = Command::new("pkcheck")
+            .args(["--action-id", ACTION_ID, "--process"])
+            .arg(process::id().to_string())
+            .arg("--allow-user-interaction")
+            .stdout(Stdio::null())
+            .status();
+        match result {
+            Ok(status) => status.success(),
+            Err(e) => {
+                warn!("Failed to run pkcheck: {e}");
+                false
+            }
+        }
+    }
+}
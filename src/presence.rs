@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::config::config;
+use crate::proto::BiometricsStatus;
+use thiserror::Error;
+
+/// Prompt text for presence checks that don't name an account.
+pub const DEFAULT_REASON: &str = "Unlock Bitwarden";
+
+/// Returns true when `biometric_only` (or `BW_BIOMETRIC_ONLY`) demands a fingerprint or face
+/// match, so a Windows Hello PIN alone doesn't release keys.
+pub fn biometric_only_selected() -> bool {
+    config().biometric_only()
+}
+
+/// Proof that the user passed a presence check, handed to the key operations it covers so they
+/// don't prompt again. It only exists as a borrow inside [`Presence::scope`], so it can't reach
+/// another thread's operations or outlive the check, even when one of them panics.
+#[derive(Debug)]
+pub struct Presence(());
+
+impl Presence {
+    /// Runs `check`, then `f` with the token it earned.
+    pub fn scope<T, E>(
+        check: impl FnOnce() -> Result<(), E>,
+        f: impl FnOnce(&Presence) -> Result<T, E>,
+    ) -> Result<T, E> {
+        check()?;
+        f(&Presence(()))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PresenceError {
+    #[error("Biometric authentication failed")]
+    Failed,
+    #[error("{0}")]
+    Unavailable(BiometricsStatus),
+    #[error("Biometric-only mode needs a fingerprint reader or face camera")]
+    NoBiometricSensor,
+    /// Nothing can ask for the user on Linux, where keys are then kept locked.
+    #[cfg(target_os = "linux")]
+    #[error(
+        "No way to verify the user ({0}): enroll a finger with fprintd or install the polkit \
+         policy printed by `bwbio polkit-policy`"
+    )]
+    NoVerifier(BiometricsStatus),
+    /// The sensor only serves the foreground process, and Windows kept bwbio's prompt behind.
+    #[cfg(windows)]
+    #[error(
+        "Biometric-only mode couldn't bring its prompt to the front, so the sensor can't be read; \
+         click the browser window and try again"
+    )]
+    NoFocus,
+    #[cfg(windows)]
+    #[error("No fingerprint or face was presented within {0} seconds")]
+    TimedOut(u64),
+}
+
+/// Asks whether the user is at the machine. [`crate::bio::WindowsHello`] is the real check, and
+/// fprintd or polkit on Linux; the key manager's software wrappers and the native messaging host
+/// take any implementation, so they can run without Windows Hello hardware.
+pub trait BiometricVerifier: Send + Sync {
+    fn status(&self) -> BiometricsStatus;
+
+    /// Shows a prompt with `reason` as its message and waits for it; false when it failed or was
+    /// cancelled.
+    fn authenticate(&self, reason: &str) -> bool;
+
+    /// [`BiometricVerifier::authenticate`], with the reason when the user wasn't verified.
+    fn try_authenticate(&self, reason: &str) -> Result<(), PresenceError> {
+        if self.authenticate(reason) {
+            Ok(())
+        } else {
+            Err(PresenceError::Failed)
+        }
+    }
+
+    /// Checks that the user is present before a key is released: a prompt when biometrics are
+    /// available, skipped otherwise unless biometric-only mode is on. Remote Desktop sessions
+    /// are refused, as nobody at the machine can be asked.
+    fn verify_presence(&self, reason: &str) -> Result<(), PresenceError> {
+        match self.status() {
+            BiometricsStatus::Available => self.try_authenticate(reason),
+            status @ BiometricsStatus::RemoteSession => Err(PresenceError::Unavailable(status)),
+            _ if biometric_only_selected() => Err(PresenceError::NoBiometricSensor),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Stands in for Windows Hello on other systems: biometrics are reported as unsupported, so
+/// presence checks pass unless biometric-only mode is on.
+#[cfg(not(windows))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoBiometrics;
+
+#[cfg(not(windows))]
+impl BiometricVerifier for NoBiometrics {
+    fn status(&self) -> BiometricsStatus {
+        BiometricsStatus::PlatformUnsupported
+    }
+
+    fn authenticate(&self, _reason: &str) -> bool {
+        false
+    }
+}
+
+/// The verifier of the system bwbio runs on. On Linux that is fprintd when a finger is
+/// enrolled, else polkit when its action is installed and a password is acceptable.
+pub fn platform_verifier() -> Box<dyn BiometricVerifier> {
+    #[cfg(windows)]
+    return Box::new(crate::bio::WindowsHello);
+    #[cfg(target_os = "linux")]
+    {
+        use crate::{fprint::Fprintd, polkit::Polkit};
+        if Fprintd.status() != BiometricsStatus::Available
+            && !biometric_only_selected()
+            && Polkit.status() == BiometricsStatus::Available
+        {
+            return Box::new(Polkit);
+        }
+        Box::new(Fprintd)
+    }
+    #[cfg(not(any(windows, target_os = "linux")))]
+    Box::new(NoBiometrics)
+}
@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use indicatif::{ProgressBar, ProgressStyle};
+use std::time::Duration;
+
+/// Feedback on stderr for operations with several slow steps: a spinner showing the current
+/// step, or a bar when the number of items is known. Nothing is drawn when stderr isn't a
+/// console, and [`Progress::hidden`] draws nothing at all, for JSON output.
+pub struct Progress(ProgressBar);
+
+impl Progress {
+    /// A spinner starting at step `message`.
+    pub fn spinner(message: &str) -> Self {
+        let bar = ProgressBar::new_spinner().with_message(message.to_string());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Self(bar)
+    }
+
+    /// A bar over `len` items, starting at step `message`.
+    pub fn bar(len: usize, message: &str) -> Self {
+        let bar = ProgressBar::new(len as u64)
+            .with_style(
+                ProgressStyle::with_template("{spinner} [{bar:30}] {pos}/{len} {msg}")
+                    .expect("valid template")
+                    .progress_chars("=> "),
+            )
+            .with_message(message.to_string());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Self(bar)
+    }
+
+    pub fn hidden() -> Self {
+        Self(ProgressBar::hidden())
+    }
+
+    /// A spinner, or nothing for JSON output.
+    pub fn spinner_unless(json: bool, message: &str) -> Self {
+        if json {
+            Self::hidden()
+        } else {
+            Self::spinner(message)
+        }
+    }
+
+    /// Shows the step now under way.
+    pub fn step(&self, message: &str) {
+        self.0.set_message(message.to_string());
+    }
+
+    /// Counts one item as done.
+    pub fn inc(&self) {
+        self.0.inc(1);
+    }
+
+    /// Runs `f` with the bar cleared, to print an item's outcome or prompt in the middle of the
+    /// operation.
+    pub fn suspend<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.0.suspend(f)
+    }
+}
+
+/// Removes the spinner or bar, leaving whatever was printed above it.
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.0.finish_and_clear();
+    }
+}
@@ -1,10 +1,159 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
 
-use crate::crypto::{base64_decode, base64_encode};
-use anyhow::Result;
-use serde::{Deserialize, Serialize, Serializer};
-use std::time::SystemTime;
+use crate::crypto::{SecretString, base64_decode, base64_encode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _};
+use serde_json::{Map, Value};
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    time::SystemTime,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ProtoError {
+    #[error("Missing '{0}' field")]
+    MissingField(&'static str),
+    #[error("Invalid base64 in '{0}' field")]
+    BadBase64(&'static str),
+    #[error("Unsupported encryption type {0}")]
+    UnknownEncType(i32),
+    #[error("Encryption type {} requires a MAC", *.0 as i32)]
+    MissingMac(EncType),
+    #[error("Encryption type {} must not carry a MAC", *.0 as i32)]
+    UnexpectedMac(EncType),
+    #[error("Invalid IV length: {0}")]
+    InvalidIvLength(usize),
+    #[error("MAC verification failed")]
+    MacMismatch,
+    #[error("AES decrypt error")]
+    Decrypt,
+    #[error("{field} must be {expected}")]
+    Schema {
+        field: &'static str,
+        expected: &'static str,
+    },
+    #[error("Malformed message: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    String,
+    Base64,
+    Integer,
+    IntegerOrString,
+    Object,
+}
+
+impl Shape {
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            Shape::String => value.is_string(),
+            Shape::Base64 => value.as_str().is_some_and(|s| base64_decode(s).is_ok()),
+            Shape::Integer => value.is_i64() || value.is_u64(),
+            Shape::IntegerOrString => value.is_i64() || value.is_u64() || value.is_string(),
+            Shape::Object => value.is_object(),
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Shape::String => "a string",
+            Shape::Base64 => "a base64 string",
+            Shape::Integer => "an integer",
+            Shape::IntegerOrString => "an integer or string",
+            Shape::Object => "an object",
+        }
+    }
+}
+
+fn check_field<'a>(
+    obj: &'a Map<String, Value>,
+    path: &'static str,
+    shape: Shape,
+    required: bool,
+) -> Result<Option<&'a Value>, ProtoError> {
+    let key = path.rsplit('.').next().unwrap_or(path);
+    match obj.get(key) {
+        None | Some(Value::Null) if !required => Ok(None),
+        Some(value) if shape.matches(value) => Ok(Some(value)),
+        _ => Err(ProtoError::Schema {
+            field: path,
+            expected: shape.describe(),
+        }),
+    }
+}
+
+fn as_object<'a>(
+    value: &'a Value,
+    path: &'static str,
+) -> Result<&'a Map<String, Value>, ProtoError> {
+    value.as_object().ok_or(ProtoError::Schema {
+        field: path,
+        expected: Shape::Object.describe(),
+    })
+}
+
+/// Checks the shape of an inbound native message before it is dispatched.
+pub fn validate_message(msg: &Value) -> Result<(), ProtoError> {
+    let root = as_object(msg, "<root>")?;
+    check_field(root, "appId", Shape::String, true)?;
+    let message = as_object(&msg["message"], "message")?;
+    if message.get("command").and_then(Value::as_str) == Some("setupEncryption") {
+        check_field(message, "message.publicKey", Shape::Base64, true)?;
+        check_field(message, "message.oaepHash", Shape::String, false)?;
+    } else {
+        check_field(message, "message.encryptionType", Shape::Integer, true)?;
+        check_field(message, "message.iv", Shape::Base64, true)?;
+        check_field(message, "message.data", Shape::Base64, true)?;
+        check_field(message, "message.mac", Shape::Base64, false)?;
+    }
+    Ok(())
+}
+
+/// Checks the shape of a decrypted command payload before it is deserialized.
+pub fn validate_encrypted_message(msg: &Value) -> Result<(), ProtoError> {
+    let root = as_object(msg, "<decrypted>")?;
+    let command = check_field(root, "command", Shape::String, true)?.and_then(Value::as_str);
+    check_field(root, "messageId", Shape::IntegerOrString, true)?;
+    let for_user = command.is_some_and(|command| USER_COMMANDS.contains(&command));
+    if for_user && root.get("userId").is_none_or(Value::is_null) {
+        return Err(ProtoError::MissingField("userId"));
+    }
+    check_field(root, "userId", Shape::String, false)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncType {
+    AesCbc256B64 = 0,
+    AesCbc256HmacSha256B64 = 2,
+    /// bwbio's own AES-256-GCM, numbered outside the types Bitwarden assigns. It is not
+    /// Bitwarden's type 7 (COSE with XChaCha20-Poly1305), and the browser host refuses it.
+    BwbioAesGcm256B64 = 128,
+}
+
+impl EncType {
+    /// Whether the Bitwarden extension uses this type in native messaging.
+    pub fn in_browser_protocol(self) -> bool {
+        self != EncType::BwbioAesGcm256B64
+    }
+}
+
+impl TryFrom<i32> for EncType {
+    type Error = ProtoError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(EncType::AesCbc256B64),
+            2 => Ok(EncType::AesCbc256HmacSha256B64),
+            128 => Ok(EncType::BwbioAesGcm256B64),
+            _ => Err(ProtoError::UnknownEncType(value)),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncString {
@@ -12,58 +161,235 @@ pub struct EncString {
     enc_type: i32,
     data: String,
     iv: String,
-    mac: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
 }
 
 impl EncString {
-    pub fn new(data: &[u8], iv: &[u8], mac: &[u8]) -> Self {
+    pub fn new(enc_type: EncType, data: &[u8], iv: &[u8], mac: Option<&[u8]>) -> Self {
         Self {
-            enc_type: 2,
+            enc_type: enc_type as i32,
             data: base64_encode(data),
             iv: base64_encode(iv),
-            mac: base64_encode(mac),
+            mac: mac.map(base64_encode),
+        }
+    }
+
+    /// Returns the declared encryption type after checking that the provided fields match it.
+    pub fn enc_type(&self) -> Result<EncType, ProtoError> {
+        let enc_type = EncType::try_from(self.enc_type)?;
+        if self.data.is_empty() {
+            return Err(ProtoError::MissingField("data"));
+        }
+        if self.iv.is_empty() {
+            return Err(ProtoError::MissingField("iv"));
+        }
+        let has_mac = self.mac.as_deref().is_some_and(|mac| !mac.is_empty());
+        match enc_type {
+            EncType::AesCbc256B64 | EncType::BwbioAesGcm256B64 if has_mac => {
+                Err(ProtoError::UnexpectedMac(enc_type))
+            }
+            EncType::AesCbc256HmacSha256B64 if !has_mac => Err(ProtoError::MissingMac(enc_type)),
+            _ => Ok(enc_type),
+        }
+    }
+
+    pub fn data(&self) -> Result<Vec<u8>, ProtoError> {
+        base64_decode(&self.data).map_err(|_| ProtoError::BadBase64("data"))
+    }
+
+    pub fn iv(&self) -> Result<Vec<u8>, ProtoError> {
+        base64_decode(&self.iv).map_err(|_| ProtoError::BadBase64("iv"))
+    }
+
+    pub fn mac(&self) -> Result<Option<Vec<u8>>, ProtoError> {
+        self.mac
+            .as_deref()
+            .map(|mac| base64_decode(mac).map_err(|_| ProtoError::BadBase64("mac")))
+            .transpose()
+    }
+}
+
+/// Parses the `type.iv|data[|mac]` string form produced by [`Display`].
+impl FromStr for EncString {
+    type Err = ProtoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (enc_type, rest) = s
+            .split_once('.')
+            .ok_or(ProtoError::MissingField("encryptionType"))?;
+        let enc_type = enc_type.parse().map_err(|_| ProtoError::Schema {
+            field: "encryptionType",
+            expected: "an integer",
+        })?;
+        let mut parts = rest.split('|');
+        let iv = parts.next().unwrap_or_default().to_string();
+        let data = parts
+            .next()
+            .ok_or(ProtoError::MissingField("data"))?
+            .to_string();
+        let mac = parts.next().map(str::to_string);
+        let enc_str = Self {
+            enc_type,
+            data,
+            iv,
+            mac,
+        };
+        enc_str.enc_type()?;
+        Ok(enc_str)
+    }
+}
+
+impl Display for EncString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.mac {
+            Some(mac) => write!(f, "{}.{}|{}|{}", self.enc_type, self.iv, self.data, mac),
+            None => write!(f, "{}.{}|{}", self.enc_type, self.iv, self.data),
         }
     }
+}
+
+/// Commands that act on the account named by their `userId`.
+const USER_COMMANDS: [&str; 2] = ["unlockWithBiometricsForUser", "getBiometricsStatusForUser"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum Command {
+    UnlockWithBiometricsForUser {
+        #[serde(rename = "userId")]
+        user_id: String,
+    },
+    AuthenticateWithBiometrics,
+    GetBiometricsStatus,
+    GetBiometricsStatusForUser {
+        #[serde(rename = "userId")]
+        user_id: String,
+    },
+    /// A command this version doesn't know, which goes unanswered.
+    #[serde(untagged, deserialize_with = "unknown_command")]
+    Unknown(String),
+}
 
-    pub fn data(&self) -> Result<Vec<u8>> {
-        base64_decode(&self.data)
+/// The name of an unknown command. A known one only ends up here when its payload is wrong,
+/// which is an error rather than a command to ignore.
+fn unknown_command<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    struct RawCommand {
+        command: String,
+    }
+    let command = RawCommand::deserialize(deserializer)?.command;
+    let known = ["authenticateWithBiometrics", "getBiometricsStatus"];
+    if known
+        .iter()
+        .chain(&USER_COMMANDS)
+        .any(|&name| name == command)
+    {
+        return Err(D::Error::custom(format!("invalid {command} payload")));
     }
+    Ok(command)
+}
 
-    pub fn iv(&self) -> Result<Vec<u8>> {
-        base64_decode(&self.iv)
+impl Command {
+    /// The wire name of the command, echoed back in responses.
+    pub fn name(&self) -> &str {
+        match self {
+            Command::UnlockWithBiometricsForUser { .. } => "unlockWithBiometricsForUser",
+            Command::AuthenticateWithBiometrics => "authenticateWithBiometrics",
+            Command::GetBiometricsStatus => "getBiometricsStatus",
+            Command::GetBiometricsStatusForUser { .. } => "getBiometricsStatusForUser",
+            Command::Unknown(name) => name,
+        }
     }
+}
 
-    pub fn mac(&self) -> Result<Vec<u8>> {
-        base64_decode(&self.mac)
+/// Biometrics availability as reported to the extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BiometricsStatus {
+    Available,
+    /// No Windows Hello capable device, or it is busy.
+    HardwareUnavailable,
+    /// Windows Hello works but no key is stored for the account.
+    ManualSetupNeeded,
+    DisabledByPolicy,
+    PlatformUnsupported,
+    /// Windows Hello is not set up for the Windows user.
+    NotEnrolled,
+    /// bwbio runs in a Remote Desktop session, where Windows Hello can't reach the local sensor.
+    RemoteSession,
+}
+
+impl BiometricsStatus {
+    /// The extension's `BiometricsStatus` value. It has no policy state, so a policy block is
+    /// reported like an unsupported platform.
+    pub fn wire_code(self) -> i32 {
+        match self {
+            BiometricsStatus::Available => 0,
+            BiometricsStatus::HardwareUnavailable | BiometricsStatus::RemoteSession => 2,
+            BiometricsStatus::ManualSetupNeeded => 4,
+            BiometricsStatus::DisabledByPolicy | BiometricsStatus::PlatformUnsupported => 5,
+            BiometricsStatus::NotEnrolled => 7,
+        }
     }
 }
 
-impl ToString for EncString {
-    fn to_string(&self) -> String {
-        format!("{}.{}|{}|{}", self.enc_type, self.iv, self.data, self.mac)
+impl Display for BiometricsStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BiometricsStatus::Available => "Windows Hello is available",
+            BiometricsStatus::HardwareUnavailable => {
+                "No Windows Hello device is present, or it is busy"
+            }
+            BiometricsStatus::ManualSetupNeeded => "No key is stored for this account",
+            BiometricsStatus::DisabledByPolicy => "Windows Hello is disabled by policy",
+            BiometricsStatus::PlatformUnsupported => "Windows Hello is not supported here",
+            BiometricsStatus::NotEnrolled => {
+                "Windows Hello is not set up; add a PIN, fingerprint or face in Settings > Accounts > Sign-in options"
+            }
+            BiometricsStatus::RemoteSession => {
+                "Running in a Remote Desktop session, where Windows Hello is unavailable; unlock at the machine itself"
+            }
+        })
     }
 }
 
+/// Message identifier as sent by the extension; some builds send a string instead of an integer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageId {
+    Number(i64),
+    String(String),
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct EncryptedMessage {
-    command: String,
+    #[serde(flatten)]
+    command: Command,
     #[serde(rename = "messageId")]
-    message_id: i64,
-    #[serde(rename = "userId")]
-    user_id: Option<String>,
+    message_id: MessageId,
 }
 
 impl EncryptedMessage {
-    pub fn command(&self) -> &str {
+    pub fn command(&self) -> &Command {
         &self.command
     }
 
-    pub fn message_id(&self) -> i64 {
-        self.message_id
+    pub fn message_id(&self) -> &MessageId {
+        &self.message_id
     }
+}
 
-    pub fn user_id(&self) -> Option<&str> {
-        self.user_id.as_deref()
+#[derive(Debug, Clone, Serialize)]
+pub struct ResponseError {
+    code: String,
+    message: String,
+}
+
+impl ResponseError {
+    pub fn new(code: &str, message: &str) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
     }
 }
 
@@ -71,6 +397,9 @@ impl EncryptedMessage {
 pub enum ResponseData {
     Number(i32),
     Bool(bool),
+    String(String),
+    Object(Map<String, Value>),
+    Error(ResponseError),
 }
 
 impl Serialize for ResponseData {
@@ -81,31 +410,73 @@ impl Serialize for ResponseData {
         match self {
             ResponseData::Number(n) => serializer.serialize_i32(*n),
             ResponseData::Bool(b) => serializer.serialize_bool(*b),
+            ResponseData::String(s) => serializer.serialize_str(s),
+            ResponseData::Object(o) => o.serialize(serializer),
+            ResponseData::Error(e) => e.serialize(serializer),
         }
     }
 }
 
+impl From<i32> for ResponseData {
+    fn from(n: i32) -> Self {
+        ResponseData::Number(n)
+    }
+}
+
+impl From<BiometricsStatus> for ResponseData {
+    fn from(status: BiometricsStatus) -> Self {
+        ResponseData::Number(status.wire_code())
+    }
+}
+
+impl From<bool> for ResponseData {
+    fn from(b: bool) -> Self {
+        ResponseData::Bool(b)
+    }
+}
+
+impl From<String> for ResponseData {
+    fn from(s: String) -> Self {
+        ResponseData::String(s)
+    }
+}
+
+impl From<Map<String, Value>> for ResponseData {
+    fn from(o: Map<String, Value>) -> Self {
+        ResponseData::Object(o)
+    }
+}
+
+impl From<ResponseError> for ResponseData {
+    fn from(e: ResponseError) -> Self {
+        ResponseData::Error(e)
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ResponseMessage {
     timestamp: u64,
     command: String,
     #[serde(rename = "messageId")]
-    message_id: i64,
+    message_id: MessageId,
     response: ResponseData,
     #[serde(rename = "userKeyB64")]
-    key: Option<String>,
+    key: Option<SecretString>,
+    /// Pre-`userKeyB64` name of the key field, only emitted for older extension releases.
+    #[serde(rename = "keyB64", skip_serializing_if = "Option::is_none")]
+    legacy_key: Option<SecretString>,
 }
 
 impl ResponseMessage {
-    pub fn new<T: Into<ResponseData>>(command: &str, message_id: i64, response: T) -> Self {
+    pub fn new<T: Into<ResponseData>>(command: &str, message_id: MessageId, response: T) -> Self {
         Self::with_key(command, message_id, response, None)
     }
 
     pub fn with_key<T: Into<ResponseData>>(
         command: &str,
-        message_id: i64,
+        message_id: MessageId,
         response: T,
-        key: Option<String>,
+        key: Option<SecretString>,
     ) -> Self {
         Self {
             timestamp: SystemTime::now()
@@ -116,10 +487,212 @@ impl ResponseMessage {
             message_id,
             response: response.into(),
             key,
+            legacy_key: None,
+        }
+    }
+
+    pub fn with_legacy_key(mut self) -> Self {
+        self.legacy_key = self.key.clone();
+        self
+    }
+
+    pub fn message_id(&self) -> &MessageId {
+        &self.message_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response_json(response: impl Into<ResponseData>) -> Value {
+        let message = ResponseMessage::new("biometricUnlock", MessageId::Number(7), response);
+        let mut value = serde_json::to_value(message).unwrap();
+        assert!(value["timestamp"].is_u64());
+        value.as_object_mut().unwrap().remove("timestamp");
+        value
+    }
+
+    const CBC_HMAC: &str = "2.QEFCQ0RFRkdISUpLTE1OTw==|X6YSrTHeP9P9aGxbkb6r0i0GYY2WoN5pT/TdstddX9M=|vgBez3GaToA1M5pXLVTK5hlAcIi2XjmKReLdF+/VWhA=";
+
+    #[test]
+    fn enc_string_roundtrip() {
+        let enc_str = EncString::from_str(CBC_HMAC).unwrap();
+        assert_eq!(enc_str.enc_type().unwrap(), EncType::AesCbc256HmacSha256B64);
+        assert_eq!(enc_str.iv().unwrap(), (0x40..0x50).collect::<Vec<u8>>());
+        assert_eq!(enc_str.mac().unwrap().unwrap().len(), 32);
+        assert_eq!(enc_str.to_string(), CBC_HMAC);
+
+        let gcm = EncString::new(EncType::BwbioAesGcm256B64, b"data", &[1; 12], None);
+        assert_eq!(gcm.to_string(), "128.AQEBAQEBAQEBAQEB|ZGF0YQ==");
+        let parsed = EncString::from_str(&gcm.to_string()).unwrap();
+        assert_eq!(parsed.enc_type().unwrap(), EncType::BwbioAesGcm256B64);
+        assert_eq!(parsed.data().unwrap(), b"data");
+        assert_eq!(parsed.mac().unwrap(), None);
+    }
+
+    #[test]
+    fn enc_string_json() {
+        let enc_str = EncString::new(
+            EncType::AesCbc256HmacSha256B64,
+            b"data",
+            &[0; 16],
+            Some(&[1; 32]),
+        );
+        let value = serde_json::to_value(&enc_str).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "encryptionType": 2,
+                "data": "ZGF0YQ==",
+                "iv": "AAAAAAAAAAAAAAAAAAAAAA==",
+                "mac": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=",
+            })
+        );
+        let parsed: EncString = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.to_string(), enc_str.to_string());
+
+        let gcm = EncString::new(EncType::BwbioAesGcm256B64, b"data", &[0; 12], None);
+        assert!(serde_json::to_value(gcm).unwrap().get("mac").is_none());
+    }
+
+    #[test]
+    fn commands() {
+        let parse = |msg: Value| {
+            validate_encrypted_message(&msg)?;
+            Ok::<_, ProtoError>(serde_json::from_value::<EncryptedMessage>(msg)?)
+        };
+        let unlock =
+            json!({ "command": "unlockWithBiometricsForUser", "userId": "alice", "messageId": 1 });
+        assert!(matches!(
+            parse(unlock).unwrap().command(),
+            Command::UnlockWithBiometricsForUser { user_id } if user_id == "alice"
+        ));
+        let unknown = json!({ "command": "somethingNew", "messageId": "2" });
+        assert!(matches!(
+            parse(unknown).unwrap().command(),
+            Command::Unknown(name) if name == "somethingNew"
+        ));
+
+        // A known command without its account is refused, not ignored as an unknown one.
+        for command in USER_COMMANDS {
+            let msg = json!({ "command": command, "messageId": 3 });
+            assert_eq!(
+                parse(msg.clone()).unwrap_err().to_string(),
+                "Missing 'userId' field"
+            );
+            assert!(serde_json::from_value::<EncryptedMessage>(msg).is_err());
         }
     }
 
-    pub fn message_id(&self) -> i64 {
-        self.message_id
+    #[test]
+    fn enc_string_rejects_malformed() {
+        let rejects = |s: &str| EncString::from_str(s).unwrap_err();
+        assert!(matches!(
+            rejects("AAAA|BBBB"),
+            ProtoError::MissingField("encryptionType")
+        ));
+        assert!(matches!(rejects("x.AAAA|BBBB"), ProtoError::Schema { .. }));
+        assert!(matches!(
+            rejects("3.AAAA|BBBB"),
+            ProtoError::UnknownEncType(3)
+        ));
+        // Bitwarden's type 7 is COSE, which bwbio doesn't speak.
+        assert!(matches!(
+            rejects("7.AAAA|BBBB"),
+            ProtoError::UnknownEncType(7)
+        ));
+        assert!(matches!(
+            rejects("2.AAAA"),
+            ProtoError::MissingField("data")
+        ));
+        assert!(matches!(
+            rejects("2.|BBBB|CCCC"),
+            ProtoError::MissingField("iv")
+        ));
+        assert!(matches!(rejects("2.AAAA|BBBB"), ProtoError::MissingMac(_)));
+        assert!(matches!(
+            rejects("128.AAAA|BBBB|CCCC"),
+            ProtoError::UnexpectedMac(_)
+        ));
+        assert!(matches!(
+            rejects("0.AAAA|BBBB|CCCC"),
+            ProtoError::UnexpectedMac(_)
+        ));
+
+        let bad_base64 = EncString::from_str("0.AAAA|!!!!").unwrap();
+        assert!(matches!(
+            bad_base64.data(),
+            Err(ProtoError::BadBase64("data"))
+        ));
+    }
+
+    #[test]
+    fn number_response() {
+        assert_eq!(
+            response_json(BiometricsStatus::Available),
+            json!({
+                "command": "biometricUnlock",
+                "messageId": 7,
+                "response": 0,
+                "userKeyB64": null,
+            })
+        );
+        assert_eq!(
+            response_json(BiometricsStatus::NotEnrolled)["response"],
+            json!(7)
+        );
+    }
+
+    #[test]
+    fn bool_response() {
+        assert_eq!(response_json(true)["response"], json!(true));
+        assert_eq!(response_json(false)["response"], json!(false));
+    }
+
+    #[test]
+    fn string_response() {
+        assert_eq!(
+            response_json("unlocked".to_string())["response"],
+            json!("unlocked")
+        );
+    }
+
+    #[test]
+    fn object_response() {
+        let mut object = Map::new();
+        object.insert("status".to_string(), json!("ok"));
+        object.insert("count".to_string(), json!(2));
+        assert_eq!(
+            response_json(object)["response"],
+            json!({ "status": "ok", "count": 2 })
+        );
+    }
+
+    #[test]
+    fn error_response() {
+        assert_eq!(
+            response_json(ResponseError::new("canceled", "User canceled"))["response"],
+            json!({ "code": "canceled", "message": "User canceled" })
+        );
+    }
+
+    #[test]
+    fn key_fields() {
+        let key = SecretString::from("a2V5".to_string());
+        let message = ResponseMessage::with_key(
+            "unlockWithBiometricsForUser",
+            MessageId::String("abc".to_string()),
+            true,
+            Some(key),
+        );
+        let value = serde_json::to_value(&message).unwrap();
+        assert_eq!(value["messageId"], json!("abc"));
+        assert_eq!(value["userKeyB64"], json!("a2V5"));
+        assert!(value.get("keyB64").is_none());
+
+        let value = serde_json::to_value(message.with_legacy_key()).unwrap();
+        assert_eq!(value["keyB64"], json!("a2V5"));
     }
 }
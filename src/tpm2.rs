@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::crypto::SecretBytes;
+use crate::error::Result;
+use crate::keyfile::{KeyProvider, WrapAlgorithm};
+use crate::kmgr::KeyWrapper;
+use crate::presence::{BiometricVerifier, DEFAULT_REASON, Presence, PresenceError};
+use crate::proto::BiometricsStatus;
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+use thiserror::Error;
+
+/// Name sealed into every credential; `systemd-creds` refuses to decrypt one made for another.
+const CREDENTIAL_NAME: &str = "bwbio";
+
+#[derive(Debug, Error)]
+pub enum Tpm2Error {
+    #[error("Failed to run systemd-creds: {0}")]
+    Spawn(#[source] io::Error),
+    #[error("systemd-creds {action} failed: {message}")]
+    Failed {
+        action: &'static str,
+        message: String,
+    },
+}
+
+/// The Linux backend: data keys are sealed to this machine's TPM2 by `systemd-creds`, so key
+/// files copied elsewhere can't be opened. The TPM doesn't ask for the user, so `verifier` does
+/// before each unwrap; unlike the other backends, unwrapping fails when it can't. The user needs
+/// access to `/dev/tpmrm0`, usually through the `tss` group.
+pub struct Tpm2KeyWrap {
+    verifier: Box<dyn BiometricVerifier>,
+}
+
+impl Tpm2KeyWrap {
+    pub fn new(verifier: Box<dyn BiometricVerifier>) -> Self {
+        Self { verifier }
+    }
+
+    /// Asks for the user, refusing when neither fprintd nor polkit can; the TPM would release
+    /// the keys to anyone otherwise.
+    fn verify_presence(&self, reason: &str) -> Result<(), PresenceError> {
+        match self.verifier.status() {
+            BiometricsStatus::Available if self.verifier.authenticate(reason) => Ok(()),
+            BiometricsStatus::Available => Err(PresenceError::Failed),
+            status @ BiometricsStatus::RemoteSession => Err(PresenceError::Unavailable(status)),
+            status => Err(PresenceError::NoVerifier(status)),
+        }
+    }
+}
+
+/// Runs `systemd-creds <action>` with `input` on stdin and returns its stdout.
+fn systemd_creds(action: &'static str, input: &[u8]) -> Result<Vec<u8>, Tpm2Error> {
+    let mut command = Command::new("systemd-creds");
+    command.arg(format!("--name={CREDENTIAL_NAME}"));
+    if action == "encrypt" {
+        command.arg("--with-key=tpm2");
+    }
+    let mut child = command
+        .args([action, "-", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Tpm2Error::Spawn)?;
+    // Keys and credentials are far smaller than a pipe buffer, so writing all of stdin before
+    // reading stdout can't block.
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(input).map_err(Tpm2Error::Spawn)?;
+    drop(stdin);
+    let output = child.wait_with_output().map_err(Tpm2Error::Spawn)?;
+    if !output.status.success() {
+        return Err(Tpm2Error::Failed {
+            action,
+            message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(output.stdout)
+}
+
+impl KeyWrapper for Tpm2KeyWrap {
+    fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>> {
+        Ok(systemd_creds("encrypt", data_key)?)
+    }
+
+    fn describe(&self) -> Result<(WrapAlgorithm, String)> {
+        Ok((WrapAlgorithm::Tpm2, String::new()))
+    }
+
+    fn unwrap(&self, wrapped: &[u8], presence: Option<&Presence>) -> Result<(SecretBytes, bool)> {
+        if presence.is_none() {
+            self.verify_presence(DEFAULT_REASON)?;
+        }
+        let data_key = SecretBytes::from(systemd_creds("decrypt", wrapped)?);
+        Ok((data_key, false))
+    }
+
+    fn with_user_presence(
+        &self,
+        reason: &str,
+        f: &mut dyn FnMut(&Presence) -> Result<()>,
+    ) -> Result<()> {
+        Presence::scope(|| Ok(self.verify_presence(reason)?), f)
+    }
+
+    fn protection(&self) -> String {
+        "TPM2 (systemd-creds)".to_string()
+    }
+
+    fn provider(&self) -> Result<Option<KeyProvider>> {
+        Ok(Some(KeyProvider::Tpm))
+    }
+}
@@ -1,20 +1,32 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2025 Aalivexy
 
-use crate::cng::default_key_name;
-use crate::kmgr::KeyManager;
-use dialoguer::{Confirm, Input, Select};
+use crate::audit::AuditSource;
+use crate::bio::biometrics_details;
+use crate::cli::describe_metadata;
+use crate::cng::CngProviderKind;
+use crate::config::{check_config, config};
+use crate::crypto::{SecretString, self_test};
+use crate::dashboard::{self, DashboardAction};
+use crate::desktop::{biometric_user_ids, default_data_path, read_accounts};
+use crate::install::{
+    EXE_NAME, ManifestTarget, NATIVE_MESSAGING_HOSTS, NativeMessagingHost, RegistryAccessDenied,
+    RegistryScope, UninstallOptions, autostart_entry, create_start_menu_shortcut,
+    default_install_dir, desktop_conflicts, detect_browsers, enable_autostart,
+    find_existing_install, key_dir_for, migrate_install, perform_install, perform_uninstall,
+    register_elevated, register_native_messaging_manifest, remember_browsers, same_dir,
+    select_browsers, start_menu_shortcut, unregister_native_messaging_manifest,
+};
+use crate::keystore::open_key_store;
+use crate::kmgr::{KeyManager, active_cng_key_name, machine_key_selected};
+use crate::log::init_logging;
+use crate::progress::Progress;
+use crate::proto::BiometricsStatus;
+use crate::update::{apply_update, check_for_update};
+use dialoguer::{Confirm, Input, MultiSelect, Password, Select};
 use std::env;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use windows_registry::CURRENT_USER;
-use windows_strings::HSTRING;
-
-const MANIFEST_NAME: &str = "chrome.json";
-const REG_KEYS: [&str; 2] = [
-    "software\\google\\chrome\\nativemessaginghosts\\com.8bit.bitwarden",
-    "software\\microsoft\\edge\\nativemessaginghosts\\com.8bit.bitwarden",
-];
 
 fn pause_before_exit() {
     let _: Result<String, _> = Input::new()
@@ -30,135 +42,136 @@ fn spawn_and_exit(path: &Path) -> Result<(), String> {
     }
 }
 
-fn register_native_messaging_manifest(manifest_path: &Path) -> Result<(), String> {
-    let manifest_abs = std::fs::canonicalize(manifest_path)
-        .map_err(|e| format!("Failed to canonicalize manifest path: {e}"))?;
-    let manifest_str = manifest_abs.to_string_lossy().to_string();
-    let manifest_str = manifest_str.strip_prefix(r"\\?\").unwrap_or(&manifest_str);
-    let mut success_count = 0;
-
-    for key_path in REG_KEYS {
-        match CURRENT_USER.create(key_path) {
-            Ok(key) => match key.set_string("", manifest_str) {
-                Ok(_) => success_count += 1,
-                Err(e) => eprintln!("Warning: failed to set default value for {key_path}: {e}"),
-            },
-            Err(e) => eprintln!("Warning: failed to create/open registry key {key_path}: {e}"),
-        }
+/// Asks which browsers to register, starting from the remembered (or installed) ones, and
+/// remembers the answer.
+fn choose_browsers() -> Result<Vec<NativeMessagingHost>, String> {
+    let selected = select_browsers(None).map_err(|e| format!("{e:#}"))?;
+    let items = NATIVE_MESSAGING_HOSTS
+        .iter()
+        .map(|host| match host.installed() {
+            Some(_) => format!("{} (installed)", host.browser),
+            None => host.browser.to_string(),
+        })
+        .collect::<Vec<_>>();
+    let defaults = NATIVE_MESSAGING_HOSTS
+        .iter()
+        .map(|host| selected.contains(host))
+        .collect::<Vec<_>>();
+    let chosen = MultiSelect::new()
+        .with_prompt("Register with which browsers? (space to toggle, enter to confirm)")
+        .items(&items)
+        .defaults(&defaults)
+        .interact()
+        .map_err(|e| format!("Failed to read selection: {e}"))?;
+    let hosts = chosen
+        .into_iter()
+        .map(|i| NATIVE_MESSAGING_HOSTS[i])
+        .collect::<Vec<_>>();
+    if let Err(e) = remember_browsers(&hosts) {
+        eprintln!("Warning: failed to remember the browsers: {e:#}");
     }
-
-    if success_count == 0 {
-        eprintln!(
-            "Warning: no supported browsers detected or registry writes failed. Manually register {} if needed.",
-            manifest_abs.display()
-        );
-    }
-
-    Ok(())
+    Ok(hosts)
 }
 
-fn unregister_native_messaging_manifest() {
-    let mut any_success = false;
-    for key_path in REG_KEYS {
-        if CURRENT_USER.remove_tree(key_path).is_ok() {
-            any_success = true;
-        }
-    }
-
-    if !any_success {
-        eprintln!(
-            "Warning: no registry values removed (no supported browsers detected or already unregistered)"
-        );
+/// Handles a failed registration: when the registry denied access, offers to retry it as
+/// administrator.
+fn retry_registration_elevated(
+    e: anyhow::Error,
+    install_dir: &Path,
+    hosts: &[NativeMessagingHost],
+) -> Result<(), String> {
+    let Some(denied) = e.downcast_ref::<RegistryAccessDenied>() else {
+        return Err(format!("{e:#}"));
+    };
+    eprintln!("{denied}.");
+    let elevate = Confirm::new()
+        .with_prompt("Retry the browser registration as administrator?")
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+    if !elevate {
+        return Err("The browsers weren't registered".to_string());
     }
+    register_elevated(install_dir, hosts, RegistryScope::User).map_err(|e| format!("{e:#}"))
 }
 
-fn perform_install(install_dir: &Path) -> Result<(), String> {
-    if let Err(e) = std::fs::create_dir_all(install_dir) {
-        return Err(format!("Failed to create install directory: {e}"));
-    }
-
-    let current_exe =
-        env::current_exe().map_err(|e| format!("Failed to get current exe path: {e}"))?;
-    let target_exe = install_dir.join("bwbio.exe");
-    if let Err(e) = std::fs::copy(&current_exe, &target_exe) {
-        return Err(format!("Failed to copy exe to target location: {e}"));
-    }
-    let target_exe = std::fs::canonicalize(&target_exe)
-        .unwrap_or(target_exe)
-        .to_string_lossy()
-        .to_string();
-    let target_exe = target_exe.strip_prefix(r"\\?\").unwrap_or(&target_exe);
-
-    let manifest = serde_json::json!({
-        "name": "com.8bit.bitwarden",
-        "description": "Bitwarden desktop <-> browser bridge",
-        "path": target_exe,
-        "type": "stdio",
-        "allowed_origins": [
-            "chrome-extension://nngceckbapebfimnlniiiahkandclblb/",
-            "chrome-extension://hccnnhgbibccigepcmlgppchkpfdophk/",
-            "chrome-extension://jbkfoedolllekgbhcbcoahefnbanhhlh/",
-            "chrome-extension://ccnckbpmaceehanjmeomladnmlffdjgn/"
-        ]
-    });
-
-    let manifest_path = install_dir.join("chrome.json");
-    if let Err(e) = std::fs::write(&manifest_path, manifest.to_string()) {
-        return Err(format!("Failed to write manifest: {e}"));
+fn install_and_spawn(install_dir: &Path) -> Result<(), String> {
+    let hosts = choose_browsers()?;
+    let shortcut = Confirm::new()
+        .with_prompt("Add a Start Menu shortcut to the management menu?")
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+    let autostart = Confirm::new()
+        .with_prompt("Start the bwbio background agent at logon?")
+        .default(false)
+        .interact()
+        .unwrap_or(false);
+    let installed = perform_install(install_dir, &hosts, &Progress::spinner("Installing bwbio"));
+    let installed_exe = match installed {
+        Ok(exe) => exe,
+        Err(e) => {
+            retry_registration_elevated(e, install_dir, &hosts)?;
+            install_dir.join(EXE_NAME)
+        }
+    };
+    if shortcut && let Err(e) = create_start_menu_shortcut(&installed_exe) {
+        eprintln!("Warning: {e:#}");
     }
-
-    if let Err(e) = register_native_messaging_manifest(manifest_path.as_path()) {
-        return Err(format!("Failed to write registry entries: {e}"));
+    if autostart && let Err(e) = enable_autostart(&installed_exe) {
+        eprintln!("Warning: failed to add the logon autostart entry: {e}");
     }
-
+    spawn_and_exit(installed_exe.as_path())?;
     Ok(())
 }
 
-fn perform_uninstall(install_dir: &Path, key_dir: &Path) -> Result<(), String> {
-    unregister_native_messaging_manifest();
-
-    if key_dir.exists() {
-        if let Err(e) = std::fs::remove_dir_all(key_dir) {
-            eprintln!("Warning: failed to remove keys directory: {e}");
-        }
-    }
-
-    let manifest_path = install_dir.join(MANIFEST_NAME);
-    if manifest_path.exists() {
-        if let Err(e) = std::fs::remove_file(&manifest_path) {
-            eprintln!("Warning: failed to remove manifest: {e}");
-        }
+/// The dashboard, coming back to it after each prompt it hands off. `simple_menu` (or
+/// `BW_SIMPLE_MENU`), or a console that can't show it, selects the management menu instead.
+fn dashboard_flow(kmgr: &mut KeyManager, install_dir: &Path, key_dir: &Path) -> Result<(), String> {
+    if config().simple_menu() {
+        return management_menu(kmgr, install_dir, key_dir);
     }
-
-    if let Ok(cur) = env::current_exe() {
-        let tmp = env::temp_dir().join("bwbio_uninstall.exe");
-        if let Err(e) = std::fs::rename(&cur, &tmp) {
-            eprintln!("Warning: failed to move exe to temp: {e}");
-        } else if let Err(e) = std::fs::remove_dir_all(install_dir) {
-            eprintln!("Warning: failed to remove install directory: {e}");
-        }
-    }
-
-    if let Ok(provider) = crate::cng::CngProvider::new() {
-        let key_name = match env::var("CNG_KEY_NAME") {
-            Ok(s) => HSTRING::from(s),
-            Err(_) => default_key_name(),
-        };
-        if let Ok(key) = provider.open_key(key_name) {
-            if let Err(e) = key.delete() {
-                eprintln!("Warning: failed to delete CNG key: {e}");
+    loop {
+        match dashboard::run(kmgr) {
+            Ok(DashboardAction::Menu) => return management_menu(kmgr, install_dir, key_dir),
+            Ok(DashboardAction::Import) => import_key_flow(kmgr)?,
+            Ok(DashboardAction::Delete(user_id)) => {
+                if confirm_delete_key(kmgr, &user_id) {
+                    match kmgr.delete_key(&user_id) {
+                        Ok(_) => println!("Key deleted."),
+                        Err(e) => eprintln!("Failed to delete key: {e}"),
+                    }
+                }
+            }
+            Ok(DashboardAction::Exit) => return Ok(()),
+            Err(e) => {
+                eprintln!("The dashboard can't be shown ({e}); using the menu.");
+                return management_menu(kmgr, install_dir, key_dir);
             }
         }
     }
-
-    Ok(())
 }
 
-fn install_and_spawn(install_dir: &Path) -> Result<(), String> {
-    perform_install(install_dir)?;
-    let installed_exe = install_dir.join("bwbio.exe");
-    spawn_and_exit(installed_exe.as_path())?;
-    Ok(())
+/// Offers to move the installation in `old` to `install_dir` with its keys rather than leave
+/// it behind. Returns whether it was moved and the new install started.
+fn offer_migration(old: &Path, install_dir: &Path) -> Result<bool, String> {
+    println!("bwbio is already installed in {}.", old.display());
+    let migrate = Confirm::new()
+        .with_prompt(format!(
+            "Move it to {}, with its keys, settings and browser registrations?",
+            install_dir.display()
+        ))
+        .default(true)
+        .interact()
+        .map_err(|e| format!("Failed to prompt for the move: {e}"))?;
+    if !migrate {
+        return Ok(false);
+    }
+    let hosts = choose_browsers()?;
+    let exe = migrate_install(old, install_dir, &hosts, &Progress::spinner("Moving bwbio"))
+        .map_err(|e| format!("{e:#}"))?;
+    spawn_and_exit(&exe)?;
+    Ok(true)
 }
 
 fn import_key_flow(kmgr: &KeyManager) -> Result<(), String> {
@@ -180,7 +193,23 @@ fn import_key_flow(kmgr: &KeyManager) -> Result<(), String> {
         Err(_) => return Ok(()),
     };
 
-    match kmgr.import_key(&user_id, &user_key) {
+    let server = match Input::<String>::new()
+        .with_prompt("Server URL (empty for none)")
+        .allow_empty(true)
+        .interact_text()
+    {
+        Ok(s) => s.trim().to_string(),
+        Err(_) => return Ok(()),
+    };
+
+    let imported = kmgr.import_key(&user_id, &user_key).and_then(|_| {
+        if server.is_empty() {
+            Ok(())
+        } else {
+            kmgr.set_server_url(&user_id, Some(&server))
+        }
+    });
+    match imported {
         Ok(_) => println!("Key imported successfully."),
         Err(e) => eprintln!("Failed to import key: {e}"),
     }
@@ -188,6 +217,194 @@ fn import_key_flow(kmgr: &KeyManager) -> Result<(), String> {
     Ok(())
 }
 
+fn import_desktop_flow(kmgr: &KeyManager) -> Result<(), String> {
+    // Accounts with desktop biometric unlock can be taken over with Windows Hello alone.
+    let biometric_ids = biometric_user_ids().unwrap_or_default();
+    if !biometric_ids.is_empty() {
+        let methods = [
+            "Windows Hello (Bitwarden desktop biometric unlock)",
+            "Master password",
+        ];
+        match Select::new().items(&methods).default(0).interact() {
+            Ok(0) => {
+                let Ok(idx) = Select::new().items(&biometric_ids).default(0).interact() else {
+                    return Ok(());
+                };
+                match kmgr.import_desktop_biometric(&biometric_ids[idx]) {
+                    Ok(_) => println!("Key imported successfully."),
+                    Err(e) => eprintln!("Failed to import key: {e}"),
+                }
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(_) => return Ok(()),
+        }
+    }
+
+    let Some(path) = default_data_path() else {
+        eprintln!("APPDATA not set. Cannot locate Bitwarden desktop data.");
+        return Ok(());
+    };
+    let accounts = match read_accounts(&path) {
+        Ok(accounts) if accounts.is_empty() => {
+            println!("No Bitwarden desktop accounts found in {}.", path.display());
+            return Ok(());
+        }
+        Ok(accounts) => accounts,
+        Err(e) => {
+            eprintln!("Failed to read {}: {e}", path.display());
+            return Ok(());
+        }
+    };
+
+    let items = accounts
+        .iter()
+        .map(|a| format!("{} ({})", a.email(), a.user_id()))
+        .collect::<Vec<_>>();
+    let Ok(idx) = Select::new().items(&items).default(0).interact() else {
+        return Ok(());
+    };
+    let account = &accounts[idx];
+
+    let password = match Password::new()
+        .with_prompt(format!("Master password for {}", account.email()))
+        .interact()
+    {
+        Ok(s) => SecretString::from(s),
+        Err(_) => return Ok(()),
+    };
+
+    match kmgr.import_desktop_account(account, password.expose()) {
+        Ok(_) => println!("Key imported successfully."),
+        Err(e) => eprintln!("Failed to import key: {e}"),
+    }
+
+    Ok(())
+}
+
+/// Takes a new install to working biometric unlock: checks Windows Hello, imports the keys
+/// of Bitwarden desktop's accounts (or a pasted key when there is no desktop app) and
+/// registers the chosen browsers.
+fn setup_wizard(kmgr: &KeyManager, install_dir: &Path) -> Result<(), String> {
+    println!("Step 1 of 3: Windows Hello");
+    match biometrics_details().status {
+        BiometricsStatus::Available => println!("Windows Hello is ready."),
+        status => println!(
+            "Windows Hello can't be used yet ({status}). Set it up under Settings > Accounts > Sign-in options; every unlock needs it."
+        ),
+    }
+
+    println!("\nStep 2 of 3: Import your Bitwarden keys");
+    wizard_import(kmgr)?;
+    let imported = kmgr
+        .list_keys()
+        .map(|keys| keys.into_iter().map(|k| k.user_id).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if imported.is_empty() {
+        println!("No key was imported; you can import one from the menu later.");
+    }
+
+    println!("\nStep 3 of 3: Browsers");
+    let hosts = choose_browsers()?;
+    unregister_native_messaging_manifest(&NATIVE_MESSAGING_HOSTS);
+    if let Err(e) = register_native_messaging_manifest(install_dir, &hosts) {
+        retry_registration_elevated(e, install_dir, &hosts)
+            .map_err(|e| format!("Failed to register the browsers: {e}"))?;
+    }
+
+    if !imported.is_empty() {
+        println!(
+            "\nAll set. Restart the browser, then turn on \"Unlock with biometrics\" under Settings > Account security in the Bitwarden extension."
+        );
+    }
+    Ok(())
+}
+
+/// Imports the Bitwarden desktop accounts the user picks: with Windows Hello when desktop has
+/// biometric unlock on for the account, else with the master password.
+fn wizard_import(kmgr: &KeyManager) -> Result<(), String> {
+    let biometric_ids = biometric_user_ids().unwrap_or_default();
+    let accounts = default_data_path()
+        .and_then(|path| read_accounts(&path).ok())
+        .unwrap_or_default();
+    let mut user_ids = accounts
+        .iter()
+        .map(|a| a.user_id().to_string())
+        .collect::<Vec<_>>();
+    user_ids.extend(
+        biometric_ids
+            .iter()
+            .filter(|id| !user_ids.contains(id))
+            .cloned()
+            .collect::<Vec<_>>(),
+    );
+    if user_ids.is_empty() {
+        println!("Bitwarden desktop isn't set up on this PC.");
+        let paste = Confirm::new()
+            .with_prompt("Import a user key by hand instead?")
+            .default(true)
+            .interact()
+            .unwrap_or(false);
+        if paste {
+            import_key_flow(kmgr)?;
+        }
+        return Ok(());
+    }
+
+    let account = |user_id: &str| accounts.iter().find(|a| a.user_id() == user_id);
+    let items = user_ids
+        .iter()
+        .map(|id| match account(id) {
+            Some(a) => format!("{} ({id})", a.email()),
+            None => id.clone(),
+        })
+        .collect::<Vec<_>>();
+    let chosen = MultiSelect::new()
+        .with_prompt("Import which Bitwarden desktop accounts? (space to toggle, enter to confirm)")
+        .items(&items)
+        .defaults(&vec![true; items.len()])
+        .interact()
+        .map_err(|e| format!("Failed to read selection: {e}"))?;
+
+    // Prompts come up between accounts, so a count stands in for a progress bar.
+    let total = chosen.len();
+    for (n, i) in chosen.into_iter().enumerate() {
+        let user_id = &user_ids[i];
+        println!("Account {} of {total}: {}", n + 1, items[i]);
+        if biometric_ids.contains(user_id) {
+            println!("Confirm with Windows Hello to take over {}.", items[i]);
+            match kmgr.import_desktop_biometric(user_id) {
+                Ok(_) => {
+                    println!("Key for {} imported.", items[i]);
+                    continue;
+                }
+                Err(e) => eprintln!("Failed to take over desktop's biometric unlock: {e}"),
+            }
+        }
+        let Some(account) = account(user_id) else {
+            continue;
+        };
+        for attempt in 1..=3 {
+            let Ok(password) = Password::new()
+                .with_prompt(format!("Master password for {}", account.email()))
+                .interact()
+                .map(SecretString::from)
+            else {
+                break;
+            };
+            match kmgr.import_desktop_account(account, password.expose()) {
+                Ok(_) => {
+                    println!("Key for {} imported.", items[i]);
+                    break;
+                }
+                Err(e) if attempt < 3 => eprintln!("{e}; try again."),
+                Err(e) => eprintln!("{e}; skipping {}.", account.email()),
+            }
+        }
+    }
+    Ok(())
+}
+
 fn list_keys_menu(kmgr: &KeyManager) -> Result<(), String> {
     match kmgr.list_keys() {
         Ok(listed) => {
@@ -195,25 +412,30 @@ fn list_keys_menu(kmgr: &KeyManager) -> Result<(), String> {
                 println!("No keys found.");
                 return Ok(());
             }
-            let mut items = listed.clone();
+            let mut items = listed
+                .iter()
+                .map(|k| format!("{}{}", k.user_id, describe_metadata(&k.metadata)))
+                .collect::<Vec<_>>();
             items.push("<Back>".to_string());
             let sel = Select::new().items(&items).default(0).interact();
-            if let Ok(idx) = sel {
-                if idx < listed.len() {
-                    let selected = &listed[idx];
-                    let actions = vec!["Export", "Delete", "Back"];
-                    if let Ok(a) = Select::new().items(&actions).default(0).interact() {
-                        match a {
-                            0 => match kmgr.export_key(selected) {
-                                Ok(k) => println!("{k}"),
-                                Err(e) => eprintln!("Failed to export key: {e}"),
-                            },
-                            1 => match kmgr.delete_key(selected) {
+            if let Ok(idx) = sel
+                && idx < listed.len()
+            {
+                let selected = &listed[idx].user_id;
+                let actions = vec!["Export", "Delete", "Back"];
+                if let Ok(a) = Select::new().items(&actions).default(0).interact() {
+                    match a {
+                        0 => match kmgr.export_key(selected) {
+                            Ok(k) => println!("{}", k.expose()),
+                            Err(e) => eprintln!("Failed to export key: {e}"),
+                        },
+                        1 if confirm_delete_key(kmgr, selected) => {
+                            match kmgr.delete_key(selected) {
                                 Ok(_) => println!("Key deleted."),
                                 Err(e) => eprintln!("Failed to delete key: {e}"),
-                            },
-                            _ => {}
+                            }
                         }
+                        _ => {}
                     }
                 }
             }
@@ -224,8 +446,120 @@ fn list_keys_menu(kmgr: &KeyManager) -> Result<(), String> {
     Ok(())
 }
 
+/// Lists what is about to be removed and asks for `word` to be typed back; anything else
+/// cancels.
+fn typed_confirmation(removed: &[String], word: &str) -> bool {
+    println!("This removes:");
+    for item in removed {
+        println!("  - {item}");
+    }
+    Input::<String>::new()
+        .with_prompt(format!("Type {word} to confirm, or press Enter to cancel"))
+        .allow_empty(true)
+        .interact_text()
+        .is_ok_and(|typed| typed.trim() == word)
+}
+
+fn confirm_delete_key(kmgr: &KeyManager, user_id: &str) -> bool {
+    let mut removed = vec![format!("the stored key for {user_id} and its metadata")];
+    if kmgr.has_dpapi_copy(user_id).unwrap_or(false) {
+        removed.push("its DPAPI copy".to_string());
+    }
+    typed_confirmation(&removed, user_id)
+}
+
+/// What `perform_uninstall` removes with the default options, for the typed confirmation.
+fn confirm_uninstall(kmgr: &KeyManager, install_dir: &Path, key_dir: &Path) -> bool {
+    let mut removed = vec![format!("the install directory {}", install_dir.display())];
+    let keys = kmgr.list_keys().map(|keys| keys.len()).unwrap_or_default();
+    removed.push(format!("{keys} stored keys in {}", key_dir.display()));
+    let mut key_paths = detect_browsers()
+        .into_iter()
+        .filter(|b| matches!(b.target, Some(ManifestTarget::Bwbio(_))))
+        .filter_map(|b| NATIVE_MESSAGING_HOSTS.iter().find(|h| h.id == b.id))
+        .map(|h| h.key_path)
+        .collect::<Vec<_>>();
+    key_paths.sort_unstable();
+    key_paths.dedup();
+    removed.extend(key_paths.into_iter().map(|path| {
+        format!(
+            "the registry key HKEY_CURRENT_USER\\{path} (a registration bwbio replaced is put back)"
+        )
+    }));
+    if let Ok(shortcut) = start_menu_shortcut()
+        && shortcut.exists()
+    {
+        removed.push(format!("the Start Menu shortcut {}", shortcut.display()));
+    }
+    if autostart_entry().is_some() {
+        removed.push("the logon autostart entry".to_string());
+    }
+    if !machine_key_selected()
+        && let Ok(store) = open_key_store(key_dir.to_path_buf())
+    {
+        let key_name = active_cng_key_name(&*store, config().cng_key_name());
+        removed.push(format!("the CNG key {key_name}"));
+    }
+    typed_confirmation(&removed, "DELETE")
+}
+
+/// Asks whether to keep the stored keys for a later reinstall or remove everything, and
+/// uninstalls after the matching confirmation. Returns whether bwbio was uninstalled.
+fn uninstall_flow(kmgr: &KeyManager, install_dir: &Path, key_dir: &Path) -> Result<bool, String> {
+    let items = [
+        "Remove bwbio but keep my keys (reinstalling picks them up again)",
+        "Remove everything, including the stored keys and the CNG key",
+        "Cancel",
+    ];
+    let keep_keys = match Select::new().items(&items).default(0).interact() {
+        Ok(0) => true,
+        Ok(1) => false,
+        _ => return Ok(false),
+    };
+    let confirmed = if keep_keys {
+        Confirm::new()
+            .with_prompt(format!(
+                "Remove the browser integration and {}, keeping the keys in {}?",
+                install_dir.display(),
+                key_dir.display()
+            ))
+            .default(false)
+            .interact()
+            .unwrap_or(false)
+    } else {
+        confirm_uninstall(kmgr, install_dir, key_dir)
+    };
+    if !confirmed {
+        return Ok(false);
+    }
+    perform_uninstall(
+        install_dir,
+        key_dir,
+        UninstallOptions {
+            keep_keys,
+            ..Default::default()
+        },
+    )
+    .map_err(|e| format!("{e:#}"))?;
+    if keep_keys {
+        println!(
+            "Uninstall finished; keys kept in {}. Install again to use them.",
+            key_dir.display()
+        );
+    } else {
+        println!("Uninstall finished.");
+    }
+    Ok(true)
+}
+
 fn init_menu(kmgr: &KeyManager, install_dir: &Path, key_dir: &Path) -> Result<(), String> {
-    let items = vec!["Import key", "Uninstall", "Exit"];
+    let items = vec![
+        "Import key",
+        "Import from Bitwarden desktop",
+        "Restore from backup",
+        "Uninstall",
+        "Exit",
+    ];
     let selection = Select::new().items(&items).default(0).interact();
     if let Ok(choice) = selection {
         match choice {
@@ -233,19 +567,13 @@ fn init_menu(kmgr: &KeyManager, install_dir: &Path, key_dir: &Path) -> Result<()
                 import_key_flow(kmgr)?;
             }
             1 => {
-                if Confirm::new()
-                    .with_prompt("Are you sure you want to uninstall? This will remove keys and integrations.")
-                    .default(false)
-                    .interact()
-                    .unwrap_or(false)
-                    && Confirm::new()
-                        .with_prompt("This action is irreversible. Confirm uninstall again?")
-                        .default(false)
-                        .interact()
-                        .unwrap_or(false)
-                {
-                    perform_uninstall(install_dir, key_dir)?;
-                    println!("Uninstall finished.");
+                import_desktop_flow(kmgr)?;
+            }
+            2 => {
+                restore_flow(kmgr)?;
+            }
+            3 => {
+                if uninstall_flow(kmgr, install_dir, key_dir)? {
                     return Ok(());
                 }
             }
@@ -256,13 +584,120 @@ fn init_menu(kmgr: &KeyManager, install_dir: &Path, key_dir: &Path) -> Result<()
     Ok(())
 }
 
-fn management_menu(kmgr: &KeyManager, install_dir: &Path, key_dir: &Path) -> Result<(), String> {
+fn backup_flow(kmgr: &KeyManager) -> Result<(), String> {
+    let path = Input::<String>::new()
+        .with_prompt("Backup file")
+        .interact_text()
+        .map_err(|e| format!("Failed to read path: {e}"))?;
+    let passphrase = Password::new()
+        .with_prompt("Backup passphrase")
+        .with_confirmation("Confirm passphrase", "Passphrases don't match")
+        .interact()
+        .map_err(|e| format!("Failed to read passphrase: {e}"))?;
+    match kmgr.backup(Path::new(path.trim()), SecretString::from(passphrase)) {
+        Ok(n) => println!("{n} key(s) backed up."),
+        Err(e) => eprintln!("Failed to back up keys: {e}"),
+    }
+    Ok(())
+}
+
+fn restore_flow(kmgr: &KeyManager) -> Result<(), String> {
+    let path = Input::<String>::new()
+        .with_prompt("Backup file")
+        .interact_text()
+        .map_err(|e| format!("Failed to read path: {e}"))?;
+    let passphrase = Password::new()
+        .with_prompt("Backup passphrase")
+        .interact()
+        .map_err(|e| format!("Failed to read passphrase: {e}"))?;
+    match kmgr.restore(Path::new(path.trim()), SecretString::from(passphrase)) {
+        Ok(n) => println!("{n} key(s) restored."),
+        Err(e) => eprintln!("Failed to restore keys: {e}"),
+    }
+    Ok(())
+}
+
+fn rotate_key_flow(kmgr: &mut KeyManager) -> Result<(), String> {
+    if !kmgr.uses_cng() {
+        println!("Key rotation needs the CNG backend.");
+        return Ok(());
+    }
+    let new_name = match Input::<String>::new()
+        .with_prompt("New CNG key name")
+        .interact_text()
+    {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Failed to read key name: {e}")),
+    };
+    let progress = Progress::spinner("Rotating the CNG key");
+    let rotated = kmgr.rotate_cng_key(new_name.trim(), &mut |step| progress.step(step));
+    drop(progress);
+    match rotated {
+        Ok(n) => println!("CNG key rotated; {n} key(s) re-wrapped."),
+        Err(e) => eprintln!("Failed to rotate CNG key: {e}"),
+    }
+    Ok(())
+}
+
+/// What an unlock depends on, shown above the management menu so a broken setup is obvious.
+fn print_status_header(kmgr: &KeyManager) {
+    let biometrics = biometrics_details().status;
+    let provider = match kmgr.cng_provider() {
+        Ok(Some(provider)) => provider.kind().to_string(),
+        Ok(None) => "software key wrap (no TPM)".to_string(),
+        Err(e) => format!("unavailable ({e})"),
+    };
+    let keys = match kmgr.list_keys() {
+        Ok(keys) => keys.len().to_string(),
+        Err(e) => format!("unreadable ({e})"),
+    };
+    let browsers = detect_browsers()
+        .into_iter()
+        .filter(|b| b.installed.is_some() || b.target.is_some())
+        .map(|b| {
+            let state = match b.target {
+                Some(ManifestTarget::Bwbio(_)) => "bwbio",
+                Some(ManifestTarget::BitwardenDesktop(_)) => "Bitwarden desktop",
+                Some(ManifestTarget::Other(_)) => "another host",
+                Some(ManifestTarget::Unreadable) => "broken manifest",
+                None => "not registered",
+            };
+            format!("{} ({state})", b.name)
+        })
+        .collect::<Vec<_>>();
+    println!();
+    println!("Windows Hello:  {biometrics}");
+    println!("Key provider:   {provider}");
+    println!("Stored keys:    {keys}");
+    println!(
+        "Browsers:       {}",
+        if browsers.is_empty() {
+            "none found".to_string()
+        } else {
+            browsers.join(", ")
+        }
+    );
+    println!();
+}
+
+fn management_menu(
+    kmgr: &mut KeyManager,
+    install_dir: &Path,
+    key_dir: &Path,
+) -> Result<(), String> {
     loop {
+        print_status_header(kmgr);
         let items = vec![
             "Import key",
+            "Import from Bitwarden desktop",
             "List keys",
             "Install browser integration",
             "Remove browser integration",
+            "Run crypto self-test",
+            "Rotate CNG key",
+            "Back up keys",
+            "Restore from backup",
+            "Lock now",
             "Uninstall",
             "Exit",
         ];
@@ -272,71 +707,171 @@ fn management_menu(kmgr: &KeyManager, install_dir: &Path, key_dir: &Path) -> Res
                 import_key_flow(kmgr)?;
             }
             Ok(1) => {
-                list_keys_menu(kmgr)?;
+                import_desktop_flow(kmgr)?;
             }
             Ok(2) => {
-                let manifest_path = install_dir.join(MANIFEST_NAME);
-                // register_native_messaging_manifest will canonicalize the path and return a
-                // useful error if the file does not exist.
-                match register_native_messaging_manifest(manifest_path.as_path()) {
-                    Ok(_) => println!("Browser integration installed/updated."),
-                    Err(e) => eprintln!("Failed to write registry manifest: {e}"),
-                }
+                list_keys_menu(kmgr)?;
             }
             Ok(3) => {
-                unregister_native_messaging_manifest();
-                println!("Browser integration removed.");
+                // Rewrites the manifests too, so a browser family added since is covered.
+                match choose_browsers().and_then(|hosts| {
+                    unregister_native_messaging_manifest(&NATIVE_MESSAGING_HOSTS);
+                    let installed =
+                        perform_install(install_dir, &hosts, &Progress::spinner("Installing"));
+                    match installed {
+                        Ok(_) => Ok(()),
+                        Err(e) => retry_registration_elevated(e, install_dir, &hosts),
+                    }
+                }) {
+                    Ok(_) => println!("Browser integration installed/updated."),
+                    Err(e) => eprintln!("Failed to write registry manifest: {e:#}"),
+                }
             }
             Ok(4) => {
-                if Confirm::new()
-                    .with_prompt("Are you sure you want to uninstall? This will remove keys and integrations.")
-                    .default(false)
-                    .interact()
-                    .unwrap_or(false)
-                    && Confirm::new()
-                        .with_prompt("This action is irreversible. Confirm uninstall again?")
-                        .default(false)
-                        .interact()
-                        .unwrap_or(false)
-                {
-                    perform_uninstall(install_dir, key_dir)?;
-                    println!("Uninstall finished.");
-                    return Ok(());
-                }
+                unregister_native_messaging_manifest(&NATIVE_MESSAGING_HOSTS);
+                println!(
+                    "Browser integration removed; registrations bwbio replaced were restored."
+                );
+            }
+            Ok(5) => match self_test() {
+                Ok(_) => println!("Crypto self-test passed."),
+                Err(e) => eprintln!("Crypto self-test failed: {e}"),
+            },
+            Ok(6) => {
+                rotate_key_flow(kmgr)?;
+            }
+            Ok(7) => {
+                backup_flow(kmgr)?;
             }
-            Ok(5) | Err(_) => return Ok(()),
+            Ok(8) => {
+                restore_flow(kmgr)?;
+            }
+            Ok(9) => match kmgr.lock_keys() {
+                Ok(_) => println!("Cached keys cleared."),
+                Err(e) => eprintln!("Failed to lock keys: {e}"),
+            },
+            Ok(10) if uninstall_flow(kmgr, install_dir, key_dir)? => return Ok(()),
+            Ok(11) | Err(_) => return Ok(()),
             _ => {}
         }
     }
 }
 
+/// Warns when browsers start Bitwarden desktop instead of bwbio and offers to take them over.
+fn offer_desktop_takeover(install_dir: &Path) {
+    let conflicts = desktop_conflicts();
+    if conflicts.is_empty() {
+        return;
+    }
+    let names = conflicts
+        .iter()
+        .map(|h| h.browser)
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("Warning: {names} start Bitwarden desktop's native messaging host, not bwbio.");
+    let take_over = Confirm::new()
+        .with_prompt(
+            "Register bwbio for them? (\"Remove browser integration\" gives them back to Bitwarden desktop)",
+        )
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+    if !take_over {
+        return;
+    }
+    match register_native_messaging_manifest(install_dir, &conflicts)
+        .map(|_| ())
+        .or_else(|e| retry_registration_elevated(e, install_dir, &conflicts))
+    {
+        Ok(_) => println!("bwbio registered for {names}."),
+        Err(e) => eprintln!("Failed to register bwbio: {e}"),
+    }
+}
+
+/// Shows an "Update available" banner when a newer release is out and offers to install it.
+/// Returns whether the updated executable was started in place of this one. Without network
+/// access nothing is shown.
+fn offer_update(install_dir: &Path) -> bool {
+    let Ok(Some(release)) = check_for_update() else {
+        return false;
+    };
+    println!(
+        "Update available: bwbio {} (running {}), {}",
+        release.version,
+        env!("CARGO_PKG_VERSION"),
+        release.url
+    );
+    let update = Confirm::new()
+        .with_prompt("Download and install it now?")
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+    if !update {
+        return false;
+    }
+    let updated = select_browsers(None)
+        .and_then(|hosts| {
+            let progress = Progress::spinner("Updating bwbio");
+            apply_update(install_dir, &release, &hosts, &progress)
+        })
+        .map_err(|e| format!("Update failed: {e:#}"))
+        .and_then(|exe| spawn_and_exit(&exe));
+    match updated {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("{e}");
+            false
+        }
+    }
+}
+
 fn run_installed_flow(install_dir: &Path, current_exe: &Path) -> Result<(), String> {
     println!("Running from installed location: {}", current_exe.display());
+    if offer_update(install_dir) {
+        return Ok(());
+    }
 
-    let key_name = match env::var("CNG_KEY_NAME") {
-        Ok(s) => HSTRING::from(s),
-        Err(_) => default_key_name(),
-    };
-    let key_dir = env::var("BW_KEY_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| {
-            current_exe
-                .parent()
-                .expect("Failed to get parent dir")
-                .to_path_buf()
-                .join("keys")
-        });
-
-    let kmgr = KeyManager::new(key_name, key_dir.clone());
+    let key_name = config().cng_key_name();
+    let key_dir = key_dir_for(current_exe.parent().expect("Failed to get parent dir"));
+
+    let mut kmgr = KeyManager::new(key_name, key_dir.clone())
+        .map_err(|e| e.to_string())?
+        .with_audit_source(AuditSource::Tui);
+    println!("Keys protected by: {}", kmgr.protection());
+    if kmgr
+        .cng_provider()
+        .is_ok_and(|p| p.is_some_and(|p| p.kind() == CngProviderKind::Software))
+    {
+        println!(
+            "Warning: no TPM available; keys are protected by the software key provider only."
+        );
+    }
+    let biometrics = biometrics_details();
+    if biometrics.status != BiometricsStatus::Available {
+        println!("Warning: {}.", biometrics.status);
+    }
+    for note in biometrics.notes() {
+        println!("Note: {note}.");
+    }
+    offer_desktop_takeover(install_dir);
 
     match kmgr.list_keys() {
-        Ok(keys) => {
-            if keys.is_empty() {
-                init_menu(&kmgr, install_dir, &key_dir)?;
+        Ok(keys) if keys.is_empty() => {
+            let wizard = Confirm::new()
+                .with_prompt("No keys are stored yet. Run the setup wizard?")
+                .default(true)
+                .interact()
+                .unwrap_or(false);
+            if wizard {
+                setup_wizard(&kmgr, install_dir)?;
+            }
+            if kmgr.list_keys().is_ok_and(|keys| !keys.is_empty()) {
+                dashboard_flow(&mut kmgr, install_dir, &key_dir)?;
             } else {
-                management_menu(&kmgr, install_dir, &key_dir)?;
+                init_menu(&kmgr, install_dir, &key_dir)?;
             }
         }
+        Ok(_) => dashboard_flow(&mut kmgr, install_dir, &key_dir)?,
         Err(e) => return Err(format!("Failed to list keys: {e}")),
     }
 
@@ -344,17 +879,23 @@ fn run_installed_flow(install_dir: &Path, current_exe: &Path) -> Result<(), Stri
 }
 
 pub fn tui_cli() {
-    let local_appdata = match env::var("LOCALAPPDATA") {
-        Ok(s) => PathBuf::from(s),
-        Err(_) => {
-            eprintln!("LOCALAPPDATA not set. Cannot determine install path.");
+    let install_dir = match default_install_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("{e}");
             pause_before_exit();
             return;
         }
     };
+    // The installed log, also before bwbio is installed there, so the install is logged too.
+    init_logging(key_dir_for(&install_dir));
+    if let Err(e) = check_config() {
+        eprintln!("{e}");
+        pause_before_exit();
+        return;
+    }
 
-    let install_dir = local_appdata.join("bwbio");
-    let target_exe = install_dir.join("bwbio.exe");
+    let target_exe = install_dir.join(EXE_NAME);
     let current_exe = env::current_exe().ok();
     let current_exe_canon = current_exe
         .as_ref()
@@ -384,6 +925,29 @@ pub fn tui_cli() {
             return;
         }
     } else {
+        let install_dir = match Input::<String>::new()
+            .with_prompt("Install directory")
+            .default(install_dir.display().to_string())
+            .interact_text()
+        {
+            Ok(dir) => PathBuf::from(dir.trim()),
+            Err(e) => {
+                eprintln!("Failed to prompt for the install directory: {e}");
+                pause_before_exit();
+                return;
+            }
+        };
+        if let Some(old) = find_existing_install().filter(|old| !same_dir(old, &install_dir)) {
+            match offer_migration(&old, &install_dir) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("Moving the installation failed: {e}");
+                    pause_before_exit();
+                    return;
+                }
+            }
+        }
         let prompt = format!("Install bwbio to {}?", install_dir.display());
         match Confirm::new().with_prompt(prompt).default(false).interact() {
             Ok(true) => {
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::crypto::verify_p256_signature;
+use crate::install::{
+    EXE_NAME, NativeMessagingHost, record_exe_hash, register_native_messaging_manifest, sha256_hex,
+    write_manifests,
+};
+use crate::progress::Progress;
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    ffi::c_void,
+    fs::{canonicalize, remove_file, rename, write},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+use windows::Win32::Networking::WinHttp::{
+    INTERNET_DEFAULT_HTTPS_PORT, WINHTTP_ACCESS_TYPE_AUTOMATIC_PROXY, WINHTTP_FLAG_SECURE,
+    WINHTTP_QUERY_FLAG_NUMBER, WINHTTP_QUERY_STATUS_CODE, WinHttpCloseHandle, WinHttpConnect,
+    WinHttpOpen, WinHttpOpenRequest, WinHttpQueryHeaders, WinHttpReadData, WinHttpReceiveResponse,
+    WinHttpSendRequest,
+};
+use windows::core::PCWSTR;
+use windows_strings::HSTRING;
+
+/// Name of the release asset holding the signature of [`EXE_NAME`]: ECDSA P-256 over SHA-256,
+/// DER-encoded, as written by `openssl dgst -sha256 -sign release.pem -out bwbio.exe.sig bwbio.exe`.
+pub const SIGNATURE_ASSET_NAME: &str = "bwbio.exe.sig";
+
+/// The public half of the release signing key, a base64 SEC1 point, set through
+/// `BWBIO_RELEASE_KEY` when building. Builds without it can't update themselves.
+const RELEASE_KEY: Option<&str> = option_env!("BWBIO_RELEASE_KEY");
+
+/// The newest published release.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Release {
+    pub version: String,
+    pub url: String,
+    #[serde(skip)]
+    exe_url: String,
+    #[serde(skip)]
+    sig_url: String,
+}
+
+impl Release {
+    /// Whether this release is newer than the running executable.
+    pub fn is_newer(&self) -> bool {
+        version_parts(&self.version) > version_parts(env!("CARGO_PKG_VERSION"))
+    }
+}
+
+/// Asks GitHub for the latest release of the repository bwbio was built from. Drafts and
+/// pre-releases are never offered.
+pub fn latest_release() -> Result<Release> {
+    let repo = env!("CARGO_PKG_REPOSITORY")
+        .strip_prefix("https://github.com/")
+        .ok_or_else(|| anyhow!("bwbio wasn't built from a GitHub repository"))?;
+    let body = http_get(&format!(
+        "https://api.github.com/repos/{repo}/releases/latest"
+    ))
+    .context("Failed to look up the latest release")?;
+    let release: Value = serde_json::from_slice(&body).context("Invalid release information")?;
+    let asset_url = |name: &str| {
+        release["assets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|asset| asset["name"] == name)
+            .and_then(|asset| asset["browser_download_url"].as_str())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("The latest release has no {name}"))
+    };
+    let version = release["tag_name"]
+        .as_str()
+        .ok_or_else(|| anyhow!("The latest release has no tag"))?;
+    Ok(Release {
+        version: version.trim_start_matches('v').to_string(),
+        url: release["html_url"].as_str().unwrap_or_default().to_string(),
+        exe_url: asset_url(EXE_NAME)?,
+        sig_url: asset_url(SIGNATURE_ASSET_NAME)?,
+    })
+}
+
+/// The latest release when it is newer than the running executable.
+pub fn check_for_update() -> Result<Option<Release>> {
+    let release = latest_release()?;
+    Ok(release.is_newer().then_some(release))
+}
+
+/// Downloads `release`, checks its signature against [`RELEASE_KEY`], puts it in place of the
+/// executable in `install_dir` and registers it with `hosts` again, showing each step on
+/// `progress`. The replaced executable may still be running, so it is renamed rather than
+/// overwritten and removed on the next update.
+pub fn apply_update(
+    install_dir: &Path,
+    release: &Release,
+    hosts: &[NativeMessagingHost],
+    progress: &Progress,
+) -> Result<PathBuf> {
+    let release_key = RELEASE_KEY
+        .ok_or_else(|| {
+            anyhow!("This build has no release signing key, so updates can't be verified")
+        })
+        .and_then(|key| {
+            STANDARD
+                .decode(key.trim())
+                .context("The release signing key isn't valid base64")
+        })?;
+    progress.step(&format!("Downloading bwbio {}", release.version));
+    let exe = http_get(&release.exe_url).context("Failed to download the update")?;
+    let signature =
+        http_get(&release.sig_url).context("Failed to download the update's signature")?;
+    verify_p256_signature(&release_key, &exe, &signature).map_err(|e| {
+        anyhow!("The downloaded executable fails the check against {SIGNATURE_ASSET_NAME} ({e}); nothing was changed")
+    })?;
+
+    progress.step("Putting the update in place");
+    let target = install_dir.join(EXE_NAME);
+    let staged = install_dir.join("bwbio.new.exe");
+    let old = install_dir.join("bwbio.old.exe");
+    match remove_file(&old) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).context("A previous update's executable is still in use");
+        }
+        _ => {}
+    }
+    write(&staged, &exe).context("Failed to write the update")?;
+    if target.exists() {
+        rename(&target, &old).context("Failed to move the current executable aside")?;
+    }
+    if let Err(e) = rename(&staged, &target) {
+        let _ = rename(&old, &target);
+        return Err(e).context("Failed to put the update in place");
+    }
+
+    if let Err(e) = record_exe_hash(&sha256_hex(&exe)) {
+        warn!("Failed to record the executable's hash: {e}");
+    }
+    let target = canonicalize(&target).unwrap_or(target);
+    progress.step("Registering with the browsers");
+    write_manifests(install_dir, &target)?;
+    register_native_messaging_manifest(install_dir, hosts)
+        .context("Failed to write registry entries")?;
+    info!(version = release.version, "Updated");
+    Ok(target)
+}
+
+/// `1.2.3` from `1.2.3` or `1.2.3-beta`; missing or malformed parts count as 0.
+fn version_parts(version: &str) -> Vec<u64> {
+    let version = version.trim_start_matches('v');
+    let version = version.split(['-', '+']).next().unwrap_or_default();
+    version.split('.').map(|p| p.parse().unwrap_or(0)).collect()
+}
+
+/// Closes a WinHTTP handle when dropped.
+struct HttpHandle(*mut c_void);
+
+impl HttpHandle {
+    fn new(handle: *mut c_void) -> Result<Self> {
+        if handle.is_null() {
+            return Err(windows::core::Error::from_win32().into());
+        }
+        Ok(Self(handle))
+    }
+}
+
+impl Drop for HttpHandle {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = WinHttpCloseHandle(self.0);
+        }
+    }
+}
+
+/// The body of a `GET` for an `https://` URL, following redirects. Anything but 200 is an
+/// error.
+fn http_get(url: &str) -> Result<Vec<u8>> {
+    let rest = url
+        .strip_prefix("https://")
+        .ok_or_else(|| anyhow!("Only https URLs can be fetched: {url}"))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let agent = HSTRING::from(concat!("bwbio/", env!("CARGO_PKG_VERSION")));
+    unsafe {
+        let session = HttpHandle::new(WinHttpOpen(
+            &agent,
+            WINHTTP_ACCESS_TYPE_AUTOMATIC_PROXY,
+            PCWSTR::null(),
+            PCWSTR::null(),
+            0,
+        ))?;
+        let connection = HttpHandle::new(WinHttpConnect(
+            session.0,
+            &HSTRING::from(host),
+            INTERNET_DEFAULT_HTTPS_PORT,
+            0,
+        ))?;
+        let request = HttpHandle::new(WinHttpOpenRequest(
+            connection.0,
+            &HSTRING::from("GET"),
+            &HSTRING::from(path),
+            PCWSTR::null(),
+            PCWSTR::null(),
+            std::ptr::null(),
+            WINHTTP_FLAG_SECURE,
+        ))?;
+        WinHttpSendRequest(request.0, None, None, 0, 0, 0)?;
+        WinHttpReceiveResponse(request.0, std::ptr::null_mut())?;
+
+        let mut status = 0u32;
+        let mut len = size_of::<u32>() as u32;
+        WinHttpQueryHeaders(
+            request.0,
+            WINHTTP_QUERY_STATUS_CODE | WINHTTP_QUERY_FLAG_NUMBER,
+            PCWSTR::null(),
+            Some(&mut status as *mut u32 as *mut c_void),
+            &mut len,
+            std::ptr::null_mut(),
+        )?;
+        if status != 200 {
+            bail!("{url} answered with HTTP {status}");
+        }
+
+        let mut body = Vec::new();
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            let mut read = 0u32;
+            WinHttpReadData(
+                request.0,
+                chunk.as_mut_ptr() as *mut c_void,
+                chunk.len() as u32,
+                &mut read,
+            )?;
+            if read == 0 {
+                return Ok(body);
+            }
+            body.extend_from_slice(&chunk[..read as usize]);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2025 Aalivexy
+
+use crate::browser::{CHROMIUM_EXTENSION_ORIGINS, FIREFOX_EXTENSION_ID};
+use crate::error::Result;
+use serde_json::{Value, json};
+use std::{
+    env,
+    fs::{create_dir_all, remove_file, write},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Debug, Error)]
+pub enum XdgError {
+    #[error("HOME not set")]
+    NoHome,
+    #[error("Unknown browser {id:?}; expected one of {known}", known = known_browsers())]
+    UnknownBrowser { id: String },
+    #[error("No supported browser found; name one with --browser")]
+    NoBrowserFound,
+    #[error("No browser could be registered")]
+    NoBrowserRegistered,
+    #[error("Failed to write {}: {source}", .path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// File name browsers look for in their native messaging host directories.
+pub const MANIFEST_FILE_NAME: &str = "com.8bit.bitwarden.json";
+
+/// A browser that loads native messaging hosts from a per-user directory on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XdgHost {
+    /// Name used by `--browser`.
+    pub id: &'static str,
+    pub browser: &'static str,
+    /// Directory holding the manifests, relative to `$XDG_CONFIG_HOME`, or to the home
+    /// directory for Firefox.
+    pub dir: &'static str,
+    pub firefox: bool,
+}
+
+pub const XDG_HOSTS: [XdgHost; 6] = [
+    XdgHost {
+        id: "chrome",
+        browser: "Google Chrome",
+        dir: "google-chrome/NativeMessagingHosts",
+        firefox: false,
+    },
+    XdgHost {
+        id: "chromium",
+        browser: "Chromium",
+        dir: "chromium/NativeMessagingHosts",
+        firefox: false,
+    },
+    XdgHost {
+        id: "edge",
+        browser: "Microsoft Edge",
+        dir: "microsoft-edge/NativeMessagingHosts",
+        firefox: false,
+    },
+    XdgHost {
+        id: "brave",
+        browser: "Brave",
+        dir: "BraveSoftware/Brave-Browser/NativeMessagingHosts",
+        firefox: false,
+    },
+    XdgHost {
+        id: "vivaldi",
+        browser: "Vivaldi",
+        dir: "vivaldi/NativeMessagingHosts",
+        firefox: false,
+    },
+    XdgHost {
+        id: "firefox",
+        browser: "Mozilla Firefox",
+        dir: ".mozilla/native-messaging-hosts",
+        firefox: true,
+    },
+];
+
+impl XdgHost {
+    /// Where this browser reads the `com.8bit.bitwarden` manifest.
+    pub fn manifest_path(&self) -> Result<PathBuf> {
+        let base = if self.firefox {
+            home_dir()?
+        } else {
+            config_home()?
+        };
+        Ok(base.join(self.dir).join(MANIFEST_FILE_NAME))
+    }
+
+    /// Whether the browser has a profile directory for the user, so it has been run.
+    pub fn is_installed(&self) -> bool {
+        self.manifest_path()
+            .ok()
+            .and_then(|path| path.parent()?.parent().map(Path::exists))
+            .unwrap_or(false)
+    }
+}
+
+fn home_dir() -> Result<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| XdgError::NoHome.into())
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config` when unset.
+pub fn config_home() -> Result<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(home_dir()?.join(".config")),
+    }
+}
+
+/// `$XDG_DATA_HOME`, or `~/.local/share` when unset.
+pub fn data_home() -> Result<PathBuf> {
+    match env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(home_dir()?.join(".local").join("share")),
+    }
+}
+
+/// The browsers with these ids, or every browser that has been run when `ids` is empty.
+pub fn select_hosts(ids: &[String]) -> Result<Vec<XdgHost>> {
+    if ids.is_empty() {
+        return Ok(XDG_HOSTS
+            .into_iter()
+            .filter(XdgHost::is_installed)
+            .collect());
+    }
+    ids.iter()
+        .map(|id| {
+            XDG_HOSTS
+                .into_iter()
+                .find(|host| host.id.eq_ignore_ascii_case(id))
+                .ok_or_else(|| XdgError::UnknownBrowser { id: id.clone() }.into())
+        })
+        .collect()
+}
+
+fn known_browsers() -> String {
+    XDG_HOSTS.map(|host| host.id).join(", ")
+}
+
+/// The `com.8bit.bitwarden` manifest starting `exe`, allowing the Bitwarden extension of
+/// Firefox or of the Chromium-based browsers.
+pub fn native_messaging_manifest(exe: &Path, firefox: bool) -> Value {
+    let mut manifest = json!({
+        "name": "com.8bit.bitwarden",
+        "description": "Bitwarden desktop <-> browser bridge",
+        "path": exe,
+        "type": "stdio",
+    });
+    if firefox {
+        manifest["allowed_extensions"] = json!([FIREFOX_EXTENSION_ID]);
+    } else {
+        manifest["allowed_origins"] = json!(CHROMIUM_EXTENSION_ORIGINS);
+    }
+    manifest
+}
+
+/// Writes a manifest starting `exe` for each browser in `hosts`. Returns the ids of the
+/// browsers registered; failures for single browsers are only warned about.
+pub fn register_manifests(exe: &Path, hosts: &[XdgHost]) -> Result<Vec<&'static str>> {
+    if hosts.is_empty() {
+        return Err(XdgError::NoBrowserFound.into());
+    }
+    let mut registered = Vec::new();
+    for host in hosts {
+        let written = host.manifest_path().and_then(|path| {
+            let manifest =
+                serde_json::to_vec_pretty(&native_messaging_manifest(exe, host.firefox))?;
+            create_dir_all(path.parent().expect("manifest path has a parent"))
+                .and_then(|_| write(&path, manifest))
+                .map_err(|source| XdgError::Write { path, source }.into())
+        });
+        match written {
+            Ok(()) => registered.push(host.id),
+            Err(e) => eprintln!("Warning: failed to register with {}: {e}", host.browser),
+        }
+    }
+    if registered.is_empty() {
+        return Err(XdgError::NoBrowserRegistered.into());
+    }
+    info!(browsers = ?registered, "Registered");
+    Ok(registered)
+}
+
+/// Removes the manifests of every supported browser. Returns the ids of the browsers that had
+/// one.
+pub fn unregister_manifests() -> Vec<&'static str> {
+    let mut removed = Vec::new();
+    for host in XDG_HOSTS {
+        let Ok(path) = host.manifest_path() else {
+            continue;
+        };
+        match remove_file(&path) {
+            Ok(()) => removed.push(host.id),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Warning: failed to remove {}: {e}", path.display()),
+        }
+    }
+    if !removed.is_empty() {
+        info!(browsers = ?removed, "Unregistered");
+    }
+    removed
+}